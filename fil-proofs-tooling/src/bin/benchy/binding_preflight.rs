@@ -0,0 +1,9 @@
+use anyhow::Result;
+use storage_proofs_porep::stacked::{preflight, BindingConfig};
+
+pub fn run() -> Result<()> {
+    let report = preflight(&BindingConfig::from_env());
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use anyhow::{ensure, Result};
+use log::info;
+use storage_proofs_porep::stacked::run_binding_selftest;
+
+pub fn run() -> Result<()> {
+    let report = run_binding_selftest();
+
+    info!("binding config: {:?}", report.config);
+    info!("core groups: {:?}", report.core_groups);
+    info!("P1 cores: {:?}", report.p1_cores);
+    info!("P2 cores: {:?}", report.p2_cores);
+    for step in &report.steps {
+        match &step.result {
+            Some(Ok(())) => info!("{}: ok", step.name),
+            Some(Err(err)) => info!("{}: FAILED: {}", step.name, err),
+            None => info!("{}: skipped", step.name),
+        }
+    }
+
+    ensure!(report.is_ok(), "binding self-test failed");
+    Ok(())
+}
@@ -2,6 +2,7 @@
 //#![warn(clippy::unwrap_used)]
 
 use std::io::{stdin, stdout};
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use anyhow::Result;
@@ -12,10 +13,13 @@ use storage_proofs_core::api_version::ApiVersion;
 
 use crate::prodbench::ProdbenchInputs;
 
+mod binding_preflight;
+mod binding_selftest;
 mod hash_fns;
 mod merkleproofs;
 mod prodbench;
 mod window_post;
+mod window_post_sectors;
 mod winning_post;
 
 fn main() -> Result<()> {
@@ -65,6 +69,23 @@ fn main() -> Result<()> {
                 .help("Test replication resume")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("sectors")
+                .long("sectors")
+                .required(false)
+                .help("Seal this many sectors, then benchmark only Window PoSt on them")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sector-dir")
+                .long("sector-dir")
+                .required(false)
+                .help("A sealed sector directory, as kept with --preserve-cache, to benchmark only Window PoSt on (repeatable)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("sectors"),
+        )
         .arg(
             Arg::with_name("cache")
                 .long("cache")
@@ -110,6 +131,12 @@ fn main() -> Result<()> {
     let hash_cmd = SubCommand::with_name("hash-constraints")
         .about("Benchmark hash function inside of a circuit");
 
+    let binding_selftest_cmd = SubCommand::with_name("binding-selftest")
+        .about("Check that thread binding works on this host");
+
+    let binding_preflight_cmd = SubCommand::with_name("binding-preflight")
+        .about("Print the cores the binding config would use, without binding");
+
     let prodbench_cmd = SubCommand::with_name("prodbench")
         .about("Benchmark prodbench")
         .arg(
@@ -177,9 +204,26 @@ fn main() -> Result<()> {
         .subcommand(hash_cmd)
         .subcommand(prodbench_cmd)
         .subcommand(merkleproof_cmd)
+        .subcommand(binding_selftest_cmd)
+        .subcommand(binding_preflight_cmd)
         .get_matches();
 
     match matches.subcommand() {
+        ("window-post", Some(m)) if m.is_present("sectors") || m.is_present("sector-dir") => {
+            let cache_dir = value_t!(m, "cache", String)?;
+            let sector_size = Byte::from_str(value_t!(m, "size", String)?)?.get_bytes() as usize;
+            let api_version = ApiVersion::from_str(&value_t!(m, "api_version", String)?)?;
+            let seal_count = if m.is_present("sectors") {
+                value_t!(m, "sectors", usize)?
+            } else {
+                0
+            };
+            let sector_dirs = m
+                .values_of("sector-dir")
+                .map(|dirs| dirs.map(PathBuf::from).collect())
+                .unwrap_or_default();
+            window_post_sectors::run(sector_size, api_version, cache_dir, sector_dirs, seal_count)?;
+        }
         ("window-post", Some(m)) => {
             let preserve_cache = m.is_present("preserve-cache");
             // For now these options are combined.
@@ -217,6 +261,12 @@ fn main() -> Result<()> {
             let proofs = value_t!(m, "proofs", usize)?;
             merkleproofs::run(size, proofs, m.is_present("validate"))?;
         }
+        ("binding-selftest", Some(_m)) => {
+            binding_selftest::run()?;
+        }
+        ("binding-preflight", Some(_m)) => {
+            binding_preflight::run()?;
+        }
         ("prodbench", Some(m)) => {
             let inputs: ProdbenchInputs = if m.is_present("config") {
                 let file = value_t!(m, "config", String).expect("failed to get config");
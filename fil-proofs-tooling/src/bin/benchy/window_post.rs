@@ -34,9 +34,9 @@ const SECTOR_ID: u64 = 0;
 const PIECE_FILE: &str = "piece-file";
 const PIECE_INFOS_FILE: &str = "piece-infos-file";
 const STAGED_FILE: &str = "staged-file";
-const SEALED_FILE: &str = "sealed-file";
+pub const SEALED_FILE: &str = "sealed-file";
 const PRECOMMIT_PHASE1_OUTPUT_FILE: &str = "precommit-phase1-output";
-const PRECOMMIT_PHASE2_OUTPUT_FILE: &str = "precommit-phase2-output";
+pub const PRECOMMIT_PHASE2_OUTPUT_FILE: &str = "precommit-phase2-output";
 const COMMIT_PHASE1_OUTPUT_FILE: &str = "commit-phase1-output";
 
 #[derive(Serialize, Deserialize)]
@@ -81,7 +81,7 @@ impl Report {
     }
 }
 
-fn get_porep_config(sector_size: u64, api_version: ApiVersion) -> PoRepConfig {
+pub fn get_porep_config(sector_size: u64, api_version: ApiVersion) -> PoRepConfig {
     let arbitrary_porep_id = [99; 32];
 
     // Replicate the staged sector, write the replica file to `sealed_path`.
@@ -99,9 +99,11 @@ fn get_porep_config(sector_size: u64, api_version: ApiVersion) -> PoRepConfig {
     }
 }
 
-fn run_pre_commit_phases<Tree: 'static + MerkleTreeTrait>(
+#[allow(clippy::too_many_arguments)]
+pub fn run_pre_commit_phases<Tree: 'static + MerkleTreeTrait>(
     sector_size: u64,
     api_version: ApiVersion,
+    sector_id: SectorId,
     cache_dir: PathBuf,
     skip_precommit_phase1: bool,
     skip_precommit_phase2: bool,
@@ -166,7 +168,6 @@ fn run_pre_commit_phases<Tree: 'static + MerkleTreeTrait>(
         )?;
 
         let piece_infos = vec![piece_info];
-        let sector_id = SectorId::from(SECTOR_ID);
         let porep_config = get_porep_config(sector_size, api_version);
 
         let seal_pre_commit_phase1_measurement: FuncMeasurement<SealPreCommitPhase1Output<Tree>> = measure(|| {
@@ -205,7 +206,7 @@ fn run_pre_commit_phases<Tree: 'static + MerkleTreeTrait>(
             info!("Test resume requested.  Removing last layer {:?}", layers[layers.len() - 1]);
             std::fs::remove_file(&layers[layers.len() - 1])?;
 
-            return run_pre_commit_phases::<Tree>(sector_size, api_version, cache_dir, skip_precommit_phase1, skip_precommit_phase2, false, true);
+            return run_pre_commit_phases::<Tree>(sector_size, api_version, sector_id, cache_dir, skip_precommit_phase1, skip_precommit_phase2, false, true);
         }
 
         // Persist piece_infos here
@@ -363,6 +364,7 @@ pub fn run_window_post_bench<Tree: 'static + MerkleTreeTrait>(
         run_pre_commit_phases::<Tree>(
             sector_size,
             api_version,
+            SectorId::from(SECTOR_ID),
             cache_dir.clone(),
             skip_precommit_phase1,
             skip_precommit_phase2,
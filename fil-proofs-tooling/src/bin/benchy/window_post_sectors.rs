@@ -0,0 +1,487 @@
+use std::collections::BTreeMap;
+use std::fs::{create_dir, read, remove_dir_all};
+use std::io::stdout;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Context, Result};
+use bincode::deserialize;
+use fil_proofs_tooling::shared::{PROVER_ID, RANDOMNESS};
+use fil_proofs_tooling::{measure, Metadata};
+use filecoin_proofs::constants::{WINDOW_POST_CHALLENGE_COUNT, WINDOW_POST_SECTOR_COUNT};
+use filecoin_proofs::types::{
+    FallbackPoStSectorProof, PoStConfig, SealPreCommitOutput, SectorSize,
+};
+use filecoin_proofs::{
+    generate_fallback_sector_challenges, generate_window_post_with_vanilla, verify_window_post,
+    with_shape, PoStType, PrivateReplicaInfo, PublicReplicaInfo,
+};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use storage_proofs_core::api_version::ApiVersion;
+use storage_proofs_core::merkle::MerkleTreeTrait;
+use storage_proofs_core::sector::SectorId;
+use storage_proofs_post::fallback::{self, PrivateInputs, PrivateSector, SectorProof};
+
+use crate::window_post::{run_pre_commit_phases, PRECOMMIT_PHASE2_OUTPUT_FILE, SEALED_FILE};
+
+const GPU_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Inputs {
+    sector_size: u64,
+    sector_count: usize,
+    sealed_sector_count: usize,
+    challenge_count: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Outputs {
+    seal_wall_time_ms: u64,
+    generate_challenges_wall_time_ms: u64,
+    vanilla_cpu_time_ms: u64,
+    vanilla_wall_time_ms: u64,
+    snark_cpu_time_ms: u64,
+    snark_wall_time_ms: u64,
+    verify_cpu_time_ms: u64,
+    verify_wall_time_ms: u64,
+    /// Challenges, vanilla and SNARK proving: what has to fit in a deadline.
+    prove_wall_time_ms: u64,
+    tree_open_latency: Histogram,
+    challenge_read_latency: Histogram,
+    gpu_utilization: Vec<GpuUtilization>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Report {
+    inputs: Inputs,
+    outputs: Outputs,
+}
+
+impl Report {
+    /// Print all results to stdout
+    pub fn print(&self) {
+        let wrapped = Metadata::wrap(&self).expect("failed to retrieve metadata");
+        serde_json::to_writer(stdout(), &wrapped).expect("cannot write report JSON to stdout");
+    }
+}
+
+/// Latencies in microseconds, counted in power of two buckets.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Histogram {
+    count: usize,
+    min_us: u64,
+    mean_us: u64,
+    p50_us: u64,
+    p90_us: u64,
+    p99_us: u64,
+    max_us: u64,
+    buckets: Vec<Bucket>,
+}
+
+/// The number of latencies up to `le_us`, and over the bound of the bucket before.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Bucket {
+    le_us: u64,
+    count: usize,
+}
+
+impl Histogram {
+    fn new(latencies: &[Duration]) -> Self {
+        let mut micros: Vec<u64> = latencies.iter().map(|l| l.as_micros() as u64).collect();
+        micros.sort_unstable();
+
+        let percentile = |p: usize| {
+            if micros.is_empty() {
+                0
+            } else {
+                micros[(micros.len() - 1) * p / 100]
+            }
+        };
+
+        let mut buckets: Vec<Bucket> = Vec::new();
+        for &latency in &micros {
+            let le_us = latency.max(1).next_power_of_two();
+            match buckets.last_mut() {
+                Some(bucket) if bucket.le_us == le_us => bucket.count += 1,
+                _ => buckets.push(Bucket { le_us, count: 1 }),
+            }
+        }
+
+        Histogram {
+            count: micros.len(),
+            min_us: percentile(0),
+            mean_us: micros.iter().sum::<u64>() / micros.len().max(1) as u64,
+            p50_us: percentile(50),
+            p90_us: percentile(90),
+            p99_us: percentile(99),
+            max_us: percentile(100),
+            buckets,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct GpuUtilization {
+    index: u32,
+    samples: usize,
+    mean_utilization_percent: f64,
+    max_utilization_percent: u32,
+    max_memory_used_mib: u64,
+}
+
+/// Samples the utilization of NVIDIA GPUs with `nvidia-smi`, until finished.
+struct GpuSampler {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<BTreeMap<u32, Vec<(u32, u64)>>>,
+}
+
+impl GpuSampler {
+    fn start() -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut samples = BTreeMap::new();
+                while !stop.load(Ordering::SeqCst) {
+                    match query_gpus() {
+                        Ok(gpus) => {
+                            for (index, utilization, memory_used) in gpus {
+                                samples
+                                    .entry(index)
+                                    .or_insert_with(Vec::new)
+                                    .push((utilization, memory_used));
+                            }
+                        }
+                        Err(err) => {
+                            warn!("not sampling GPU utilization: {:?}", err);
+                            break;
+                        }
+                    }
+                    thread::sleep(GPU_SAMPLE_INTERVAL);
+                }
+                samples
+            })
+        };
+
+        GpuSampler { stop, handle }
+    }
+
+    fn finish(self) -> Vec<GpuUtilization> {
+        self.stop.store(true, Ordering::SeqCst);
+        let samples = self.handle.join().expect("GPU sampler panicked");
+
+        samples
+            .into_iter()
+            .map(|(index, samples)| GpuUtilization {
+                index,
+                samples: samples.len(),
+                mean_utilization_percent: samples.iter().map(|(u, _)| f64::from(*u)).sum::<f64>()
+                    / samples.len() as f64,
+                max_utilization_percent: samples.iter().map(|(u, _)| *u).max().unwrap_or(0),
+                max_memory_used_mib: samples.iter().map(|(_, m)| *m).max().unwrap_or(0),
+            })
+            .collect()
+    }
+}
+
+/// Returns the index, utilization in percent and memory used in MiB of each GPU.
+fn query_gpus() -> Result<Vec<(u32, u32, u64)>> {
+    let output = Command::new("nvidia-smi")
+        .args(&[
+            "--query-gpu=index,utilization.gpu,memory.used",
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+        .context("could not run nvidia-smi")?;
+    ensure!(
+        output.status.success(),
+        "nvidia-smi failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            ensure!(fields.len() == 3, "unexpected nvidia-smi output {:?}", line);
+            Ok((fields[0].parse()?, fields[1].parse()?, fields[2].parse()?))
+        })
+        .collect()
+}
+
+/// Opens a sealed sector directory, as left by the Window PoSt bench with `--preserve-cache`.
+fn open_sector<Tree: 'static + MerkleTreeTrait>(
+    dir: &Path,
+) -> Result<(PrivateReplicaInfo<Tree>, PublicReplicaInfo)> {
+    let output_path = dir.join(PRECOMMIT_PHASE2_OUTPUT_FILE);
+    let output: SealPreCommitOutput = deserialize(
+        &read(&output_path).with_context(|| format!("could not read {:?}", output_path))?,
+    )?;
+
+    let priv_replica =
+        PrivateReplicaInfo::<Tree>::new(dir.join(SEALED_FILE), output.comm_r, dir.to_path_buf())?;
+    let pub_replica = PublicReplicaInfo::new(output.comm_r)?;
+
+    Ok((priv_replica, pub_replica))
+}
+
+/// `generate_single_vanilla_proof`, timing the opening of the tree and each challenge on its own.
+fn prove_sector<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    sector_id: SectorId,
+    replica: &PrivateReplicaInfo<Tree>,
+    challenges: &[u64],
+) -> Result<(FallbackPoStSectorProof<Tree>, Duration, Vec<Duration>)> {
+    let start = Instant::now();
+    let tree = replica.merkle_tree(post_config.sector_size)?;
+    let tree_open = start.elapsed();
+
+    let comm_c = replica.safe_comm_c();
+    let comm_r_last = replica.safe_comm_r_last();
+    let priv_sectors = vec![PrivateSector {
+        tree: &tree,
+        comm_c,
+        comm_r_last,
+    }];
+    let priv_inputs = PrivateInputs::<Tree> {
+        sectors: &priv_sectors,
+    };
+
+    let reads = challenges
+        .par_iter()
+        .map(|challenge| {
+            let start = Instant::now();
+            let proof = fallback::vanilla_proof(sector_id, &priv_inputs, &[*challenge])?;
+            let latency = start.elapsed();
+
+            let inclusion_proof = proof
+                .sectors
+                .into_iter()
+                .flat_map(|sector| sector.inclusion_proofs)
+                .next()
+                .context("empty vanilla proof")?;
+            Ok((inclusion_proof, latency))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let (inclusion_proofs, latencies) = reads.into_iter().unzip();
+
+    let proof = FallbackPoStSectorProof {
+        sector_id,
+        comm_r: replica.safe_comm_r()?,
+        vanilla_proof: fallback::Proof {
+            sectors: vec![SectorProof {
+                inclusion_proofs,
+                comm_c,
+                comm_r_last,
+            }],
+        },
+    };
+
+    Ok((proof, tree_open, latencies))
+}
+
+pub fn run_window_post_sectors_bench<Tree: 'static + MerkleTreeTrait>(
+    sector_size: u64,
+    api_version: ApiVersion,
+    sector_dirs: Vec<PathBuf>,
+    seal_count: usize,
+    work_dir: PathBuf,
+) -> Result<()> {
+    let seal_measurement = measure(|| {
+        let mut sealed = Vec::with_capacity(seal_count);
+        for i in 0..seal_count {
+            let dir = work_dir.join(format!("sector-{}", i));
+            create_dir(&dir).with_context(|| format!("could not create {:?}", dir))?;
+            info!("*** Sealing sector {} in {:?}", i, dir);
+            run_pre_commit_phases::<Tree>(
+                sector_size,
+                api_version,
+                SectorId::from(i as u64),
+                dir.clone(),
+                false, // skip precommit phase1
+                false, // skip precommit phase2
+                false, // test resume
+                false, // skip staging
+            )?;
+            sealed.push(dir);
+        }
+        Ok(sealed)
+    })?;
+    let sector_dirs = if seal_count > 0 {
+        seal_measurement.return_value
+    } else {
+        sector_dirs
+    };
+
+    // Sector ids only select the challenges, so the position on the command line is used.
+    let mut priv_replica_info: BTreeMap<SectorId, PrivateReplicaInfo<Tree>> = BTreeMap::new();
+    let mut pub_replica_info: BTreeMap<SectorId, PublicReplicaInfo> = BTreeMap::new();
+    for (i, dir) in sector_dirs.iter().enumerate() {
+        let (priv_replica, pub_replica) = open_sector::<Tree>(dir)?;
+        priv_replica_info.insert(SectorId::from(i as u64), priv_replica);
+        pub_replica_info.insert(SectorId::from(i as u64), pub_replica);
+    }
+    let sector_ids: Vec<SectorId> = priv_replica_info.keys().copied().collect();
+
+    let post_config = PoStConfig {
+        sector_size: SectorSize(sector_size),
+        challenge_count: WINDOW_POST_CHALLENGE_COUNT,
+        sector_count: *WINDOW_POST_SECTOR_COUNT
+            .read()
+            .expect("WINDOW_POST_SECTOR_COUNT poisoned")
+            .get(&sector_size)
+            .expect("unknown sector size"),
+        typ: PoStType::Window,
+        priority: true,
+        api_version,
+    };
+
+    let challenges_measurement = measure(|| {
+        generate_fallback_sector_challenges::<Tree>(
+            &post_config,
+            &RANDOMNESS,
+            &sector_ids,
+            PROVER_ID,
+        )
+    })
+    .expect("failed to generate challenges");
+    let challenges = &challenges_measurement.return_value;
+
+    let vanilla_measurement = measure(|| {
+        priv_replica_info
+            .par_iter()
+            .map(|(sector_id, replica)| {
+                prove_sector::<Tree>(&post_config, *sector_id, replica, &challenges[sector_id])
+            })
+            .collect::<Result<Vec<_>>>()
+    })
+    .expect("failed to generate vanilla proofs");
+
+    let mut vanilla_proofs = Vec::with_capacity(sector_ids.len());
+    let mut tree_opens = Vec::with_capacity(sector_ids.len());
+    let mut challenge_reads = Vec::new();
+    for (proof, tree_open, reads) in vanilla_measurement.return_value {
+        vanilla_proofs.push(proof);
+        tree_opens.push(tree_open);
+        challenge_reads.extend(reads);
+    }
+
+    let gpu_sampler = GpuSampler::start();
+    let snark_measurement = measure(|| {
+        generate_window_post_with_vanilla::<Tree>(
+            &post_config,
+            &RANDOMNESS,
+            PROVER_ID,
+            vanilla_proofs,
+        )
+    });
+    let gpu_utilization = gpu_sampler.finish();
+    let snark_measurement = snark_measurement.expect("failed to generate window post");
+
+    let proof = &snark_measurement.return_value;
+    let verify_measurement = measure(|| {
+        verify_window_post::<Tree>(
+            &post_config,
+            &RANDOMNESS,
+            &pub_replica_info,
+            PROVER_ID,
+            proof,
+        )
+    })
+    .expect("failed to verify window post proof");
+    ensure!(
+        verify_measurement.return_value,
+        "window post proof did not verify"
+    );
+
+    let report = Report {
+        inputs: Inputs {
+            sector_size,
+            sector_count: sector_ids.len(),
+            sealed_sector_count: seal_count,
+            challenge_count: post_config.challenge_count,
+        },
+        outputs: Outputs {
+            seal_wall_time_ms: seal_measurement.wall_time.as_millis() as u64,
+            generate_challenges_wall_time_ms: challenges_measurement.wall_time.as_millis() as u64,
+            vanilla_cpu_time_ms: vanilla_measurement.cpu_time.as_millis() as u64,
+            vanilla_wall_time_ms: vanilla_measurement.wall_time.as_millis() as u64,
+            snark_cpu_time_ms: snark_measurement.cpu_time.as_millis() as u64,
+            snark_wall_time_ms: snark_measurement.wall_time.as_millis() as u64,
+            verify_cpu_time_ms: verify_measurement.cpu_time.as_millis() as u64,
+            verify_wall_time_ms: verify_measurement.wall_time.as_millis() as u64,
+            prove_wall_time_ms: (challenges_measurement.wall_time
+                + vanilla_measurement.wall_time
+                + snark_measurement.wall_time)
+                .as_millis() as u64,
+            tree_open_latency: Histogram::new(&tree_opens),
+            challenge_read_latency: Histogram::new(&challenge_reads),
+            gpu_utilization,
+        },
+    };
+
+    // Create a JSON serializable report that we print to stdout (that will later be parsed using
+    // the CLI JSON parser `jq`).
+    report.print();
+    Ok(())
+}
+
+pub fn run(
+    sector_size: usize,
+    api_version: ApiVersion,
+    cache: String,
+    sector_dirs: Vec<PathBuf>,
+    seal_count: usize,
+) -> Result<()> {
+    info!(
+        "Benchy Window PoSt on sectors: sector-size={}, api_version={}, sector_dirs={:?}, seal_count={}",
+        sector_size, api_version, sector_dirs, seal_count
+    );
+    ensure!(
+        sector_dirs.is_empty() != (seal_count == 0),
+        "Either sealed sector directories or a number of sectors to seal is required"
+    );
+
+    // Sectors are sealed in the cache dir if one is given, and kept for later runs.
+    let (work_dir, preserve) = if cache.is_empty() {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        (
+            std::env::temp_dir().join(format!("window-post-sectors-bench-{}", timestamp)),
+            false,
+        )
+    } else {
+        (PathBuf::from(cache), true)
+    };
+    if seal_count > 0 && !work_dir.exists() {
+        create_dir(&work_dir)?;
+    }
+
+    let res = with_shape!(
+        sector_size as u64,
+        run_window_post_sectors_bench,
+        sector_size as u64,
+        api_version,
+        sector_dirs,
+        seal_count,
+        work_dir.clone(),
+    );
+
+    if seal_count > 0 && !preserve {
+        info!("Removing work directory {:?}", work_dir);
+        remove_dir_all(&work_dir)?;
+    }
+
+    res
+}
@@ -0,0 +1,74 @@
+use std::fs::read_to_string;
+use std::net::TcpListener;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{value_t, App, Arg};
+use filecoin_proofs::remote::{serve_remote_p2, RemoteP2Config};
+use log::info;
+
+fn main() -> Result<()> {
+    fil_logger::init();
+
+    let matches = App::new("p2-server")
+        .version("0.1")
+        .about("Runs seal_pre_commit_phase2 for remote clients")
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .help("The address to listen on")
+                .default_value("0.0.0.0:7878"),
+        )
+        .arg(
+            Arg::with_name("work-dir")
+                .long("work-dir")
+                .help("The directory to keep the files of streamed sectors in while sealing")
+                .default_value("/var/tmp/p2-server"),
+        )
+        .arg(
+            Arg::with_name("secret-file")
+                .long("secret-file")
+                .help("The file holding the secret clients must present")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shared-root")
+                .long("shared-root")
+                .help("Accept shared storage requests for sector files under this directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-concurrent")
+                .long("max-concurrent")
+                .help("The number of sectors to seal at once, further requests are refused")
+                .default_value("1"),
+        )
+        .get_matches();
+
+    let listen = value_t!(matches, "listen", String).expect("failed to get listen");
+    let work_dir = value_t!(matches, "work-dir", String).expect("failed to get work-dir");
+    let secret_file = value_t!(matches, "secret-file", String).expect("failed to get secret-file");
+    let shared_root = matches.value_of("shared-root").map(PathBuf::from);
+    let max_concurrent =
+        value_t!(matches, "max-concurrent", usize).expect("failed to get max-concurrent");
+
+    let secret = read_to_string(&secret_file)
+        .with_context(|| format!("could not read secret file {}", secret_file))?
+        .trim()
+        .to_string();
+
+    let listener =
+        TcpListener::bind(&listen).with_context(|| format!("could not listen on {}", listen))?;
+    info!("p2-server: listening on {}", listen);
+
+    serve_remote_p2(
+        listener,
+        RemoteP2Config {
+            work_dir: work_dir.into(),
+            shared_root,
+            secret,
+            max_concurrent,
+        },
+    )
+}
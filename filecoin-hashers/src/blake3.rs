@@ -0,0 +1,250 @@
+//! Blake3, for data trees and piece commitments of experimental proof types. It is much faster
+//! than Sha256 on CPUs without SHA extensions, but has no circuit, so `Blake3Hasher` is only a
+//! `TreeHasher` and not a `Hasher`: its trees can not be part of a proof type.
+
+use std::fmt::{self, Debug, Formatter};
+use std::hash::Hasher as StdHasher;
+use std::panic::panic_any;
+
+use anyhow::ensure;
+use bellperson::bls::{Fr, FrRepr};
+use ff::{Field, PrimeField, PrimeFieldRepr};
+use merkletree::{
+    hash::{Algorithm, Hashable},
+    merkle::Element,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Domain, TreeHasher};
+
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Blake3Hasher {}
+
+impl TreeHasher for Blake3Hasher {
+    type Domain = Blake3Domain;
+    type Function = Blake3Function;
+}
+
+#[derive(Default, Clone)]
+pub struct Blake3Function(::blake3::Hasher);
+
+impl Debug for Blake3Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Blake3Function")
+    }
+}
+
+impl StdHasher for Blake3Function {
+    #[inline]
+    fn write(&mut self, msg: &[u8]) {
+        self.0.update(msg);
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        unreachable!("unused by Function -- should never be called")
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize, Hash)]
+pub struct Blake3Domain(pub [u8; 32]);
+
+impl Debug for Blake3Domain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Blake3Domain({})", hex::encode(&self.0))
+    }
+}
+
+impl AsRef<Blake3Domain> for Blake3Domain {
+    fn as_ref(&self) -> &Self {
+        self
+    }
+}
+
+impl Blake3Domain {
+    fn trim_to_fr32(&mut self) {
+        // strip last two bits, to ensure result is in Fr.
+        self.0[31] &= 0b0011_1111;
+    }
+}
+
+impl AsRef<[u8]> for Blake3Domain {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl Hashable<Blake3Function> for Blake3Domain {
+    fn hash(&self, state: &mut Blake3Function) {
+        state.write(self.as_ref())
+    }
+}
+
+impl From<Fr> for Blake3Domain {
+    fn from(val: Fr) -> Self {
+        let mut res = Self::default();
+        val.into_repr()
+            .write_le(&mut res.0[0..32])
+            .expect("write_le failure");
+
+        res
+    }
+}
+
+impl From<FrRepr> for Blake3Domain {
+    fn from(val: FrRepr) -> Self {
+        let mut res = Self::default();
+        val.write_le(&mut res.0[0..32]).expect("write_le failure");
+
+        res
+    }
+}
+
+impl From<Blake3Domain> for Fr {
+    fn from(val: Blake3Domain) -> Self {
+        let mut res = FrRepr::default();
+        res.read_le(&val.0[0..32]).expect("read_le failure");
+
+        Fr::from_repr(res).expect("from_repr failure")
+    }
+}
+
+impl Domain for Blake3Domain {
+    fn into_bytes(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+
+    fn try_from_bytes(raw: &[u8]) -> anyhow::Result<Self> {
+        ensure!(
+            raw.len() == Blake3Domain::byte_len(),
+            "invalid number of bytes"
+        );
+
+        let mut res = Blake3Domain::default();
+        res.0.copy_from_slice(&raw[0..Blake3Domain::byte_len()]);
+        Ok(res)
+    }
+
+    fn write_bytes(&self, dest: &mut [u8]) -> anyhow::Result<()> {
+        ensure!(
+            dest.len() >= Blake3Domain::byte_len(),
+            "invalid number of bytes"
+        );
+
+        dest[0..Blake3Domain::byte_len()].copy_from_slice(&self.0[..]);
+        Ok(())
+    }
+
+    fn random<R: RngCore>(rng: &mut R) -> Self {
+        // generating an Fr and converting it, to ensure we stay in the field
+        Fr::random(rng).into()
+    }
+}
+
+impl Element for Blake3Domain {
+    fn byte_len() -> usize {
+        32
+    }
+
+    fn from_slice(bytes: &[u8]) -> Self {
+        match Blake3Domain::try_from_bytes(bytes) {
+            Ok(res) => res,
+            Err(err) => panic_any(err),
+        }
+    }
+
+    fn copy_to_slice(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.0);
+    }
+}
+
+impl From<::blake3::Hash> for Blake3Domain {
+    fn from(hash: ::blake3::Hash) -> Self {
+        let mut res = Blake3Domain(*hash.as_bytes());
+        res.trim_to_fr32();
+        res
+    }
+}
+
+impl Blake3Function {
+    pub fn hash2(a: &Blake3Domain, b: &Blake3Domain) -> Blake3Domain {
+        let mut hasher = ::blake3::Hasher::new();
+        hasher.update(AsRef::<[u8]>::as_ref(a));
+        hasher.update(AsRef::<[u8]>::as_ref(b));
+        hasher.finalize().into()
+    }
+}
+
+impl Algorithm<Blake3Domain> for Blake3Function {
+    #[inline]
+    fn hash(&mut self) -> Blake3Domain {
+        self.0.finalize().into()
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+
+    fn leaf(&mut self, leaf: Blake3Domain) -> Blake3Domain {
+        leaf
+    }
+
+    fn node(&mut self, left: Blake3Domain, right: Blake3Domain, _height: usize) -> Blake3Domain {
+        left.hash(self);
+        right.hash(self);
+        self.hash()
+    }
+
+    fn multi_node(&mut self, parts: &[Blake3Domain], _height: usize) -> Blake3Domain {
+        for part in parts {
+            part.hash(self)
+        }
+        self.hash()
+    }
+}
+
+impl From<[u8; 32]> for Blake3Domain {
+    #[inline]
+    fn from(val: [u8; 32]) -> Self {
+        Blake3Domain(val)
+    }
+}
+
+impl From<Blake3Domain> for [u8; 32] {
+    #[inline]
+    fn from(val: Blake3Domain) -> Self {
+        val.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    #[test]
+    fn test_node_matches_hash2() {
+        let rng = &mut XorShiftRng::from_seed([
+            0x59, 0x62, 0xbe, 0x5d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06,
+            0xbc, 0xe5,
+        ]);
+        let a = Blake3Domain::random(rng);
+        let b = Blake3Domain::random(rng);
+
+        let mut function = Blake3Function::default();
+        let node = function.node(a, b, 0);
+        assert_eq!(node, Blake3Function::hash2(&a, &b));
+
+        let mut bytes = a.into_bytes();
+        bytes.extend(b.into_bytes());
+        assert_eq!(node, Blake3Domain::from(::blake3::hash(&bytes)));
+
+        // The result is always in the field.
+        let fr: Fr = node.into();
+        assert_eq!(Blake3Domain::from(fr), node);
+    }
+}
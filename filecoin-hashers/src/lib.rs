@@ -6,6 +6,8 @@
 
 #[cfg(feature = "blake2s")]
 pub mod blake2s;
+#[cfg(feature = "blake3")]
+pub mod blake3;
 #[cfg(feature = "poseidon")]
 pub mod poseidon;
 #[cfg(feature = "poseidon")]
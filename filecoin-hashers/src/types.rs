@@ -122,3 +122,16 @@ pub trait Hasher: Clone + Debug + Eq + Default + Send + Sync {
 
     fn name() -> String;
 }
+
+/// The part of a `Hasher` needed to build trees which are never proven in a circuit, e.g. piece
+/// commitments. Every `Hasher` is a `TreeHasher`; hashers without a circuit, like
+/// `blake3::Blake3Hasher`, are only this, so they can not end up in a proof type.
+pub trait TreeHasher: Clone + Debug + Eq + Default + Send + Sync {
+    type Domain: Domain + LightHashable<Self::Function> + AsRef<Self::Domain>;
+    type Function: Clone + Debug + Send + Sync + LightAlgorithm<Self::Domain>;
+}
+
+impl<H: Hasher> TreeHasher for H {
+    type Domain = H::Domain;
+    type Function = H::Function;
+}
@@ -0,0 +1,4 @@
+fn main() {
+    #[cfg(feature = "remote-p2")]
+    tonic_build::compile_protos("proto/remote_p2.proto").expect("failed to compile remote_p2.proto");
+}
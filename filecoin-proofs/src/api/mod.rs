@@ -1,5 +1,5 @@
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
 use std::path::{Path, PathBuf};
 
 use anyhow::{ensure, Context, Result};
@@ -15,7 +15,7 @@ use storage_proofs_core::{
     merkle::get_base_tree_count,
     pieces::generate_piece_commitment_bytes_from_source,
     sector::SectorId,
-    util::default_rows_to_discard,
+    util::{default_rows_to_discard, NODE_SIZE},
 };
 use storage_proofs_porep::{
     stacked::{generate_replica_id, PersistentAux, StackedDrg, TemporaryAux},
@@ -41,15 +41,21 @@ use crate::{
 mod fake_seal;
 mod post_util;
 mod seal;
+mod store;
+mod update;
 mod util;
 mod window_post;
 mod winning_post;
 mod calibration;
 mod generate_labels_bench;
+#[cfg(feature = "async-api")]
+pub mod nonblocking;
 
 pub use fake_seal::*;
 pub use post_util::*;
 pub use seal::*;
+pub use store::*;
+pub use update::*;
 pub use util::*;
 pub use window_post::*;
 pub use winning_post::*;
@@ -247,6 +253,134 @@ where
     result
 }
 
+/// Number of sealed bytes `unseal_range_streaming` reads and decodes at a time. A multiple of
+/// the 128 bytes holding 127 unpadded ones, so each window unpads on its own.
+pub const UNSEAL_WINDOW_BYTES: usize = 1 << 20;
+
+/// Unpadded bytes held by the 128 padded bytes of four field elements.
+const FR32_UNPADDED_CHUNK: u64 = 127;
+const FR32_PADDED_CHUNK: u64 = 128;
+
+/// Like `unseal_range`, but reads `sealed_sector` and the key data window by window, writing
+/// each decoded window to `unsealed_output` before reading the next. Only the nodes holding the
+/// requested bytes are read and decoded. The key is read from the last layer in `cache_path`,
+/// with memory bounded by `UNSEAL_WINDOW_BYTES` instead of the sector size. Without the last
+/// layer, once the cache was cleared, all layers are generated again as by `unseal_range`.
+///
+/// # Arguments
+///
+/// * `porep_config` - porep configuration containing the sector size.
+/// * `cache_path` - path to the directory in which the sector data's Merkle Tree is written.
+/// * `sealed_sector` - a seekable byte source from which we read sealed sector data.
+/// * `unsealed_output` - a byte sink to which we write unsealed, un-bit-padded sector bytes.
+/// * `prover_id` - the prover-id that sealed the sector.
+/// * `sector_id` - the sector-id of the sealed sector.
+/// * `comm_d` - the commitment to the sector's data.
+/// * `ticket` - the ticket that was used to generate the sector's replica-id.
+/// * `offset` - the byte index in the unsealed sector of the first byte that we want to read.
+/// * `num_bytes` - the number of bytes that we want to read.
+#[allow(clippy::too_many_arguments)]
+pub fn unseal_range_streaming<P, R, W, Tree>(
+    porep_config: PoRepConfig,
+    cache_path: P,
+    sealed_sector: R,
+    mut unsealed_output: W,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    comm_d: Commitment,
+    ticket: Ticket,
+    offset: UnpaddedByteIndex,
+    num_bytes: UnpaddedBytesAmount,
+) -> Result<UnpaddedBytesAmount>
+where
+    P: Into<PathBuf> + AsRef<Path>,
+    R: Read + Seek,
+    W: Write,
+    Tree: 'static + MerkleTreeTrait,
+{
+    info!("unseal_range_streaming:start");
+    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+
+    let comm_d =
+        as_safe_commitment::<<DefaultPieceHasher as Hasher>::Domain, _>(&comm_d, "comm_d")?;
+
+    let replica_id = generate_replica_id::<Tree::Hasher, _>(
+        &prover_id,
+        sector_id.into(),
+        &ticket,
+        comm_d,
+        &porep_config.porep_id,
+    );
+
+    let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(porep_config.sector_size)?;
+    let base_tree_leafs = get_base_tree_leafs::<DefaultBinaryTree>(base_tree_size)?;
+    let config = StoreConfig::new(
+        cache_path.as_ref(),
+        CacheKey::CommDTree.to_string(),
+        default_rows_to_discard(
+            base_tree_leafs,
+            <DefaultBinaryTree as MerkleTreeTrait>::Arity::to_usize(),
+        ),
+    );
+    let pp = public_params(
+        PaddedBytesAmount::from(porep_config),
+        usize::from(PoRepProofPartitions::from(porep_config)),
+        porep_config.porep_id,
+        porep_config.api_version,
+    )?;
+
+    // Decode whole chunks of four field elements, so every window starts at the start of one
+    // and unpads on its own. Only the first window skips the bytes before `offset`.
+    let offset = u64::from(offset);
+    let end = offset + u64::from(num_bytes);
+    let first_chunk = offset / FR32_UNPADDED_CHUNK;
+    let last_chunk = (end + FR32_UNPADDED_CHUNK - 1) / FR32_UNPADDED_CHUNK;
+    let sector_size = u64::from(PaddedBytesAmount::from(porep_config));
+    let start_padded = first_chunk * FR32_PADDED_CHUNK;
+    let end_padded = std::cmp::min(last_chunk * FR32_PADDED_CHUNK, sector_size);
+    ensure!(
+        start_padded <= end_padded,
+        "offset {} out of range for a sector of {} bytes",
+        offset,
+        sector_size
+    );
+
+    let mut skip = (offset - first_chunk * FR32_UNPADDED_CHUNK) as usize;
+    let mut remaining = u64::from(num_bytes) as usize;
+    let mut written = 0;
+    StackedDrg::<Tree, DefaultPieceHasher>::extract_range(
+        &pp.graph,
+        &pp.layer_challenges,
+        &replica_id,
+        sealed_sector,
+        (start_padded as usize / NODE_SIZE)..(end_padded as usize / NODE_SIZE),
+        UNSEAL_WINDOW_BYTES / NODE_SIZE,
+        config,
+        |window| {
+            let capacity = window.len() / FR32_PADDED_CHUNK as usize
+                * FR32_UNPADDED_CHUNK as usize;
+            let len = std::cmp::min(remaining, capacity.saturating_sub(skip));
+            if len > 0 {
+                written += write_unpadded(window, &mut unsealed_output, skip, len)
+                    .context("write_unpadded failed")?;
+            }
+            remaining -= len;
+            skip = 0;
+            Ok(())
+        },
+    )?;
+    ensure!(
+        remaining == 0,
+        "requested {} bytes at offset {}, only {} are in the sector",
+        u64::from(num_bytes),
+        offset,
+        written
+    );
+
+    info!("unseal_range_streaming:finish");
+    Ok(UnpaddedBytesAmount(written as u64))
+}
+
 /// Unseals the sector read from `sealed_sector` and returns the bytes for a
 /// piece whose first (unpadded) byte begins at `offset` and ends at `offset`
 /// plus `num_bytes`, inclusive. Note that the entire sector is unsealed each
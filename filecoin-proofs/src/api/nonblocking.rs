@@ -0,0 +1,396 @@
+//! Non-blocking versions of the sealing calls, for services built on an async runtime.
+//!
+//! Each call is queued on a dedicated pool of blocking threads and returns a `JobHandle`, a
+//! future resolving to the result of the call. The handle also tells which step the call is
+//! at, see `JobPhase`. The pool is separate from the runtime's own threads and from the rayon
+//! pools sealing uses internally, so sealing never stalls the runtime. Enabled with the
+//! `async-api` feature.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+    mpsc, Arc, Mutex,
+};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use bellperson::bls::Fr;
+use lazy_static::lazy_static;
+use log::{debug, error};
+use storage_proofs_core::{
+    progress::{with_progress, ProgressEvent},
+    sector::SectorId,
+};
+
+use crate::types::{
+    AggregateSnarkProof, MerkleTreeTrait, PoRepConfig, ProverId, SealCommitOutput,
+    SealCommitPhase1Output, SealPreCommitOutput, SealPreCommitPhase1Output, Ticket,
+};
+
+/// Number of blocking threads used if `set_blocking_threads` is not called. Each sealing call
+/// keeps its thread for its whole duration, so this bounds the calls running at once, the rest
+/// wait in the queue.
+pub const DEFAULT_BLOCKING_THREADS: usize = 8;
+
+static BLOCKING_THREADS: AtomicUsize = AtomicUsize::new(DEFAULT_BLOCKING_THREADS);
+static POOL_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the number of threads of the blocking pool. Only has an effect before the first call is
+/// queued, as the pool is started then; returns whether it had an effect.
+pub fn set_blocking_threads(threads: usize) -> bool {
+    BLOCKING_THREADS.store(threads.max(1), SeqCst);
+    !POOL_STARTED.load(SeqCst)
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    static ref POOL: Mutex<mpsc::Sender<Job>> = Mutex::new(start_pool());
+}
+
+fn start_pool() -> mpsc::Sender<Job> {
+    POOL_STARTED.store(true, SeqCst);
+    let threads = BLOCKING_THREADS.load(SeqCst);
+    debug!("starting {} blocking threads", threads);
+
+    let (tx, rx) = mpsc::channel::<Job>();
+    let rx = Arc::new(Mutex::new(rx));
+    for i in 0..threads {
+        let rx = rx.clone();
+        thread::Builder::new()
+            .name(format!("fil-proofs-blocking-{}", i))
+            .spawn(move || loop {
+                let job = match rx.lock().expect("poisoned lock").recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                job();
+            })
+            .expect("failed to spawn blocking thread");
+    }
+    tx
+}
+
+/// The step a queued call is at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobPhase {
+    /// Waiting for a thread of the blocking pool.
+    Queued,
+    /// Running, with the last progress it reported, if any.
+    Running(Option<ProgressEvent>),
+    /// Done, the handle resolves to its result.
+    Finished,
+}
+
+struct Shared<T> {
+    phase: Mutex<JobPhase>,
+    result: Mutex<(Option<Result<T>>, Option<Waker>)>,
+}
+
+/// A call running on the blocking pool. Resolves to the result of the call; dropping it does
+/// not stop the call.
+pub struct JobHandle<T> {
+    operation: &'static str,
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// The name of the call, e.g. `"seal_pre_commit_phase1"`.
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+
+    /// The step the call is at.
+    pub fn phase(&self) -> JobPhase {
+        *self.shared.phase.lock().expect("poisoned lock")
+    }
+}
+
+impl<T> Future for JobHandle<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut result = self.shared.result.lock().expect("poisoned lock");
+        match result.0.take() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                result.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Queues `f` on the blocking pool as `operation`. The progress `f` reports, see
+/// `with_progress`, is exposed through the phase of the returned handle. A panic in `f` is
+/// returned as an error.
+pub fn spawn<T, F>(operation: &'static str, f: F) -> JobHandle<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let shared = Arc::new(Shared {
+        phase: Mutex::new(JobPhase::Queued),
+        result: Mutex::new((None, None)),
+    });
+
+    let job_shared = shared.clone();
+    let job: Job = Box::new(move || {
+        *job_shared.phase.lock().expect("poisoned lock") = JobPhase::Running(None);
+
+        let progress_shared = job_shared.clone();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            with_progress(
+                move |event| {
+                    *progress_shared.phase.lock().expect("poisoned lock") =
+                        JobPhase::Running(Some(*event))
+                },
+                f,
+            )
+        }))
+        .unwrap_or_else(|_| {
+            error!("{} panicked", operation);
+            Err(anyhow!("{} panicked", operation))
+        });
+
+        *job_shared.phase.lock().expect("poisoned lock") = JobPhase::Finished;
+        let waker = {
+            let mut result = job_shared.result.lock().expect("poisoned lock");
+            result.0 = Some(res);
+            result.1.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+
+    POOL.lock()
+        .expect("poisoned lock")
+        .send(job)
+        .expect("blocking pool stopped");
+
+    JobHandle { operation, shared }
+}
+
+/// `seal_pre_commit_phase1` on the blocking pool.
+pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+) -> JobHandle<SealPreCommitPhase1Output<Tree>>
+where
+    R: AsRef<Path> + Send + 'static,
+    S: AsRef<Path> + Send + 'static,
+    T: AsRef<Path> + Send + 'static,
+{
+    spawn("seal_pre_commit_phase1", move || {
+        super::seal_pre_commit_phase1::<_, _, _, Tree>(
+            porep_config,
+            cache_path,
+            in_path,
+            out_path,
+            prover_id,
+            sector_id,
+            ticket,
+        )
+    })
+}
+
+/// `seal_pre_commit_phase2` on the blocking pool.
+pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+) -> JobHandle<SealPreCommitOutput>
+where
+    R: AsRef<Path> + Send + 'static,
+    S: AsRef<Path> + Send + 'static,
+{
+    spawn("seal_pre_commit_phase2", move || {
+        super::seal_pre_commit_phase2::<_, _, Tree>(
+            porep_config,
+            phase1_output,
+            cache_path,
+            replica_path,
+        )
+    })
+}
+
+/// `generate_synth_proofs` on the blocking pool.
+pub fn generate_synth_proofs<T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: T,
+    replica_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    pre_commit: SealPreCommitOutput,
+) -> JobHandle<()>
+where
+    T: AsRef<Path> + Send + 'static,
+{
+    spawn("generate_synth_proofs", move || {
+        super::generate_synth_proofs::<_, Tree>(
+            porep_config,
+            cache_path,
+            replica_path,
+            prover_id,
+            sector_id,
+            ticket,
+            pre_commit,
+        )
+    })
+}
+
+/// `seal_commit_phase1` on the blocking pool.
+#[allow(clippy::too_many_arguments)]
+pub fn seal_commit_phase1<T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: T,
+    replica_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+    pre_commit: SealPreCommitOutput,
+) -> JobHandle<SealCommitPhase1Output<Tree>>
+where
+    T: AsRef<Path> + Send + 'static,
+{
+    spawn("seal_commit_phase1", move || {
+        super::seal_commit_phase1::<_, Tree>(
+            porep_config,
+            cache_path,
+            replica_path,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            pre_commit,
+        )
+    })
+}
+
+/// `seal_commit_phase2` on the blocking pool.
+pub fn seal_commit_phase2<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealCommitPhase1Output<Tree>,
+    prover_id: ProverId,
+    sector_id: SectorId,
+) -> JobHandle<SealCommitOutput> {
+    spawn("seal_commit_phase2", move || {
+        super::seal_commit_phase2::<Tree>(porep_config, phase1_output, prover_id, sector_id)
+    })
+}
+
+/// `aggregate_seal_commit_proofs` on the blocking pool.
+pub fn aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_rs: Vec<[u8; 32]>,
+    seeds: Vec<[u8; 32]>,
+    commit_outputs: Vec<SealCommitOutput>,
+) -> JobHandle<AggregateSnarkProof> {
+    spawn("aggregate_seal_commit_proofs", move || {
+        super::aggregate_seal_commit_proofs::<Tree>(
+            porep_config,
+            &comm_rs,
+            &seeds,
+            &commit_outputs,
+        )
+    })
+}
+
+/// `verify_aggregate_seal_commit_proofs` on the blocking pool.
+pub fn verify_aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    aggregate_proof_bytes: AggregateSnarkProof,
+    comm_rs: Vec<[u8; 32]>,
+    seeds: Vec<[u8; 32]>,
+    commit_inputs: Vec<Vec<Fr>>,
+) -> JobHandle<bool> {
+    spawn("verify_aggregate_seal_commit_proofs", move || {
+        super::verify_aggregate_seal_commit_proofs::<Tree>(
+            porep_config,
+            aggregate_proof_bytes,
+            &comm_rs,
+            &seeds,
+            commit_inputs,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::task::Wake;
+
+    /// Wakes a test blocked on a handle.
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(mut future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        // Safety: `future` is not moved again after being pinned here.
+        let mut future = unsafe { Pin::new_unchecked(&mut future) };
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(res) => return res,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_spawn_reports_phase() {
+        let (started_tx, started_rx) = channel();
+        let (proceed_tx, proceed_rx) = channel::<()>();
+
+        let handle = spawn("test", move || {
+            storage_proofs_core::progress::report(ProgressEvent::LayerStarted {
+                layer: 3,
+                layers: 11,
+            });
+            started_tx.send(()).expect("send failed");
+            proceed_rx.recv().expect("recv failed");
+            Ok(42)
+        });
+        assert_eq!(handle.operation(), "test");
+
+        started_rx.recv().expect("recv failed");
+        assert_eq!(
+            handle.phase(),
+            JobPhase::Running(Some(ProgressEvent::LayerStarted {
+                layer: 3,
+                layers: 11
+            }))
+        );
+
+        proceed_tx.send(()).expect("send failed");
+        let res = block_on(handle);
+        assert_eq!(res.expect("job failed"), 42);
+    }
+
+    #[test]
+    fn test_spawn_catches_panic() {
+        let handle = spawn::<(), _>("panicking", || panic!("boom"));
+        let err = block_on(handle).expect_err("panic not returned");
+        assert_eq!(err.to_string(), "panicking panicked");
+    }
+}
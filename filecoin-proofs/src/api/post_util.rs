@@ -40,6 +40,17 @@ pub fn clear_cache<Tree: MerkleTreeTrait>(cache_dir: &Path) -> Result<()> {
     result
 }
 
+/// Removes the synthetic proofs `generate_synth_proofs` stored in `cache_dir`, if any. Unlike
+/// the layers, they are needed until `seal_commit_phase1` ran, so `clear_cache` keeps them.
+pub fn clear_synthetic_proofs(cache_dir: &Path) -> Result<()> {
+    let path = cache_dir.join(CacheKey::SynthPoRepProofs.to_string());
+    if path.exists() {
+        fs::remove_file(&path).with_context(|| format!("could not remove {:?}", path))?;
+    }
+
+    Ok(())
+}
+
 // Ensure that any associated cached data persisted is discarded.
 pub fn clear_caches<Tree: MerkleTreeTrait>(
     replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
@@ -18,17 +18,17 @@ use storage_proofs_core::{
     compound_proof::{self, CompoundProof},
     drgraph::Graph,
     measurements::{measure_op, Operation},
-    merkle::{create_base_merkle_tree, BinaryMerkleTree, MerkleTreeTrait},
+    merkle::{BinaryMerkleTree, MerkleTreeTrait},
     multi_proof::MultiProof,
     parameter_cache::SRS_MAX_PROOFS_TO_AGGREGATE,
     proof::ProofScheme,
     sector::SectorId,
     util::default_rows_to_discard,
-    Data,
+    is_synthetic_porep_id, Data,
 };
 use storage_proofs_porep::stacked::{
-    self, generate_replica_id, ChallengeRequirements, StackedCompound, StackedDrg, Tau,
-    TemporaryAux, TemporaryAuxCache, get_p1_core_group, get_core_pool,
+    self, build_tree_d, generate_replica_id, ChallengeRequirements, StackedCompound, StackedDrg,
+    Tau, TemporaryAux, TemporaryAuxCache, admit_p2, get_p1_core_group, get_core_pool,
 };
 
 use crate::{
@@ -36,18 +36,20 @@ use crate::{
     caches::{get_stacked_params, get_stacked_verifying_key, 
         get_stacked_srs_key, get_stacked_srs_verifier_key},
     constants::{
-        DefaultBinaryTree, DefaultPieceDomain, DefaultPieceHasher, POREP_MINIMUM_CHALLENGES,
-        SINGLE_PARTITION_PROOF_LEN,
+        DefaultBinaryTree, DefaultPieceDomain, DefaultPieceHasher, SINGLE_PARTITION_PROOF_LEN,
     },
-    parameters::setup_params,
+    parameters::{porep_minimum_challenges, setup_params},
     pieces::{self},
     types::{
-        Commitment, PaddedBytesAmount, PieceInfo, PoRepConfig, PoRepProofPartitions, ProverId,
+        CcTreeD, Commitment, PaddedBytesAmount, PieceInfo, PoRepConfig, PoRepProofPartitions, ProverId,
         SealCommitOutput, SealCommitPhase1Output, SealPreCommitOutput, SealPreCommitPhase1Output,
-        SectorSize, Ticket, BINARY_ARITY, ProverError, AggregateSnarkProof,
+        SealPreCommitPhase1Sector, SectorSize, Ticket, BINARY_ARITY, ProverError, AggregateSnarkProof,
     },
 };
 
+/// Wrap the sealing calls in `with_progress` to be told how far along they are.
+pub use storage_proofs_core::progress::{with_progress, ProgressEvent};
+
 #[allow(clippy::too_many_arguments)]
 pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
@@ -62,9 +64,113 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
         R: AsRef<Path>,
         S: AsRef<Path>,
         T: AsRef<Path>,
+{
+    seal_pre_commit_phase1_resumable(
+        porep_config,
+        cache_path,
+        in_path,
+        out_path,
+        prover_id,
+        sector_id,
+        ticket,
+        true,
+    )
+}
+
+/// Like `seal_pre_commit_phase1`, choosing whether to `resume` after a process running it for
+/// the same sector died. With `resume`, the layers in `cache_path` which match the labeling
+/// checkpoint stored there are validated and kept, and labeling continues with the first layer
+/// missing; without, all layers are labeled again. There are no checkpoints within a layer, so
+/// an interrupted layer is labeled again from its first node. The tree over the data is always
+/// rebuilt.
+#[allow(clippy::too_many_arguments)]
+pub fn seal_pre_commit_phase1_resumable<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    resume: bool,
+) -> Result<SealPreCommitPhase1Output<Tree>>
+    where
+        R: AsRef<Path>,
+        S: AsRef<Path>,
+        T: AsRef<Path>,
 {
     info!("seal_pre_commit_phase1:start: {:?}", sector_id);
 
+    let compound_setup_params = compound_proof::SetupParams {
+        vanilla_params: setup_params(
+            PaddedBytesAmount::from(porep_config),
+            usize::from(PoRepProofPartitions::from(porep_config)),
+            porep_config.porep_id,
+            porep_config.api_version,
+        )?,
+        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
+        priority: false,
+    };
+
+    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+        StackedDrg<'_, Tree, DefaultPieceHasher>,
+        _,
+    >>::setup(&compound_setup_params)?;
+
+    let (config, comm_d) = stage_pre_commit_phase1_data(
+        porep_config,
+        compound_public_params.vanilla_params.graph.size(),
+        cache_path,
+        in_path,
+        out_path,
+    )?;
+
+    /*info!("verifying pieces");
+
+    ensure!(
+        verify_pieces(&comm_d, piece_infos, porep_config.into())?,
+        "pieces and comm_d do not match"
+    );*/
+
+    let replica_id = generate_replica_id::<Tree::Hasher, _>(
+        &prover_id,
+        sector_id.into(),
+        &ticket,
+        comm_d,
+        &porep_config.porep_id,
+    );
+
+    let labels = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1_resumable(
+        &compound_public_params.vanilla_params,
+        &replica_id,
+        config.clone(),
+        resume,
+    )?;
+
+    let out = SealPreCommitPhase1Output {
+        labels,
+        config,
+        comm_d,
+    };
+
+    info!("seal_pre_commit_phase1:finish: {:?}", sector_id);
+    Ok(out)
+}
+
+/// Copies the data of a sector from `in_path` to `out_path`, where it is sealed in place, and
+/// builds its tree_d in `cache_path`. Returns the config of tree_d and comm_d.
+fn stage_pre_commit_phase1_data<R, S, T>(
+    porep_config: PoRepConfig,
+    graph_size: usize,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+) -> Result<(StoreConfig, Commitment)>
+    where
+        R: AsRef<Path>,
+        S: AsRef<Path>,
+        T: AsRef<Path>,
+{
     // Sanity check all input path types.
     ensure!(
         metadata(in_path.as_ref())?.is_file(),
@@ -110,22 +216,6 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
             .with_context(|| format!("could not mmap out_path={:?}", out_path.as_ref().display()))?
     };
 
-    let compound_setup_params = compound_proof::SetupParams {
-        vanilla_params: setup_params(
-            PaddedBytesAmount::from(porep_config),
-            usize::from(PoRepProofPartitions::from(porep_config)),
-            porep_config.porep_id,
-            porep_config.api_version,
-        )?,
-        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
-        priority: false,
-    };
-
-    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
-        StackedDrg<'_, Tree, DefaultPieceHasher>,
-        _,
-    >>::setup(&compound_setup_params)?;
-
     info!("building merkle tree for the original data");
     let bind_tree = bind_p1_tree();
     let (guard, core_group) = if bind_tree {
@@ -144,12 +234,12 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
     };
 
     let core_group = Arc::new(core_group);
-    let pool = get_core_pool(core_group.clone());
+    let pool = get_core_pool("p1", core_group.clone());
     let (config, comm_d) = measure_op(Operation::CommD, || -> Result<_> {
         let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(porep_config.sector_size)?;
         let base_tree_leafs = get_base_tree_leafs::<DefaultBinaryTree>(base_tree_size)?;
         ensure!(
-            compound_public_params.vanilla_params.graph.size() == base_tree_leafs,
+            graph_size == base_tree_leafs,
             "graph size and leaf size don't match"
         );
 
@@ -167,8 +257,8 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
         );
 
         let data_tree = pool.install(|| {
-            create_base_merkle_tree::<BinaryMerkleTree<DefaultPieceHasher>>(
-                Some(config.clone()),
+            build_tree_d::<DefaultPieceHasher>(
+                config.clone(),
                 base_tree_leafs,
                 &data,
             ).unwrap()
@@ -186,37 +276,255 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
         Ok((config, comm_d))
     }).unwrap();
 
-    /*info!("verifying pieces");
+    Ok((config, comm_d))
+}
+
+/// `seal_pre_commit_phase1` for a batch of sectors, which are labeled together, see
+/// `create_label::batch`. The data of each sector is staged and its tree_d built one after the
+/// other, then all sectors are labeled at once. Labeling keeps two layers of every sector in
+/// memory. Returns the outputs in the order of `sectors`.
+pub fn seal_pre_commit_phase1_batch<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    sectors: &[SealPreCommitPhase1Sector],
+) -> Result<Vec<SealPreCommitPhase1Output<Tree>>> {
+    info!("seal_pre_commit_phase1_batch:start: {} sectors", sectors.len());
 
+    let compound_setup_params = compound_proof::SetupParams {
+        vanilla_params: setup_params(
+            PaddedBytesAmount::from(porep_config),
+            usize::from(PoRepProofPartitions::from(porep_config)),
+            porep_config.porep_id,
+            porep_config.api_version,
+        )?,
+        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
+        priority: false,
+    };
+
+    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+        StackedDrg<'_, Tree, DefaultPieceHasher>,
+        _,
+    >>::setup(&compound_setup_params)?;
+
+    let mut configs = Vec::with_capacity(sectors.len());
+    let mut comm_ds = Vec::with_capacity(sectors.len());
+    let mut replica_ids = Vec::with_capacity(sectors.len());
+    for sector in sectors {
+        let (config, comm_d) = stage_pre_commit_phase1_data(
+            porep_config,
+            compound_public_params.vanilla_params.graph.size(),
+            &sector.cache_path,
+            &sector.in_path,
+            &sector.out_path,
+        )
+        .with_context(|| format!("failed to stage sector {:?}", sector.sector_id))?;
+
+        replica_ids.push(generate_replica_id::<Tree::Hasher, _>(
+            &sector.prover_id,
+            sector.sector_id.into(),
+            &sector.ticket,
+            comm_d,
+            &porep_config.porep_id,
+        ));
+        configs.push(config);
+        comm_ds.push(comm_d);
+    }
+
+    let labels = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1_batch(
+        &compound_public_params.vanilla_params,
+        &replica_ids,
+        configs.clone(),
+    )?;
+
+    let out = labels
+        .into_iter()
+        .zip(configs)
+        .zip(comm_ds)
+        .map(|((labels, config), comm_d)| SealPreCommitPhase1Output {
+            labels,
+            config,
+            comm_d,
+        })
+        .collect();
+
+    info!("seal_pre_commit_phase1_batch:finish");
+    Ok(out)
+}
+
+/// Creates the tree_d of committed capacity sectors of `sector_size` in the directory `dir`, or
+/// opens it if it is already there. Building it takes as long as building the tree_d of any
+/// sector, but is only done once per sector size, see `seal_pre_commit_phase1_cc`.
+pub fn generate_cc_tree_d<P: AsRef<Path>>(sector_size: SectorSize, dir: P) -> Result<CcTreeD> {
+    ensure!(metadata(dir.as_ref())?.is_dir(), "dir must be a directory");
+
+    let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(sector_size)?;
+    let base_tree_leafs = get_base_tree_leafs::<DefaultBinaryTree>(base_tree_size)?;
+    let mut config = StoreConfig::new(
+        dir.as_ref(),
+        CacheKey::CommDTree.to_string(),
+        default_rows_to_discard(base_tree_leafs, BINARY_ARITY),
+    );
+    config.size = Some(base_tree_size);
+    let comm_d = compute_comm_d(sector_size, &[])?;
+
+    let data_path = StoreConfig::data_path(&config.path, &config.id);
+    if data_path.exists() {
+        let store: DiskStore<DefaultPieceDomain> =
+            DiskStore::new_from_disk(base_tree_size, BINARY_ARITY, &config)
+                .with_context(|| format!("could not open tree_d in {:?}", dir.as_ref()))?;
+        let root: Fr = store.read_at(base_tree_size - 1)?.into();
+        if commitment_from_fr(root) == comm_d {
+            info!("using the tree_d of committed capacity sectors in {:?}", dir.as_ref());
+            return Ok(CcTreeD {
+                sector_size,
+                config,
+                comm_d,
+            });
+        }
+        info!("tree_d in {:?} is not the one of zeroes, rebuilding it", dir.as_ref());
+        drop(store);
+        fs::remove_file(&data_path)?;
+    }
+
+    info!(
+        "building the tree_d of committed capacity sectors of {} bytes",
+        u64::from(sector_size)
+    );
+    // Anonymous memory reads as zeroes and is not backed by pages until written.
+    let data = MmapOptions::new()
+        .len(u64::from(sector_size) as usize)
+        .map_anon()?;
+    let data_tree = measure_op(Operation::CommD, || {
+        build_tree_d::<DefaultPieceHasher>(
+            config.clone(),
+            base_tree_leafs,
+            &data,
+        )
+    })?;
+    let root: Fr = data_tree.root().into();
     ensure!(
-        verify_pieces(&comm_d, piece_infos, porep_config.into())?,
-        "pieces and comm_d do not match"
-    );*/
+        commitment_from_fr(root) == comm_d,
+        "tree_d of zeroes does not match the comm_d of an empty sector"
+    );
+    config.size = Some(data_tree.len());
+
+    Ok(CcTreeD {
+        sector_size,
+        config,
+        comm_d,
+    })
+}
+
+/// `seal_pre_commit_phase1` for a committed capacity sector, whose data is all zeroes. Instead of
+/// copying the data from an `in_path` and building tree_d over it, `out_path` is zeroed and the
+/// shared `cc_tree_d` is linked into `cache_path`. This skips hashing the data, and if
+/// `cache_path` is on the filesystem of `cc_tree_d`, takes no space for tree_d; otherwise
+/// tree_d is copied.
+#[allow(clippy::too_many_arguments)]
+pub fn seal_pre_commit_phase1_cc<R, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cc_tree_d: &CcTreeD,
+    cache_path: R,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+) -> Result<SealPreCommitPhase1Output<Tree>>
+    where
+        R: AsRef<Path>,
+        T: AsRef<Path>,
+{
+    info!("seal_pre_commit_phase1_cc:start: {:?}", sector_id);
+
+    ensure!(
+        cc_tree_d.sector_size == porep_config.sector_size,
+        "tree_d is for sectors of {} bytes, not {}",
+        u64::from(cc_tree_d.sector_size),
+        u64::from(porep_config.sector_size)
+    );
+    ensure!(
+        metadata(out_path.as_ref())?.is_file(),
+        "out_path must be a file"
+    );
+    ensure!(
+        metadata(cache_path.as_ref())?.is_dir(),
+        "cache_path must be a directory"
+    );
+
+    // Zero the data to be sealed in place, without writing the zeroes.
+    let f_data = OpenOptions::new()
+        .write(true)
+        .open(&out_path)
+        .with_context(|| format!("could not open out_path={:?}", out_path.as_ref().display()))?;
+    f_data.set_len(0)?;
+    f_data.set_len(u64::from(PaddedBytesAmount::from(porep_config)))?;
+    drop(f_data);
+
+    let config = link_tree_d(&cc_tree_d.config, cache_path.as_ref())?;
+
+    let compound_setup_params = compound_proof::SetupParams {
+        vanilla_params: setup_params(
+            PaddedBytesAmount::from(porep_config),
+            usize::from(PoRepProofPartitions::from(porep_config)),
+            porep_config.porep_id,
+            porep_config.api_version,
+        )?,
+        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
+        priority: false,
+    };
+
+    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+        StackedDrg<'_, Tree, DefaultPieceHasher>,
+        _,
+    >>::setup(&compound_setup_params)?;
 
     let replica_id = generate_replica_id::<Tree::Hasher, _>(
         &prover_id,
         sector_id.into(),
         &ticket,
-        comm_d,
+        cc_tree_d.comm_d,
         &porep_config.porep_id,
     );
 
-    let labels = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1(
+    let labels = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1_resumable(
         &compound_public_params.vanilla_params,
         &replica_id,
         config.clone(),
+        true,
     )?;
 
     let out = SealPreCommitPhase1Output {
         labels,
         config,
-        comm_d,
+        comm_d: cc_tree_d.comm_d,
     };
 
-    info!("seal_pre_commit_phase1:finish: {:?}", sector_id);
+    info!("seal_pre_commit_phase1_cc:finish: {:?}", sector_id);
     Ok(out)
 }
 
+/// Hard links the tree_d of `config` into `cache_path`, replacing one already there, or copies it
+/// if it cannot be linked, e.g. from another filesystem. Returns the config of the tree_d in
+/// `cache_path`.
+fn link_tree_d(config: &StoreConfig, cache_path: &Path) -> Result<StoreConfig> {
+    let mut linked = config.clone();
+    linked.path = cache_path.to_path_buf();
+
+    let src = StoreConfig::data_path(&config.path, &config.id);
+    let dst = StoreConfig::data_path(&linked.path, &linked.id);
+    if dst.exists() {
+        if fs::canonicalize(&src)? == fs::canonicalize(&dst)? {
+            return Ok(linked);
+        }
+        fs::remove_file(&dst).with_context(|| format!("could not remove {:?}", dst))?;
+    }
+
+    if let Err(err) = fs::hard_link(&src, &dst) {
+        info!("could not link {:?} to {:?}, copying it: {}", src, dst, err);
+        fs::copy(&src, &dst).with_context(|| format!("could not copy {:?} to {:?}", src, dst))?;
+    }
+    Ok(linked)
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
@@ -240,6 +548,9 @@ pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
         "replica_path must be a file"
     );
 
+    // One admission for the whole seal, shared by the tree builds, which may run at once.
+    let p2_slot = admit_p2()?;
+
     let SealPreCommitPhase1Output {
         mut labels,
         mut config,
@@ -250,25 +561,14 @@ pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
     labels.update_root(cache_path.as_ref());
     config.path = cache_path.as_ref().into();
 
-    let f_data = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&replica_path)
-        .with_context(|| {
-            format!(
-                "could not open replica_path={:?}",
-                replica_path.as_ref().display()
-            )
-        })?;
-    let data = unsafe {
-        MmapOptions::new().map_mut(&f_data).with_context(|| {
-            format!(
-                "could not mmap replica_path={:?}",
-                replica_path.as_ref().display()
-            )
-        })?
-    };
-    let data: Data<'_> = (data, PathBuf::from(replica_path.as_ref())).into();
+    // Mapped or read into memory, as the replica io strategy says.
+    let mut data = Data::from_path(PathBuf::from(replica_path.as_ref()));
+    data.ensure_data().with_context(|| {
+        format!(
+            "could not load replica_path={:?}",
+            replica_path.as_ref().display()
+        )
+    })?;
 
     // Load data tree from disk
     let data_tree = {
@@ -314,7 +614,9 @@ pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
         data_tree,
         config,
         replica_path.as_ref().to_path_buf(),
+        &p2_slot,
     )?;
+    drop(p2_slot);
 
     let comm_r = commitment_from_fr(tau.comm_r.into());
 
@@ -373,32 +675,6 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
         "pieces and comm_d do not match"
     );*/
 
-    let p_aux = {
-        let p_aux_path = cache_path.as_ref().join(CacheKey::PAux.to_string());
-        let p_aux_bytes = fs::read(&p_aux_path)
-            .with_context(|| format!("could not read file p_aux={:?}", p_aux_path))?;
-
-        deserialize(&p_aux_bytes)
-    }?;
-
-    let t_aux = {
-        let t_aux_path = cache_path.as_ref().join(CacheKey::TAux.to_string());
-        let t_aux_bytes = fs::read(&t_aux_path)
-            .with_context(|| format!("could not read file t_aux={:?}", t_aux_path))?;
-
-        let mut res: TemporaryAux<_, _> = deserialize(&t_aux_bytes)?;
-
-        // Switch t_aux to the passed in cache_path
-        res.set_cache_path(cache_path);
-        res
-    };
-
-    // Convert TemporaryAux to TemporaryAuxCache, which instantiates all
-    // elements based on the configs stored in TemporaryAux.
-    let t_aux_cache: TemporaryAuxCache<Tree, DefaultPieceHasher> =
-        TemporaryAuxCache::new(&t_aux, replica_path.as_ref().to_path_buf())
-            .context("failed to restore contents of t_aux")?;
-
     let comm_r_safe = as_safe_commitment(&comm_r, "comm_r")?;
     let comm_d_safe = DefaultPieceDomain::try_from_bytes(&comm_d)?;
 
@@ -420,11 +696,6 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
         seed,
     };
 
-    let private_inputs = stacked::PrivateInputs::<Tree, DefaultPieceHasher> {
-        p_aux,
-        t_aux: t_aux_cache,
-    };
-
     let compound_setup_params = compound_proof::SetupParams {
         vanilla_params: setup_params(
             PaddedBytesAmount::from(porep_config),
@@ -441,12 +712,33 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
         _,
     >>::setup(&compound_setup_params)?;
 
-    let vanilla_proofs = StackedDrg::prove_all_partitions(
-        &compound_public_params.vanilla_params,
-        &public_inputs,
-        &private_inputs,
-        StackedCompound::partition_count(&compound_public_params),
-    )?;
+    let synth_proofs_path = cache_path
+        .as_ref()
+        .join(CacheKey::SynthPoRepProofs.to_string());
+    let use_synth_proofs =
+        is_synthetic_porep_id(porep_config.porep_id) && synth_proofs_path.exists();
+    let vanilla_proofs = if use_synth_proofs {
+        // Selected from the proofs of `generate_synth_proofs`, the layers may be gone already.
+        info!("selecting the proofs from {:?}", synth_proofs_path);
+        StackedDrg::read_synthetic_proofs(
+            &compound_public_params.vanilla_params,
+            &public_inputs,
+            &synth_proofs_path,
+            StackedCompound::partition_count(&compound_public_params),
+        )?
+    } else {
+        let private_inputs = stacked::PrivateInputs::<Tree, DefaultPieceHasher> {
+            p_aux: read_p_aux::<Tree>(cache_path.as_ref())?,
+            t_aux: restore_t_aux_cache(cache_path.as_ref(), replica_path.as_ref())?,
+        };
+
+        StackedDrg::prove_all_partitions(
+            &compound_public_params.vanilla_params,
+            &public_inputs,
+            &private_inputs,
+            StackedCompound::partition_count(&compound_public_params),
+        )?
+    };
 
     let sanity_check = StackedDrg::<Tree, DefaultPieceHasher>::verify_all_partitions(
         &compound_public_params.vanilla_params,
@@ -468,6 +760,119 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
     Ok(out)
 }
 
+/// Proves all synthetic challenges of a sector sealed with a synthetic PoRep `porep_id`, see
+/// `is_synthetic_porep_id`, and stores the proofs in `cache_path`. Called after
+/// `seal_pre_commit_phase2`; once it returns, the layers and tree_c can be discarded with
+/// `clear_cache`, as `seal_commit_phase1` then selects its proofs from the stored ones. Those
+/// are removed with `clear_synthetic_proofs` once the sector is committed.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_synth_proofs<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: T,
+    replica_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    pre_commit: SealPreCommitOutput,
+) -> Result<()> {
+    info!("generate_synth_proofs:start: {:?}", sector_id);
+    ensure!(
+        is_synthetic_porep_id(porep_config.porep_id),
+        "synthetic proofs need a synthetic porep_id"
+    );
+    ensure!(
+        metadata(cache_path.as_ref())?.is_dir(),
+        "cache_path must be a directory"
+    );
+    ensure!(
+        metadata(replica_path.as_ref())?.is_file(),
+        "replica_path must be a file"
+    );
+
+    let SealPreCommitOutput { comm_d, comm_r } = pre_commit;
+    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+    ensure!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
+
+    let comm_d_safe = DefaultPieceDomain::try_from_bytes(&comm_d)?;
+    let replica_id = generate_replica_id::<Tree::Hasher, _>(
+        &prover_id,
+        sector_id.into(),
+        &ticket,
+        comm_d_safe,
+        &porep_config.porep_id,
+    );
+
+    let public_inputs = stacked::PublicInputs {
+        replica_id,
+        tau: Some(stacked::Tau {
+            comm_d: comm_d_safe,
+            comm_r: as_safe_commitment(&comm_r, "comm_r")?,
+        }),
+        k: None,
+        // The synthetic challenges do not depend on the seed.
+        seed: [0u8; 32],
+    };
+
+    let private_inputs = stacked::PrivateInputs::<Tree, DefaultPieceHasher> {
+        p_aux: read_p_aux::<Tree>(cache_path.as_ref())?,
+        t_aux: restore_t_aux_cache(cache_path.as_ref(), replica_path.as_ref())?,
+    };
+
+    let vanilla_params = setup_params(
+        PaddedBytesAmount::from(porep_config),
+        usize::from(PoRepProofPartitions::from(porep_config)),
+        porep_config.porep_id,
+        porep_config.api_version,
+    )?;
+    let public_params = <StackedDrg<'_, Tree, DefaultPieceHasher> as ProofScheme<'_>>::setup(
+        &vanilla_params,
+    )?;
+
+    StackedDrg::prove_synthetic(
+        &public_params,
+        &public_inputs,
+        &private_inputs,
+        &cache_path
+            .as_ref()
+            .join(CacheKey::SynthPoRepProofs.to_string()),
+    )?;
+
+    info!("generate_synth_proofs:finish: {:?}", sector_id);
+    Ok(())
+}
+
+pub(crate) fn read_p_aux<Tree: MerkleTreeTrait>(
+    cache_path: &Path,
+) -> Result<stacked::PersistentAux<<Tree::Hasher as Hasher>::Domain>> {
+    let p_aux_path = cache_path.join(CacheKey::PAux.to_string());
+    let p_aux_bytes = fs::read(&p_aux_path)
+        .with_context(|| format!("could not read file p_aux={:?}", p_aux_path))?;
+
+    deserialize(&p_aux_bytes).map_err(Into::into)
+}
+
+fn restore_t_aux_cache<Tree: 'static + MerkleTreeTrait>(
+    cache_path: &Path,
+    replica_path: &Path,
+) -> Result<TemporaryAuxCache<Tree, DefaultPieceHasher>> {
+    let t_aux = {
+        let t_aux_path = cache_path.join(CacheKey::TAux.to_string());
+        let t_aux_bytes = fs::read(&t_aux_path)
+            .with_context(|| format!("could not read file t_aux={:?}", t_aux_path))?;
+
+        let mut res: TemporaryAux<_, _> = deserialize(&t_aux_bytes)?;
+
+        // Switch t_aux to the passed in cache_path
+        res.set_cache_path(cache_path);
+        res
+    };
+
+    // Convert TemporaryAux to TemporaryAuxCache, which instantiates all
+    // elements based on the configs stored in TemporaryAux.
+    TemporaryAuxCache::new(&t_aux, replica_path.to_path_buf())
+        .context("failed to restore contents of t_aux")
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn seal_commit_phase2<Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
@@ -731,11 +1136,10 @@ pub fn verify_seal<Tree: 'static + MerkleTreeTrait>(
             &public_inputs,
             &proof,
             &ChallengeRequirements {
-                minimum_challenges: *POREP_MINIMUM_CHALLENGES
-                    .read()
-                    .expect("POREP_MINIMUM_CHALLENGES poisoned")
-                    .get(&u64::from(SectorSize::from(porep_config)))
-                    .expect("unknown sector size") as usize,
+                minimum_challenges: porep_minimum_challenges(
+                    u64::from(SectorSize::from(porep_config)),
+                    porep_config.porep_id,
+                ),
             },
         )
     };
@@ -851,11 +1255,10 @@ pub fn verify_batch_seal<Tree: 'static + MerkleTreeTrait>(
         &public_inputs,
         &proofs,
         &ChallengeRequirements {
-            minimum_challenges: *POREP_MINIMUM_CHALLENGES
-                .read()
-                .expect("POREP_MINIMUM_CHALLENGES poisoned")
-                .get(&u64::from(SectorSize::from(porep_config)))
-                .expect("unknown sector size") as usize,
+            minimum_challenges: porep_minimum_challenges(
+                u64::from(SectorSize::from(porep_config)),
+                porep_config.porep_id,
+            ),
         },
     )
         .map_err(Into::into);
@@ -954,7 +1357,7 @@ pub fn get_seal_inputs<Tree: 'static + MerkleTreeTrait>(
 }
 
 /// Given a value, get one suitable for aggregation.
-fn get_aggregate_target_len(len: usize) -> usize {
+pub(crate) fn get_aggregate_target_len(len: usize) -> usize {
     if len == 1 {
         2
     } else {
@@ -963,7 +1366,10 @@ fn get_aggregate_target_len(len: usize) -> usize {
 }
 
 /// Given a list of proofs and a target_len, make sure that the proofs list is padded to the target_len size.
-fn pad_proofs_to_target(proofs: &mut Vec<groth16::Proof<Bls12>>, target_len: usize) -> Result<()> {
+pub(crate) fn pad_proofs_to_target(
+    proofs: &mut Vec<groth16::Proof<Bls12>>,
+    target_len: usize,
+) -> Result<()> {
     trace!(
         "pad_proofs_to_target target_len {}, proofs len {}",
         target_len,
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::info;
+use merkletree::store::StoreConfig;
+use storage_proofs_core::{cache_key::CacheKey, merkle::MerkleTreeTrait, sector::SectorId};
+
+use crate::{
+    api::{
+        generate_window_post, generate_winning_post, seal_commit_phase1, seal_pre_commit_phase1,
+        seal_pre_commit_phase2, unseal_range,
+    },
+    sector_store::{SectorStore, Staged, REPLICA},
+    types::{
+        ChallengeSeed, Commitment, PoRepConfig, PoStConfig, PrivateReplicaInfo, ProverId,
+        SealCommitPhase1Output, SealPreCommitOutput, SealPreCommitPhase1Output, SnarkProof, Ticket,
+        UnpaddedByteIndex, UnpaddedBytesAmount,
+    },
+};
+
+/// `seal_pre_commit_phase1` for a sector in `store`, from the unsealed sector at `in_path`.
+pub fn seal_pre_commit_phase1_with_store<S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    store: &dyn SectorStore,
+    in_path: S,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+) -> Result<SealPreCommitPhase1Output<Tree>>
+where
+    S: AsRef<Path>,
+{
+    info!("seal_pre_commit_phase1_with_store:start: {:?}", sector_id);
+
+    let staged = Staged::new(store)?;
+    // The replica is written in place, so it has to exist.
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(staged.replica_path())
+        .with_context(|| format!("could not create {:?}", staged.replica_path()))?;
+
+    let output = seal_pre_commit_phase1::<_, _, _, Tree>(
+        porep_config,
+        staged.cache_path(),
+        in_path,
+        staged.replica_path(),
+        prover_id,
+        sector_id,
+        ticket,
+    )?;
+    staged.persist(true)?;
+
+    info!("seal_pre_commit_phase1_with_store:finish: {:?}", sector_id);
+    Ok(output)
+}
+
+/// `seal_pre_commit_phase2` for a sector in `store`.
+pub fn seal_pre_commit_phase2_with_store<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    store: &dyn SectorStore,
+) -> Result<SealPreCommitOutput> {
+    info!("seal_pre_commit_phase2_with_store:start");
+
+    let mut staged = Staged::new(store)?;
+    // Phase 2 reads the layers, tree_d and the replica.
+    let mut names = vec![REPLICA.to_string()];
+    for config in phase1_output
+        .labels
+        .labels
+        .iter()
+        .chain(std::iter::once(&phase1_output.config))
+    {
+        let path = StoreConfig::data_path(staged.cache_path(), &config.id);
+        names.push(
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .with_context(|| format!("{:?} has no file name", path))?
+                .to_string(),
+        );
+    }
+    staged.fetch(names)?;
+
+    let output = seal_pre_commit_phase2(
+        porep_config,
+        phase1_output,
+        staged.cache_path(),
+        staged.replica_path(),
+    )?;
+    staged.persist(true)?;
+
+    info!("seal_pre_commit_phase2_with_store:finish");
+    Ok(output)
+}
+
+/// `seal_commit_phase1` for a sector in `store`.
+pub fn seal_commit_phase1_with_store<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    store: &dyn SectorStore,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+    pre_commit: SealPreCommitOutput,
+) -> Result<SealCommitPhase1Output<Tree>> {
+    info!("seal_commit_phase1_with_store:start: {:?}", sector_id);
+
+    let mut staged = Staged::new(store)?;
+    staged.fetch_all()?;
+
+    let output = seal_commit_phase1::<_, Tree>(
+        porep_config,
+        staged.cache_path(),
+        staged.replica_path(),
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+        pre_commit,
+    )?;
+
+    info!("seal_commit_phase1_with_store:finish: {:?}", sector_id);
+    Ok(output)
+}
+
+/// Stages what proving a sector for PoSt reads: p_aux, t_aux, tree_r_last and the replica.
+fn stage_for_post<Tree: 'static + MerkleTreeTrait>(
+    store: &dyn SectorStore,
+    comm_r: Commitment,
+) -> Result<(Staged<'_>, PrivateReplicaInfo<Tree>)> {
+    let mut staged = Staged::new(store)?;
+    let tree_r_last = CacheKey::CommRLastTree.to_string();
+    let p_aux = CacheKey::PAux.to_string();
+    let t_aux = CacheKey::TAux.to_string();
+    let names = store.list()?.into_iter().filter(|name| {
+        name == REPLICA || *name == p_aux || *name == t_aux || name.contains(&tree_r_last)
+    });
+    staged.fetch(names)?;
+
+    let replica = PrivateReplicaInfo::new(
+        staged.replica_path().to_path_buf(),
+        comm_r,
+        staged.cache_path().to_path_buf(),
+    )?;
+    Ok((staged, replica))
+}
+
+/// `generate_window_post` for sectors in stores, given with their comm_r.
+pub fn generate_window_post_with_store<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    stores: &BTreeMap<SectorId, (Commitment, &dyn SectorStore)>,
+    prover_id: ProverId,
+) -> Result<SnarkProof> {
+    info!("generate_window_post_with_store:start");
+
+    let mut staged = Vec::with_capacity(stores.len());
+    let mut replicas = BTreeMap::new();
+    for (sector_id, (comm_r, store)) in stores {
+        let (sector, replica) = stage_for_post::<Tree>(*store, *comm_r)?;
+        staged.push(sector);
+        replicas.insert(*sector_id, replica);
+    }
+
+    let proof = generate_window_post::<Tree>(post_config, randomness, &replicas, prover_id)?;
+
+    info!("generate_window_post_with_store:finish");
+    Ok(proof)
+}
+
+/// `generate_winning_post` for sectors in stores, given with their comm_r.
+pub fn generate_winning_post_with_store<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    stores: &[(SectorId, Commitment, &dyn SectorStore)],
+    prover_id: ProverId,
+) -> Result<SnarkProof> {
+    info!("generate_winning_post_with_store:start");
+
+    let mut staged = Vec::with_capacity(stores.len());
+    let mut replicas = Vec::with_capacity(stores.len());
+    for (sector_id, comm_r, store) in stores {
+        let (sector, replica) = stage_for_post::<Tree>(*store, *comm_r)?;
+        staged.push(sector);
+        replicas.push((*sector_id, replica));
+    }
+
+    let proof = generate_winning_post::<Tree>(post_config, randomness, &replicas, prover_id)?;
+
+    info!("generate_winning_post_with_store:finish");
+    Ok(proof)
+}
+
+/// `unseal_range` for a sector in `store`, streaming its replica.
+#[allow(clippy::too_many_arguments)]
+pub fn unseal_range_with_store<W, Tree>(
+    porep_config: PoRepConfig,
+    store: &dyn SectorStore,
+    unsealed_output: W,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    comm_d: Commitment,
+    ticket: Ticket,
+    offset: UnpaddedByteIndex,
+    num_bytes: UnpaddedBytesAmount,
+) -> Result<UnpaddedBytesAmount>
+where
+    W: Write,
+    Tree: 'static + MerkleTreeTrait,
+{
+    info!("unseal_range_with_store:start");
+
+    let staged = Staged::new(store)?;
+    let amount = unseal_range::<_, _, _, Tree>(
+        porep_config,
+        staged.cache_path(),
+        store.stream(REPLICA)?,
+        unsealed_output,
+        prover_id,
+        sector_id,
+        comm_d,
+        ticket,
+        offset,
+        num_bytes,
+    )?;
+
+    info!("unseal_range_with_store:finish");
+    Ok(amount)
+}
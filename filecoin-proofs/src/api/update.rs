@@ -0,0 +1,384 @@
+use std::fs::{metadata, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use bellperson::bls::Fr;
+use bincode::serialize;
+use filecoin_hashers::{HashFunction, Hasher};
+use log::info;
+use memmap::MmapOptions;
+use merkletree::store::{DiskStore, StoreConfig};
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    compound_proof::{self, CompoundProof},
+    merkle::{BinaryMerkleTree, MerkleTreeTrait},
+    multi_proof::MultiProof,
+    proof::{NoRequirements, ProofScheme},
+    util::{default_rows_to_discard, NODE_SIZE},
+};
+use storage_proofs_porep::{
+    stacked::{build_tree_d, PersistentAux},
+    update::{
+        self, build_tree_r_last, open_tree_r_last, EmptySectorUpdate, EmptySectorUpdateCompound,
+    },
+};
+use typenum::Unsigned;
+
+use crate::{
+    api::{
+        as_safe_commitment, commitment_from_fr, compute_comm_d, get_base_tree_leafs,
+        get_base_tree_size, read_p_aux,
+    },
+    caches::{get_empty_sector_update_params, get_empty_sector_update_verifying_key},
+    constants::{DefaultBinaryTree, DefaultPieceDomain, DefaultPieceHasher},
+    types::{
+        Commitment, EmptySectorUpdateEncoded, EmptySectorUpdateProof, PaddedBytesAmount, PieceInfo,
+        PoRepConfig, SectorSize, BINARY_ARITY,
+    },
+};
+
+/// Encodes the deal data at `staged_data_path` into the committed capacity sector whose replica,
+/// the sector key, is at `sector_key_path`, see `storage_proofs_porep::update`. The new replica is
+/// written to `new_replica_path`, and its tree_r_last, the tree_d of the data and its p_aux to
+/// `new_cache_path`. The sector keeps its tree_c, so the sector key cache only needs its p_aux and
+/// tree_r_last.
+///
+/// # Arguments
+///
+/// * `porep_config` - porep configuration containing the sector size.
+/// * `new_replica_path` - path to the file the new replica is written to.
+/// * `new_cache_path` - path to the directory the trees of the new replica are written to.
+/// * `sector_key_path` - path to the replica of the committed capacity sector.
+/// * `sector_key_cache_path` - path to the cache directory of the committed capacity sector.
+/// * `staged_data_path` - path to the padded deal data, a whole sector.
+/// * `piece_infos` - the pieces of the deal data, whose commitments have to match it.
+pub fn encode_into<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    new_replica_path: &Path,
+    new_cache_path: &Path,
+    sector_key_path: &Path,
+    sector_key_cache_path: &Path,
+    staged_data_path: &Path,
+    piece_infos: &[PieceInfo],
+) -> Result<EmptySectorUpdateEncoded> {
+    info!("encode_into:start");
+    ensure!(
+        metadata(new_cache_path)?.is_dir(),
+        "new_cache_path must be a directory"
+    );
+    let sector_bytes = usize::from(PaddedBytesAmount::from(porep_config));
+
+    let p_aux = read_p_aux::<Tree>(sector_key_cache_path)?;
+    let comm_r_old = <Tree::Hasher as Hasher>::Function::hash2(&p_aux.comm_c, &p_aux.comm_r_last);
+
+    let data = map_sector(staged_data_path, sector_bytes)?;
+    let tree_d_config = tree_d_config(porep_config.sector_size, new_cache_path)?;
+    let tree_d_new = build_tree_d::<DefaultPieceHasher>(
+        tree_d_config,
+        sector_bytes / NODE_SIZE,
+        &data,
+    )?;
+    let comm_d_new = tree_d_new.root();
+    let comm_d_root: Fr = comm_d_new.into();
+    ensure!(
+        commitment_from_fr(comm_d_root) == compute_comm_d(porep_config.sector_size, piece_infos)?,
+        "the staged data does not match the comm_d of its pieces"
+    );
+    drop(tree_d_new);
+
+    let replica_old = map_sector(sector_key_path, sector_bytes)?;
+    let f_replica_new = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(new_replica_path)
+        .with_context(|| format!("could not open new_replica_path={:?}", new_replica_path))?;
+    f_replica_new.set_len(sector_bytes as u64)?;
+    let mut replica_new = unsafe {
+        MmapOptions::new()
+            .map_mut(&f_replica_new)
+            .with_context(|| format!("could not mmap new_replica_path={:?}", new_replica_path))?
+    };
+
+    info!("encoding the data into the sector key");
+    EmptySectorUpdate::<Tree, DefaultPieceHasher>::encode(
+        &comm_d_new,
+        &comm_r_old,
+        &replica_old,
+        &data,
+        &mut replica_new,
+    )?;
+    replica_new.flush()?;
+    drop(data);
+    drop(replica_old);
+
+    info!("building tree_r_last of the new replica");
+    let tree_r_new = build_tree_r_last::<Tree>(
+        &replica_new,
+        tree_r_last_config::<Tree>(porep_config.sector_size, new_cache_path)?,
+        new_replica_path.to_path_buf(),
+    )?;
+    let comm_r_last_new = tree_r_new.root();
+    let comm_r_new = <Tree::Hasher as Hasher>::Function::hash2(&p_aux.comm_c, &comm_r_last_new);
+
+    // The new replica is proven in PoSt like any other, which needs its p_aux.
+    let p_aux_new = PersistentAux {
+        comm_c: p_aux.comm_c,
+        comm_r_last: comm_r_last_new,
+    };
+    let p_aux_path = new_cache_path.join(CacheKey::PAux.to_string());
+    let mut f_p_aux = File::create(&p_aux_path)
+        .with_context(|| format!("could not create file p_aux={:?}", p_aux_path))?;
+    f_p_aux
+        .write_all(&serialize(&p_aux_new)?)
+        .with_context(|| format!("could not write to file p_aux={:?}", p_aux_path))?;
+
+    info!("encode_into:finish");
+    Ok(EmptySectorUpdateEncoded {
+        comm_r_new: commitment_from_fr(comm_r_new.into()),
+        comm_r_last_new: commitment_from_fr(comm_r_last_new.into()),
+        comm_d_new: commitment_from_fr(comm_d_root),
+    })
+}
+
+/// Recovers the deal data encoded by `encode_into` into the replica at `replica_path` and writes
+/// it to `out_data_path`.
+pub fn decode_from<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    out_data_path: &Path,
+    replica_path: &Path,
+    sector_key_path: &Path,
+    sector_key_cache_path: &Path,
+    comm_d_new: Commitment,
+) -> Result<()> {
+    info!("decode_from:start");
+    let sector_bytes = usize::from(PaddedBytesAmount::from(porep_config));
+    let comm_d_new: DefaultPieceDomain = as_safe_commitment(&comm_d_new, "comm_d_new")?;
+
+    let p_aux = read_p_aux::<Tree>(sector_key_cache_path)?;
+    let comm_r_old = <Tree::Hasher as Hasher>::Function::hash2(&p_aux.comm_c, &p_aux.comm_r_last);
+
+    let replica_old = map_sector(sector_key_path, sector_bytes)?;
+    let replica_new = map_sector(replica_path, sector_bytes)?;
+    let f_data = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(out_data_path)
+        .with_context(|| format!("could not open out_data_path={:?}", out_data_path))?;
+    f_data.set_len(sector_bytes as u64)?;
+    let mut data = unsafe {
+        MmapOptions::new()
+            .map_mut(&f_data)
+            .with_context(|| format!("could not mmap out_data_path={:?}", out_data_path))?
+    };
+
+    EmptySectorUpdate::<Tree, DefaultPieceHasher>::decode(
+        &comm_d_new,
+        &comm_r_old,
+        &replica_old,
+        &replica_new,
+        &mut data,
+    )?;
+    data.flush()?;
+
+    info!("decode_from:finish");
+    Ok(())
+}
+
+/// Proves that the replica at `replica_path` encodes the data of `comm_d_new` into the sector key
+/// at `sector_key_path`, see `encode_into`.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_empty_sector_update_proof<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r_old: Commitment,
+    comm_r_new: Commitment,
+    comm_d_new: Commitment,
+    sector_key_path: &Path,
+    sector_key_cache_path: &Path,
+    replica_path: &Path,
+    replica_cache_path: &Path,
+) -> Result<EmptySectorUpdateProof> {
+    info!("generate_empty_sector_update_proof:start");
+    let sector_size = porep_config.sector_size;
+
+    let p_aux = read_p_aux::<Tree>(sector_key_cache_path)?;
+    let tree_r_old_config = tree_r_last_config::<Tree>(sector_size, sector_key_cache_path)?;
+    let tree_r_old = open_tree_r_last::<Tree>(&tree_r_old_config, sector_key_path.to_path_buf())?;
+    let tree_r_new_config = tree_r_last_config::<Tree>(sector_size, replica_cache_path)?;
+    let tree_r_new = open_tree_r_last::<Tree>(&tree_r_new_config, replica_path.to_path_buf())?;
+
+    let tree_d_config = tree_d_config(sector_size, replica_cache_path)?;
+    let tree_d_size = tree_d_config.size.context("tree_d config has no size")?;
+    let tree_d_store: DiskStore<DefaultPieceDomain> =
+        DiskStore::new_from_disk(tree_d_size, BINARY_ARITY, &tree_d_config)
+            .with_context(|| format!("could not open tree_d in {:?}", replica_cache_path))?;
+    let tree_d_new = BinaryMerkleTree::<DefaultPieceHasher>::from_data_store(
+        tree_d_store,
+        get_base_tree_leafs::<DefaultBinaryTree>(tree_d_size)?,
+    )?;
+
+    let public_inputs = update::PublicInputs {
+        k: 0,
+        comm_r_old: as_safe_commitment(&comm_r_old, "comm_r_old")?,
+        comm_d_new: as_safe_commitment(&comm_d_new, "comm_d_new")?,
+        comm_r_new: as_safe_commitment(&comm_r_new, "comm_r_new")?,
+    };
+    let private_inputs = update::PrivateInputs::<Tree, DefaultPieceHasher> {
+        comm_c: p_aux.comm_c,
+        tree_r_old: &tree_r_old,
+        tree_d_new: &tree_d_new,
+        tree_r_new: &tree_r_new,
+        tree_r_rows_to_discard: tree_r_old_config.rows_to_discard,
+    };
+
+    let compound_public_params = compound_public_params::<Tree>(porep_config)?;
+    let partitions = EmptySectorUpdateCompound::<Tree, DefaultPieceHasher>::partition_count(
+        &compound_public_params,
+    );
+    let vanilla_proofs = EmptySectorUpdate::<Tree, DefaultPieceHasher>::prove_all_partitions(
+        &compound_public_params.vanilla_params,
+        &public_inputs,
+        &private_inputs,
+        partitions,
+    )?;
+
+    let groth_params = get_empty_sector_update_params::<Tree>(porep_config)?;
+    info!("snark_proof:start");
+    let groth_proofs = EmptySectorUpdateCompound::<Tree, DefaultPieceHasher>::circuit_proofs(
+        &public_inputs,
+        vanilla_proofs,
+        &compound_public_params.vanilla_params,
+        &groth_params,
+    )?;
+    info!("snark_proof:finish");
+
+    let mut proof_bytes = Vec::new();
+    MultiProof::new(groth_proofs, &groth_params.pvk).write(&mut proof_bytes)?;
+
+    // It is never correct to return a proof which does not verify.
+    ensure!(
+        verify_empty_sector_update_proof::<Tree>(
+            porep_config,
+            &proof_bytes,
+            comm_r_old,
+            comm_r_new,
+            comm_d_new,
+        )?,
+        "post-proof verification failed"
+    );
+
+    info!("generate_empty_sector_update_proof:finish");
+    Ok(EmptySectorUpdateProof(proof_bytes))
+}
+
+/// Verifies a proof generated by `generate_empty_sector_update_proof`.
+pub fn verify_empty_sector_update_proof<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    proof: &[u8],
+    comm_r_old: Commitment,
+    comm_r_new: Commitment,
+    comm_d_new: Commitment,
+) -> Result<bool> {
+    info!("verify_empty_sector_update_proof:start");
+    ensure!(
+        comm_d_new != [0; 32],
+        "Invalid all zero commitment (comm_d_new)"
+    );
+    ensure!(
+        comm_r_old != [0; 32],
+        "Invalid all zero commitment (comm_r_old)"
+    );
+    ensure!(
+        comm_r_new != [0; 32],
+        "Invalid all zero commitment (comm_r_new)"
+    );
+
+    let public_inputs =
+        update::PublicInputs::<<Tree::Hasher as Hasher>::Domain, DefaultPieceDomain> {
+            k: 0,
+            comm_r_old: as_safe_commitment(&comm_r_old, "comm_r_old")?,
+            comm_d_new: as_safe_commitment(&comm_d_new, "comm_d_new")?,
+            comm_r_new: as_safe_commitment(&comm_r_new, "comm_r_new")?,
+        };
+
+    let compound_public_params = compound_public_params::<Tree>(porep_config)?;
+    let verifying_key = get_empty_sector_update_verifying_key::<Tree>(porep_config)?;
+    let multi_proof = MultiProof::new_from_reader(
+        Some(
+            EmptySectorUpdateCompound::<Tree, DefaultPieceHasher>::partition_count(
+                &compound_public_params,
+            ),
+        ),
+        proof,
+        &verifying_key,
+    )?;
+
+    let result = EmptySectorUpdateCompound::verify(
+        &compound_public_params,
+        &public_inputs,
+        &multi_proof,
+        &NoRequirements,
+    );
+
+    info!("verify_empty_sector_update_proof:finish");
+    result
+}
+
+fn compound_public_params<'a, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+) -> Result<compound_proof::PublicParams<'a, EmptySectorUpdate<'a, Tree, DefaultPieceHasher>>> {
+    let sector_bytes = u64::from(PaddedBytesAmount::from(porep_config));
+    let compound_setup_params = compound_proof::SetupParams {
+        vanilla_params: update::SetupParams { sector_bytes },
+        partitions: Some(update::partition_count(sector_bytes as usize / NODE_SIZE)),
+        priority: false,
+    };
+
+    EmptySectorUpdateCompound::<Tree, DefaultPieceHasher>::setup(&compound_setup_params)
+}
+
+/// The config of the tree_r_last of a sector in `cache_path`.
+fn tree_r_last_config<Tree: MerkleTreeTrait>(
+    sector_size: SectorSize,
+    cache_path: &Path,
+) -> Result<StoreConfig> {
+    let base_tree_size = get_base_tree_size::<Tree>(sector_size)?;
+    let base_tree_leafs = get_base_tree_leafs::<Tree>(base_tree_size)?;
+    let mut config = StoreConfig::new(
+        cache_path,
+        CacheKey::CommRLastTree.to_string(),
+        default_rows_to_discard(base_tree_leafs, Tree::Arity::to_usize()),
+    );
+    config.size = Some(base_tree_size);
+
+    Ok(config)
+}
+
+/// The config of the tree_d of a sector in `cache_path`.
+fn tree_d_config(sector_size: SectorSize, cache_path: &Path) -> Result<StoreConfig> {
+    let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(sector_size)?;
+    let base_tree_leafs = get_base_tree_leafs::<DefaultBinaryTree>(base_tree_size)?;
+    let mut config = StoreConfig::new(
+        cache_path,
+        CacheKey::CommDTree.to_string(),
+        default_rows_to_discard(base_tree_leafs, BINARY_ARITY),
+    );
+    config.size = Some(base_tree_size);
+
+    Ok(config)
+}
+
+/// Maps the sector of `sector_bytes` at `path` read only.
+fn map_sector(path: &Path, sector_bytes: usize) -> Result<memmap::Mmap> {
+    let file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+    ensure!(
+        file.metadata()?.len() == sector_bytes as u64,
+        "{:?} does not hold a sector of {} bytes",
+        path,
+        sector_bytes
+    );
+
+    unsafe { MmapOptions::new().map(&file) }.with_context(|| format!("could not mmap {:?}", path))
+}
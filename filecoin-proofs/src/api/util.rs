@@ -5,7 +5,10 @@ use bellperson::bls::Fr;
 use filecoin_hashers::{Domain, Hasher};
 use fr32::{bytes_into_fr, fr_into_bytes};
 use merkletree::merkle::{get_merkle_tree_leafs, get_merkle_tree_len};
-use storage_proofs_core::merkle::{get_base_tree_count, MerkleTreeTrait};
+use storage_proofs_core::{
+    merkle::{get_base_tree_count, MerkleTreeTrait},
+    settings,
+};
 use typenum::Unsigned;
 use log::error;
 
@@ -41,7 +44,7 @@ pub fn get_base_tree_leafs<Tree: MerkleTreeTrait>(base_tree_size: usize) -> Resu
 }
 
 pub fn bind_p1_tree() -> bool {
-    let res: usize = std::env::var("FIL_PROOFS_BIND_P1_TREE")
+    let res: usize = settings::var("FIL_PROOFS_BIND_P1_TREE")
         .and_then(|v| match v.parse() {
             Ok(val) => Ok(val),
             Err(_) => {
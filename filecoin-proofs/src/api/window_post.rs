@@ -1,8 +1,13 @@
 use std::collections::BTreeMap;
 
 use anyhow::{ensure, Context, Result};
-use filecoin_hashers::Hasher;
-use log::info;
+use bellperson::{
+    bls::{Bls12, Fr},
+    groth16,
+};
+use filecoin_hashers::{Domain, Hasher};
+use log::{info, trace};
+use sha2::{Digest, Sha256};
 use storage_proofs_core::{
     compound_proof::{self, CompoundProof},
     merkle::MerkleTreeTrait,
@@ -14,12 +19,18 @@ use storage_proofs_post::fallback::{
 };
 
 use crate::{
-    api::{as_safe_commitment, get_partitions_for_window_post, partition_vanilla_proofs},
-    caches::{get_post_params, get_post_verifying_key},
+    api::{
+        as_safe_commitment, get_aggregate_target_len, get_partitions_for_window_post,
+        pad_proofs_to_target, partition_vanilla_proofs,
+    },
+    caches::{
+        get_post_params, get_post_verifying_key, get_window_post_srs_key,
+        get_window_post_srs_verifier_key,
+    },
     parameters::window_post_setup_params,
     types::{
-        ChallengeSeed, FallbackPoStSectorProof, PoStConfig, PrivateReplicaInfo, ProverId,
-        PublicReplicaInfo, SnarkProof,
+        AggregateSnarkProof, ChallengeSeed, FallbackPoStSectorProof, PartitionSnarkProof,
+        PoStConfig, PrivateReplicaInfo, ProverId, PublicReplicaInfo, SnarkProof,
     },
     PoStType,
 };
@@ -242,3 +253,237 @@ pub fn verify_window_post<Tree: 'static + MerkleTreeTrait>(
 
     Ok(true)
 }
+
+/// The public inputs of a Window PoSt over `replicas`, in the order of their sector ids.
+fn window_post_public_inputs<Tree: 'static + MerkleTreeTrait>(
+    randomness: &ChallengeSeed,
+    prover_id: ProverId,
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+) -> Result<fallback::PublicInputs<<Tree::Hasher as Hasher>::Domain>> {
+    let randomness_safe = as_safe_commitment(randomness, "randomness")?;
+    let prover_id_safe = as_safe_commitment(&prover_id, "prover_id")?;
+
+    let pub_sectors: Vec<_> = replicas
+        .iter()
+        .map(|(sector_id, replica)| {
+            let comm_r = replica.safe_comm_r().with_context(|| {
+                format!("window_post_public_inputs: safe_comm_r failed: {:?}", sector_id)
+            })?;
+            Ok(PublicSector {
+                id: *sector_id,
+                comm_r,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(fallback::PublicInputs {
+        randomness: randomness_safe,
+        prover_id: prover_id_safe,
+        sectors: pub_sectors,
+        k: None,
+    })
+}
+
+/// Hashes the randomness, prover id and replicas of a Window PoSt, binding an aggregate of its
+/// partition proofs to them.
+fn hash_window_post_inputs<D: Domain>(pub_inputs: &fallback::PublicInputs<D>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(AsRef::<[u8]>::as_ref(&pub_inputs.randomness));
+    hasher.update(AsRef::<[u8]>::as_ref(&pub_inputs.prover_id));
+    for sector in &pub_inputs.sectors {
+        hasher.update(&u64::from(sector.id).to_le_bytes()[..]);
+        hasher.update(AsRef::<[u8]>::as_ref(&sector.comm_r));
+    }
+    hasher.finalize().into()
+}
+
+/// Generates the proof of the partition `partition_index` of a Window proof-of-spacetime over
+/// `replicas`, from the vanilla proofs of at least the sectors of that partition. The partitions
+/// can be proven independently, e.g. on different machines, and their proofs combined with
+/// `aggregate_window_post_proofs`.
+pub fn generate_single_window_post_with_vanilla<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    prover_id: ProverId,
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+    partition_index: usize,
+    vanilla_proofs: &[FallbackPoStSectorProof<Tree>],
+) -> Result<PartitionSnarkProof> {
+    info!("generate_single_window_post_with_vanilla:start");
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+
+    let vanilla_params = window_post_setup_params(&post_config);
+    let partitions = get_partitions_for_window_post(replicas.len(), &post_config);
+    ensure!(
+        partition_index < partitions.unwrap_or(1),
+        "invalid partition index {} of {} partitions",
+        partition_index,
+        partitions.unwrap_or(1)
+    );
+
+    let setup_params = compound_proof::SetupParams {
+        vanilla_params,
+        partitions,
+        priority: post_config.priority,
+    };
+    let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+        FallbackPoStCompound::setup(&setup_params)?;
+    let groth_params = get_post_params::<Tree>(&post_config)?;
+    let pub_inputs = window_post_public_inputs::<Tree>(randomness, prover_id, replicas)?;
+
+    let sector_count = pub_params.vanilla_params.sector_count;
+    let mut sector_proofs = Vec::with_capacity(sector_count);
+    for pub_sector in pub_inputs
+        .sectors
+        .chunks(sector_count)
+        .nth(partition_index)
+        .expect("partition index checked above")
+    {
+        let sector_proof = vanilla_proofs
+            .iter()
+            .find(|proof| proof.sector_id == pub_sector.id)
+            .with_context(|| format!("missing vanilla proof of {:?}", pub_sector.id))?;
+        sector_proofs.extend(sector_proof.vanilla_proof.sectors.clone());
+    }
+    // Pad the partition to the sectors of the circuit, like `partition_vanilla_proofs`.
+    while sector_proofs.len() < sector_count {
+        sector_proofs.push(sector_proofs[sector_proofs.len() - 1].clone());
+    }
+
+    let groth_proof = FallbackPoStCompound::partition_circuit_proof(
+        &pub_inputs,
+        fallback::Proof {
+            sectors: sector_proofs,
+        },
+        &pub_params.vanilla_params,
+        &groth_params,
+        partition_index,
+    )?;
+    let mut proof = Vec::new();
+    groth_proof.write(&mut proof)?;
+
+    info!("generate_single_window_post_with_vanilla:finish");
+
+    Ok(PartitionSnarkProof(proof))
+}
+
+/// Aggregates the proofs of all partitions of a Window proof-of-spacetime over `replicas`, in
+/// order of their partition index, into a single proof. The count is padded to a power of two,
+/// by duplicating the last proof.
+pub fn aggregate_window_post_proofs<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    prover_id: ProverId,
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+    partition_proofs: &[PartitionSnarkProof],
+) -> Result<AggregateSnarkProof> {
+    info!("aggregate_window_post_proofs:start");
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+
+    let partitions = get_partitions_for_window_post(replicas.len(), &post_config).unwrap_or(1);
+    ensure!(
+        partition_proofs.len() == partitions,
+        "expected the proofs of {} partitions, got {}",
+        partitions,
+        partition_proofs.len()
+    );
+
+    let mut proofs = partition_proofs
+        .iter()
+        .map(|proof| Ok(groth16::Proof::<Bls12>::read(&proof.0[..])?))
+        .collect::<Result<Vec<_>>>()?;
+    let target_proofs_len = get_aggregate_target_len(proofs.len());
+    trace!(
+        "aggregate_window_post_proofs will pad {} proofs to target_len {}",
+        proofs.len(),
+        target_proofs_len
+    );
+    pad_proofs_to_target(&mut proofs, target_proofs_len)?;
+
+    let pub_inputs = window_post_public_inputs::<Tree>(randomness, prover_id, replicas)?;
+    let hashed_inputs = hash_window_post_inputs(&pub_inputs);
+
+    let srs_prover_key = get_window_post_srs_key::<Tree>(&post_config, proofs.len())?;
+    let aggregate_proof =
+        FallbackPoStCompound::<Tree>::aggregate_proofs(&srs_prover_key, &hashed_inputs, &proofs)?;
+    let mut aggregate_proof_bytes = Vec::new();
+    aggregate_proof.write(&mut aggregate_proof_bytes)?;
+
+    info!("aggregate_window_post_proofs:finish");
+
+    Ok(aggregate_proof_bytes)
+}
+
+/// Verifies an aggregate of the partition proofs of a Window proof-of-spacetime over `replicas`.
+pub fn verify_aggregate_window_post_proofs<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    prover_id: ProverId,
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+    aggregate_proof_bytes: &[u8],
+) -> Result<bool> {
+    info!("verify_aggregate_window_post_proofs:start");
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+
+    let aggregate_proof =
+        groth16::aggregate::AggregateProof::read(std::io::Cursor::new(aggregate_proof_bytes))?;
+    let aggregated_proofs_len = aggregate_proof.tmipp.gipa.nproofs as usize;
+
+    let vanilla_params = window_post_setup_params(&post_config);
+    let partitions = get_partitions_for_window_post(replicas.len(), &post_config);
+    let partition_count = partitions.unwrap_or(1);
+    ensure!(
+        aggregated_proofs_len == get_aggregate_target_len(partition_count),
+        "aggregate of {} proofs does not match {} partitions",
+        aggregated_proofs_len,
+        partition_count
+    );
+
+    let setup_params = compound_proof::SetupParams {
+        vanilla_params,
+        partitions,
+        priority: false,
+    };
+    let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+        FallbackPoStCompound::setup(&setup_params)?;
+    let pub_inputs = window_post_public_inputs::<Tree>(randomness, prover_id, replicas)?;
+    let hashed_inputs = hash_window_post_inputs(&pub_inputs);
+
+    let mut inputs = (0..partition_count)
+        .map(|k| {
+            FallbackPoStCompound::generate_public_inputs(
+                &pub_inputs,
+                &pub_params.vanilla_params,
+                Some(k),
+            )
+        })
+        .collect::<Result<Vec<Vec<Fr>>>>()?;
+    // Pad the inputs like the proofs were padded.
+    while inputs.len() < aggregated_proofs_len {
+        inputs.push(inputs[inputs.len() - 1].clone());
+    }
+
+    let verifying_key = get_post_verifying_key::<Tree>(&post_config)?;
+    let srs_verifier_key =
+        get_window_post_srs_verifier_key::<Tree>(&post_config, aggregated_proofs_len)?;
+    let result = FallbackPoStCompound::<Tree>::verify_aggregate_proofs(
+        &srs_verifier_key,
+        &verifying_key,
+        &hashed_inputs,
+        &inputs,
+        &aggregate_proof,
+    )?;
+
+    info!("verify_aggregate_window_post_proofs:finish");
+
+    Ok(result)
+}
@@ -11,12 +11,18 @@ use log::{info, trace};
 use once_cell::sync::OnceCell;
 use rand::rngs::OsRng;
 use storage_proofs_core::{compound_proof::CompoundProof, merkle::MerkleTreeTrait};
-use storage_proofs_porep::stacked::{StackedCompound, StackedDrg};
+use storage_proofs_porep::{
+    stacked::{StackedCompound, StackedDrg},
+    update::{EmptySectorUpdate, EmptySectorUpdateCompound},
+};
 use storage_proofs_post::fallback::{FallbackPoSt, FallbackPoStCircuit, FallbackPoStCompound};
 
 use crate::{
     constants::{DefaultPieceHasher, PUBLISHED_SECTOR_SIZES},
-    parameters::{public_params, window_post_public_params, winning_post_public_params},
+    parameters::{
+        empty_sector_update_public_params, public_params, window_post_public_params,
+        winning_post_public_params,
+    },
     types::{PaddedBytesAmount, PoRepConfig, PoRepProofPartitions, PoStConfig, PoStType},
 };
 
@@ -49,6 +55,17 @@ lazy_static! {
         SRSCache::with_defaults(SRS_VERIFIER_IDENTIFIER);
 }
 
+/// The sector sizes srs keys can be looked up for: the published ones, and with the
+/// `shape-registry` feature, those registered before the first lookup.
+fn srs_sector_sizes() -> Vec<u64> {
+    #[allow(unused_mut)]
+    let mut sector_sizes = PUBLISHED_SECTOR_SIZES.to_vec();
+    #[cfg(feature = "shape-registry")]
+    sector_sizes.extend(crate::registry::registered_sector_sizes());
+
+    sector_sizes
+}
+
 /// We have a separate SRSCache type for srs keys since they are
 /// cached differently (as a hashmap per type, keyed by identifier
 /// consisting of sector size and pow2 num proofs to aggregate).
@@ -63,15 +80,18 @@ impl<G> SRSCache<G> {
     pub fn with_defaults(identifier: &str) -> Self {
         let mut data = HashMap::new();
         let mut num_proofs_to_aggregate = PROOFS_TESTS_MIN_SNARKS;
+        let sector_sizes = srs_sector_sizes();
 
         loop {
-            for sector_size in &PUBLISHED_SECTOR_SIZES {
-                let key = format!(
-                    "STACKED[{}-{}]-{}",
-                    sector_size, num_proofs_to_aggregate, identifier,
-                );
-                trace!("inserting placeholder srs key with hash key {}", key);
-                data.insert(key, OnceCell::new());
+            for sector_size in &sector_sizes {
+                for proof in &["STACKED", "WINDOW_POST"] {
+                    let key = format!(
+                        "{}[{}-{}]-{}",
+                        proof, sector_size, num_proofs_to_aggregate, identifier,
+                    );
+                    trace!("inserting placeholder srs key with hash key {}", key);
+                    data.insert(key, OnceCell::new());
+                }
             }
 
             num_proofs_to_aggregate <<= 1;
@@ -337,6 +357,52 @@ pub fn get_post_verifying_key<Tree: 'static + MerkleTreeTrait>(
     }
 }
 
+pub fn get_empty_sector_update_params<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+) -> Result<Arc<Bls12GrothParams>> {
+    let public_params =
+        empty_sector_update_public_params::<Tree>(PaddedBytesAmount::from(porep_config))?;
+
+    let parameters_generator = || {
+        <EmptySectorUpdateCompound<Tree, DefaultPieceHasher> as CompoundProof<
+            EmptySectorUpdate<'_, Tree, DefaultPieceHasher>,
+            _,
+        >>::groth_params::<OsRng>(None, &public_params)
+        .map_err(Into::into)
+    };
+
+    lookup_groth_params(
+        format!(
+            "EMPTY_SECTOR_UPDATE[{}]",
+            usize::from(PaddedBytesAmount::from(porep_config))
+        ),
+        parameters_generator,
+    )
+}
+
+pub fn get_empty_sector_update_verifying_key<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+) -> Result<Arc<Bls12PreparedVerifyingKey>> {
+    let public_params =
+        empty_sector_update_public_params::<Tree>(PaddedBytesAmount::from(porep_config))?;
+
+    let vk_generator = || {
+        let vk = <EmptySectorUpdateCompound<Tree, DefaultPieceHasher> as CompoundProof<
+            EmptySectorUpdate<'_, Tree, DefaultPieceHasher>,
+            _,
+        >>::verifying_key::<OsRng>(None, &public_params)?;
+        Ok(prepare_verifying_key(&vk))
+    };
+
+    lookup_verifying_key(
+        format!(
+            "EMPTY_SECTOR_UPDATE[{}]",
+            usize::from(PaddedBytesAmount::from(porep_config))
+        ),
+        vk_generator,
+    )
+}
+
 pub fn get_stacked_srs_key<Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
     num_proofs_to_aggregate: usize,
@@ -404,3 +470,59 @@ pub fn get_stacked_srs_verifier_key<Tree: 'static + MerkleTreeTrait>(
         srs_verifier_generator,
     )
 }
+
+pub fn get_window_post_srs_key<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    num_proofs_to_aggregate: usize,
+) -> Result<Arc<Bls12ProverSRSKey>> {
+    let post_public_params = window_post_public_params::<Tree>(post_config)?;
+
+    let srs_generator = || {
+        trace!(
+            "get_window_post_srs_key specializing WINDOW_POST[{}-{}]",
+            usize::from(post_config.padded_sector_size()),
+            num_proofs_to_aggregate,
+        );
+        <FallbackPoStCompound<Tree> as CompoundProof<
+            FallbackPoSt<'_, Tree>,
+            FallbackPoStCircuit<Tree>,
+        >>::srs_key::<OsRng>(None, &post_public_params, num_proofs_to_aggregate)
+    };
+
+    lookup_srs_key(
+        format!(
+            "WINDOW_POST[{}-{}]",
+            usize::from(post_config.padded_sector_size()),
+            num_proofs_to_aggregate,
+        ),
+        srs_generator,
+    )
+}
+
+pub fn get_window_post_srs_verifier_key<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    num_proofs_to_aggregate: usize,
+) -> Result<Arc<Bls12VerifierSRSKey>> {
+    let post_public_params = window_post_public_params::<Tree>(post_config)?;
+
+    let srs_verifier_generator = || {
+        trace!(
+            "get_window_post_srs_verifier_key specializing WINDOW_POST[{}-{}]",
+            usize::from(post_config.padded_sector_size()),
+            num_proofs_to_aggregate,
+        );
+        <FallbackPoStCompound<Tree> as CompoundProof<
+            FallbackPoSt<'_, Tree>,
+            FallbackPoStCircuit<Tree>,
+        >>::srs_verifier_key::<OsRng>(None, &post_public_params, num_proofs_to_aggregate)
+    };
+
+    lookup_srs_verifier_key(
+        format!(
+            "WINDOW_POST[{}-{}]",
+            usize::from(post_config.padded_sector_size()),
+            num_proofs_to_aggregate,
+        ),
+        srs_verifier_generator,
+    )
+}
@@ -82,6 +82,44 @@ lazy_static! {
         .copied()
         .collect()
     );
+    // Non-interactive PoRep takes more challenges, spread over more partitions of the same
+    // number of challenges each, so the circuit and its parameters are those of interactive PoRep.
+    // These are the values of the registered non-interactive proof types; the partitions do not
+    // depend on the `PoRepConfig`, see `porep_partitions`.
+    pub static ref NI_POREP_MINIMUM_CHALLENGES: RwLock<HashMap<u64, u64>> = RwLock::new(
+        [
+            (SECTOR_SIZE_2_KIB, 4),
+            (SECTOR_SIZE_4_KIB, 4),
+            (SECTOR_SIZE_16_KIB, 4),
+            (SECTOR_SIZE_32_KIB, 4),
+            (SECTOR_SIZE_8_MIB, 4),
+            (SECTOR_SIZE_16_MIB, 4),
+            (SECTOR_SIZE_512_MIB, 4),
+            (SECTOR_SIZE_1_GIB, 4),
+            (SECTOR_SIZE_32_GIB, 2253),
+            (SECTOR_SIZE_64_GIB, 2253),
+        ]
+        .iter()
+        .copied()
+        .collect()
+    );
+    pub static ref NI_POREP_PARTITIONS: RwLock<HashMap<u64, u8>> = RwLock::new(
+        [
+            (SECTOR_SIZE_2_KIB, 2),
+            (SECTOR_SIZE_4_KIB, 2),
+            (SECTOR_SIZE_16_KIB, 2),
+            (SECTOR_SIZE_32_KIB, 2),
+            (SECTOR_SIZE_8_MIB, 2),
+            (SECTOR_SIZE_16_MIB, 2),
+            (SECTOR_SIZE_512_MIB, 2),
+            (SECTOR_SIZE_1_GIB, 2),
+            (SECTOR_SIZE_32_GIB, 126),
+            (SECTOR_SIZE_64_GIB, 126),
+        ]
+        .iter()
+        .copied()
+        .collect()
+    );
     pub static ref LAYERS: RwLock<HashMap<u64, usize>> = RwLock::new(
         [
             (SECTOR_SIZE_2_KIB, 2),
@@ -188,7 +226,19 @@ pub fn is_sector_shape_top2(sector_size: u64) -> bool {
     matches!(sector_size, SECTOR_SIZE_32_KIB | SECTOR_SIZE_64_GIB)
 }
 
-/// Calls a function with the type hint of the sector shape matching the provided sector.
+/// `with_shape!` for sector sizes it does not know, which are only supported with the
+/// `shape-registry` feature, see `registry`.
+#[cfg(not(feature = "shape-registry"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! with_registered_shape {
+    ($size:expr, $f:ident, $($args:expr,)*) => {
+        panic!("unsupported sector size: {}", $size)
+    };
+}
+
+/// Calls a function with the type hint of the sector shape matching the provided sector, or of
+/// its registered shape with the `shape-registry` feature.
 /// Panics if provided with an unknown sector size.
 #[macro_export]
 macro_rules! with_shape {
@@ -227,7 +277,7 @@ macro_rules! with_shape {
             _x if $size == $crate::constants::SECTOR_SIZE_64_GIB => {
               $f::<$crate::constants::SectorShape64GiB>($($args),*)
             },
-            _ => panic!("unsupported sector size: {}", $size),
+            _ => $crate::with_registered_shape!($size, $f, $($args,)*),
         }
     };
     ($size:expr, $f:ident, $($args:expr),*) => {
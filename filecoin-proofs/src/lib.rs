@@ -8,6 +8,11 @@ pub mod constants;
 pub mod param;
 pub mod parameters;
 pub mod pieces;
+#[cfg(feature = "shape-registry")]
+pub mod registry;
+#[cfg(feature = "remote-p2")]
+pub mod remote;
+pub mod sector_store;
 pub mod types;
 
 mod api;
@@ -1,10 +1,21 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
 use anyhow::{ensure, Result};
-use storage_proofs_core::{api_version::ApiVersion, proof::ProofScheme};
-use storage_proofs_porep::stacked::{self, LayerChallenges, StackedDrg};
+use storage_proofs_core::{
+    api_version::ApiVersion, is_ni_porep_id, is_synthetic_porep_id, proof::ProofScheme,
+};
+use storage_proofs_porep::{
+    stacked::{self, ChallengeMode, LayerChallenges, StackedDrg},
+    update::{self, EmptySectorUpdate},
+};
 use storage_proofs_post::fallback::{self, FallbackPoSt};
 
 use crate::{
-    constants::{DefaultPieceHasher, DRG_DEGREE, EXP_DEGREE, LAYERS, POREP_MINIMUM_CHALLENGES},
+    constants::{
+        DefaultPieceHasher, DRG_DEGREE, EXP_DEGREE, LAYERS, NI_POREP_MINIMUM_CHALLENGES,
+        NI_POREP_PARTITIONS, POREP_MINIMUM_CHALLENGES,
+    },
     types::{MerkleTreeTrait, PaddedBytesAmount, PoStConfig},
 };
 
@@ -28,6 +39,14 @@ pub fn public_params<Tree: 'static + MerkleTreeTrait>(
     )?)
 }
 
+pub fn empty_sector_update_public_params<Tree: 'static + MerkleTreeTrait>(
+    sector_bytes: PaddedBytesAmount,
+) -> Result<update::PublicParams> {
+    EmptySectorUpdate::<Tree, DefaultPieceHasher>::setup(&update::SetupParams {
+        sector_bytes: u64::from(sector_bytes),
+    })
+}
+
 pub fn winning_post_public_params<Tree: 'static + MerkleTreeTrait>(
     post_config: &PoStConfig,
 ) -> Result<WinningPostPublicParams> {
@@ -81,17 +100,14 @@ pub fn setup_params(
     api_version: ApiVersion,
 ) -> Result<stacked::SetupParams> {
     let layer_challenges = select_challenges(
-        partitions,
-        *POREP_MINIMUM_CHALLENGES
-            .read()
-            .expect("POREP_MINIMUM_CHALLENGES poisoned")
-            .get(&u64::from(sector_bytes))
-            .expect("unknown sector size") as usize,
+        porep_partitions(u64::from(sector_bytes), partitions, porep_id),
+        porep_minimum_challenges(u64::from(sector_bytes), porep_id),
         *LAYERS
             .read()
             .expect("LAYERS poisoned")
             .get(&u64::from(sector_bytes))
             .expect("unknown sector size"),
+        challenge_mode(porep_id),
     );
     let sector_bytes = u64::from(sector_bytes);
 
@@ -115,36 +131,141 @@ pub fn setup_params(
     })
 }
 
+/// The number of challenges a seal proof of `sector_bytes` for `porep_id` needs over all its
+/// partitions.
+pub fn porep_minimum_challenges(sector_bytes: u64, porep_id: [u8; 32]) -> usize {
+    let minimum_challenges: &RwLock<HashMap<u64, u64>> = if is_ni_porep_id(porep_id) {
+        &NI_POREP_MINIMUM_CHALLENGES
+    } else {
+        &POREP_MINIMUM_CHALLENGES
+    };
+
+    *minimum_challenges
+        .read()
+        .expect("POREP_MINIMUM_CHALLENGES poisoned")
+        .get(&sector_bytes)
+        .expect("unknown sector size") as usize
+}
+
+/// The number of partitions of a seal proof of `sector_bytes` for `porep_id`. That is
+/// `partitions`, the configured number, unless the proof type fixes it: non-interactive proofs
+/// always have the partitions of `NI_POREP_PARTITIONS`.
+pub fn porep_partitions(sector_bytes: u64, partitions: usize, porep_id: [u8; 32]) -> usize {
+    if is_ni_porep_id(porep_id) {
+        *NI_POREP_PARTITIONS
+            .read()
+            .expect("NI_POREP_PARTITIONS poisoned")
+            .get(&sector_bytes)
+            .expect("unknown sector size") as usize
+    } else {
+        partitions
+    }
+}
+
+/// How the challenges of seal proofs for `porep_id` are derived.
+pub fn challenge_mode(porep_id: [u8; 32]) -> ChallengeMode {
+    if is_ni_porep_id(porep_id) {
+        ChallengeMode::NonInteractive
+    } else if is_synthetic_porep_id(porep_id) {
+        ChallengeMode::Synthetic
+    } else {
+        ChallengeMode::Interactive
+    }
+}
+
 fn select_challenges(
     partitions: usize,
     minimum_total_challenges: usize,
     layers: usize,
+    mode: ChallengeMode,
 ) -> LayerChallenges {
     let mut count = 1;
-    let mut guess = LayerChallenges::new(layers, count);
-    while partitions * guess.challenges_count_all() < minimum_total_challenges {
+    while partitions * count < minimum_total_challenges {
         count += 1;
-        guess = LayerChallenges::new(layers, count);
     }
 
-    guess
+    match mode {
+        ChallengeMode::Interactive => LayerChallenges::new(layers, count),
+        ChallengeMode::Synthetic => LayerChallenges::new_synthetic(layers, count),
+        ChallengeMode::NonInteractive => LayerChallenges::new_non_interactive(layers, count),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use crate::{DefaultOctLCTree, PoRepProofPartitions, PoStType};
+    use crate::{
+        DefaultOctLCTree, PoRepConfig, PoRepProofPartitions, PoStType, SectorSize,
+        SECTOR_SIZE_2_KIB, SECTOR_SIZE_32_GIB,
+    };
 
     #[test]
     fn partition_layer_challenges_test() {
-        let f = |partitions| select_challenges(partitions, 12, 11).challenges_count_all();
+        let f = |partitions| {
+            select_challenges(partitions, 12, 11, ChallengeMode::Interactive).challenges_count_all()
+        };
         // Update to ensure all supported PoRepProofPartitions options are represented here.
         assert_eq!(6, f(usize::from(PoRepProofPartitions(2))));
 
         assert_eq!(12, f(1));
         assert_eq!(6, f(2));
         assert_eq!(3, f(4));
+
+        let synthetic = select_challenges(2, 12, 11, ChallengeMode::Synthetic);
+        assert_eq!(synthetic.mode(), ChallengeMode::Synthetic);
+        assert_eq!(synthetic.challenges_count_all(), 6);
+    }
+
+    #[test]
+    fn non_interactive_layer_challenges_test() {
+        let mut porep_id = [0u8; 32];
+        porep_id[..8].copy_from_slice(&15u64.to_le_bytes());
+        assert_eq!(challenge_mode(porep_id), ChallengeMode::NonInteractive);
+
+        for &(sector_bytes, partitions, minimum_challenges) in
+            &[(SECTOR_SIZE_2_KIB, 2, 4), (SECTOR_SIZE_32_GIB, 126, 2253)]
+        {
+            assert_eq!(
+                porep_minimum_challenges(sector_bytes, porep_id),
+                minimum_challenges
+            );
+
+            // The partitions are those of the proof type, whatever the config says, also for a
+            // config not built with `PoRepConfig::new`.
+            assert_eq!(porep_partitions(sector_bytes, 1, porep_id), partitions);
+            let config = PoRepConfig {
+                sector_size: SectorSize(sector_bytes),
+                partitions: PoRepProofPartitions(1),
+                porep_id,
+                api_version: ApiVersion::V1_1_0,
+            };
+            assert_eq!(usize::from(PoRepProofPartitions::from(config)), partitions);
+
+            // As many challenges per partition as interactive PoRep, so the circuit is the same.
+            let layer_challenges = |config: PoRepConfig| {
+                setup_params(
+                    PaddedBytesAmount::from(config),
+                    usize::from(PoRepProofPartitions::from(config)),
+                    config.porep_id,
+                    config.api_version,
+                )
+                .expect("setup_params failed")
+                .layer_challenges
+            };
+            let challenges = layer_challenges(config);
+            let interactive = layer_challenges(PoRepConfig::new(
+                SectorSize(sector_bytes),
+                [0u8; 32],
+                ApiVersion::V1_1_0,
+            ));
+            assert_eq!(challenges.mode(), ChallengeMode::NonInteractive);
+            assert_eq!(
+                challenges.challenges_count_all(),
+                interactive.challenges_count_all()
+            );
+            assert!(partitions * challenges.challenges_count_all() >= minimum_challenges);
+        }
     }
 
     #[test]
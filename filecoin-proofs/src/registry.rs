@@ -0,0 +1,250 @@
+//! Registering sector sizes beyond the published ones, for test networks.
+//!
+//! The shape of the trees of a sector is a type, so a registered sector size takes one of the
+//! shapes of `SectorShape`, which `with_shape!` dispatches on once the built-in sizes did not
+//! match. Registering a size also fills in its entries in the per sector size settings of
+//! `constants`. Parameters of registered sizes are identified like those of any other, from the
+//! shape of their trees and their public parameters, and generated on first use as they are not
+//! published.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use anyhow::{ensure, Result};
+use lazy_static::lazy_static;
+use merkletree::merkle::is_merkle_tree_size_valid;
+use storage_proofs_core::{merkle::LCTree, util::NODE_SIZE};
+use typenum::{U0, U16, U4, U8};
+
+use crate::constants::{
+    DefaultTreeHasher, LAYERS, NI_POREP_MINIMUM_CHALLENGES, NI_POREP_PARTITIONS,
+    POREP_MINIMUM_CHALLENGES, POREP_PARTITIONS, WINDOW_POST_SECTOR_COUNT,
+};
+
+// Shapes only available to registered sector sizes.
+pub type SectorShapeSub4 = LCTree<DefaultTreeHasher, U8, U4, U0>;
+pub type SectorShapeSub16 = LCTree<DefaultTreeHasher, U8, U16, U0>;
+pub type SectorShapeTop4 = LCTree<DefaultTreeHasher, U8, U8, U4>;
+pub type SectorShapeTop16 = LCTree<DefaultTreeHasher, U8, U8, U16>;
+
+/// The shapes a registered sector size can take, named after their type in `constants` or this
+/// module. All have oct base trees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SectorShape {
+    Base,
+    Sub2,
+    Sub4,
+    Sub8,
+    Sub16,
+    Top2,
+    Top4,
+    Top16,
+}
+
+impl SectorShape {
+    /// The base, sub and top tree arities of the shape.
+    pub fn arities(self) -> (usize, usize, usize) {
+        match self {
+            SectorShape::Base => (8, 0, 0),
+            SectorShape::Sub2 => (8, 2, 0),
+            SectorShape::Sub4 => (8, 4, 0),
+            SectorShape::Sub8 => (8, 8, 0),
+            SectorShape::Sub16 => (8, 16, 0),
+            SectorShape::Top2 => (8, 8, 2),
+            SectorShape::Top4 => (8, 8, 4),
+            SectorShape::Top16 => (8, 8, 16),
+        }
+    }
+
+    /// The shape of the given base, sub and top tree arities, if there is one.
+    pub fn from_arities(base: usize, sub: usize, top: usize) -> Option<Self> {
+        [
+            SectorShape::Base,
+            SectorShape::Sub2,
+            SectorShape::Sub4,
+            SectorShape::Sub8,
+            SectorShape::Sub16,
+            SectorShape::Top2,
+            SectorShape::Top4,
+            SectorShape::Top16,
+        ]
+        .iter()
+        .copied()
+        .find(|shape| shape.arities() == (base, sub, top))
+    }
+
+    /// The number of base trees of a sector of this shape.
+    pub fn base_tree_count(self) -> usize {
+        let (_, sub, top) = self.arities();
+        sub.max(1) * top.max(1)
+    }
+}
+
+/// A sector size to register, with its shape and the settings `constants` holds per sector
+/// size. `new` picks the settings of the small published sizes.
+#[derive(Debug, Clone)]
+pub struct SectorSizeRegistration {
+    pub sector_size: u64,
+    pub shape: SectorShape,
+    pub porep_partitions: u8,
+    pub porep_minimum_challenges: u64,
+    pub ni_porep_partitions: u8,
+    pub ni_porep_minimum_challenges: u64,
+    pub layers: usize,
+    pub window_post_sector_count: usize,
+}
+
+impl SectorSizeRegistration {
+    pub fn new(sector_size: u64, shape: SectorShape) -> Self {
+        SectorSizeRegistration {
+            sector_size,
+            shape,
+            porep_partitions: 1,
+            porep_minimum_challenges: 2,
+            ni_porep_partitions: 1,
+            ni_porep_minimum_challenges: 2,
+            layers: 2,
+            window_post_sector_count: 2,
+        }
+    }
+}
+
+lazy_static! {
+    static ref REGISTERED_SHAPES: RwLock<HashMap<u64, SectorShape>> = RwLock::new(HashMap::new());
+}
+
+/// Registers a sector size, after which it can be sealed and proven like a published one.
+/// Fails for sector sizes that are already known, or that do not fit their shape.
+pub fn register_sector_size(registration: SectorSizeRegistration) -> Result<()> {
+    let SectorSizeRegistration {
+        sector_size,
+        shape,
+        porep_partitions,
+        porep_minimum_challenges,
+        ni_porep_partitions,
+        ni_porep_minimum_challenges,
+        layers,
+        window_post_sector_count,
+    } = registration;
+
+    ensure!(
+        sector_size.is_power_of_two() && sector_size as usize >= NODE_SIZE,
+        "sector size {} is not a power of two of at least one node",
+        sector_size
+    );
+    let nodes = sector_size as usize / NODE_SIZE;
+    let base_tree_count = shape.base_tree_count();
+    ensure!(
+        nodes % base_tree_count == 0
+            && is_merkle_tree_size_valid(nodes / base_tree_count, shape.arities().0),
+        "sector size {} does not fit the shape {:?}",
+        sector_size,
+        shape
+    );
+    ensure!(
+        porep_partitions > 0 && ni_porep_partitions > 0,
+        "sector size {} needs at least one partition",
+        sector_size
+    );
+    ensure!(
+        layers > 0,
+        "sector size {} needs at least one layer",
+        sector_size
+    );
+    ensure!(
+        window_post_sector_count > 0,
+        "sector size {} needs at least one sector per window post partition",
+        sector_size
+    );
+
+    let mut porep_partitions_map = POREP_PARTITIONS.write().expect("POREP_PARTITIONS poisoned");
+    ensure!(
+        !porep_partitions_map.contains_key(&sector_size),
+        "sector size {} is already known",
+        sector_size
+    );
+    porep_partitions_map.insert(sector_size, porep_partitions);
+    POREP_MINIMUM_CHALLENGES
+        .write()
+        .expect("POREP_MINIMUM_CHALLENGES poisoned")
+        .insert(sector_size, porep_minimum_challenges);
+    NI_POREP_PARTITIONS
+        .write()
+        .expect("NI_POREP_PARTITIONS poisoned")
+        .insert(sector_size, ni_porep_partitions);
+    NI_POREP_MINIMUM_CHALLENGES
+        .write()
+        .expect("NI_POREP_MINIMUM_CHALLENGES poisoned")
+        .insert(sector_size, ni_porep_minimum_challenges);
+    LAYERS
+        .write()
+        .expect("LAYERS poisoned")
+        .insert(sector_size, layers);
+    WINDOW_POST_SECTOR_COUNT
+        .write()
+        .expect("WINDOW_POST_SECTOR_COUNT poisoned")
+        .insert(sector_size, window_post_sector_count);
+    REGISTERED_SHAPES
+        .write()
+        .expect("REGISTERED_SHAPES poisoned")
+        .insert(sector_size, shape);
+
+    Ok(())
+}
+
+/// The shape of a registered sector size.
+pub fn registered_shape(sector_size: u64) -> Option<SectorShape> {
+    REGISTERED_SHAPES
+        .read()
+        .expect("REGISTERED_SHAPES poisoned")
+        .get(&sector_size)
+        .copied()
+}
+
+/// All registered sector sizes.
+pub fn registered_sector_sizes() -> Vec<u64> {
+    let mut sector_sizes: Vec<u64> = REGISTERED_SHAPES
+        .read()
+        .expect("REGISTERED_SHAPES poisoned")
+        .keys()
+        .copied()
+        .collect();
+    sector_sizes.sort_unstable();
+    sector_sizes
+}
+
+/// `with_shape!` for sector sizes it does not know, calling `$f` with the type of a registered
+/// shape. Panics if the sector size is not registered either.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! with_registered_shape {
+    ($size:expr, $f:ident, $($args:expr,)*) => {
+        match $crate::registry::registered_shape($size) {
+            Some($crate::registry::SectorShape::Base) => {
+                $f::<$crate::constants::SectorShapeBase>($($args),*)
+            },
+            Some($crate::registry::SectorShape::Sub2) => {
+                $f::<$crate::constants::SectorShapeSub2>($($args),*)
+            },
+            Some($crate::registry::SectorShape::Sub4) => {
+                $f::<$crate::registry::SectorShapeSub4>($($args),*)
+            },
+            Some($crate::registry::SectorShape::Sub8) => {
+                $f::<$crate::constants::SectorShapeSub8>($($args),*)
+            },
+            Some($crate::registry::SectorShape::Sub16) => {
+                $f::<$crate::registry::SectorShapeSub16>($($args),*)
+            },
+            Some($crate::registry::SectorShape::Top2) => {
+                $f::<$crate::constants::SectorShapeTop2>($($args),*)
+            },
+            Some($crate::registry::SectorShape::Top4) => {
+                $f::<$crate::registry::SectorShapeTop4>($($args),*)
+            },
+            Some($crate::registry::SectorShape::Top16) => {
+                $f::<$crate::registry::SectorShapeTop16>($($args),*)
+            },
+            None => panic!("unsupported sector size: {}", $size),
+        }
+    };
+}
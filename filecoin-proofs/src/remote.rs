@@ -0,0 +1,667 @@
+//! Running `seal_pre_commit_phase2` on a remote machine, typically one with the GPUs that build
+//! tree_c and tree_r_last, while phase 1 stays on the machine with the sector.
+//!
+//! Client and server talk gRPC, with the `RemoteP2` service of `proto/remote_p2.proto`. The
+//! client sends the phase 1 output, and either the paths of the sector files on storage shared
+//! with the server, or the files themselves: the replica, the layers and tree_d, in chunks
+//! following the request. The server answers with the commitments, and when the files were
+//! streamed, with the encoded replica and the files phase 2 added to the cache directory, among
+//! them p_aux and t_aux. The cache directory paths in t_aux are those of the server, which is
+//! harmless, as they are switched to the local cache directory whenever t_aux is read.
+//!
+//! Requests carry a secret shared by client and server. Shared storage paths must resolve to
+//! within the shared root of the server, which refuses them without one.
+
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, create_dir_all};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bincode::{deserialize, serialize};
+use log::{error, info};
+use merkletree::store::StoreConfig;
+use storage_proofs_core::{api_version::ApiVersion, merkle::MerkleTreeTrait};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Runtime,
+    sync::{mpsc, Semaphore},
+    task,
+};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{metadata::MetadataMap, transport::Server, Response, Status, Streaming};
+
+use crate::{
+    api::seal_pre_commit_phase2,
+    types::{
+        PoRepConfig, PoRepProofPartitions, SealPreCommitOutput, SealPreCommitPhase1Output,
+        SectorSize,
+    },
+    with_shape,
+};
+
+mod proto {
+    tonic::include_proto!("remote_p2");
+}
+
+use proto::{
+    client_message, remote_p2_client::RemoteP2Client, remote_p2_server, request::Storage,
+    server_message, ClientMessage, FileHeader, Sealed, ServerMessage, SharedStorage,
+    StreamedStorage,
+};
+
+/// The version of the protocol, which client and server must agree on.
+pub const REMOTE_P2_PROTOCOL_VERSION: u32 = 2;
+
+/// The metadata key of the secret shared by client and server.
+const SECRET_KEY: &str = "x-remote-p2-secret";
+
+/// Files are sent in chunks of this size.
+const CHUNK_SIZE: u64 = 1 << 20;
+
+/// How the server gets to the files of the sector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteStorage {
+    /// The sector files are on storage the server mounts too, at `server_root` where the client
+    /// mounts it at `local_root`. The server reads and writes them in place.
+    Shared {
+        local_root: PathBuf,
+        server_root: PathBuf,
+    },
+    /// The files are sent to the server, and the results sent back.
+    Streamed,
+}
+
+/// How `serve_remote_p2` serves requests.
+#[derive(Debug, Clone)]
+pub struct RemoteP2Config {
+    /// The files of streamed requests are kept in a directory under it until they are sent back.
+    pub work_dir: PathBuf,
+    /// Shared storage requests may only name files under it, and are refused without it.
+    pub shared_root: Option<PathBuf>,
+    /// Requests without it are refused, see `seal_pre_commit_phase2_remote`.
+    pub secret: String,
+    /// Requests beyond this many sealing at once are refused.
+    pub max_concurrent: usize,
+}
+
+/// `seal_pre_commit_phase2`, run by the server at `endpoint`, e.g. `http://gpu-box:7878`,
+/// presenting `secret`. The results are the same as those of a local phase 2: the replica is
+/// encoded, and the cache directory holds the trees, p_aux and t_aux.
+pub fn seal_pre_commit_phase2_remote<R, S, Tree: 'static + MerkleTreeTrait>(
+    endpoint: &str,
+    secret: &str,
+    storage: &RemoteStorage,
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+) -> Result<SealPreCommitOutput>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+{
+    info!("seal_pre_commit_phase2_remote:start");
+
+    let cache_path = cache_path.as_ref();
+    let replica_path = replica_path.as_ref();
+
+    // The replica, the layers and tree_d, which phase 2 reads from the cache directory.
+    let mut files = Vec::new();
+    let request_storage = match storage {
+        RemoteStorage::Shared {
+            local_root,
+            server_root,
+        } => {
+            let rebase = |path: &Path| -> Result<String> {
+                let relative = path.strip_prefix(local_root).with_context(|| {
+                    format!("{:?} is not on the shared storage {:?}", path, local_root)
+                })?;
+                let path = server_root.join(relative);
+                path.to_str()
+                    .map(Into::into)
+                    .with_context(|| format!("{:?} is not valid unicode", path))
+            };
+            Storage::Shared(SharedStorage {
+                cache_path: rebase(cache_path)?,
+                replica_path: rebase(replica_path)?,
+            })
+        }
+        RemoteStorage::Streamed => {
+            let replica_len = file_len(replica_path)?;
+            files.push((replica_path.to_path_buf(), replica_len));
+
+            let mut cache_files = Vec::new();
+            let configs = phase1_output
+                .labels
+                .labels
+                .iter()
+                .chain(std::iter::once(&phase1_output.config));
+            for config in configs {
+                let path = StoreConfig::data_path(cache_path, &config.id);
+                let len = file_len(&path)?;
+                cache_files.push(FileHeader {
+                    name: file_name(&path)?,
+                    len,
+                });
+                files.push((path, len));
+            }
+
+            Storage::Streamed(StreamedStorage {
+                replica_len,
+                cache_files,
+            })
+        }
+    };
+
+    let request = proto::Request {
+        version: REMOTE_P2_PROTOCOL_VERSION,
+        sector_size: u64::from(porep_config.sector_size),
+        partitions: u32::from(PoRepProofPartitions::from(porep_config).0),
+        porep_id: porep_config.porep_id.to_vec(),
+        api_version: porep_config.api_version.to_string(),
+        phase1_output: serialize(&phase1_output)?,
+        storage: Some(request_storage),
+    };
+
+    let runtime = Runtime::new().context("could not start the runtime")?;
+    let output = runtime.block_on(async {
+        let mut client = RemoteP2Client::connect(endpoint.to_string())
+            .await
+            .with_context(|| format!("could not connect to the p2 server {}", endpoint))?;
+
+        let (messages, outgoing) = mpsc::channel(4);
+        let sender = tokio::spawn(async move {
+            let request = ClientMessage {
+                message: Some(client_message::Message::Request(request)),
+            };
+            messages
+                .send(request)
+                .await
+                .map_err(|_| anyhow!("the p2 server hung up"))?;
+            for (path, len) in files {
+                send_file(&messages, client_chunk, &path, len).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let mut request = tonic::Request::new(ReceiverStream::new(outgoing));
+        request.metadata_mut().insert(
+            SECRET_KEY,
+            secret.parse().context("the secret is not valid metadata")?,
+        );
+        let mut responses = client
+            .seal_pre_commit_phase2(request)
+            .await
+            .context("remote seal_pre_commit_phase2 failed")?
+            .into_inner();
+
+        let sealed = match responses
+            .message()
+            .await
+            .context("remote seal_pre_commit_phase2 failed")?
+        {
+            Some(ServerMessage {
+                message: Some(server_message::Message::Sealed(sealed)),
+            }) => sealed,
+            _ => bail!("the p2 server did not answer with the commitments"),
+        };
+        sender.await??;
+
+        if *storage == RemoteStorage::Streamed {
+            receive_file(&mut responses, replica_path, sealed.replica_len).await?;
+            for header in &sealed.cache_files {
+                let path = cache_path.join(checked_file_name(&header.name)?);
+                receive_file(&mut responses, &path, header.len).await?;
+            }
+        }
+
+        Ok::<_, anyhow::Error>(SealPreCommitOutput {
+            comm_r: sealed
+                .comm_r
+                .as_slice()
+                .try_into()
+                .context("invalid comm_r")?,
+            comm_d: sealed
+                .comm_d
+                .as_slice()
+                .try_into()
+                .context("invalid comm_d")?,
+        })
+    })?;
+
+    info!("seal_pre_commit_phase2_remote:finish");
+    Ok(output)
+}
+
+/// Serves `seal_pre_commit_phase2_remote` clients on `listener`, as configured by `config`.
+/// Only returns if serving fails.
+pub fn serve_remote_p2(listener: TcpListener, config: RemoteP2Config) -> Result<()> {
+    ensure!(!config.secret.is_empty(), "a secret is required");
+    ensure!(
+        config.max_concurrent > 0,
+        "at least one concurrent request is required"
+    );
+    create_dir_all(&config.work_dir)
+        .with_context(|| format!("could not create work dir {:?}", config.work_dir))?;
+    let shared_root = config
+        .shared_root
+        .map(|root| {
+            root.canonicalize()
+                .with_context(|| format!("could not resolve shared root {:?}", root))
+        })
+        .transpose()?;
+
+    let service = P2Service {
+        work_dir: config.work_dir,
+        shared_root,
+        secret: config.secret,
+        permits: Arc::new(Semaphore::new(config.max_concurrent)),
+    };
+
+    listener.set_nonblocking(true)?;
+    let runtime = Runtime::new().context("could not start the runtime")?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        Server::builder()
+            .add_service(remote_p2_server::RemoteP2Server::new(service))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await
+            .context("serving remote p2 failed")
+    })
+}
+
+struct P2Service {
+    work_dir: PathBuf,
+    shared_root: Option<PathBuf>,
+    secret: String,
+    permits: Arc<Semaphore>,
+}
+
+#[tonic::async_trait]
+impl remote_p2_server::RemoteP2 for P2Service {
+    type SealPreCommitPhase2Stream = ReceiverStream<Result<ServerMessage, Status>>;
+
+    async fn seal_pre_commit_phase2(
+        &self,
+        request: tonic::Request<Streaming<ClientMessage>>,
+    ) -> Result<Response<Self::SealPreCommitPhase2Stream>, Status> {
+        static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+        check_secret(request.metadata(), &self.secret)?;
+        let permit = self
+            .permits
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Status::resource_exhausted("too many sectors in phase 2"))?;
+
+        let mut messages = request.into_inner();
+        let request = match messages.message().await? {
+            Some(ClientMessage {
+                message: Some(client_message::Message::Request(request)),
+            }) => request,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "the first message must be the request",
+                ))
+            }
+        };
+
+        let sector_dir = self.work_dir.join(format!(
+            "p2-{}-{}",
+            process::id(),
+            REQUESTS.fetch_add(1, Ordering::SeqCst)
+        ));
+        let shared_root = self.shared_root.clone();
+        let (responses, outgoing) = mpsc::channel(4);
+        tokio::spawn(async move {
+            let res = seal_request(
+                request,
+                &mut messages,
+                &responses,
+                &sector_dir,
+                shared_root.as_deref(),
+            )
+            .await;
+            if sector_dir.exists() {
+                if let Err(err) = tokio::fs::remove_dir_all(&sector_dir).await {
+                    error!("remote p2: could not remove {:?}: {:?}", sector_dir, err);
+                }
+            }
+            if let Err(err) = res {
+                error!("remote p2: request failed: {:?}", err);
+                // Ignored if the client is gone already.
+                let _ = responses
+                    .send(Err(Status::internal(format!("{:?}", err))))
+                    .await;
+            }
+            drop(permit);
+        });
+
+        Ok(Response::new(ReceiverStream::new(outgoing)))
+    }
+}
+
+async fn seal_request(
+    request: proto::Request,
+    messages: &mut Streaming<ClientMessage>,
+    responses: &mpsc::Sender<Result<ServerMessage, Status>>,
+    sector_dir: &Path,
+    shared_root: Option<&Path>,
+) -> Result<()> {
+    ensure!(
+        request.version == REMOTE_P2_PROTOCOL_VERSION,
+        "unsupported protocol version {}, expected {}",
+        request.version,
+        REMOTE_P2_PROTOCOL_VERSION
+    );
+
+    let porep_config = PoRepConfig {
+        sector_size: SectorSize(request.sector_size),
+        partitions: PoRepProofPartitions(
+            u8::try_from(request.partitions).context("invalid number of partitions")?,
+        ),
+        porep_id: request
+            .porep_id
+            .as_slice()
+            .try_into()
+            .context("invalid porep id")?,
+        api_version: request.api_version.parse::<ApiVersion>()?,
+    };
+
+    let (cache_path, replica_path, streamed) =
+        match request.storage.context("the request has no storage")? {
+            Storage::Shared(shared) => {
+                let root =
+                    shared_root.context("this server does not accept shared storage requests")?;
+                (
+                    shared_path(root, &shared.cache_path)?,
+                    shared_path(root, &shared.replica_path)?,
+                    None,
+                )
+            }
+            Storage::Streamed(streamed) => {
+                let cache_path = sector_dir.join("cache");
+                let replica_path = sector_dir.join("replica");
+                create_dir_all(&cache_path)
+                    .with_context(|| format!("could not create {:?}", cache_path))?;
+
+                receive_file(messages, &replica_path, streamed.replica_len).await?;
+                for header in &streamed.cache_files {
+                    let path = cache_path.join(checked_file_name(&header.name)?);
+                    receive_file(messages, &path, header.len).await?;
+                }
+                (cache_path, replica_path, Some(streamed))
+            }
+        };
+
+    let output = {
+        let phase1_output = request.phase1_output;
+        let cache_path = cache_path.clone();
+        let replica_path = replica_path.clone();
+        task::spawn_blocking(move || {
+            with_shape!(
+                u64::from(porep_config.sector_size),
+                run_phase2,
+                porep_config,
+                &phase1_output,
+                &cache_path,
+                &replica_path,
+            )
+        })
+        .await??
+    };
+
+    let mut sealed = Sealed {
+        comm_r: output.comm_r.to_vec(),
+        comm_d: output.comm_d.to_vec(),
+        replica_len: 0,
+        cache_files: Vec::new(),
+    };
+    if let Some(streamed) = &streamed {
+        sealed.replica_len = streamed.replica_len;
+        // Everything phase 2 added to the cache directory goes back.
+        for entry in fs::read_dir(&cache_path)? {
+            let path = entry?.path();
+            let name = file_name(&path)?;
+            if streamed
+                .cache_files
+                .iter()
+                .all(|header| header.name != name)
+            {
+                sealed.cache_files.push(FileHeader {
+                    len: file_len(&path)?,
+                    name,
+                });
+            }
+        }
+    }
+
+    let new_files = sealed.cache_files.clone();
+    responses
+        .send(Ok(ServerMessage {
+            message: Some(server_message::Message::Sealed(sealed)),
+        }))
+        .await
+        .map_err(|_| anyhow!("the client hung up"))?;
+    if let Some(streamed) = &streamed {
+        send_file(responses, server_chunk, &replica_path, streamed.replica_len).await?;
+        for header in &new_files {
+            send_file(
+                responses,
+                server_chunk,
+                &cache_path.join(&header.name),
+                header.len,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn run_phase2<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: &[u8],
+    cache_path: &Path,
+    replica_path: &Path,
+) -> Result<SealPreCommitOutput> {
+    let phase1_output: SealPreCommitPhase1Output<Tree> = deserialize(phase1_output)?;
+    seal_pre_commit_phase2(porep_config, phase1_output, cache_path, replica_path)
+}
+
+fn check_secret(metadata: &MetadataMap, secret: &str) -> Result<(), Status> {
+    let presented = metadata
+        .get(SECRET_KEY)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if secrets_match(presented.as_bytes(), secret.as_bytes()) {
+        Ok(())
+    } else {
+        Err(Status::unauthenticated("invalid secret"))
+    }
+}
+
+/// Compares in a time independent of where the secrets differ.
+fn secrets_match(presented: &[u8], secret: &[u8]) -> bool {
+    presented.len() == secret.len()
+        && presented
+            .iter()
+            .zip(secret)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Resolves a path of a shared storage request, which must be within the canonical `root`.
+fn shared_path(root: &Path, path: &str) -> Result<PathBuf> {
+    let resolved = Path::new(path)
+        .canonicalize()
+        .with_context(|| format!("could not resolve {:?}", path))?;
+    ensure!(
+        resolved.starts_with(root),
+        "{:?} is not within the shared root {:?}",
+        path,
+        root
+    );
+    Ok(resolved)
+}
+
+fn client_chunk(chunk: Vec<u8>) -> ClientMessage {
+    ClientMessage {
+        message: Some(client_message::Message::Chunk(chunk)),
+    }
+}
+
+fn server_chunk(chunk: Vec<u8>) -> Result<ServerMessage, Status> {
+    Ok(ServerMessage {
+        message: Some(server_message::Message::Chunk(chunk)),
+    })
+}
+
+/// The file contents of a message, as sent by `client_chunk` or `server_chunk`.
+trait Chunk {
+    fn into_chunk(self) -> Option<Vec<u8>>;
+}
+
+impl Chunk for ClientMessage {
+    fn into_chunk(self) -> Option<Vec<u8>> {
+        match self.message {
+            Some(client_message::Message::Chunk(chunk)) => Some(chunk),
+            _ => None,
+        }
+    }
+}
+
+impl Chunk for ServerMessage {
+    fn into_chunk(self) -> Option<Vec<u8>> {
+        match self.message {
+            Some(server_message::Message::Chunk(chunk)) => Some(chunk),
+            _ => None,
+        }
+    }
+}
+
+fn file_len(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)
+        .with_context(|| format!("could not read metadata of {:?}", path))?
+        .len())
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(Into::into)
+        .with_context(|| format!("{:?} has no file name", path))
+}
+
+/// Names of files sent by the other side must stay in the directory they are written to.
+fn checked_file_name(name: &str) -> Result<&str> {
+    ensure!(
+        !name.is_empty()
+            && name != "."
+            && name != ".."
+            && !name.contains(|c: char| c == '/' || c == '\\'),
+        "invalid file name {:?}",
+        name
+    );
+    Ok(name)
+}
+
+async fn send_file<T>(
+    messages: &mpsc::Sender<T>,
+    wrap: fn(Vec<u8>) -> T,
+    path: &Path,
+    len: u64,
+) -> Result<()> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("could not open {:?}", path))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let mut chunk = vec![0u8; remaining.min(CHUNK_SIZE) as usize];
+        file.read_exact(&mut chunk)
+            .await
+            .with_context(|| format!("{:?} is shorter than {} bytes", path, len))?;
+        remaining -= chunk.len() as u64;
+        messages
+            .send(wrap(chunk))
+            .await
+            .map_err(|_| anyhow!("the other side hung up"))?;
+    }
+    Ok(())
+}
+
+async fn receive_file<T: Chunk>(messages: &mut Streaming<T>, path: &Path, len: u64) -> Result<()> {
+    let mut file = File::create(path)
+        .await
+        .with_context(|| format!("could not create {:?}", path))?;
+    let mut received = 0;
+    while received < len {
+        let chunk = messages
+            .message()
+            .await?
+            .with_context(|| {
+                format!(
+                    "stream ended after {} of the {} bytes of {:?}",
+                    received, len, path
+                )
+            })?
+            .into_chunk()
+            .with_context(|| format!("expected the contents of {:?}", path))?;
+        ensure!(
+            received + chunk.len() as u64 <= len,
+            "more than {} bytes sent for {:?}",
+            len,
+            path
+        );
+        file.write_all(&chunk).await?;
+        received += chunk.len() as u64;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_checked_file_name() {
+        assert!(checked_file_name("sc-02-data-tree-r-last-0.dat").is_ok());
+        assert!(checked_file_name("").is_err());
+        assert!(checked_file_name("..").is_err());
+        assert!(checked_file_name("../p_aux").is_err());
+        assert!(checked_file_name("/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_secrets_match() {
+        assert!(secrets_match(b"secret", b"secret"));
+        assert!(!secrets_match(b"secreT", b"secret"));
+        assert!(!secrets_match(b"secret ", b"secret"));
+        assert!(!secrets_match(b"", b"secret"));
+    }
+
+    #[test]
+    fn test_shared_path() {
+        let root = tempdir().expect("tempdir failed");
+        let sector = root.path().join("sector");
+        create_dir_all(&sector).expect("create_dir_all failed");
+        let root_path = root.path().canonicalize().expect("canonicalize failed");
+
+        let inside = sector.to_str().expect("invalid path");
+        assert!(shared_path(&root_path, inside).is_ok());
+
+        // Leaving the root, directly or through `..`, is refused.
+        let outside = tempdir().expect("tempdir failed");
+        assert!(shared_path(&root_path, outside.path().to_str().expect("invalid path")).is_err());
+        let escape = format!("{}/../..", inside);
+        assert!(shared_path(&root_path, &escape).is_err());
+    }
+}
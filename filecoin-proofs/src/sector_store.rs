@@ -0,0 +1,402 @@
+//! Where the files of a sector are kept, for integrators whose replicas and caches are not in
+//! a local directory, such as object storage or raw block devices.
+//!
+//! A `SectorStore` holds the artifacts of one sector by name: the files of its cache directory
+//! under their usual names, and the replica under `REPLICA`. The `_with_store` versions of the
+//! sealing, PoSt and unsealing calls work on a store. Stores that are local directories, like
+//! `LocalSectorStore`, are used in place; the artifacts of other stores are copied to a
+//! staging directory under the `sector_store_staging` setting for the call, and the artifacts
+//! the call created are written back to the store.
+
+use std::collections::HashSet;
+use std::fs::{self, create_dir_all, remove_dir_all, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{ensure, Context, Result};
+use log::warn;
+use storage_proofs_core::settings::SETTINGS;
+
+/// The name of the replica among the artifacts of a sector.
+pub const REPLICA: &str = "replica";
+
+/// The artifacts of a sector.
+pub trait SectorStore: Send + Sync {
+    /// The names of all artifacts, including `REPLICA` once it exists.
+    fn list(&self) -> Result<Vec<String>>;
+
+    /// The size of an artifact in bytes.
+    fn size(&self, name: &str) -> Result<u64>;
+
+    /// Fills `buf` with the bytes of an artifact from `offset` on.
+    fn read_at(&self, name: &str, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Reads an artifact from the start.
+    fn stream(&self, name: &str) -> Result<Box<dyn Read + Send + '_>>;
+
+    /// Creates or replaces an artifact with everything read from `data`, returning its size.
+    fn write(&self, name: &str, data: &mut dyn Read) -> Result<u64>;
+
+    /// Removes an artifact.
+    fn remove(&self, name: &str) -> Result<()>;
+
+    /// The cache directory and the replica path, if the store keeps its artifacts in local
+    /// files at the paths the path based calls expect. The artifacts are used in place then,
+    /// rather than staged.
+    fn local_paths(&self) -> Option<(&Path, &Path)> {
+        None
+    }
+}
+
+/// A sector in a local cache directory and replica file, as used by the path based calls.
+#[derive(Debug, Clone)]
+pub struct LocalSectorStore {
+    cache_dir: PathBuf,
+    replica_path: PathBuf,
+}
+
+impl LocalSectorStore {
+    /// Opens the sector in `cache_dir`, which is created if it does not exist yet.
+    pub fn open<P: AsRef<Path>, R: AsRef<Path>>(cache_dir: P, replica_path: R) -> Result<Self> {
+        let cache_dir = cache_dir.as_ref().to_path_buf();
+        create_dir_all(&cache_dir)
+            .with_context(|| format!("could not create cache_dir={:?}", cache_dir))?;
+
+        Ok(LocalSectorStore {
+            cache_dir,
+            replica_path: replica_path.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        if name == REPLICA {
+            return Ok(self.replica_path.clone());
+        }
+        ensure!(
+            !name.is_empty()
+                && name != "."
+                && name != ".."
+                && !name.contains(|c: char| c == '/' || c == '\\'),
+            "invalid artifact name {:?}",
+            name
+        );
+        Ok(self.cache_dir.join(name))
+    }
+}
+
+impl SectorStore for LocalSectorStore {
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.cache_dir)
+            .with_context(|| format!("could not read cache_dir={:?}", self.cache_dir))?
+        {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        if self.replica_path.is_file() {
+            names.push(REPLICA.to_string());
+        }
+
+        Ok(names)
+    }
+
+    fn size(&self, name: &str) -> Result<u64> {
+        let path = self.path(name)?;
+        Ok(fs::metadata(&path)
+            .with_context(|| format!("could not read metadata of {:?}", path))?
+            .len())
+    }
+
+    fn read_at(&self, name: &str, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let path = self.path(name)?;
+        let mut file = File::open(&path).with_context(|| format!("could not open {:?}", path))?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf).with_context(|| {
+            format!(
+                "could not read {} bytes at {} of {:?}",
+                buf.len(),
+                offset,
+                path
+            )
+        })?;
+
+        Ok(())
+    }
+
+    fn stream(&self, name: &str) -> Result<Box<dyn Read + Send + '_>> {
+        let path = self.path(name)?;
+        let file = File::open(&path).with_context(|| format!("could not open {:?}", path))?;
+
+        Ok(Box::new(io::BufReader::new(file)))
+    }
+
+    fn write(&self, name: &str, data: &mut dyn Read) -> Result<u64> {
+        let path = self.path(name)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| format!("could not create {:?}", path))?;
+
+        Ok(io::copy(data, &mut file)?)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let path = self.path(name)?;
+        fs::remove_file(&path).with_context(|| format!("could not remove {:?}", path))
+    }
+
+    fn local_paths(&self) -> Option<(&Path, &Path)> {
+        Some((self.cache_dir.as_path(), self.replica_path.as_path()))
+    }
+}
+
+/// The local cache directory and replica of a sector, for one call of the path based API.
+pub(crate) struct Staged<'a> {
+    store: &'a dyn SectorStore,
+    cache_path: PathBuf,
+    replica_path: PathBuf,
+    /// The staging directory, if the store is not used in place.
+    dir: Option<PathBuf>,
+    /// The artifacts copied from the store, which need not be written back.
+    fetched: HashSet<String>,
+}
+
+impl<'a> Staged<'a> {
+    pub(crate) fn new(store: &'a dyn SectorStore) -> Result<Self> {
+        static STAGED: AtomicUsize = AtomicUsize::new(0);
+
+        if let Some((cache_path, replica_path)) = store.local_paths() {
+            return Ok(Staged {
+                store,
+                cache_path: cache_path.to_path_buf(),
+                replica_path: replica_path.to_path_buf(),
+                dir: None,
+                fetched: HashSet::new(),
+            });
+        }
+
+        let dir = Path::new(&SETTINGS.sector_store_staging).join(format!(
+            "{}-{}",
+            process::id(),
+            STAGED.fetch_add(1, Ordering::SeqCst)
+        ));
+        let cache_path = dir.join("cache");
+        create_dir_all(&cache_path)
+            .with_context(|| format!("could not create staging dir {:?}", cache_path))?;
+
+        Ok(Staged {
+            store,
+            cache_path,
+            replica_path: dir.join(REPLICA),
+            dir: Some(dir),
+            fetched: HashSet::new(),
+        })
+    }
+
+    pub(crate) fn cache_path(&self) -> &Path {
+        &self.cache_path
+    }
+
+    pub(crate) fn replica_path(&self) -> &Path {
+        &self.replica_path
+    }
+
+    /// Copies the given artifacts from the store.
+    pub(crate) fn fetch<I, S>(&mut self, names: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        if self.dir.is_none() {
+            return Ok(());
+        }
+
+        for name in names {
+            let name = name.as_ref();
+            let path = if name == REPLICA {
+                self.replica_path.clone()
+            } else {
+                self.cache_path.join(name)
+            };
+            let mut file =
+                File::create(&path).with_context(|| format!("could not create {:?}", path))?;
+            io::copy(&mut self.store.stream(name)?, &mut file)
+                .with_context(|| format!("could not fetch {:?}", name))?;
+            self.fetched.insert(name.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Copies all artifacts from the store.
+    pub(crate) fn fetch_all(&mut self) -> Result<()> {
+        if self.dir.is_none() {
+            return Ok(());
+        }
+        let names = self.store.list()?;
+        self.fetch(names)
+    }
+
+    /// Writes the cache files created since staging back to the store, and the replica if
+    /// `replica` is set.
+    pub(crate) fn persist(&self, replica: bool) -> Result<()> {
+        if self.dir.is_none() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(&self.cache_path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if self.fetched.contains(&name) || !entry.file_type()?.is_file() {
+                continue;
+            }
+            self.store
+                .write(&name, &mut File::open(entry.path())?)
+                .with_context(|| format!("could not persist {:?}", name))?;
+        }
+        if replica {
+            self.store
+                .write(REPLICA, &mut File::open(&self.replica_path)?)
+                .context("could not persist the replica")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Staged<'_> {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            if let Err(err) = remove_dir_all(dir) {
+                warn!("could not remove staging dir {:?}: {}", dir, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use tempfile::tempdir;
+
+    /// A store that is not a local directory.
+    #[derive(Default)]
+    struct MemorySectorStore(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl SectorStore for MemorySectorStore {
+        fn list(&self) -> Result<Vec<String>> {
+            Ok(self.0.lock().expect("poisoned").keys().cloned().collect())
+        }
+
+        fn size(&self, name: &str) -> Result<u64> {
+            let artifacts = self.0.lock().expect("poisoned");
+            Ok(artifacts.get(name).context("missing artifact")?.len() as u64)
+        }
+
+        fn read_at(&self, name: &str, offset: u64, buf: &mut [u8]) -> Result<()> {
+            let artifacts = self.0.lock().expect("poisoned");
+            let data = artifacts.get(name).context("missing artifact")?;
+            let start = offset as usize;
+            buf.copy_from_slice(&data[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn stream(&self, name: &str) -> Result<Box<dyn Read + Send + '_>> {
+            let artifacts = self.0.lock().expect("poisoned");
+            let data = artifacts.get(name).context("missing artifact")?.clone();
+            Ok(Box::new(io::Cursor::new(data)))
+        }
+
+        fn write(&self, name: &str, data: &mut dyn Read) -> Result<u64> {
+            let mut bytes = Vec::new();
+            data.read_to_end(&mut bytes)?;
+            let len = bytes.len() as u64;
+            self.0
+                .lock()
+                .expect("poisoned")
+                .insert(name.to_string(), bytes);
+            Ok(len)
+        }
+
+        fn remove(&self, name: &str) -> Result<()> {
+            self.0.lock().expect("poisoned").remove(name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_local_sector_store() {
+        let dir = tempdir().expect("failed to create tempdir");
+        let store = LocalSectorStore::open(dir.path().join("cache"), dir.path().join("sealed"))
+            .expect("failed to open store");
+
+        store
+            .write("p_aux", &mut &b"aux"[..])
+            .expect("failed to write");
+        store
+            .write(REPLICA, &mut &b"replica bytes"[..])
+            .expect("failed to write");
+        assert_eq!(
+            fs::read(dir.path().join("sealed")).expect("failed to read"),
+            b"replica bytes"
+        );
+
+        let mut names = store.list().expect("failed to list");
+        names.sort();
+        assert_eq!(names, vec!["p_aux".to_string(), REPLICA.to_string()]);
+        assert_eq!(store.size(REPLICA).expect("failed to get size"), 13);
+
+        let mut buf = [0u8; 5];
+        store.read_at(REPLICA, 8, &mut buf).expect("failed to read");
+        assert_eq!(&buf, b"bytes");
+
+        assert!(store.write("../escape", &mut &b""[..]).is_err());
+        store.remove("p_aux").expect("failed to remove");
+        assert_eq!(store.list().expect("failed to list"), vec![REPLICA]);
+    }
+
+    #[test]
+    fn test_staged_round_trip() {
+        let store = MemorySectorStore::default();
+        store
+            .write("layer", &mut &b"layer"[..])
+            .expect("failed to write");
+        store
+            .write(REPLICA, &mut &b"data"[..])
+            .expect("failed to write");
+
+        let staging_dir;
+        {
+            let mut staged = Staged::new(&store).expect("failed to stage");
+            staging_dir = staged.dir.clone().expect("store used in place");
+            staged.fetch(&["layer", REPLICA]).expect("failed to fetch");
+            assert_eq!(
+                fs::read(staged.cache_path().join("layer")).expect("failed to read"),
+                b"layer"
+            );
+
+            fs::write(staged.cache_path().join("tree"), b"tree").expect("failed to write");
+            fs::write(staged.replica_path(), b"sealed").expect("failed to write");
+            staged.persist(true).expect("failed to persist");
+        }
+
+        let artifacts = store.0.lock().expect("poisoned");
+        assert_eq!(artifacts["tree"], b"tree");
+        assert_eq!(artifacts[REPLICA], b"sealed");
+        assert_eq!(artifacts["layer"], b"layer");
+        // The staging directory is removed with the staged sector.
+        assert!(!staging_dir.exists());
+    }
+}
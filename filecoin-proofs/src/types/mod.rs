@@ -2,6 +2,8 @@ pub use merkletree::store::StoreConfig;
 pub use storage_proofs_core::merkle::{MerkleProof, MerkleTreeTrait};
 pub use storage_proofs_porep::stacked::{Labels, PersistentAux, TemporaryAux};
 
+use std::path::PathBuf;
+
 use filecoin_hashers::Hasher;
 use serde::{Deserialize, Serialize};
 use storage_proofs_core::{merkle::BinaryMerkleTree, sector::SectorId};
@@ -52,6 +54,29 @@ pub struct SealPreCommitOutput {
 
 pub type VanillaSealProof<Tree> = stacked::Proof<Tree, DefaultPieceHasher>;
 
+/// One sector of a `seal_pre_commit_phase1_batch`, with the arguments
+/// `seal_pre_commit_phase1` takes for it.
+#[derive(Debug, Clone)]
+pub struct SealPreCommitPhase1Sector {
+    pub cache_path: PathBuf,
+    pub in_path: PathBuf,
+    pub out_path: PathBuf,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    pub ticket: Ticket,
+}
+
+/// The tree_d of committed capacity sectors of `sector_size`. Their data is all zeroes, so they
+/// all share the same tree_d and comm_d. Created once with `generate_cc_tree_d` and passed to
+/// `seal_pre_commit_phase1_cc` for each sector.
+#[derive(Debug, Clone)]
+pub struct CcTreeD {
+    pub sector_size: SectorSize,
+    /// The tree_d on disk.
+    pub config: StoreConfig,
+    pub comm_d: Commitment,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SealCommitPhase1Output<Tree: MerkleTreeTrait> {
     #[serde(bound(
@@ -82,7 +107,22 @@ pub struct SealPreCommitPhase1Output<Tree: MerkleTreeTrait> {
     pub comm_d: Commitment,
 }
 
+/// The commitments of a committed capacity sector after `encode_into` added deal data to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmptySectorUpdateEncoded {
+    pub comm_r_new: Commitment,
+    pub comm_r_last_new: Commitment,
+    pub comm_d_new: Commitment,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmptySectorUpdateProof(pub Vec<u8>);
+
 pub type SnarkProof = Vec<u8>;
+
+/// The proof of a single partition of a Window PoSt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartitionSnarkProof(pub Vec<u8>);
 pub type AggregateSnarkProof = Vec<u8>;
 pub type VanillaProof<Tree> = fallback::Proof<<Tree as MerkleTreeTrait>::Proof>;
 
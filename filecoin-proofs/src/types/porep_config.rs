@@ -12,8 +12,8 @@ use storage_proofs_core::{
 use storage_proofs_porep::stacked::{StackedCircuit, StackedCompound};
 
 use crate::{
-    constants::DefaultPieceHasher,
-    parameters::public_params,
+    constants::{DefaultPieceHasher, POREP_PARTITIONS},
+    parameters::{porep_partitions, public_params},
     types::{PaddedBytesAmount, PoRepProofPartitions, SectorSize, UnpaddedBytesAmount},
 };
 
@@ -41,8 +41,14 @@ impl From<PoRepConfig> for UnpaddedBytesAmount {
 
 impl From<PoRepConfig> for PoRepProofPartitions {
     fn from(x: PoRepConfig) -> Self {
-        let PoRepConfig { partitions, .. } = x;
-        partitions
+        let PoRepConfig {
+            sector_size,
+            partitions,
+            porep_id,
+            ..
+        } = x;
+        let partitions = porep_partitions(u64::from(sector_size), partitions.into(), porep_id);
+        PoRepProofPartitions(partitions as u8)
     }
 }
 
@@ -54,11 +60,32 @@ impl From<PoRepConfig> for SectorSize {
 }
 
 impl PoRepConfig {
+    /// The config for `porep_id`, with the partitions its registered proof type has for
+    /// `sector_size`. Non-interactive proof types have more partitions, see `porep_partitions`.
+    pub fn new(sector_size: SectorSize, porep_id: [u8; 32], api_version: ApiVersion) -> Self {
+        let partitions = *POREP_PARTITIONS
+            .read()
+            .expect("POREP_PARTITIONS poisoned")
+            .get(&u64::from(sector_size))
+            .expect("unknown sector size") as usize;
+
+        PoRepConfig {
+            sector_size,
+            partitions: PoRepProofPartitions(porep_partitions(
+                u64::from(sector_size),
+                partitions,
+                porep_id,
+            ) as u8),
+            porep_id,
+            api_version,
+        }
+    }
+
     /// Returns the cache identifier as used by `storage-proofs::paramater_cache`.
     pub fn get_cache_identifier<Tree: 'static + MerkleTreeTrait>(&self) -> Result<String> {
         let params = public_params::<Tree>(
             self.sector_size.into(),
-            self.partitions.into(),
+            PoRepProofPartitions::from(*self).into(),
             self.porep_id,
             self.api_version,
         )?;
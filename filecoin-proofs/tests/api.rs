@@ -11,13 +11,16 @@ use bincode::serialize;
 use ff::Field;
 use filecoin_hashers::Hasher;
 use filecoin_proofs::{
-    add_piece, aggregate_seal_commit_proofs, clear_cache, compute_comm_d, fauxrep_aux,
-    generate_fallback_sector_challenges, generate_piece_commitment, generate_single_vanilla_proof,
+    add_piece, aggregate_seal_commit_proofs, aggregate_window_post_proofs, clear_cache, clear_synthetic_proofs, compute_comm_d,
+    fauxrep_aux, generate_cc_tree_d, generate_fallback_sector_challenges,
+    generate_piece_commitment, generate_single_vanilla_proof,
+    generate_single_window_post_with_vanilla, generate_synth_proofs,
     generate_window_post, generate_window_post_with_vanilla, generate_winning_post,
     generate_winning_post_sector_challenge, generate_winning_post_with_vanilla, get_seal_inputs,
-    seal_commit_phase1, seal_commit_phase2, seal_pre_commit_phase1, seal_pre_commit_phase2,
-    unseal_range, validate_cache_for_commit, validate_cache_for_precommit_phase2,
-    verify_aggregate_seal_commit_proofs, verify_seal, verify_window_post, verify_winning_post,
+    seal_commit_phase1, seal_commit_phase2, seal_pre_commit_phase1, seal_pre_commit_phase1_cc,
+    seal_pre_commit_phase2,
+    unseal_range, unseal_range_streaming, validate_cache_for_commit, validate_cache_for_precommit_phase2,
+    verify_aggregate_seal_commit_proofs, verify_aggregate_window_post_proofs, verify_seal, verify_window_post, verify_winning_post,
     Commitment, DefaultTreeDomain, MerkleTreeTrait, PaddedBytesAmount, PieceInfo, PoRepConfig,
     PoRepProofPartitions, PoStConfig, PoStType, PrivateReplicaInfo, ProverId, PublicReplicaInfo,
     SealCommitOutput, SealPreCommitOutput, SealPreCommitPhase1Output, SectorShape16KiB,
@@ -28,7 +31,10 @@ use filecoin_proofs::{
 };
 use rand::{random, Rng, SeedableRng};
 use rand_xorshift::XorShiftRng;
-use storage_proofs_core::{api_version::ApiVersion, is_legacy_porep_id, sector::SectorId};
+use storage_proofs_core::{
+    api_version::ApiVersion, is_legacy_porep_id, is_ni_porep_id, is_synthetic_porep_id,
+    sector::SectorId,
+};
 use tempfile::{tempdir, NamedTempFile, TempDir};
 
 // Use a fixed PoRep ID, so that the parents cache can be re-used between some tests.
@@ -450,6 +456,213 @@ fn clear_cache_dir_keep_data_layer(cache_dir: &TempDir) {
     }
 }
 
+#[test]
+fn test_seal_pre_commit_phase1_cc_2kib() -> Result<()> {
+    init_logger();
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+    let config = porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0);
+    let ticket = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+
+    let cc_dir = tempdir()?;
+    let cc_tree_d = generate_cc_tree_d(SectorSize(sector_size), cc_dir.path())?;
+    assert_eq!(cc_tree_d.comm_d, compute_comm_d(SectorSize(sector_size), &[])?);
+    // A second call opens the tree_d built by the first.
+    let reopened = generate_cc_tree_d(SectorSize(sector_size), cc_dir.path())?;
+    assert_eq!(reopened.comm_d, cc_tree_d.comm_d);
+
+    // The same sector, sealed from a file of zeroes.
+    let zeroes = NamedTempFile::new()?;
+    zeroes.as_file().set_len(sector_size)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+    let phase1_output = seal_pre_commit_phase1::<_, _, _, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        zeroes.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+    )?;
+    let expected = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    let cc_sealed_sector_file = NamedTempFile::new()?;
+    let cc_cache_dir = tempdir()?;
+    let cc_phase1_output = seal_pre_commit_phase1_cc::<_, _, SectorShape2KiB>(
+        config,
+        &cc_tree_d,
+        cc_cache_dir.path(),
+        cc_sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        cc_phase1_output,
+        cc_cache_dir.path(),
+        cc_sealed_sector_file.path(),
+    )?;
+
+    assert_eq!(pre_commit_output.comm_d, expected.comm_d);
+    assert_eq!(pre_commit_output.comm_r, expected.comm_r);
+    Ok(())
+}
+
+#[test]
+fn test_seal_commit_phase1_synthetic_2kib() -> Result<()> {
+    init_logger();
+
+    let porep_id_synthetic: u64 = 10; // This is a RegisteredSealProof value
+
+    let mut porep_id = [0u8; 32];
+    porep_id[..8].copy_from_slice(&porep_id_synthetic.to_le_bytes());
+    assert!(is_synthetic_porep_id(porep_id));
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+    let config = porep_config(sector_size, porep_id, ApiVersion::V1_1_0);
+    let ticket = rng.gen();
+    let seed = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(sector_size)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+    let (_piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        prover_id,
+        sector_id,
+        ticket,
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    // Without synthetic proofs, the selected challenges are proven from the layers.
+    let expected = seal_commit_phase1::<_, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+        pre_commit_output.clone(),
+    )?;
+
+    generate_synth_proofs::<_, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+        pre_commit_output.clone(),
+    )?;
+    clear_cache::<SectorShape2KiB>(cache_dir.path())?;
+    assert!(get_layer_file_paths(&cache_dir).is_empty());
+
+    let phase1_output = seal_commit_phase1::<_, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+        pre_commit_output,
+    )?;
+    assert_eq!(
+        serialize(&phase1_output.vanilla_proofs)?,
+        serialize(&expected.vanilla_proofs)?
+    );
+
+    clear_synthetic_proofs(cache_dir.path())?;
+    Ok(())
+}
+
+#[test]
+fn test_seal_commit_phase1_non_interactive_2kib() -> Result<()> {
+    init_logger();
+
+    let porep_id_ni: u64 = 15; // This is a RegisteredSealProof value
+
+    let mut porep_id = [0u8; 32];
+    porep_id[..8].copy_from_slice(&porep_id_ni.to_le_bytes());
+    assert!(is_ni_porep_id(porep_id));
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+    let config = PoRepConfig::new(SectorSize(sector_size), porep_id, ApiVersion::V1_1_0);
+    let ticket = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(sector_size)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+    let (_piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        prover_id,
+        sector_id,
+        ticket,
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    // The challenges are derived from comm_r, whatever the seed.
+    let outputs = [[1u8; 32], [2u8; 32]]
+        .iter()
+        .map(|seed| {
+            seal_commit_phase1::<_, SectorShape2KiB>(
+                config,
+                cache_dir.path(),
+                sealed_sector_file.path(),
+                prover_id,
+                sector_id,
+                ticket,
+                *seed,
+                pre_commit_output.clone(),
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        serialize(&outputs[0].vanilla_proofs)?,
+        serialize(&outputs[1].vanilla_proofs)?
+    );
+    Ok(())
+}
+
 #[test]
 fn test_resumable_seal_skip_proofs_v1() {
     let porep_id_v1: u64 = 0; // This is a RegisteredSealProof value
@@ -1139,13 +1352,59 @@ fn window_post<Tree: 'static + MerkleTreeTrait>(
         vanilla_proofs.push(single_proof);
     }
 
-    let proof =
-        generate_window_post_with_vanilla::<Tree>(&config, &randomness, prover_id, vanilla_proofs)?;
+    let proof = generate_window_post_with_vanilla::<Tree>(
+        &config,
+        &randomness,
+        prover_id,
+        vanilla_proofs.clone(),
+    )?;
     /////////////////////////////////////////////
 
     let valid = verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &proof)?;
     assert!(valid, "proof did not verify");
 
+    // The partitions proven one by one, and aggregated.
+    let partitions = (total_sector_count + sector_count - 1) / sector_count;
+    let partition_proofs = (0..partitions)
+        .map(|partition_index| {
+            generate_single_window_post_with_vanilla::<Tree>(
+                &config,
+                &randomness,
+                prover_id,
+                &pub_replicas,
+                partition_index,
+                &vanilla_proofs,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let aggregate_proof = aggregate_window_post_proofs::<Tree>(
+        &config,
+        &randomness,
+        prover_id,
+        &pub_replicas,
+        &partition_proofs,
+    )?;
+    let valid = verify_aggregate_window_post_proofs::<Tree>(
+        &config,
+        &randomness,
+        prover_id,
+        &pub_replicas,
+        &aggregate_proof,
+    )?;
+    assert!(valid, "aggregate proof did not verify");
+
+    // The aggregate is bound to the randomness.
+    let mut wrong_randomness = randomness;
+    wrong_randomness[0] ^= 1;
+    let valid = verify_aggregate_window_post_proofs::<Tree>(
+        &config,
+        &wrong_randomness,
+        prover_id,
+        &pub_replicas,
+        &aggregate_proof,
+    )?;
+    assert!(!valid, "aggregate proof verified with the wrong randomness");
+
     Ok(())
 }
 
@@ -1315,6 +1574,23 @@ fn unseal<Tree: 'static + MerkleTreeTrait>(
     assert_eq!(contents.len(), 508);
     assert_eq!(&piece_bytes[508..508 + 508], &contents[..]);
 
+    // A range which does not start or end on a field element decodes the same when streamed.
+    let mut streamed = Vec::new();
+    let written = unseal_range_streaming::<_, _, _, Tree>(
+        config,
+        cache_dir_path,
+        sealed_sector_file.reopen()?,
+        &mut streamed,
+        prover_id,
+        sector_id,
+        comm_d,
+        ticket,
+        UnpaddedByteIndex(300),
+        UnpaddedBytesAmount(900),
+    )?;
+    assert_eq!(written, UnpaddedBytesAmount(900));
+    assert_eq!(&piece_bytes[300..300 + 900], &streamed[..]);
+
     let computed_comm_d = compute_comm_d(config.sector_size, &piece_infos)?;
 
     assert_eq!(
@@ -0,0 +1,56 @@
+#![cfg(feature = "shape-registry")]
+
+use filecoin_proofs::{
+    registry::{register_sector_size, registered_shape, SectorShape, SectorSizeRegistration},
+    with_shape, PoRepConfig, SectorSize, LAYERS, SECTOR_SIZE_2_KIB,
+};
+use generic_array::typenum::Unsigned;
+use storage_proofs_core::{
+    api_version::ApiVersion,
+    merkle::{get_base_tree_count, MerkleTreeTrait},
+};
+
+fn arities_to_usize<Tree: MerkleTreeTrait>() -> (usize, usize, usize) {
+    (
+        Tree::Arity::to_usize(),
+        Tree::SubTreeArity::to_usize(),
+        Tree::TopTreeArity::to_usize(),
+    )
+}
+
+#[test]
+fn test_register_sector_size() {
+    // 128 base trees of 64 nodes each.
+    let sector_size = 1 << 18;
+    register_sector_size(SectorSizeRegistration::new(sector_size, SectorShape::Top16))
+        .expect("failed to register sector size");
+
+    assert_eq!(registered_shape(sector_size), Some(SectorShape::Top16));
+    assert_eq!(with_shape!(sector_size, arities_to_usize), (8, 8, 16));
+    assert_eq!(with_shape!(sector_size, get_base_tree_count), 128);
+    assert_eq!(
+        *LAYERS
+            .read()
+            .expect("LAYERS poisoned")
+            .get(&sector_size)
+            .expect("missing layers"),
+        2
+    );
+
+    let porep_config = PoRepConfig::new(SectorSize(sector_size), [0; 32], ApiVersion::V1_1_0);
+    assert_eq!(usize::from(porep_config.partitions), 1);
+
+    // Sizes are only registered once, and published sizes cannot be registered.
+    assert!(
+        register_sector_size(SectorSizeRegistration::new(sector_size, SectorShape::Top16)).is_err()
+    );
+    assert!(register_sector_size(SectorSizeRegistration::new(
+        SECTOR_SIZE_2_KIB,
+        SectorShape::Base
+    ))
+    .is_err());
+    // The base trees of a 512KiB sector of that shape are not oct trees.
+    assert!(
+        register_sector_size(SectorSizeRegistration::new(1 << 19, SectorShape::Top16)).is_err()
+    );
+}
@@ -0,0 +1,133 @@
+#![cfg(feature = "remote-p2")]
+
+use std::fs::{copy, read, read_dir};
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread;
+
+use anyhow::Result;
+use bincode::{deserialize, serialize};
+use filecoin_proofs::{
+    remote::{seal_pre_commit_phase2_remote, serve_remote_p2, RemoteP2Config, RemoteStorage},
+    seal_pre_commit_phase1, seal_pre_commit_phase2, PoRepConfig, SealPreCommitPhase1Output,
+    SectorShape2KiB, SectorSize, SECTOR_SIZE_2_KIB,
+};
+use storage_proofs_core::{api_version::ApiVersion, cache_key::CacheKey};
+use tempfile::{tempdir, NamedTempFile};
+
+const SECRET: &str = "remote-p2-test-secret";
+
+/// Starts a server on a free port, returning its endpoint.
+fn start_server(work_dir: &Path) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    let config = RemoteP2Config {
+        work_dir: work_dir.to_path_buf(),
+        shared_root: None,
+        secret: SECRET.into(),
+        max_concurrent: 1,
+    };
+    thread::spawn(move || serve_remote_p2(listener, config));
+    Ok(endpoint)
+}
+
+fn seal_phase1(
+    config: PoRepConfig,
+    cache_dir: &Path,
+    sealed_sector_file: &Path,
+) -> Result<SealPreCommitPhase1Output<SectorShape2KiB>> {
+    let zeroes = NamedTempFile::new()?;
+    zeroes.as_file().set_len(SECTOR_SIZE_2_KIB)?;
+    seal_pre_commit_phase1::<_, _, _, SectorShape2KiB>(
+        config,
+        cache_dir,
+        zeroes.path(),
+        sealed_sector_file,
+        [1; 32],
+        7.into(),
+        [2; 32],
+    )
+}
+
+#[test]
+fn test_seal_pre_commit_phase2_remote_streamed() -> Result<()> {
+    let config = PoRepConfig::new(SectorSize(SECTOR_SIZE_2_KIB), [128; 32], ApiVersion::V1_1_0);
+
+    let work_dir = tempdir()?;
+    let server = start_server(work_dir.path())?;
+
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+    let phase1_output = seal_phase1(config, cache_dir.path(), sealed_sector_file.path())?;
+
+    // A copy of the sector, to run phase 2 on locally.
+    let local_sector_file = NamedTempFile::new()?;
+    copy(sealed_sector_file.path(), local_sector_file.path())?;
+    let local_cache_dir = tempdir()?;
+    for entry in read_dir(cache_dir.path())? {
+        let path = entry?.path();
+        copy(
+            &path,
+            local_cache_dir
+                .path()
+                .join(path.file_name().expect("no file name")),
+        )?;
+    }
+    let local_phase1_output: SealPreCommitPhase1Output<SectorShape2KiB> =
+        deserialize(&serialize(&phase1_output)?)?;
+    let expected = seal_pre_commit_phase2(
+        config,
+        local_phase1_output,
+        local_cache_dir.path(),
+        local_sector_file.path(),
+    )?;
+
+    let output = seal_pre_commit_phase2_remote(
+        &server,
+        SECRET,
+        &RemoteStorage::Streamed,
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    assert_eq!(output.comm_r, expected.comm_r);
+    assert_eq!(output.comm_d, expected.comm_d);
+    assert_eq!(
+        read(sealed_sector_file.path())?,
+        read(local_sector_file.path())?
+    );
+    let p_aux = CacheKey::PAux.to_string();
+    assert_eq!(
+        read(cache_dir.path().join(&p_aux))?,
+        read(local_cache_dir.path().join(&p_aux))?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_seal_pre_commit_phase2_remote_wrong_secret() -> Result<()> {
+    let config = PoRepConfig::new(SectorSize(SECTOR_SIZE_2_KIB), [128; 32], ApiVersion::V1_1_0);
+
+    let work_dir = tempdir()?;
+    let server = start_server(work_dir.path())?;
+
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+    let phase1_output = seal_phase1(config, cache_dir.path(), sealed_sector_file.path())?;
+
+    let res = seal_pre_commit_phase2_remote(
+        &server,
+        "not-the-secret",
+        &RemoteStorage::Streamed,
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    );
+    assert!(res.is_err(), "a wrong secret must be refused");
+
+    Ok(())
+}
@@ -14,4 +14,5 @@ mod sha256;
 mod sha256_intrinsics;
 mod sha256_utils;
 
-pub use sha256::Sha256;
+pub use platform::{ShaImpl, SHA_IMPL_ENV};
+pub use sha256::{compress256, implementation_name, Sha256};
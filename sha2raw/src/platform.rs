@@ -2,6 +2,48 @@
 use crate::sha256_intrinsics;
 use crate::sha256_utils;
 
+use std::str::FromStr;
+
+/// Name of the environment variable which forces a SHA-256 implementation.
+pub const SHA_IMPL_ENV: &str = "FIL_PROOFS_SHA_IMPL";
+
+/// Which SHA-256 implementation to use, see `FIL_PROOFS_SHA_IMPL`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShaImpl {
+    /// The fastest implementation the CPU supports, detected at runtime.
+    Auto,
+    /// The implementation used on CPUs without the SHA extensions.
+    Software,
+    /// The SHA extensions (SHA-NI), if the CPU supports them.
+    Ni,
+}
+
+impl FromStr for ShaImpl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ShaImpl::Auto),
+            "sw" => Ok(ShaImpl::Software),
+            "ni" => Ok(ShaImpl::Ni),
+            _ => Err(format!("unknown SHA implementation: {}", s)),
+        }
+    }
+}
+
+impl ShaImpl {
+    /// Reads `FIL_PROOFS_SHA_IMPL`, defaulting to `Auto`.
+    pub fn from_env() -> Self {
+        match std::env::var(SHA_IMPL_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|err| {
+                println!("WARN: {}, using auto", err);
+                ShaImpl::Auto
+            }),
+            Err(_) => ShaImpl::Auto,
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum Platform {
@@ -34,10 +76,51 @@ impl Implementation {
         Self::portable()
     }
 
+    /// The implementation for `choice`. A choice the CPU does not support falls back to `detect`.
+    pub fn select(choice: ShaImpl) -> Self {
+        match choice {
+            ShaImpl::Auto => Self::detect(),
+            ShaImpl::Software => Self::software(),
+            ShaImpl::Ni => {
+                #[cfg(target_arch = "x86_64")]
+                {
+                    if let Some(sha_impl) = Self::sha_if_supported() {
+                        return sha_impl;
+                    }
+                }
+                println!("WARN: sha-ni requested but not available, falling back");
+                Self::detect()
+            }
+        }
+    }
+
+    /// The fastest implementation which does not need the SHA extensions.
+    pub fn software() -> Self {
+        #[cfg(feature = "asm")]
+        {
+            if let Some(asm_impl) = Self::asm_if_supported() {
+                return asm_impl;
+            }
+        }
+
+        Self::portable()
+    }
+
     pub fn portable() -> Self {
         Implementation(Platform::Portable)
     }
 
+    /// Short name of the implementation, e.g. for logging.
+    pub fn name(self) -> &'static str {
+        match self.0 {
+            Platform::Portable => "portable",
+            #[cfg(feature = "asm")]
+            Platform::Asm => "asm",
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::Sha => "sha-ni",
+        }
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[allow(unreachable_code)]
     pub fn sha_if_supported() -> Option<Self> {
@@ -1,10 +1,26 @@
 use byteorder::{ByteOrder, BE};
 use lazy_static::lazy_static;
 
-use crate::{consts::H256, platform::Implementation};
+use crate::{
+    consts::H256,
+    platform::{Implementation, ShaImpl},
+};
 
 lazy_static! {
-    static ref IMPL: Implementation = Implementation::detect();
+    static ref IMPL: Implementation = Implementation::select(ShaImpl::from_env());
+}
+
+/// Compresses `blocks`, pairs of 32 byte halves of 64 byte blocks, into `state`, using the
+/// implementation selected once per process (see `FIL_PROOFS_SHA_IMPL`).
+#[inline]
+pub fn compress256(state: &mut [u32; 8], blocks: &[&[u8]]) {
+    debug_assert_eq!(blocks.len() % 2, 0, "invalid block length");
+    IMPL.compress256(state, blocks);
+}
+
+/// Name of the implementation `compress256` and `Sha256` use.
+pub fn implementation_name() -> &'static str {
+    IMPL.name()
 }
 
 #[derive(Clone)]
@@ -94,6 +110,29 @@ mod tests {
         fuzz(10);
     }
 
+    #[test]
+    fn test_select_implementations_agree() {
+        let rng = &mut XorShiftRng::from_seed([
+            0x59, 0x62, 0xbe, 0x5d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06,
+            0xbc, 0xe5,
+        ]);
+        let mut input = vec![0u8; 64 * 4];
+        rng.fill_bytes(&mut input);
+        let blocks = input.chunks(32).collect::<Vec<_>>();
+
+        let mut expected = H256;
+        Implementation::portable().compress256(&mut expected, &blocks);
+        for choice in &[ShaImpl::Auto, ShaImpl::Software, ShaImpl::Ni] {
+            let mut state = H256;
+            Implementation::select(*choice).compress256(&mut state, &blocks);
+            assert_eq!(state, expected, "{:?}", choice);
+        }
+
+        assert_eq!("sw".parse::<ShaImpl>(), Ok(ShaImpl::Software));
+        assert_eq!("NI".parse::<ShaImpl>(), Ok(ShaImpl::Ni));
+        assert!("avx".parse::<ShaImpl>().is_err());
+    }
+
     #[test]
     #[ignore]
     fn test_fuzz_long() {
@@ -0,0 +1,380 @@
+//! How the large files of a sector are read and written: memory mapped, with plain buffered
+//! reads and writes, or with O_DIRECT, which bypasses the page cache.
+//!
+//! Memory mapping is the fastest on an idle host with enough memory, but under memory pressure
+//! or on network block devices the page cache thrashes, and reading into buffers of our own,
+//! possibly without the page cache, is faster. The strategy is chosen per kind of artifact with
+//! the `io_*` settings. O_DIRECT needs buffers, offsets and lengths aligned to
+//! `DIRECT_IO_ALIGNMENT`, which `AlignedBuffer` and the functions here take care of. It is only
+//! available on Linux; elsewhere direct reads and writes are buffered ones.
+
+use std::alloc::{self, Layout};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context, Result};
+use log::warn;
+use memmap::{Mmap, MmapOptions};
+
+use crate::settings::SETTINGS;
+
+/// The alignment of buffers, offsets and lengths of O_DIRECT reads and writes.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Direct writes go through an aligned buffer of this size.
+const DIRECT_WRITE_CHUNK: usize = 1 << 23;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoStrategy {
+    Mmap,
+    Buffered,
+    Direct,
+}
+
+impl FromStr for IoStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "mmap" => Ok(IoStrategy::Mmap),
+            "buffered" => Ok(IoStrategy::Buffered),
+            "direct" => Ok(IoStrategy::Direct),
+            _ => bail!(
+                "unknown io strategy {:?}, expected mmap, buffered or direct",
+                s
+            ),
+        }
+    }
+}
+
+impl Display for IoStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            IoStrategy::Mmap => write!(f, "mmap"),
+            IoStrategy::Buffered => write!(f, "buffered"),
+            IoStrategy::Direct => write!(f, "direct"),
+        }
+    }
+}
+
+/// The kinds of artifacts with their own `IoStrategy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Artifact {
+    /// The labels of the layers, read when building tree_c and tree_r_last.
+    Layers,
+    /// The windows of the parent cache read while labeling.
+    ParentCache,
+    /// tree_r_last, as written by its GPU builder.
+    Tree,
+    /// The replica, encoded in place by phase 2.
+    Replica,
+}
+
+impl Artifact {
+    /// The strategy given by the settings, or the default if the setting is invalid. The
+    /// defaults are what was used before strategies could be chosen.
+    pub fn io_strategy(self) -> IoStrategy {
+        let (name, value, default) = match self {
+            Artifact::Layers => ("io_layers", &SETTINGS.io_layers, IoStrategy::Buffered),
+            Artifact::ParentCache => (
+                "io_parent_cache",
+                &SETTINGS.io_parent_cache,
+                IoStrategy::Mmap,
+            ),
+            Artifact::Tree => ("io_tree", &SETTINGS.io_tree, IoStrategy::Buffered),
+            Artifact::Replica => ("io_replica", &SETTINGS.io_replica, IoStrategy::Mmap),
+        };
+
+        value.parse().unwrap_or_else(|err| {
+            warn!("invalid {}: {:?}, using {}", name, err, default);
+            default
+        })
+    }
+}
+
+/// A zeroed buffer whose start and capacity are aligned to `DIRECT_IO_ALIGNMENT`.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// The buffer is owned memory like a `Vec<u8>`.
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> Self {
+        let capacity = align_up(len.max(1));
+        let layout = Layout::from_size_align(capacity, DIRECT_IO_ALIGNMENT)
+            .expect("invalid aligned buffer layout");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+
+        AlignedBuffer { ptr, len, layout }
+    }
+
+    /// The whole buffer, including the bytes beyond its length up to the aligned capacity.
+    fn capacity_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "AlignedBuffer({} bytes)", self.len)
+    }
+}
+
+/// Reads ranges of a file with a given strategy.
+#[derive(Debug)]
+pub struct ArtifactReader {
+    path: PathBuf,
+    len: u64,
+    inner: ReaderInner,
+}
+
+#[derive(Debug)]
+enum ReaderInner {
+    Mmap(Mmap),
+    Buffered(File),
+    Direct(File),
+}
+
+impl ArtifactReader {
+    /// Opens `path` with the strategy of `artifact`.
+    pub fn open<P: AsRef<Path>>(path: P, artifact: Artifact) -> Result<Self> {
+        Self::with_strategy(path, artifact.io_strategy())
+    }
+
+    pub fn with_strategy<P: AsRef<Path>>(path: P, strategy: IoStrategy) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path).with_context(|| format!("could not open path={:?}", path))?;
+        let len = file.metadata()?.len();
+
+        let inner = match strategy {
+            // Empty files cannot be mapped.
+            IoStrategy::Mmap if len > 0 => ReaderInner::Mmap(unsafe {
+                MmapOptions::new()
+                    .map(&file)
+                    .with_context(|| format!("could not mmap path={:?}", path))?
+            }),
+            IoStrategy::Mmap | IoStrategy::Buffered => ReaderInner::Buffered(file),
+            IoStrategy::Direct => ReaderInner::Direct(open_direct(&path, false)?),
+        };
+
+        Ok(ArtifactReader { path, len, inner })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Fills `buf` with the bytes of the file from `offset` on.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        ensure!(
+            offset + buf.len() as u64 <= self.len,
+            "read of {} bytes at {} is beyond the {} bytes of {:?}",
+            buf.len(),
+            offset,
+            self.len,
+            self.path
+        );
+
+        match &self.inner {
+            ReaderInner::Mmap(data) => {
+                let start = offset as usize;
+                buf.copy_from_slice(&data[start..start + buf.len()]);
+            }
+            ReaderInner::Buffered(file) => file
+                .read_exact_at(buf, offset)
+                .with_context(|| format!("could not read path={:?}", self.path))?,
+            ReaderInner::Direct(file) => read_direct(file, offset, buf)
+                .with_context(|| format!("could not read path={:?}", self.path))?,
+        }
+
+        Ok(())
+    }
+
+    /// Reads the whole file.
+    pub fn read_all(&self) -> Result<AlignedBuffer> {
+        let mut buf = AlignedBuffer::new(self.len as usize);
+        self.read_at(0, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Writes `data` to the start of the file at `path`, which is created or resized to the
+/// length of `data`.
+pub fn write_file<P: AsRef<Path>>(path: P, data: &[u8], strategy: IoStrategy) -> Result<()> {
+    let path = path.as_ref();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("could not open path={:?}", path))?;
+    file.set_len(data.len() as u64)?;
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    match strategy {
+        IoStrategy::Mmap => {
+            let mut map = unsafe {
+                MmapOptions::new()
+                    .map_mut(&file)
+                    .with_context(|| format!("could not mmap path={:?}", path))?
+            };
+            map.copy_from_slice(data);
+            map.flush()?;
+        }
+        IoStrategy::Buffered => file.write_all_at(data, 0)?,
+        IoStrategy::Direct => {
+            // The aligned part goes through O_DIRECT, the tail, if any, through the page cache.
+            let aligned_len = data.len() - data.len() % DIRECT_IO_ALIGNMENT;
+            let direct = open_direct(path, true)?;
+            let mut buf = AlignedBuffer::new(DIRECT_WRITE_CHUNK.min(aligned_len));
+            for (i, chunk) in data[..aligned_len].chunks(DIRECT_WRITE_CHUNK).enumerate() {
+                buf[..chunk.len()].copy_from_slice(chunk);
+                direct.write_all_at(&buf[..chunk.len()], (i * DIRECT_WRITE_CHUNK) as u64)?;
+            }
+            file.write_all_at(&data[aligned_len..], aligned_len as u64)?;
+        }
+    }
+    file.sync_data()
+        .with_context(|| format!("could not sync path={:?}", path))?;
+
+    Ok(())
+}
+
+fn align_up(n: usize) -> usize {
+    (n + DIRECT_IO_ALIGNMENT - 1) / DIRECT_IO_ALIGNMENT * DIRECT_IO_ALIGNMENT
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path, write: bool) -> Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    match OpenOptions::new()
+        .read(true)
+        .write(write)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+    {
+        // Filesystems such as tmpfs do not support O_DIRECT.
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            warn!("{:?} does not support O_DIRECT, using buffered io", path);
+            OpenOptions::new()
+                .read(true)
+                .write(write)
+                .open(path)
+                .with_context(|| format!("could not open path={:?}", path))
+        }
+        res => res.with_context(|| format!("could not open path={:?} with O_DIRECT", path)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(path: &Path, write: bool) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(path)
+        .with_context(|| format!("could not open path={:?}", path))
+}
+
+/// Reads the aligned range around `buf` into an aligned buffer, then copies `buf` out of it.
+fn read_direct(file: &File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    let alignment = DIRECT_IO_ALIGNMENT as u64;
+    let start = offset - offset % alignment;
+    let needed = (offset - start) as usize + buf.len();
+
+    let mut aligned = AlignedBuffer::new(needed);
+    let capacity = aligned.capacity_mut();
+    let mut read = 0;
+    while read < needed {
+        // Only the read hitting the end of the file can be short of the aligned length.
+        let n = file.read_at(&mut capacity[read..], start + read as u64)?;
+        ensure!(n > 0, "unexpected end of file");
+        read += n;
+    }
+
+    let skip = (offset - start) as usize;
+    buf.copy_from_slice(&aligned[skip..skip + buf.len()]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_strategies_read_and_write_the_same() {
+        // Neither a multiple of the alignment, nor of the write chunk.
+        let data: Vec<u8> = (0..3 * DIRECT_IO_ALIGNMENT + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        for &write_strategy in &[IoStrategy::Mmap, IoStrategy::Buffered, IoStrategy::Direct] {
+            let file = NamedTempFile::new().expect("failed to create file");
+            // Larger than the data, to check it is truncated.
+            std::fs::write(file.path(), vec![1u8; data.len() * 2]).expect("failed to write");
+            write_file(file.path(), &data, write_strategy).expect("failed to write file");
+
+            for &read_strategy in &[IoStrategy::Mmap, IoStrategy::Buffered, IoStrategy::Direct] {
+                let reader = ArtifactReader::with_strategy(file.path(), read_strategy)
+                    .expect("failed to open reader");
+                assert_eq!(reader.len(), data.len() as u64);
+                assert_eq!(&reader.read_all().expect("failed to read")[..], &data[..]);
+
+                let mut buf = vec![0u8; 5000];
+                reader.read_at(1234, &mut buf).expect("failed to read");
+                assert_eq!(&buf[..], &data[1234..6234]);
+                assert!(reader.read_at(data.len() as u64 - 10, &mut buf).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_io_strategy() {
+        assert_eq!("mmap".parse::<IoStrategy>().ok(), Some(IoStrategy::Mmap));
+        assert_eq!(
+            "Direct".parse::<IoStrategy>().ok(),
+            Some(IoStrategy::Direct)
+        );
+        assert!("odirect".parse::<IoStrategy>().is_err());
+        assert_eq!(IoStrategy::Buffered.to_string(), "buffered");
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{ensure, Context};
+use anyhow::{anyhow, ensure, Context};
 use bellperson::{
     bls::{Bls12, Fr},
     groth16::{
@@ -22,6 +22,7 @@ use crate::{
     multi_proof::MultiProof,
     parameter_cache::{CacheableParameters, ParameterSetMetadata},
     partitions::partition_count,
+    progress::{self, ProgressEvent},
     proof::ProofScheme,
 };
 
@@ -242,6 +243,7 @@ pub trait CompoundProof<'a, S: ProofScheme<'a>, C: Circuit<Bls12> + CircuitCompo
             "cannot create a circuit proof over missing vanilla proofs"
         );
 
+        let partitions = vanilla_proofs.len();
         let circuits = vanilla_proofs
             .into_par_iter()
             .enumerate()
@@ -256,7 +258,9 @@ pub trait CompoundProof<'a, S: ProofScheme<'a>, C: Circuit<Bls12> + CircuitCompo
             })
             .collect::<Result<Vec<_>>>()?;
 
+        progress::report(ProgressEvent::SnarkStarted { partitions });
         let groth_proofs = groth16::create_proof_batch(circuits, groth_params)?;
+        progress::report(ProgressEvent::SnarkFinished { partitions });
 
 
         groth_proofs
@@ -270,6 +274,32 @@ pub trait CompoundProof<'a, S: ProofScheme<'a>, C: Circuit<Bls12> + CircuitCompo
             .collect()
     }
 
+    /// Generates the groth proof of the single partition `k`, so that the partitions of a proof
+    /// can be proven independently of each other.
+    fn partition_circuit_proof(
+        pub_in: &S::PublicInputs,
+        vanilla_proof: S::Proof,
+        pub_params: &S::PublicParams,
+        groth_params: &groth16::MappedParameters<Bls12>,
+        k: usize,
+    ) -> Result<groth16::Proof<Bls12>> {
+        let circuit = Self::circuit(
+            &pub_in,
+            C::ComponentPrivateInputs::default(),
+            &vanilla_proof,
+            &pub_params,
+            Some(k),
+        )?;
+
+        progress::report(ProgressEvent::SnarkStarted { partitions: 1 });
+        let mut groth_proofs = groth16::create_proof_batch(vec![circuit], groth_params)?;
+        progress::report(ProgressEvent::SnarkFinished { partitions: 1 });
+
+        groth_proofs
+            .pop()
+            .ok_or_else(|| anyhow!("missing partition proof"))
+    }
+
     /// Given a prover_srs key, a list of groth16 proofs, and an ordered list of seeds
     /// (used to derive the PoRep challenges) hashed pair-wise with the comm_rs using sha256, aggregate them all into
     /// an AggregateProof type.
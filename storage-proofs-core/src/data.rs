@@ -3,9 +3,11 @@ use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
 
 use anyhow::{ensure, Context, Result};
-use log::info;
+use log::{error, info};
 use memmap::{MmapMut, MmapOptions};
 
+use crate::artifact_io::{write_file, AlignedBuffer, Artifact, ArtifactReader, IoStrategy};
+
 /// A wrapper around data either on disk or a slice in memory, that can be dropped and read back into memory,
 /// to allow for better control of memory consumption.
 #[derive(Debug)]
@@ -19,6 +21,12 @@ pub struct Data<'a> {
 enum RawData<'a> {
     Slice(&'a mut [u8]),
     Mmap(MmapMut),
+    /// Read from the path, to be written back when dropped if it changed.
+    Buffer {
+        buf: AlignedBuffer,
+        strategy: IoStrategy,
+        dirty: bool,
+    },
 }
 
 impl<'a> Deref for RawData<'a> {
@@ -28,6 +36,7 @@ impl<'a> Deref for RawData<'a> {
         match self {
             RawData::Slice(ref raw) => raw,
             RawData::Mmap(ref raw) => raw,
+            RawData::Buffer { ref buf, .. } => buf,
         }
     }
 }
@@ -37,6 +46,14 @@ impl<'a> DerefMut for RawData<'a> {
         match self {
             RawData::Slice(ref mut raw) => raw,
             RawData::Mmap(ref mut raw) => raw,
+            RawData::Buffer {
+                ref mut buf,
+                ref mut dirty,
+                ..
+            } => {
+                *dirty = true;
+                buf
+            }
         }
     }
 }
@@ -118,19 +135,31 @@ impl<'a> Data<'a> {
 
                 info!("restoring {}", path.display());
 
-                let f_data = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .open(path)
-                    .with_context(|| format!("could not open path={:?}", path))?;
-                let data = unsafe {
-                    MmapOptions::new()
-                        .map_mut(&f_data)
-                        .with_context(|| format!("could not mmap path={:?}", path))?
-                };
-
-                self.len = data.len();
-                self.raw = Some(RawData::Mmap(data));
+                let strategy = Artifact::Replica.io_strategy();
+                if strategy == IoStrategy::Mmap {
+                    let f_data = OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(path)
+                        .with_context(|| format!("could not open path={:?}", path))?;
+                    let data = unsafe {
+                        MmapOptions::new()
+                            .map_mut(&f_data)
+                            .with_context(|| format!("could not mmap path={:?}", path))?
+                    };
+
+                    self.len = data.len();
+                    self.raw = Some(RawData::Mmap(data));
+                } else {
+                    let buf = ArtifactReader::with_strategy(path, strategy)?.read_all()?;
+
+                    self.len = buf.len();
+                    self.raw = Some(RawData::Buffer {
+                        buf,
+                        strategy,
+                        dirty: false,
+                    });
+                }
             }
         }
 
@@ -142,8 +171,14 @@ impl<'a> Data<'a> {
         if let Some(ref p) = self.path {
             info!("dropping data {}", p.display());
 
-            if let Some(RawData::Mmap(raw)) = &self.raw {
-                raw.flush()?;
+            match &self.raw {
+                Some(RawData::Mmap(raw)) => raw.flush()?,
+                Some(RawData::Buffer {
+                    buf,
+                    strategy,
+                    dirty: true,
+                }) => write_file(p, buf, *strategy)?,
+                _ => {}
             }
 
             self.raw.take();
@@ -152,3 +187,14 @@ impl<'a> Data<'a> {
         Ok(())
     }
 }
+
+impl Drop for Data<'_> {
+    fn drop(&mut self) {
+        // Mapped changes reach the file without a flush, buffered ones have to be written.
+        if matches!(self.raw, Some(RawData::Buffer { dirty: true, .. })) {
+            if let Err(err) = self.drop_data() {
+                error!("could not write back {:?}: {:?}", self.path, err);
+            }
+        }
+    }
+}
@@ -12,6 +12,7 @@
 use std::convert::TryInto;
 
 pub mod api_version;
+pub mod artifact_io;
 pub mod cache_key;
 pub mod compound_proof;
 pub mod crypto;
@@ -25,6 +26,7 @@ pub mod multi_proof;
 pub mod parameter_cache;
 pub mod partitions;
 pub mod pieces;
+pub mod progress;
 pub mod por;
 pub mod proof;
 pub mod sector;
@@ -40,6 +42,12 @@ pub const TEST_SEED: [u8; 16] = [
 
 pub const MAX_LEGACY_POREP_REGISTERED_PROOF_ID: u64 = 4;
 
+/// The registered proof ids of the synthetic PoRep variants of the V1_1 seal proofs.
+pub const SYNTHETIC_POREP_REGISTERED_PROOF_IDS: std::ops::RangeInclusive<u64> = 10..=14;
+
+/// The registered proof ids of the non-interactive PoRep seal proofs.
+pub const NI_POREP_REGISTERED_PROOF_IDS: std::ops::RangeInclusive<u64> = 15..=19;
+
 pub type PoRepID = [u8; 32];
 
 pub fn is_legacy_porep_id(porep_id: PoRepID) -> bool {
@@ -56,3 +64,25 @@ pub fn is_legacy_porep_id(porep_id: PoRepID) -> bool {
     );
     id <= MAX_LEGACY_POREP_REGISTERED_PROOF_ID
 }
+
+/// Whether `porep_id` selects synthetic challenges. As for `is_legacy_porep_id`, this only
+/// examines the registered proof type id in its first 8 bytes.
+pub fn is_synthetic_porep_id(porep_id: PoRepID) -> bool {
+    let id = u64::from_le_bytes(
+        porep_id[..8]
+            .try_into()
+            .expect("8 bytes is always a valid u64"),
+    );
+    SYNTHETIC_POREP_REGISTERED_PROOF_IDS.contains(&id)
+}
+
+/// Whether `porep_id` selects non-interactive challenges, derived from comm_r instead of the
+/// seed. As for `is_legacy_porep_id`, this only examines the registered proof type id.
+pub fn is_ni_porep_id(porep_id: PoRepID) -> bool {
+    let id = u64::from_le_bytes(
+        porep_id[..8]
+            .try_into()
+            .expect("8 bytes is always a valid u64"),
+    );
+    NI_POREP_REGISTERED_PROOF_IDS.contains(&id)
+}
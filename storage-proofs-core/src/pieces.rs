@@ -1,13 +1,14 @@
 use std::io::Read;
 
 use anyhow::{ensure, Context};
-use filecoin_hashers::{Domain, Hasher};
+use filecoin_hashers::{Domain, TreeHasher};
 use fr32::Fr32Ary;
-use merkletree::merkle::next_pow2;
+use generic_array::typenum::U2;
+use merkletree::merkle::{next_pow2, MerkleTree};
 
 use crate::{
     error::{Error, Result},
-    merkle::BinaryMerkleTree,
+    merkle::DiskStore,
     util::NODE_SIZE,
 };
 
@@ -45,8 +46,9 @@ impl PieceSpec {
     }
 }
 
-/// Generate `comm_p` from a source and return it as bytes.
-pub fn generate_piece_commitment_bytes_from_source<H: Hasher>(
+/// Generate `comm_p` from a source and return it as bytes. Any `TreeHasher` works, as piece
+/// trees are not proven in a circuit.
+pub fn generate_piece_commitment_bytes_from_source<H: TreeHasher>(
     source: &mut dyn Read,
     padded_piece_size: usize,
 ) -> Result<Fr32Ary> {
@@ -57,11 +59,13 @@ pub fn generate_piece_commitment_bytes_from_source<H: Hasher>(
 
     let parts = (padded_piece_size as f64 / NODE_SIZE as f64).ceil() as usize;
 
-    let tree = BinaryMerkleTree::<H>::try_from_iter((0..parts).map(|_| {
-        source.read_exact(&mut buf)?;
-        <H::Domain as Domain>::try_from_bytes(&buf).context("invalid Fr element")
-    }))
-        .context("failed to build tree")?;
+    let tree = MerkleTree::<H::Domain, H::Function, DiskStore<H::Domain>, U2>::try_from_iter(
+        (0..parts).map(|_| {
+            source.read_exact(&mut buf)?;
+            <H::Domain as Domain>::try_from_bytes(&buf).context("invalid Fr element")
+        }),
+    )
+    .context("failed to build tree")?;
 
     let mut comm_p_bytes = [0; NODE_SIZE];
     let comm_p = tree.root();
@@ -169,4 +173,48 @@ mod tests {
 
         Ok(())
     }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_generate_piece_commitment_bytes_from_source_blake3() {
+        use filecoin_hashers::{
+            blake3::{Blake3Domain, Blake3Function, Blake3Hasher},
+            sha256::Sha256Hasher,
+        };
+
+        let nodes = 64;
+        let data: Vec<u8> = (0..nodes * NODE_SIZE)
+            .map(|i| {
+                if i % NODE_SIZE == NODE_SIZE - 1 {
+                    0
+                } else {
+                    i as u8
+                }
+            })
+            .collect();
+
+        let comm_p = generate_piece_commitment_bytes_from_source::<Blake3Hasher>(
+            &mut data.as_slice(),
+            data.len(),
+        )
+        .expect("failed to generate comm_p");
+        let mut row = data
+            .chunks(NODE_SIZE)
+            .map(|node| Blake3Domain::try_from_bytes(node).expect("invalid node"))
+            .collect::<Vec<_>>();
+        while row.len() > 1 {
+            row = row
+                .chunks(2)
+                .map(|pair| Blake3Function::hash2(&pair[0], &pair[1]))
+                .collect();
+        }
+        assert_eq!(comm_p.to_vec(), row[0].into_bytes());
+
+        let comm_p_sha256 = generate_piece_commitment_bytes_from_source::<Sha256Hasher>(
+            &mut data.as_slice(),
+            data.len(),
+        )
+        .expect("failed to generate comm_p");
+        assert_ne!(comm_p, comm_p_sha256);
+    }
 }
@@ -0,0 +1,154 @@
+//! Reporting the progress of sealing as it runs.
+//!
+//! A callback installed with `with_progress` is told about each step the sealing calls made
+//! inside it reach: the layer being labeled and how many of its nodes are done, each tree_c and
+//! tree_r_last config built, and the start and end of the SNARK. The callback is installed for
+//! the calling thread and carried along to the threads sealing spawns, so concurrent sealings
+//! on different threads each report to their own callback.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::Arc;
+
+/// A step reached while sealing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Labeling of `layer` (1-based) of `layers` started.
+    LayerStarted { layer: usize, layers: usize },
+    /// `labeled` of the `nodes` of `layer` are labeled. Reported about every percent.
+    NodesLabeled { layer: usize, labeled: u64, nodes: u64 },
+    /// Tree_c `config` (1-based) of `configs` is built.
+    TreeCBuilt { config: usize, configs: usize },
+    /// Tree_r_last `config` (1-based) of `configs` is built.
+    TreeRLastBuilt { config: usize, configs: usize },
+    /// The SNARK over `partitions` partitions started. Circuit synthesis and proving are a
+    /// single call, so there is no separate event between them.
+    SnarkStarted { partitions: usize },
+    /// The SNARK over `partitions` partitions is done.
+    SnarkFinished { partitions: usize },
+}
+
+type Callback = Arc<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+/// The callback progress is reported to, if any.
+#[derive(Clone, Default)]
+pub struct Progress(Option<Callback>);
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Progress").field(&self.0.is_some()).finish()
+    }
+}
+
+impl Progress {
+    pub fn new<F: Fn(&ProgressEvent) + Send + Sync + 'static>(callback: F) -> Self {
+        Progress(Some(Arc::new(callback)))
+    }
+
+    /// Reports `event` to the callback, if any.
+    pub fn report(&self, event: ProgressEvent) {
+        if let Some(callback) = &self.0 {
+            callback(&event);
+        }
+    }
+
+    /// Runs `f` with this as the progress of the current thread. Used to carry the progress of
+    /// a sealing over to the threads it spawns.
+    pub fn scope<T, F: FnOnce() -> T>(self, f: F) -> T {
+        let previous = CURRENT.with(|current| current.replace(self));
+        let _restore = Restore(Some(previous));
+        f()
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Progress> = RefCell::new(Progress::default());
+}
+
+/// Puts back the progress a scope replaced, also when it unwinds.
+struct Restore(Option<Progress>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+}
+
+/// Runs `f`, reporting the progress of the sealing calls made in it to `callback`.
+pub fn with_progress<T, C, F>(callback: C, f: F) -> T
+where
+    C: Fn(&ProgressEvent) + Send + Sync + 'static,
+    F: FnOnce() -> T,
+{
+    Progress::new(callback).scope(f)
+}
+
+/// The progress of the current thread.
+pub fn current() -> Progress {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Reports `event` to the progress of the current thread, if any.
+pub fn report(event: ProgressEvent) {
+    current().report(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn test_with_progress() {
+        let outer = Arc::new(Mutex::new(Vec::new()));
+        let inner = Arc::new(Mutex::new(Vec::new()));
+
+        report(ProgressEvent::SnarkStarted { partitions: 1 });
+
+        let outer_events = outer.clone();
+        with_progress(
+            move |event| outer_events.lock().expect("poisoned lock").push(*event),
+            || {
+                report(ProgressEvent::LayerStarted { layer: 1, layers: 2 });
+
+                let inner_events = inner.clone();
+                with_progress(
+                    move |event| inner_events.lock().expect("poisoned lock").push(*event),
+                    || report(ProgressEvent::LayerStarted { layer: 2, layers: 2 }),
+                );
+
+                // Spawned threads report nothing unless the progress is carried over.
+                let progress = current();
+                thread::spawn(|| report(ProgressEvent::TreeCBuilt { config: 1, configs: 8 }))
+                    .join()
+                    .expect("join failed");
+                thread::spawn(move || {
+                    progress.scope(|| report(ProgressEvent::TreeCBuilt { config: 2, configs: 8 }))
+                })
+                .join()
+                .expect("join failed");
+
+                report(ProgressEvent::SnarkFinished { partitions: 1 });
+            },
+        );
+
+        report(ProgressEvent::SnarkFinished { partitions: 1 });
+
+        assert_eq!(
+            *outer.lock().expect("poisoned lock"),
+            vec![
+                ProgressEvent::LayerStarted { layer: 1, layers: 2 },
+                ProgressEvent::TreeCBuilt { config: 2, configs: 8 },
+                ProgressEvent::SnarkFinished { partitions: 1 },
+            ]
+        );
+        assert_eq!(
+            *inner.lock().expect("poisoned lock"),
+            vec![ProgressEvent::LayerStarted { layer: 2, layers: 2 }]
+        );
+    }
+}
@@ -1,4 +1,8 @@
+use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
 
 use config::{Config, ConfigError, Environment, File};
 use lazy_static::lazy_static;
@@ -6,10 +10,68 @@ use serde::{Deserialize, Serialize};
 
 lazy_static! {
     pub static ref SETTINGS: Settings = Settings::new().expect("invalid configuration");
+    static ref FILE_VARS: HashMap<String, String> = {
+        let (path, required) = settings_path();
+        read_config_vars(Path::new(&path), required).expect("invalid configuration")
+    };
 }
 
 const SETTINGS_PATH: &str = "./rust-fil-proofs.config.toml";
 const PREFIX: &str = "FIL_PROOFS";
+/// Names the configuration file to read instead of `SETTINGS_PATH`.
+const CONFIG_PATH_VAR: &str = "FIL_PROOFS_CONFIG";
+
+/// The configuration file, and whether it must exist, which it must if named explicitly.
+fn settings_path() -> (String, bool) {
+    match env::var(CONFIG_PATH_VAR) {
+        Ok(path) => (path, true),
+        Err(_) => (SETTINGS_PATH.to_string(), false),
+    }
+}
+
+/// Reads the top level values of the TOML configuration file at `path` as `FIL_PROOFS_*`
+/// variables, e.g. `p2_bound_cores = 8` as `FIL_PROOFS_P2_BOUND_CORES=8`. Booleans become `1`
+/// and `0`. Tables and arrays are ignored, as no setting takes them.
+fn read_config_vars(path: &Path, required: bool) -> Result<HashMap<String, String>, ConfigError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !required => {
+            return Ok(HashMap::new())
+        }
+        Err(err) => return Err(ConfigError::Foreign(Box::new(err))),
+    };
+    let table: toml::value::Table =
+        toml::from_str(&contents).map_err(|err| ConfigError::Foreign(Box::new(err)))?;
+
+    Ok(table
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => if value { "1" } else { "0" }.to_string(),
+                toml::Value::Datetime(value) => value.to_string(),
+                toml::Value::Array(_) | toml::Value::Table(_) => return None,
+            };
+            Some((format!("{}_{}", PREFIX, key.to_uppercase()), value))
+        })
+        .collect())
+}
+
+/// The value of the `FIL_PROOFS_*` setting `name`, like `std::env::var`, but falling back to
+/// the configuration file if the variable is not set. This way settings which are not fields of
+/// `Settings` can be kept in the configuration file too, with the environment taking
+/// precedence. The file is read once, at first use.
+pub fn var(name: &str) -> Result<String, env::VarError> {
+    match env::var(name) {
+        Err(env::VarError::NotPresent) => FILE_VARS
+            .get(name)
+            .cloned()
+            .ok_or(env::VarError::NotPresent),
+        res => res,
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -22,15 +84,22 @@ pub struct Settings {
     pub use_gpu_tree_builder: bool,
     pub gpu_for_parallel_tree_r: u32,
     pub max_gpu_tree_batch_size: u32,
+    pub use_gpu_tree_d_builder: bool,
+    pub max_gpu_tree_d_batch_size: u32,
     pub rows_to_discard: u32,
     pub sdr_parents_cache_size: u32,
     pub window_post_synthesis_num_cpus: u32,
     pub parameter_cache: String,
     pub parent_cache: String,
+    pub sector_store_staging: String,
     pub use_multicore_sdr: bool,
     pub multicore_sdr_producers: usize,
     pub multicore_sdr_producer_stride: u64,
     pub multicore_sdr_lookahead: usize,
+    pub io_layers: String,
+    pub io_parent_cache: String,
+    pub io_tree: String,
+    pub io_replica: String,
 }
 
 impl Default for Settings {
@@ -44,6 +113,8 @@ impl Default for Settings {
             use_gpu_tree_builder: true,
             gpu_for_parallel_tree_r: 0,
             max_gpu_tree_batch_size: 700_000,
+            use_gpu_tree_d_builder: true,
+            max_gpu_tree_d_batch_size: 4_194_304,
             rows_to_discard: 2,
             sdr_parents_cache_size: 2_048,
             window_post_synthesis_num_cpus: num_cpus::get() as u32,
@@ -52,10 +123,15 @@ impl Default for Settings {
             // The name is retained for backwards compatibility.
             parameter_cache: "/var/tmp/filecoin-proof-parameters/".to_string(),
             parent_cache: cache("filecoin-parents"),
+            sector_store_staging: cache("filecoin-sector-store"),
             use_multicore_sdr: true,
             multicore_sdr_producers: 3,
             multicore_sdr_producer_stride: 128,
             multicore_sdr_lookahead: 800,
+            io_layers: "buffered".to_string(),
+            io_parent_cache: "mmap".to_string(),
+            io_tree: "buffered".to_string(),
+            io_replica: "mmap".to_string(),
         }
     }
 }
@@ -74,9 +150,37 @@ impl Settings {
     fn new() -> Result<Settings, ConfigError> {
         let mut s = Config::new();
 
-        s.merge(File::with_name(SETTINGS_PATH).required(false))?;
+        let (path, required) = settings_path();
+        s.merge(File::with_name(&path).required(required))?;
         s.merge(Environment::with_prefix(PREFIX))?;
 
         s.try_into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn test_read_config_vars() {
+        let mut file = tempfile::NamedTempFile::new().expect("failed to create file");
+        writeln!(
+            file,
+            "p1_binding_policy = \"Core\"\nmin_bind_bytes = 4_096\nbinding_use_locality = false\n\n[table]\nkey = 1"
+        )
+        .expect("failed to write file");
+
+        let vars = read_config_vars(file.path(), true).expect("failed to read");
+        assert_eq!(vars.len(), 3);
+        assert_eq!(vars["FIL_PROOFS_P1_BINDING_POLICY"], "Core");
+        assert_eq!(vars["FIL_PROOFS_MIN_BIND_BYTES"], "4096");
+        assert_eq!(vars["FIL_PROOFS_BINDING_USE_LOCALITY"], "0");
+
+        let missing = file.path().with_extension("missing");
+        assert!(read_config_vars(&missing, false).expect("optional").is_empty());
+        assert!(read_config_vars(&missing, true).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use storage_proofs_porep::stacked::buffers_equal;
+
+/// The plain byte loop `buffers_equal` is compared against.
+fn naive_buffers_equal(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(a, b)| a != b) {
+        Some(index) => Some(index),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+fn buffers_equal_benchmark(c: &mut Criterion) {
+    let size = 1 << 20;
+    let a = vec![0xabu8; size];
+    // Equal up to the last byte, so both have to scan the whole buffer.
+    let mut b = a.clone();
+    b[size - 1] = 0;
+
+    let mut group = c.benchmark_group("buffers_equal");
+    group.throughput(Throughput::Bytes(size as u64));
+    group.bench_function("chunked", |bench| {
+        bench.iter(|| buffers_equal(black_box(&a), black_box(&b)))
+    });
+    group.bench_function("naive", |bench| {
+        bench.iter(|| naive_buffers_equal(black_box(&a), black_box(&b)))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, buffers_equal_benchmark);
+criterion_main!(benches);
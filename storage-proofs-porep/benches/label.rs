@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use storage_proofs_porep::stacked::create_label::bench::bench_label;
+
+fn label_benchmark(c: &mut Criterion) {
+    let layers = 2;
+    let workers = 4;
+    let sizes = vec![1 << 10, 1 << 14];
+
+    let mut group = c.benchmark_group("label");
+    group.sample_size(10);
+    for size in sizes {
+        group.bench_function(format!("nodes-{}", size), |b| {
+            b.iter_custom(|iters| {
+                (0..iters).fold(Duration::default(), |total, _| {
+                    total + bench_label(size, layers, workers).expect("bench_label failed")
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, label_benchmark);
+criterion_main!(benches);
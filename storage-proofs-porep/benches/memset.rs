@@ -0,0 +1,25 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use storage_proofs_porep::stacked::memset;
+
+fn memset_benchmark(c: &mut Criterion) {
+    let size = 1 << 20;
+    // One spare word, so the unaligned benchmark can start a byte into the buffer.
+    let mut buf = vec![0u64; size / 8 + 1];
+
+    let mut group = c.benchmark_group("memset");
+    group.throughput(Throughput::Bytes(size as u64));
+    for (name, offset) in &[("aligned", 0), ("unaligned", 1)] {
+        group.bench_function(*name, |b| {
+            let bytes = unsafe {
+                std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, (size / 8 + 1) * 8)
+            };
+            let slice = &mut bytes[*offset..*offset + size];
+            b.iter(|| memset(black_box(&mut *slice), 0xab))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, memset_benchmark);
+criterion_main!(benches);
@@ -241,7 +241,7 @@ for StackedCompound<Tree, G>
         let por_params = PoR::<Tree>::setup(&por_setup_params)?;
         let por_params_d = PoR::<BinaryMerkleTree<G>>::setup(&por_setup_params)?;
 
-        let all_challenges = pub_in.challenges(&pub_params.layer_challenges, graph.size(), k);
+        let all_challenges = pub_in.challenges(&pub_params.layer_challenges, graph.size(), k)?;
 
         for challenge in all_challenges.into_iter() {
             // comm_d inclusion proof for the data leaf
@@ -1,6 +1,7 @@
 use std::collections::{BTreeMap, HashSet};
 use std::fs::{remove_file, File};
 use std::io;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
@@ -10,10 +11,13 @@ use filecoin_hashers::Hasher;
 use lazy_static::lazy_static;
 use log::{info, trace};
 use mapr::{Mmap, MmapOptions};
-use rayon::prelude::{IndexedParallelIterator, ParallelIterator, ParallelSliceMut};
+use rayon::prelude::{
+    IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator, ParallelSliceMut,
+};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use storage_proofs_core::{
+    artifact_io::{AlignedBuffer, Artifact, ArtifactReader, IoStrategy},
     drgraph::{Graph, BASE_DEGREE},
     error::Result,
     parameter_cache::{with_exclusive_lock, LockedFile, ParameterSetMetadata, VERSION},
@@ -21,11 +25,19 @@ use storage_proofs_core::{
     util::NODE_SIZE,
 };
 
-use crate::stacked::vanilla::graph::{StackedGraph, DEGREE};
+use crate::stacked::vanilla::{
+    graph::{StackedGraph, DEGREE},
+    memory_handling::{advise_will_need, read_ahead},
+    utils::parent_cache_prefetch,
+};
 
 /// u32 = 4 bytes
 const NODE_BYTES: usize = 4;
 
+/// Nodes whose parents are generated, or hashed for the digest, at once when the cache is
+/// prefetched, see `parent_cache_prefetch`. Keeps the chunks page aligned.
+const PREFETCH_CHUNK_NODES: usize = 1 << 20;
+
 pub const PARENT_CACHE_DATA: &str = include_str!("../../../parent_cache.json");
 
 pub type ParentCacheDataMap = BTreeMap<String, ParentCacheData>;
@@ -57,13 +69,35 @@ pub struct ParentCache {
 #[derive(Debug)]
 struct CacheData {
     /// This is a large list of fixed (parent) sized arrays.
-    data: Mmap,
+    data: Window,
     /// Offset in nodes.
     offset: u32,
     /// Len in nodes.
     len: u32,
     /// The underlyling file.
     file: LockedFile,
+    /// Whether the window after the mapped one is read ahead.
+    prefetch: bool,
+    /// Reads the windows unless they are mapped, see `Artifact::ParentCache`.
+    reader: Option<ArtifactReader>,
+}
+
+/// The part of the cache in memory.
+#[derive(Debug)]
+enum Window {
+    Mapped(Mmap),
+    Read(AlignedBuffer),
+}
+
+impl Deref for Window {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Window::Mapped(data) => data,
+            Window::Read(data) => data,
+        }
+    }
 }
 
 impl CacheData {
@@ -79,14 +113,12 @@ impl CacheData {
         let offset = new_offset as usize * DEGREE * NODE_BYTES;
         let len = self.len as usize * DEGREE * NODE_BYTES;
 
-        self.data = unsafe {
-            MmapOptions::new()
-                .offset(offset as u64)
-                .len(len)
-                .map(self.file.as_ref())
-                .context("could not shift mmap}")?
-        };
+        self.data = Self::load_window(&self.file, self.reader.as_ref(), offset, len)
+            .context("could not shift window")?;
         self.offset = new_offset;
+        if self.prefetch {
+            read_ahead(self.file.as_ref(), (offset + len) as u64, len);
+        }
 
         Ok(())
     }
@@ -133,21 +165,57 @@ impl CacheData {
             );
         }
 
-        let data = unsafe {
-            MmapOptions::new()
-                .offset((offset as usize * DEGREE * NODE_BYTES) as u64)
-                .len(len as usize * DEGREE * NODE_BYTES)
-                .map(file.as_ref())
-                .with_context(|| format!("could not mmap path={}", path.display()))?
+        let reader = match Artifact::ParentCache.io_strategy() {
+            IoStrategy::Mmap => None,
+            strategy => Some(ArtifactReader::with_strategy(path, strategy)?),
         };
+        let data = Self::load_window(
+            &file,
+            reader.as_ref(),
+            offset as usize * DEGREE * NODE_BYTES,
+            len as usize * DEGREE * NODE_BYTES,
+        )
+        .with_context(|| format!("could not load path={}", path.display()))?;
+
+        let prefetch = parent_cache_prefetch();
+        if prefetch {
+            if let Window::Mapped(data) = &data {
+                advise_will_need(data);
+            }
+        }
 
         Ok(Self {
             data,
             file,
             len,
             offset,
+            prefetch,
+            reader,
         })
     }
+
+    /// Maps the `len` bytes from `offset` on, or reads them with `reader`.
+    fn load_window(
+        file: &LockedFile,
+        reader: Option<&ArtifactReader>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Window> {
+        match reader {
+            Some(reader) => {
+                let mut data = AlignedBuffer::new(len);
+                reader.read_at(offset as u64, &mut data)?;
+                Ok(Window::Read(data))
+            }
+            None => Ok(Window::Mapped(unsafe {
+                MmapOptions::new()
+                    .offset(offset as u64)
+                    .len(len)
+                    .map(file.as_ref())
+                    .context("could not mmap window")?
+            })),
+        }
+    }
 }
 
 impl ParentCache {
@@ -238,19 +306,24 @@ impl ParentCache {
         if verify_cache {
             // Always check all of the data for integrity checks, even
             // if we're only opening a portion of it.
-            let mut hasher = Sha256::new();
             info!("[open] parent cache: calculating consistency digest");
             let file = File::open(&path)?;
-            let data = unsafe {
-                MmapOptions::new()
-                    .map(&file)
-                    .with_context(|| format!("could not mmap path={}", path.display()))?
+            digest_hex = if parent_cache_prefetch() {
+                digest_chunked(&file, PREFETCH_CHUNK_NODES * DEGREE * NODE_BYTES)
+                    .with_context(|| format!("could not hash path={}", path.display()))?
+            } else {
+                let mut hasher = Sha256::new();
+                let data = unsafe {
+                    MmapOptions::new()
+                        .map(&file)
+                        .with_context(|| format!("could not mmap path={}", path.display()))?
+                };
+                hasher.update(&data);
+                drop(data);
+
+                let hash = hasher.finalize();
+                hash.iter().map(|x| format!("{:01$x}", x, 2)).collect()
             };
-            hasher.update(&data);
-            drop(data);
-
-            let hash = hasher.finalize();
-            digest_hex = hash.iter().map(|x| format!("{:01$x}", x, 2)).collect();
 
             info!(
                 "[open] parent cache: calculated consistency digest: {:?}",
@@ -323,27 +396,25 @@ impl ParentCache {
                     .with_context(|| format!("could not mmap path={}", path.display()))?
             };
 
-            data.par_chunks_mut(DEGREE * NODE_BYTES)
-                .enumerate()
-                .try_for_each(|(node, entry)| -> Result<()> {
-                    let mut parents = [0u32; DEGREE];
-                    graph
-                        .base_graph()
-                        .parents(node, &mut parents[..BASE_DEGREE])?;
-                    graph.generate_expanded_parents(node, &mut parents[BASE_DEGREE..]);
-
-                    LittleEndian::write_u32_into(&parents, entry);
-                    Ok(())
-                })?;
-
-            info!("parent cache: generated");
-            data.flush().context("failed to flush parent cache")?;
-
-            info!("[generate] parent cache: generating consistency digest");
-            let mut hasher = Sha256::new();
-            hasher.update(&data);
-            let hash = hasher.finalize();
-            digest_hex = hash.iter().map(|x| format!("{:01$x}", x, 2)).collect();
+            if parent_cache_prefetch() {
+                info!("[generate] parent cache: generating with consistency digest");
+                digest_hex = generate_pipelined(graph, &mut data, PREFETCH_CHUNK_NODES)?;
+                info!("parent cache: generated");
+                data.flush().context("failed to flush parent cache")?;
+            } else {
+                data.par_chunks_mut(DEGREE * NODE_BYTES)
+                    .enumerate()
+                    .try_for_each(|(node, entry)| write_parents(graph, node, entry))?;
+
+                info!("parent cache: generated");
+                data.flush().context("failed to flush parent cache")?;
+
+                info!("[generate] parent cache: generating consistency digest");
+                let mut hasher = Sha256::new();
+                hasher.update(&data);
+                let hash = hasher.finalize();
+                digest_hex = hash.iter().map(|x| format!("{:01$x}", x, 2)).collect();
+            }
             info!(
                 "[generate] parent cache: generated consistency digest: {:?}",
                 digest_hex
@@ -408,6 +479,99 @@ impl ParentCache {
     }
 }
 
+/// Writes the parents of `node` to its `entry` of the cache.
+fn write_parents<H, G>(graph: &StackedGraph<H, G>, node: usize, entry: &mut [u8]) -> Result<()>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+{
+    let mut parents = [0u32; DEGREE];
+    graph
+        .base_graph()
+        .parents(node, &mut parents[..BASE_DEGREE])?;
+    graph.generate_expanded_parents(node, &mut parents[BASE_DEGREE..]);
+
+    LittleEndian::write_u32_into(&parents, entry);
+    Ok(())
+}
+
+/// Fills the cache `data` with the parents of all nodes and returns its digest. Chunks of
+/// `chunk_nodes` nodes are generated in parallel, a batch of one per thread at a time, and
+/// each batch is hashed while the next one is generated, so the digest does not take a second
+/// pass over the whole cache.
+fn generate_pipelined<H, G>(
+    graph: &StackedGraph<H, G>,
+    data: &mut [u8],
+    chunk_nodes: usize,
+) -> Result<String>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+{
+    let entry_bytes = DEGREE * NODE_BYTES;
+    let mut chunks = data.chunks_mut(chunk_nodes * entry_bytes).collect::<Vec<_>>();
+    let batch_len = rayon::current_num_threads().max(1);
+
+    let mut hasher = Sha256::new();
+    let mut generated: &[&mut [u8]] = &[];
+    for (batch_index, batch) in chunks.chunks_mut(batch_len).enumerate() {
+        let first_node = batch_index * batch_len * chunk_nodes;
+        let (res, _) = rayon::join(
+            || {
+                batch
+                    .par_iter_mut()
+                    .enumerate()
+                    .try_for_each(|(i, chunk)| -> Result<()> {
+                        let first_node = first_node + i * chunk_nodes;
+                        for (node, entry) in chunk.chunks_mut(entry_bytes).enumerate() {
+                            write_parents(graph, first_node + node, entry)?;
+                        }
+                        Ok(())
+                    })
+            },
+            || generated.iter().for_each(|chunk| hasher.update(&chunk[..])),
+        );
+        res?;
+        generated = batch;
+    }
+    generated.iter().for_each(|chunk| hasher.update(&chunk[..]));
+
+    let hash = hasher.finalize();
+    Ok(hash.iter().map(|x| format!("{:01$x}", x, 2)).collect())
+}
+
+/// The digest of the cache in `file`, mapped and hashed `chunk_bytes` at a time, which must be
+/// a multiple of the page size. The next chunk is read in the background while one is hashed,
+/// and at most these two are mapped.
+fn digest_chunked(file: &File, chunk_bytes: usize) -> Result<String> {
+    let size = file.metadata()?.len() as usize;
+    let map_chunk = |offset: usize| -> Result<Mmap> {
+        let chunk = unsafe {
+            MmapOptions::new()
+                .offset(offset as u64)
+                .len(chunk_bytes.min(size - offset))
+                .map(file)?
+        };
+        advise_will_need(&chunk);
+        Ok(chunk)
+    };
+
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    let mut next = if size > 0 { Some(map_chunk(0)?) } else { None };
+    while let Some(chunk) = next.take() {
+        let next_offset = offset + chunk.len();
+        if next_offset < size {
+            next = Some(map_chunk(next_offset)?);
+        }
+        hasher.update(&chunk[..]);
+        offset = next_offset;
+    }
+
+    let hash = hasher.finalize();
+    Ok(hash.iter().map(|x| format!("{:01$x}", x, 2)).collect())
+}
+
 fn parent_cache_dir_name() -> String {
     SETTINGS.parent_cache.clone()
 }
@@ -491,6 +655,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_generate_pipelined_matches_plain() {
+        init_logger();
+        let nodes = 1024usize;
+        let graph = StackedBucketGraph::<PoseidonHasher>::new_stacked(
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [0u8; 32],
+            ApiVersion::V1_1_0,
+        )
+            .expect("new_stacked failure");
+
+        let mut expected = vec![0u8; nodes * DEGREE * NODE_BYTES];
+        for (node, entry) in expected.chunks_mut(DEGREE * NODE_BYTES).enumerate() {
+            write_parents(&graph, node, entry).expect("write_parents failure");
+        }
+        let expected_digest: String = Sha256::digest(&expected)
+            .iter()
+            .map(|x| format!("{:01$x}", x, 2))
+            .collect();
+
+        // An uneven chunk size, so the last chunk and batch are partial.
+        let mut data = vec![0u8; nodes * DEGREE * NODE_BYTES];
+        let digest = generate_pipelined(&graph, &mut data, 7).expect("generate_pipelined failure");
+        assert!(data == expected, "pipelined generation differs");
+        assert_eq!(digest, expected_digest);
+
+        let mut file = tempfile::tempfile().expect("tempfile failure");
+        io::Write::write_all(&mut file, &data).expect("write failure");
+        let page_size = 4096;
+        assert_eq!(
+            digest_chunked(&file, page_size).expect("digest_chunked failure"),
+            expected_digest
+        );
+    }
+
     #[test]
     #[cfg(feature = "isolated-testing")]
     fn test_parallel_generation_and_read_partial_range_v1_0() {
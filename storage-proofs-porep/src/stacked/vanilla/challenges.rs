@@ -1,20 +1,92 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
 use filecoin_hashers::Domain;
 use num_bigint::BigUint;
 use num_traits::cast::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Number of synthetic challenges of a sector, see `ChallengeMode::Synthetic`. Sectors with
+/// fewer nodes have one synthetic challenge per node.
+pub const SYNTHETIC_CHALLENGE_COUNT: usize = 1 << 18;
+
+/// Prepended to what synthetic challenges are hashed from, so they never equal other challenges.
+const SYNTHETIC_TAG: &[u8] = b"filecoin.io|PoRep|1|Synthetic|1";
+
+/// Prepended to what non-interactive challenges are hashed from, so they never equal
+/// interactive ones.
+const NON_INTERACTIVE_TAG: &[u8] = b"filecoin.io|PoRep|1|NonInteractive|1";
+
+/// How the challenges of a partition are derived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChallengeMode {
+    /// Each challenge is derived from the replica id and the seed.
+    Interactive,
+    /// A fixed set of synthetic challenges is derived from the replica id and comm_r, so it can
+    /// be proven right after precommit, before the seed is known. The seed then only selects
+    /// which of the synthetic challenges are the challenges of each partition. As comm_r is only
+    /// known once all layers are labeled, the synthetic challenges are too.
+    Synthetic,
+    /// Each challenge is derived from the replica id and comm_r, without any chain randomness,
+    /// so a sector can be proven right after precommit. As the prover can grind comm_r, many
+    /// more challenges are needed than for interactive challenges.
+    NonInteractive,
+}
+
+impl Default for ChallengeMode {
+    fn default() -> Self {
+        ChallengeMode::Interactive
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct LayerChallenges {
     /// How many layers we are generating challenges for.
     layers: usize,
     /// The maximum count of challenges
     max_count: usize,
+    /// How the challenges are derived.
+    #[serde(default)]
+    mode: ChallengeMode,
+}
+
+// The debug output is part of the parameter identifiers, see `PublicParams::identifier`. The
+// circuit is the same for all modes, so the mode is left out to share their parameters.
+impl fmt::Debug for LayerChallenges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LayerChallenges")
+            .field("layers", &self.layers)
+            .field("max_count", &self.max_count)
+            .finish()
+    }
 }
 
 impl LayerChallenges {
     pub const fn new(layers: usize, max_count: usize) -> Self {
-        LayerChallenges { layers, max_count }
+        LayerChallenges {
+            layers,
+            max_count,
+            mode: ChallengeMode::Interactive,
+        }
+    }
+
+    /// Like `new`, but with the challenges selected from the synthetic challenges.
+    pub const fn new_synthetic(layers: usize, max_count: usize) -> Self {
+        LayerChallenges {
+            layers,
+            max_count,
+            mode: ChallengeMode::Synthetic,
+        }
+    }
+
+    /// Like `new`, but with non-interactive challenges.
+    pub const fn new_non_interactive(layers: usize, max_count: usize) -> Self {
+        LayerChallenges {
+            layers,
+            max_count,
+            mode: ChallengeMode::NonInteractive,
+        }
     }
 
     pub fn layers(&self) -> usize {
@@ -25,15 +97,37 @@ impl LayerChallenges {
         self.max_count
     }
 
-    /// Derive all challenges.
+    pub fn mode(&self) -> ChallengeMode {
+        self.mode
+    }
+
+    /// Derive all challenges. Synthetic and non-interactive challenges are bound to `comm_r`,
+    /// so deriving them without it is an error; interactive challenges do not need it.
     pub fn derive<D: Domain>(
         &self,
         leaves: usize,
         replica_id: &D,
+        comm_r: Option<&D>,
         seed: &[u8; 32],
         k: u8,
-    ) -> Vec<usize> {
-        self.derive_internal(self.challenges_count_all(), leaves, replica_id, seed, k)
+    ) -> Result<Vec<usize>> {
+        Ok(match self.mode {
+            ChallengeMode::Interactive => {
+                self.derive_internal(self.challenges_count_all(), leaves, replica_id, seed, k)
+            }
+            ChallengeMode::Synthetic => {
+                let comm_r = comm_r.context("synthetic challenges are derived from comm_r")?;
+                self.derive_synthetic_indexes(leaves, replica_id, seed, k)
+                    .into_iter()
+                    .map(|i| derive_synthetic_challenge(leaves, replica_id, comm_r, i))
+                    .collect()
+            }
+            ChallengeMode::NonInteractive => {
+                let comm_r =
+                    comm_r.context("non-interactive challenges are derived from comm_r")?;
+                self.derive_non_interactive(leaves, replica_id, comm_r, k)
+            }
+        })
     }
 
     pub fn derive_internal<D: Domain>(
@@ -56,18 +150,102 @@ impl LayerChallenges {
                     .chain(&j.to_le_bytes())
                     .finalize();
 
-                let big_challenge = BigUint::from_bytes_le(hash.as_ref());
-
                 // We cannot try to prove the first node, so make sure the challenge
                 // can never be 0.
-                let big_mod_challenge = big_challenge % (leaves - 1);
-                let big_mod_challenge = big_mod_challenge
-                    .to_usize()
-                    .expect("`big_mod_challenge` exceeds size of `usize`");
-                big_mod_challenge + 1
+                hash_mod(hash.as_ref(), leaves - 1) + 1
             })
             .collect()
     }
+
+    /// Derives the non-interactive challenges of partition `k`, challenge `i` of it from
+    /// `NON_INTERACTIVE_TAG || replica_id || comm_r || j`, with `j` its index over all partitions.
+    fn derive_non_interactive<D: Domain>(
+        &self,
+        leaves: usize,
+        replica_id: &D,
+        comm_r: &D,
+        k: u8,
+    ) -> Vec<usize> {
+        assert!(leaves > 2, "Too few leaves: {}", leaves);
+
+        let challenges_count = self.challenges_count_all();
+        (0..challenges_count)
+            .map(|i| {
+                let j: u32 = ((challenges_count * k as usize) + i) as u32;
+
+                let hash = Sha256::new()
+                    .chain(NON_INTERACTIVE_TAG)
+                    .chain(replica_id.into_bytes())
+                    .chain(comm_r.into_bytes())
+                    .chain(&j.to_le_bytes())
+                    .finalize();
+
+                hash_mod(hash.as_ref(), leaves - 1) + 1
+            })
+            .collect()
+    }
+
+    /// Derives which of the synthetic challenges, by their index in `0..synthetic_count(leaves)`,
+    /// are the challenges of partition `k`.
+    pub fn derive_synthetic_indexes<D: Domain>(
+        &self,
+        leaves: usize,
+        replica_id: &D,
+        seed: &[u8; 32],
+        k: u8,
+    ) -> Vec<usize> {
+        assert!(leaves > 2, "Too few leaves: {}", leaves);
+
+        let challenges_count = self.challenges_count_all();
+        (0..challenges_count)
+            .map(|i| {
+                let j: u32 = ((challenges_count * k as usize) + i) as u32;
+
+                let hash = Sha256::new()
+                    .chain(replica_id.into_bytes())
+                    .chain(seed)
+                    .chain(&j.to_le_bytes())
+                    .finalize();
+
+                hash_mod(hash.as_ref(), synthetic_count(leaves))
+            })
+            .collect()
+    }
+}
+
+/// Number of synthetic challenges of a sector of `leaves` nodes.
+pub fn synthetic_count(leaves: usize) -> usize {
+    std::cmp::min(SYNTHETIC_CHALLENGE_COUNT, leaves)
+}
+
+/// Derives synthetic challenge `i` of a sector of `leaves` nodes, from
+/// `SYNTHETIC_TAG || replica_id || comm_r || i`.
+pub fn derive_synthetic_challenge<D: Domain>(
+    leaves: usize,
+    replica_id: &D,
+    comm_r: &D,
+    i: usize,
+) -> usize {
+    assert!(leaves > 2, "Too few leaves: {}", leaves);
+
+    let hash = Sha256::new()
+        .chain(SYNTHETIC_TAG)
+        .chain(replica_id.into_bytes())
+        .chain(comm_r.into_bytes())
+        .chain(&(i as u32).to_le_bytes())
+        .finalize();
+
+    // As for the interactive challenges, never challenge the first node.
+    hash_mod(hash.as_ref(), leaves - 1) + 1
+}
+
+/// Interprets `hash` as a little endian integer, reduced modulo `modulus`.
+fn hash_mod(hash: &[u8], modulus: usize) -> usize {
+    let big_challenge = BigUint::from_bytes_le(hash);
+    let big_mod_challenge = big_challenge % modulus;
+    big_mod_challenge
+        .to_usize()
+        .expect("`big_mod_challenge` exceeds size of `usize`")
 }
 
 #[derive(Debug, Default)]
@@ -111,7 +289,9 @@ mod test {
         for _layer in 1..=layers {
             let mut histogram = HashMap::new();
             for k in 0..partitions {
-                let challenges = challenges.derive(leaves, &replica_id, &seed, k as u8);
+                let challenges = challenges
+                    .derive(leaves, &replica_id, None, &seed, k as u8)
+                    .expect("derive failed");
 
                 for challenge in challenges {
                     let counter = histogram.entry(challenge).or_insert(0);
@@ -145,19 +325,110 @@ mod test {
         let total_challenges = n * partitions;
 
         for _layer in 1..=layers {
-            let one_partition_challenges = LayerChallenges::new(layers, total_challenges).derive(
-                leaves,
-                &replica_id,
-                &seed,
-                0,
-            );
+            let one_partition_challenges = LayerChallenges::new(layers, total_challenges)
+                .derive(leaves, &replica_id, None, &seed, 0)
+                .expect("derive failed");
             let many_partition_challenges = (0..partitions)
                 .flat_map(|k| {
-                    LayerChallenges::new(layers, n).derive(leaves, &replica_id, &seed, k as u8)
+                    LayerChallenges::new(layers, n)
+                        .derive(leaves, &replica_id, None, &seed, k as u8)
+                        .expect("derive failed")
                 })
                 .collect::<Vec<_>>();
 
             assert_eq!(one_partition_challenges, many_partition_challenges);
         }
     }
+
+    #[test]
+    fn synthetic_challenge_selection() {
+        let n = 18;
+        let leaves = 1 << 20;
+        let rng = &mut thread_rng();
+        let replica_id: Sha256Domain = Sha256Domain::random(rng);
+        let comm_r: Sha256Domain = Sha256Domain::random(rng);
+        let seed: [u8; 32] = rng.gen();
+        let layer_challenges = LayerChallenges::new_synthetic(11, n);
+
+        for k in 0..10u8 {
+            let indexes = layer_challenges.derive_synthetic_indexes(leaves, &replica_id, &seed, k);
+            let challenges = layer_challenges
+                .derive(leaves, &replica_id, Some(&comm_r), &seed, k)
+                .expect("derive failed");
+            assert_eq!(challenges.len(), n);
+
+            for (index, challenge) in indexes.into_iter().zip(challenges) {
+                assert!(index < SYNTHETIC_CHALLENGE_COUNT);
+                assert!(challenge > 0 && challenge < leaves);
+                assert_eq!(
+                    challenge,
+                    derive_synthetic_challenge(leaves, &replica_id, &comm_r, index)
+                );
+            }
+        }
+
+        // The synthetic challenges are bound to comm_r.
+        let other_comm_r: Sha256Domain = Sha256Domain::random(rng);
+        let synthetic = |comm_r| {
+            (0..64)
+                .map(|i| derive_synthetic_challenge(leaves, &replica_id, comm_r, i))
+                .collect::<Vec<_>>()
+        };
+        assert_ne!(synthetic(&comm_r), synthetic(&other_comm_r));
+        let derive = |comm_r| {
+            layer_challenges
+                .derive(leaves, &replica_id, Some(comm_r), &seed, 0)
+                .expect("derive failed")
+        };
+        assert_ne!(derive(&comm_r), derive(&other_comm_r));
+
+        // Small sectors have one synthetic challenge per node.
+        assert_eq!(synthetic_count(64), 64);
+        assert_eq!(synthetic_count(leaves), SYNTHETIC_CHALLENGE_COUNT);
+    }
+
+    #[test]
+    fn non_interactive_challenge_derivation() {
+        let n = 18;
+        let leaves = 1 << 30;
+        let rng = &mut thread_rng();
+        let replica_id: Sha256Domain = Sha256Domain::random(rng);
+        let comm_r: Sha256Domain = Sha256Domain::random(rng);
+        let seed: [u8; 32] = rng.gen();
+
+        let interactive = LayerChallenges::new(11, n);
+        let non_interactive = LayerChallenges::new_non_interactive(11, n);
+        let derive = |challenges: &LayerChallenges, comm_r, seed| {
+            challenges
+                .derive(leaves, &replica_id, Some(comm_r), seed, 3)
+                .expect("derive failed")
+        };
+        let challenges = derive(&non_interactive, &comm_r, &seed);
+        assert_eq!(challenges.len(), n);
+        assert!(challenges.iter().all(|&c| c > 0 && c < leaves));
+        assert_ne!(challenges, derive(&interactive, &comm_r, &seed));
+
+        // The seed is not used, only comm_r.
+        let other_seed: [u8; 32] = rng.gen();
+        assert_eq!(challenges, derive(&non_interactive, &comm_r, &other_seed));
+        let other_comm_r: Sha256Domain = Sha256Domain::random(rng);
+        assert_ne!(challenges, derive(&non_interactive, &other_comm_r, &seed));
+
+        // Without comm_r, there are no non-interactive challenges.
+        assert!(non_interactive
+            .derive(leaves, &replica_id, None, &seed, 3)
+            .is_err());
+    }
+
+    #[test]
+    fn synthetic_mode_keeps_identifier() {
+        assert_eq!(
+            format!("{:?}", LayerChallenges::new_synthetic(11, 18)),
+            format!("{:?}", LayerChallenges::new(11, 18))
+        );
+        assert_eq!(
+            format!("{:?}", LayerChallenges::new(11, 18)),
+            "LayerChallenges { layers: 11, max_count: 18 }"
+        );
+    }
 }
@@ -1,92 +1,249 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
 use std::sync::{Mutex, MutexGuard, Arc};
 
-use anyhow::{format_err, Result};
+use anyhow::{format_err, Context, Result};
 use hwloc2::{Bitmap, ObjectType, Topology, TopologyObject, CpuBindFlags, CpuSet};
 use lazy_static::lazy_static;
 use log::{debug, info, warn};
-use storage_proofs_core::settings::SETTINGS;
-use super::utils::{env_lock_p2_cores, p1_binding_policy, p2_binding_policy, binding_use_locality, P2BoundPolicy, P1BoundPolicy};
+use serde::{Deserialize, Serialize};
+use super::create_label::multi::{estimate_node_rate, sha_acceleration_active};
+use super::phase::Phase;
+use super::utils::{bind_seed, multicore_sdr_producers, binding_log_json, max_p2_concurrent, env_lock_p2_cores, P2_GROUP_SIZE, log_binding, min_bind_bytes, BindingConfig, p1_binding_policy, p2_binding_policy, binding_use_locality, core_group_wait_strategy, CoreGroupWait, P2BoundPolicy, P1BoundPolicy};
 
 pub type CoreGroup = Vec<CoreIndex>;
 
 lazy_static! {
     pub static ref TOPOLOGY: Mutex<Topology> = Mutex::new(Topology::new().unwrap());
     pub static ref CORE_GROUPS: Option<Vec<Mutex<CoreGroup>>> = {
-        let num_producers = &SETTINGS.multicore_sdr_producers;
-        let cores_per_unit = num_producers + 1;
+        let cores_per_unit = multicore_sdr_producers() + 1;
 
         core_groups(cores_per_unit)
     };
     pub static ref PU_PER_CORE: Mutex<usize> = Mutex::new(1);
 }
 
+/// The number of cores in all of `CORE_GROUPS`, recorded when they are planned.
+static CORE_GROUP_CORES: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 /// `CoreIndex` is a simple wrapper type for indexes into the set of vixible cores. A `CoreIndex` should only ever be
 /// created with a value known to be less than the number of visible cores.
 pub struct CoreIndex(pub usize);
 
+/// How long `wait_for_core_groups` waits for free core groups before running unbound.
+const CORE_GROUP_WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Retries `checkout` according to the configured `CoreGroupWait` strategy until it succeeds,
+/// for at most `CORE_GROUP_WAIT_TIMEOUT`. With `CoreGroupWait::NoWait` (the default) only a
+/// single attempt is made. `min_cores` is the fewest cores `checkout` succeeds with: if the
+/// `group_cores` of all core groups are fewer, it never will, and is also only tried once.
+fn wait_for_core_groups<T, F: FnMut() -> Option<T>>(
+    group_cores: usize,
+    min_cores: usize,
+    checkout: F,
+) -> Option<T> {
+    let possible = min_cores <= group_cores;
+    if !possible {
+        warn!(
+            "{} cores requested, but the core groups only have {} in total, not waiting for them",
+            min_cores, group_cores
+        );
+    }
+    retry_checkout(core_group_wait_strategy(), CORE_GROUP_WAIT_TIMEOUT, possible, checkout)
+}
+
+fn retry_checkout<T, F: FnMut() -> Option<T>>(
+    strategy: CoreGroupWait,
+    timeout: Duration,
+    possible: bool,
+    mut checkout: F,
+) -> Option<T> {
+    let start = Instant::now();
+    loop {
+        let res = checkout();
+        if res.is_some() || !possible || strategy == CoreGroupWait::NoWait {
+            return res;
+        }
+        if start.elapsed() >= timeout {
+            warn!("no core group free after {:?}, running unbound", timeout);
+            return None;
+        }
+        strategy.wait();
+    }
+}
+
 pub fn checkout_core_group() -> Option<MutexGuard<'static, CoreGroup>> {
     match &*CORE_GROUPS {
-        Some(groups) => {
-            for (i, group) in groups.iter().enumerate() {
-                match group.try_lock() {
-                    Ok(guard) => {
-                        debug!("checked out core group {}", i);
-                        return Some(guard);
-                    }
-                    Err(_) => debug!("core group {} locked, could not checkout", i),
-                }
+        Some(groups) => wait_for_core_groups(CORE_GROUP_CORES.load(SeqCst), 1, || {
+            try_checkout_core_group(groups)
+        }),
+        None => None,
+    }
+}
+
+/// The order in which core groups are checked out. Without a seed this is simply the group
+/// order. With a seed it starts at a group which only depends on the seed and the number of
+/// groups, so the same cores are chosen on every run (see `FIL_PROOFS_BIND_SEED`), and wraps
+/// around to the groups before it.
+fn core_group_order(num_groups: usize, seed: Option<u64>) -> Vec<usize> {
+    let start = match seed {
+        Some(seed) if num_groups > 0 => (splitmix64(seed) % num_groups as u64) as usize,
+        _ => 0,
+    };
+    (start..num_groups).chain(0..start).collect()
+}
+
+/// splitmix64, so the start group does not depend on any rng crate's implementation.
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn try_checkout_core_group(groups: &'static [Mutex<CoreGroup>]) -> Option<MutexGuard<'static, CoreGroup>> {
+    for i in core_group_order(groups.len(), bind_seed()) {
+        match groups[i].try_lock() {
+            Ok(guard) => {
+                debug!("checked out core group {}", i);
+                return Some(guard);
+            }
+            Err(_) => {
+                debug!("core group {} locked, could not checkout", i);
             }
-            None
         }
-        None => None,
     }
+    None
 }
 
 pub fn get_p1_core_group() -> (Option<Vec<MutexGuard<'static, CoreGroup>>>, Option<CoreGroup>) {
     match &*CORE_GROUPS {
-        Some(groups) => {
-            let total_size = &SETTINGS.multicore_sdr_producers + 1;
-            let policy = p1_binding_policy();
-            let mut total_size_multiplier = 1;
-            if policy == P1BoundPolicy::Default || policy == P1BoundPolicy::Core {
-                total_size_multiplier = *PU_PER_CORE.lock().unwrap();
-            }
+        Some(groups) => match wait_for_core_groups(CORE_GROUP_CORES.load(SeqCst), 1, || {
+            try_get_p1_core_group(groups)
+        }) {
+            Some((guards, group)) => (Some(guards), Some(group)),
+            None => (None, None),
+        },
+        None => (None, None),
+    }
+}
 
-            let mut current_size: usize = 0;
-            let mut res: CoreGroup = CoreGroup::new();
-            let mut res_guard = vec![];
-            for (i, group) in groups.iter().enumerate() {
-                match group.try_lock() {
-                    Ok(guard) => {
-                        let n = guard.len();
-                        for core_id in (0..guard.len()).step_by(total_size_multiplier) {
-                            let core_index = guard.get(core_id);                        
-                            if let Some(core_index) = core_index {
-                                res.push(*core_index);
-                            }
-                        }
+/// Like `get_p1_core_group`, but skips binding entirely when labeling only `bytes` of data,
+/// see `FIL_PROOFS_MIN_BIND_BYTES`.
+pub fn get_p1_core_group_for(bytes: u64) -> (Option<Vec<MutexGuard<'static, CoreGroup>>>, Option<CoreGroup>) {
+    if bytes < min_bind_bytes() {
+        debug!("skipping core binding for {} bytes", bytes);
+        return (None, None);
+    }
 
-                        current_size += n;
-                        res_guard.push(guard);
-                        if current_size >= total_size {
-                            return (Some(res_guard), Some(res));
-                        }
+    get_p1_core_group()
+}
+
+fn try_get_p1_core_group(groups: &'static [Mutex<CoreGroup>]) -> Option<(Vec<MutexGuard<'static, CoreGroup>>, CoreGroup)> {
+    let total_size = multicore_sdr_producers() + 1;
+    let policy = p1_binding_policy();
+    let mut total_size_multiplier = 1;
+    if policy == P1BoundPolicy::Default || policy == P1BoundPolicy::Core {
+        total_size_multiplier = *PU_PER_CORE.lock().unwrap();
+    }
+
+    let mut current_size: usize = 0;
+    let mut res: CoreGroup = CoreGroup::new();
+    let mut res_guard = vec![];
+    for i in core_group_order(groups.len(), bind_seed()) {
+        match groups[i].try_lock() {
+            Ok(guard) => {
+                let n = guard.len();
+                for core_id in (0..guard.len()).step_by(total_size_multiplier) {
+                    let core_index = guard.get(core_id);
+                    if let Some(core_index) = core_index {
+                        res.push(*core_index);
                     }
-                    Err(_) => debug!("core group {} locked, could not checkout", i),
+                }
+
+                current_size += n;
+                res_guard.push(guard);
+                if current_size >= total_size {
+                    return Some((res_guard, res));
                 }
             }
-            if res.len() > 0 {
-                info!("not enough free cores, P1 uses only {}", current_size);
-                return (Some(res_guard), Some(res));
+            Err(_) => {
+                debug!("core group {} locked, could not checkout", i);
             }
-            (None, None)
         }
-        None => (None, None),
     }
+    if res.len() > 0 {
+        info!("not enough free cores, P1 uses only {}", current_size);
+        return Some((res_guard, res));
+    }
+    None
+}
+
+/// Why a P2 run was not admitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckoutError {
+    /// `max` P2 runs already hold their core groups, see `FIL_PROOFS_MAX_P2_CONCURRENT`.
+    AtCapacity { max: usize },
+}
+
+impl fmt::Display for CheckoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckoutError::AtCapacity { max } => {
+                write!(f, "already running the maximum of {} concurrent P2 instances", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckoutError {}
+
+/// Number of P2 runs currently admitted by `admit_p2`.
+static P2_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Admission of one P2 run, counted against `FIL_PROOFS_MAX_P2_CONCURRENT` until dropped. A run
+/// takes one for the whole seal and hands it to each of its tree builds, which may run at once.
+#[derive(Debug)]
+pub struct P2Slot(&'static AtomicUsize);
+
+impl P2Slot {
+    /// Admits a run counted in `running`, unless `max` are running already.
+    fn acquire(
+        running: &'static AtomicUsize,
+        max: Option<usize>,
+    ) -> std::result::Result<Self, CheckoutError> {
+        running
+            .fetch_update(SeqCst, SeqCst, |count| match max {
+                Some(max) if count >= max => None,
+                _ => Some(count + 1),
+            })
+            .map(|_| P2Slot(running))
+            .map_err(|count| CheckoutError::AtCapacity {
+                max: max.unwrap_or(count),
+            })
+    }
+}
+
+impl Drop for P2Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, SeqCst);
+    }
+}
+
+/// Admits a P2 run. Fails with `CheckoutError::AtCapacity` if `FIL_PROOFS_MAX_P2_CONCURRENT` runs
+/// are admitted already.
+pub fn admit_p2() -> std::result::Result<P2Slot, CheckoutError> {
+    P2Slot::acquire(&P2_RUNNING, max_p2_concurrent())
 }
 
-pub fn get_p2_core_group() -> Option<Vec<MutexGuard<'static, CoreGroup>>> {
+/// Checks out the core groups of a tree build of the P2 run admitted with `_slot`, if it binds.
+pub fn get_p2_core_group(_slot: &P2Slot) -> Option<Vec<MutexGuard<'static, CoreGroup>>> {
     match &*CORE_GROUPS {
         Some(groups) => {
             let binding_policy = p2_binding_policy();
@@ -94,34 +251,181 @@ pub fn get_p2_core_group() -> Option<Vec<MutexGuard<'static, CoreGroup>>> {
                 return None;
             }
 
-            let total_size = env_lock_p2_cores();
-            let mut current_size: usize = 0;
-            let mut res = vec![];
-            for (i, group) in groups.iter().enumerate() {
-                match group.try_lock() {
-                    Ok(guard) => {
-                        let n = guard.len();
-                        res.push(guard);
-                        current_size += n;
-                        if current_size >= total_size {
-                            return Some(res);
-                        }
-                    }
-                    Err(_) => debug!("core group {} locked, could not checkout", i),
+            // Only the Strict policy insists on all the cores.
+            let min_cores = match binding_policy {
+                P2BoundPolicy::Strict => env_lock_p2_cores(),
+                _ => 1,
+            };
+            wait_for_core_groups(CORE_GROUP_CORES.load(SeqCst), min_cores, || {
+                try_get_p2_core_group(groups, &binding_policy)
+            })
+        }
+        None => None,
+    }
+}
+
+fn try_get_p2_core_group(groups: &'static [Mutex<CoreGroup>], binding_policy: &P2BoundPolicy) -> Option<Vec<MutexGuard<'static, CoreGroup>>> {
+    let total_size = env_lock_p2_cores();
+    let mut current_size: usize = 0;
+    let mut res = vec![];
+    for i in core_group_order(groups.len(), bind_seed()) {
+        match groups[i].try_lock() {
+            Ok(guard) => {
+                let n = guard.len();
+                res.push(guard);
+                current_size += n;
+                if current_size >= total_size {
+                    return Some(res);
                 }
             }
-            if res.len() < total_size && binding_policy == P2BoundPolicy::Strict {
-                info!("not enough free cores, Strict bound policy implies not use binding");
-                return None;
-            }
-            if res.len() > 0 {
-                info!("not enough free cores, Weak bound policy, P2 uses only {}", current_size);
-                return Some(res);
+            Err(_) => {
+                debug!("core group {} locked, could not checkout", i);
             }
-            None
         }
-        None => None,
     }
+    if res.len() < total_size && *binding_policy == P2BoundPolicy::Strict {
+        info!("not enough free cores, Strict bound policy implies not use binding");
+        return None;
+    }
+    if res.len() > 0 {
+        info!("not enough free cores, Weak bound policy, P2 uses only {}", current_size);
+        return Some(res);
+    }
+    None
+}
+
+/// Number of logical CPUs this process can actually be scheduled on. All binding math should
+/// use this rather than `num_cpus::get()`, so it agrees with what is schedulable.
+/// On Linux this is read from the affinity mask with `sched_getaffinity`, which respects cpusets.
+pub fn logical_cpu_count() -> usize {
+    match affinity_cpus() {
+        Some(cpus) if !cpus.is_empty() => cpus.len(),
+        _ => num_cpus::get(),
+    }
+}
+
+/// OS indexes of the CPUs the calling thread may run on, `None` if they can not be determined.
+#[cfg(target_os = "linux")]
+pub(crate) fn affinity_cpus() -> Option<Vec<usize>> {
+    // Safety: `cpu_set_t` is a plain bit set, for which all zeroes is a valid (empty) value.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let res =
+        unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if res != 0 {
+        warn!(
+            "sched_getaffinity failed, falling back to num_cpus: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+
+    Some(
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+            .collect(),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn affinity_cpus() -> Option<Vec<usize>> {
+    None
+}
+
+/// Suggested number of cores for one P2 group: the logical cores sharing one L3 cache (a CCX on
+/// AMD), so a P2 instance does not spread its working set over several cache domains.
+/// Falls back to the default of `P2_GROUP_SIZE` if the topology does not report an L3 cache.
+pub fn suggest_p2_group_size(topo: &Topology) -> usize {
+    let l3_count = topo
+        .objects_with_type(&ObjectType::L3Cache)
+        .map(|objs| objs.len())
+        .unwrap_or(0);
+    let pu_count = topo
+        .objects_with_type(&ObjectType::PU)
+        .map(|objs| objs.len())
+        .unwrap_or(0);
+
+    if l3_count == 0 || pu_count < l3_count {
+        debug!("no L3 cache found, suggesting the default P2 group size {}", P2_GROUP_SIZE);
+        return P2_GROUP_SIZE;
+    }
+
+    pu_count / l3_count
+}
+
+/// `suggest_p2_group_size` for the detected topology.
+pub fn suggested_p2_group_size() -> usize {
+    suggest_p2_group_size(&TOPOLOGY.lock().expect("poisoned lock"))
+}
+
+/// Whether the topology has a NUMA node with the OS index `node`.
+pub fn numa_node_exists(node: u32) -> bool {
+    let topo = TOPOLOGY.lock().expect("poisoned lock");
+    topo.objects_with_type(&ObjectType::NUMANode)
+        .map(|nodes| nodes.iter().any(|obj| obj.os_index() == node))
+        .unwrap_or(false)
+}
+
+/// Binds the whole pages within the `len` bytes at `addr` to NUMA node `node`, moving pages that
+/// are already allocated on another node. Pages only partially in the range are left alone.
+#[cfg(target_os = "linux")]
+pub fn bind_memory_to_node(addr: *mut u8, len: usize, node: u32) -> Result<()> {
+    const MPOL_BIND: libc::c_long = 2;
+    set_memory_policy(addr, len, node, MPOL_BIND)
+}
+
+/// Like `bind_memory_to_node`, but only prefers `node`: pages are allocated on other nodes once
+/// it is full, instead of failing.
+#[cfg(target_os = "linux")]
+pub fn prefer_memory_node(addr: *mut u8, len: usize, node: u32) -> Result<()> {
+    const MPOL_PREFERRED: libc::c_long = 1;
+    set_memory_policy(addr, len, node, MPOL_PREFERRED)
+}
+
+#[cfg(target_os = "linux")]
+fn set_memory_policy(addr: *mut u8, len: usize, node: u32, mode: libc::c_long) -> Result<()> {
+    const MPOL_MF_MOVE: libc::c_long = 1 << 1;
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = (addr as usize + page_size - 1) / page_size * page_size;
+    let end = (addr as usize + len) / page_size * page_size;
+    if end <= start {
+        return Ok(());
+    }
+
+    let bits = std::mem::size_of::<libc::c_ulong>() * 8;
+    let mut mask = vec![0 as libc::c_ulong; node as usize / bits + 1];
+    mask[node as usize / bits] |= 1 << (node as usize % bits);
+
+    // Safety: the range lies within the caller's allocation and the mask holds `maxnode - 1` bits.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            start as libc::c_ulong,
+            (end - start) as libc::c_ulong,
+            mode,
+            mask.as_ptr(),
+            (mask.len() * bits + 1) as libc::c_ulong,
+            MPOL_MF_MOVE,
+        )
+    };
+    if res != 0 {
+        return Err(format_err!(
+            "mbind to NUMA node {} failed: {}",
+            node,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_memory_to_node(_addr: *mut u8, _len: usize, node: u32) -> Result<()> {
+    Err(format_err!("binding memory to NUMA node {} is only supported on Linux", node))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn prefer_memory_node(_addr: *mut u8, _len: usize, node: u32) -> Result<()> {
+    Err(format_err!("placing memory on NUMA node {} is only supported on Linux", node))
 }
 
 #[cfg(not(target_os = "windows"))]
@@ -151,13 +455,50 @@ impl Drop for Cleanup {
     fn drop(&mut self) {
         if let Some(prior) = self.prior_state.take() {
             let child_topo = &TOPOLOGY;
+            if std::thread::panicking() {
+                // A panic in a bound phase, the binding is only restored by unwinding. Make it
+                // visible in the logs, in case the thread stays pinned into the next phase.
+                warn!("thread is unwinding while bound, restoring its prior binding {:?}", prior);
+                // Panicking again would abort, so take the topology even if the panic poisoned it.
+                let mut locked_topo = child_topo.lock().unwrap_or_else(|err| err.into_inner());
+                if let Err(err) =
+                    locked_topo.set_cpubind_for_thread(self.tid, prior, CpuBindFlags::CPUBIND_THREAD)
+                {
+                    warn!("failed to restore the binding of an unwinding thread: {:?}", err);
+                }
+                return;
+            }
             let mut locked_topo = child_topo.lock().expect("poisded lock");
             let _ = locked_topo.set_cpubind_for_thread(self.tid, prior, CpuBindFlags::CPUBIND_THREAD);
         }
     }
 }
 
+impl Cleanup {
+    /// Restores the prior binding right away, reporting a failure instead of ignoring it.
+    fn restore(mut self) -> Result<()> {
+        match self.prior_state.take() {
+            Some(prior) => {
+                let mut locked_topo = TOPOLOGY.lock().expect("poisoned lock");
+                locked_topo
+                    .set_cpubind_for_thread(self.tid, prior, CpuBindFlags::CPUBIND_THREAD)
+                    .map_err(|err| format_err!("failed to restore CPU binding: {:?}", err))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
 pub fn bind_core(core_index: CoreIndex) -> Result<Cleanup> {
+    let (cleanup, result) = try_bind_core(core_index)?;
+    if result.is_err() {
+        warn!("error in bind_core, {:?}", result);
+    }
+
+    Ok(cleanup)
+}
+
+fn try_bind_core(core_index: CoreIndex) -> Result<(Cleanup, Result<()>)> {
     let child_topo = &TOPOLOGY;
     let tid = get_thread_id();
     let mut locked_topo = child_topo.lock().expect("poisoned lock");
@@ -176,26 +517,19 @@ pub fn bind_core(core_index: CoreIndex) -> Result<Cleanup> {
         bind_to.singlify();
     }
 
-    // Thread binding before explicit set.
-    let before = locked_topo.get_cpubind_for_thread(tid, CpuBindFlags::CPUBIND_THREAD);
-
-    debug!("binding to {:?}", bind_to);
-    // Set the binding.
-    let result = locked_topo
-        .set_cpubind_for_thread(tid, bind_to, CpuBindFlags::CPUBIND_THREAD)
-        .map_err(|err| format_err!("failed to bind CPU: {:?}", err));
+    Ok(set_cpubind_for_thread(&mut locked_topo, tid, &[core_index], bind_to))
+}
 
+pub fn bind_core_set(core_set: Arc<Vec<CoreIndex>>) -> Result<Cleanup> {
+    let (cleanup, result) = try_bind_core_set(&core_set)?;
     if result.is_err() {
         warn!("error in bind_core, {:?}", result);
     }
 
-    Ok(Cleanup {
-        tid,
-        prior_state: before,
-    })
+    Ok(cleanup)
 }
 
-pub fn bind_core_set(core_set: Arc<Vec<CoreIndex>>) -> Result<Cleanup> {
+fn try_bind_core_set(core_set: &[CoreIndex]) -> Result<(Cleanup, Result<()>)> {
     let child_topo = &TOPOLOGY;
     let tid = get_thread_id();
     let mut locked_topo = child_topo.lock().expect("poisoned lock");
@@ -215,25 +549,193 @@ pub fn bind_core_set(core_set: Arc<Vec<CoreIndex>>) -> Result<Cleanup> {
         acc_cpuset = CpuSet::or(acc_cpuset, x);
     }
     debug!("allowed cpuset: {:?}", acc_cpuset);
-    let bind_to = acc_cpuset;
 
+    Ok(set_cpubind_for_thread(&mut locked_topo, tid, core_set, acc_cpuset))
+}
+
+/// Binds the calling thread to the cores of the NUMA node closest to the PCI device on bus
+/// `pci_bus_id`, e.g. the host thread feeding a GPU, to shorten host-device transfers. The node
+/// is read from sysfs. Where that is not available, or binding fails, this only warns and
+/// returns `None`, leaving the thread as it is.
+pub fn bind_thread_near_device(pci_bus_id: u32) -> Option<Cleanup> {
+    let device = format!("PCI bus {:#04x}", pci_bus_id);
+    bind_thread_to_node(pci_device_numa_node(pci_bus_id), &device)
+}
+
+/// Like `bind_thread_near_device` for the GPU on bus `pci_bus_id`. GPUs which do not report
+/// their bus leave the thread as it is, as nothing else identifies their PCI device reliably.
+pub fn bind_thread_near_gpu(pci_bus_id: Option<u32>) -> Option<Cleanup> {
+    match pci_bus_id {
+        Some(pci_bus_id) => bind_thread_near_device(pci_bus_id),
+        None => {
+            debug!("GPU does not report its PCI bus, not binding near it");
+            None
+        }
+    }
+}
+
+/// Binds the calling thread to the cores of NUMA node `node`, the one of `device`, if known.
+fn bind_thread_to_node(node: Option<u32>, device: &str) -> Option<Cleanup> {
+    let node = match node {
+        Some(node) => node,
+        None => {
+            warn!("no NUMA node known for {}, not binding near it", device);
+            return None;
+        }
+    };
+
+    let cores = numa_node_cores(&TOPOLOGY.lock().expect("poisoned lock"), node);
+    if cores.is_empty() {
+        warn!("NUMA node {} of {} has no cores", node, device);
+        return None;
+    }
+
+    debug!("binding thread near {}, on NUMA node {}", device, node);
+    match bind_core_set(Arc::new(cores)) {
+        Ok(cleanup) => Some(cleanup),
+        Err(err) => {
+            warn!("failed to bind thread to NUMA node {}: {:?}", node, err);
+            None
+        }
+    }
+}
+
+/// The bus number of a PCI address like `0000:3b:00.0`.
+fn parse_pci_bus(address: &str) -> Option<u32> {
+    let bus = address.split(':').nth(1)?;
+    u32::from_str_radix(bus, 16).ok()
+}
+
+/// The NUMA node sysfs reports for the first PCI device on bus `pci_bus_id`, if any.
+#[cfg(target_os = "linux")]
+fn pci_device_numa_node(pci_bus_id: u32) -> Option<u32> {
+    let devices = std::fs::read_dir("/sys/bus/pci/devices").ok()?;
+    devices
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| parse_pci_bus(&entry.file_name().to_string_lossy()) == Some(pci_bus_id))
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("numa_node")).ok())
+        // The kernel reports -1 if the device is not attached to a node.
+        .find_map(|node| node.trim().parse::<u32>().ok())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pci_device_numa_node(_pci_bus_id: u32) -> Option<u32> {
+    None
+}
+
+/// Logical cores (hwloc PU indexes) of the NUMA node with the OS index `node`.
+fn numa_node_cores(topo: &Topology, node: u32) -> Vec<CoreIndex> {
+    let cpuset = topo
+        .objects_with_type(&ObjectType::NUMANode)
+        .ok()
+        .and_then(|nodes| nodes.iter().find(|obj| obj.os_index() == node).and_then(|obj| obj.cpuset()));
+    let cpuset = match cpuset {
+        Some(cpuset) => cpuset,
+        None => return Vec::new(),
+    };
+
+    topo.objects_with_type(&ObjectType::PU)
+        .map(|pus| {
+            pus.iter()
+                .enumerate()
+                .filter(|(_, pu)| cpuset.is_set(pu.os_index()))
+                .map(|(index, _)| CoreIndex(index))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The OS index of the NUMA node holding all of `cores`, `None` if they span several nodes or
+/// the topology has fewer than two, so there is nothing to place memory on.
+fn cores_numa_node(topo: &Topology, cores: &[CoreIndex]) -> Option<u32> {
+    let nodes = topo.objects_with_type(&ObjectType::NUMANode).ok()?;
+    if nodes.len() < 2 {
+        return None;
+    }
+    let pus = topo.objects_with_type(&ObjectType::PU).ok()?;
+
+    let mut found = None;
+    for core in cores {
+        let pu = pus.get(core.0)?;
+        let node = nodes
+            .iter()
+            .find(|node| node.cpuset().map_or(false, |cpuset| cpuset.is_set(pu.os_index())))?
+            .os_index();
+        match found {
+            Some(found) if found != node => return None,
+            _ => found = Some(node),
+        }
+    }
+    found
+}
+
+/// `cores_numa_node` for the detected topology: the NUMA node memory used by `cores` is best
+/// placed on.
+pub fn numa_node_of_cores(cores: &[CoreIndex]) -> Option<u32> {
+    cores_numa_node(&TOPOLOGY.lock().expect("poisoned lock"), cores)
+}
+
+/// The result of comparing a thread's actual binding with the intended one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BindingCheck {
+    pub expected: CpuSet,
+    /// `None` if the binding could not be read back.
+    pub actual: Option<CpuSet>,
+}
+
+impl BindingCheck {
+    pub fn matches(&self) -> bool {
+        self.actual.as_ref() == Some(&self.expected)
+    }
+}
+
+/// Reads back the binding of the thread `tid` and compares it with `expected`. Cpusets or
+/// SMT siblings can make the kernel apply a different mask than the one requested.
+pub fn verify_binding(topo: &Topology, tid: ThreadId, expected: &CpuSet) -> BindingCheck {
+    BindingCheck {
+        expected: expected.clone(),
+        actual: topo.get_cpubind_for_thread(tid, CpuBindFlags::CPUBIND_THREAD),
+    }
+}
+
+/// Binds the thread `tid` to `bind_to`, the cpuset of the `intended` cores. The returned
+/// `Cleanup` restores the prior binding even if setting the new one failed.
+fn set_cpubind_for_thread(
+    topo: &mut Topology,
+    tid: ThreadId,
+    intended: &[CoreIndex],
+    bind_to: CpuSet,
+) -> (Cleanup, Result<()>) {
     // Thread binding before explicit set.
-    let before = locked_topo.get_cpubind_for_thread(tid, CpuBindFlags::CPUBIND_THREAD);
+    let before = topo.get_cpubind_for_thread(tid, CpuBindFlags::CPUBIND_THREAD);
 
     debug!("binding to {:?}", bind_to);
+    let expected = bind_to.clone();
     // Set the binding.
-    let result = locked_topo
+    let result = topo
         .set_cpubind_for_thread(tid, bind_to, CpuBindFlags::CPUBIND_THREAD)
         .map_err(|err| format_err!("failed to bind CPU: {:?}", err));
 
-    if result.is_err() {
-        warn!("error in bind_core, {:?}", result);
+    if log_binding() {
+        let check = verify_binding(topo, tid, &expected);
+        let cores = intended.iter().map(|core| core.0).collect::<Vec<_>>();
+        if check.matches() {
+            info!("binding: thread {:?} -> cores {:?} -> {:?}", tid, cores, check.actual);
+        } else {
+            warn!(
+                "binding: thread {:?} -> cores {:?} -> {:?}, expected {:?}",
+                tid, cores, check.actual, check.expected
+            );
+        }
     }
 
-    Ok(Cleanup {
-        tid,
-        prior_state: before,
-    })
+    (
+        Cleanup {
+            tid,
+            prior_state: before,
+        },
+        result,
+    )
 }
 
 fn get_core_by_index(topo: &Topology, index: CoreIndex, get_pu: bool) -> Result<&TopologyObject> {
@@ -257,7 +759,26 @@ fn get_core_by_index(topo: &Topology, index: CoreIndex, get_pu: bool) -> Result<
 
 fn core_groups(cores_per_unit: usize) -> Option<Vec<Mutex<Vec<CoreIndex>>>> {
     let topo = TOPOLOGY.lock().expect("poisoned lock");
+    let (core_groups, pu_per_core) =
+        plan_core_groups(&topo, cores_per_unit, binding_use_locality())?;
+    *PU_PER_CORE.lock().unwrap() = pu_per_core;
+    CORE_GROUP_CORES.store(core_groups.iter().map(Vec::len).sum(), SeqCst);
 
+    Some(
+        core_groups
+            .into_iter()
+            .map(Mutex::new)
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// The core groups of `topo` for units of `cores_per_unit` cores, grouped per shared cache if
+/// `use_locality`, along with the number of PUs per core.
+fn plan_core_groups(
+    topo: &Topology,
+    cores_per_unit: usize,
+    use_locality: bool,
+) -> Option<(Vec<CoreGroup>, usize)> {
     let core_depth = match topo.depth_or_below_for_type(&ObjectType::Core) {
         Ok(depth) => depth,
         Err(_) => return None,
@@ -272,8 +793,7 @@ fn core_groups(cores_per_unit: usize) -> Option<Vec<Mutex<Vec<CoreIndex>>>> {
         .expect("objects_with_type failed");
     let pu_count = all_pu.len();
 
-    let mut pu_per_core = PU_PER_CORE.lock().unwrap();
-    *pu_per_core = pu_count / core_count;
+    let pu_per_core = pu_count / core_count;
 
     let mut cache_depth = core_depth;
     let mut cache_count = 1;
@@ -290,10 +810,10 @@ fn core_groups(cores_per_unit: usize) -> Option<Vec<Mutex<Vec<CoreIndex>>>> {
     }
 
     assert_eq!(0, core_count % cache_count);
-    let mut group_size = (core_count / cache_count) * (*pu_per_core);
+    let mut group_size = (core_count / cache_count) * pu_per_core;
     let mut group_count = cache_count;
 
-    if !binding_use_locality() {
+    if !use_locality {
         group_size = 1;
         group_count = pu_count;
         debug!(
@@ -332,12 +852,667 @@ fn core_groups(cores_per_unit: usize) -> Option<Vec<Mutex<Vec<CoreIndex>>>> {
         })
         .collect::<Vec<_>>();
 
-    Some(
-        core_groups
+    Some((core_groups, pu_per_core))
+}
+
+/// Outcome of a single step of `run_binding_selftest`.
+#[derive(Debug, Clone)]
+pub struct BindingSelftestStep {
+    pub name: String,
+    /// `None` if the step was not applicable with the current configuration.
+    pub result: Option<std::result::Result<(), String>>,
+}
+
+impl BindingSelftestStep {
+    fn new(name: &str, result: Option<Result<()>>) -> Self {
+        BindingSelftestStep {
+            name: name.to_string(),
+            result: result.map(|res| res.map_err(|err| format!("{:#}", err))),
+        }
+    }
+}
+
+/// Report returned by `run_binding_selftest`, with the resolved configuration and plan.
+#[derive(Debug, Clone)]
+pub struct BindingSelftestReport {
+    pub config: BindingConfig,
+    /// The free core groups found on this host, `None` if the topology could not be enumerated.
+    pub core_groups: Option<Vec<CoreGroup>>,
+    /// The cores P1 would use.
+    pub p1_cores: Option<CoreGroup>,
+    /// The cores P2 would use.
+    pub p2_cores: Option<CoreGroup>,
+    pub steps: Vec<BindingSelftestStep>,
+}
+
+impl BindingSelftestReport {
+    /// Returns true if no step failed.
+    pub fn is_ok(&self) -> bool {
+        self.steps
             .iter()
-            .map(|group| Mutex::new(group.clone()))
-            .collect::<Vec<_>>(),
-    )
+            .all(|step| step.result.as_ref().map_or(true, |res| res.is_ok()))
+    }
+}
+
+/// Binds `bind` on a throwaway thread and restores the prior binding afterwards,
+/// reporting failures of either syscall.
+fn selftest_bind_on_thread<F>(bind: F) -> Result<()>
+where
+    F: FnOnce() -> Result<(Cleanup, Result<()>)> + Send + 'static,
+{
+    std::thread::spawn(move || -> Result<()> {
+        let (cleanup, bound) = bind()?;
+        let restored = cleanup.restore();
+        bound.and(restored)
+    })
+    .join()
+    .map_err(|_| format_err!("binding thread panicked"))?
+}
+
+/// Exercises the full binding path on throwaway threads: enumerate the core groups, bind the
+/// P1 cores, bind the P2 core set and unbind again. Running this before sealing catches
+/// permission and cpuset problems in seconds instead of hours into labeling.
+pub fn run_binding_selftest() -> BindingSelftestReport {
+    let config = BindingConfig::from_env();
+    let mut steps = Vec::new();
+
+    let groups = match &*CORE_GROUPS {
+        Some(groups) => {
+            steps.push(BindingSelftestStep::new("enumerate", Some(Ok(()))));
+            groups
+        }
+        None => {
+            steps.push(BindingSelftestStep::new(
+                "enumerate",
+                Some(Err(format_err!("failed to enumerate core groups"))),
+            ));
+            return BindingSelftestReport {
+                config,
+                core_groups: None,
+                p1_cores: None,
+                p2_cores: None,
+                steps,
+            };
+        }
+    };
+    // Groups checked out by a running seal are left out rather than waited for.
+    let core_groups = groups
+        .iter()
+        .filter_map(|group| group.try_lock().ok().map(|guard| guard.clone()))
+        .collect::<Vec<_>>();
+
+    // P1: every thread is bound to a single core of the group.
+    let (p1_guards, p1_cores) = match try_get_p1_core_group(groups) {
+        Some((guards, cores)) => (Some(guards), Some(cores)),
+        None => (None, None),
+    };
+    let p1_result = match &p1_cores {
+        Some(cores) => Some(cores.iter().try_for_each(|core_index| {
+            let core_index = *core_index;
+            selftest_bind_on_thread(move || try_bind_core(core_index))
+                .with_context(|| format!("binding P1 core {}", core_index.0))
+        })),
+        None => Some(Err(format_err!("no free core group for P1"))),
+    };
+    steps.push(BindingSelftestStep::new("bind P1", p1_result));
+    drop(p1_guards);
+
+    // P2: every thread is bound to the whole set of cores.
+    let p2_guards = if config.p2_binding_policy == P2BoundPolicy::NoBinding {
+        None
+    } else {
+        try_get_p2_core_group(groups, &config.p2_binding_policy)
+    };
+    let p2_cores = p2_guards.as_ref().map(|guards| {
+        guards
+            .iter()
+            .flat_map(|guard| guard.iter().copied())
+            .collect::<CoreGroup>()
+    });
+    let p2_result = match &p2_cores {
+        Some(cores) => {
+            let cores = cores.clone();
+            Some(
+                selftest_bind_on_thread(move || try_bind_core_set(&cores))
+                    .context("binding P2 core set"),
+            )
+        }
+        None if config.p2_binding_policy == P2BoundPolicy::NoBinding => None,
+        None => Some(Err(format_err!("no free core groups for P2"))),
+    };
+    steps.push(BindingSelftestStep::new("bind P2", p2_result));
+    drop(p2_guards);
+
+    BindingSelftestReport {
+        config,
+        core_groups: Some(core_groups),
+        p1_cores,
+        p2_cores,
+        steps,
+    }
+}
+
+/// Report returned by `preflight`, in plain types so it can be serialized for logging.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreflightReport {
+    /// The checked configuration, as the environment variables it corresponds to.
+    pub config: Vec<(String, String)>,
+    /// Logical cores (hwloc PU indexes) P1 would bind, empty if it would not bind.
+    pub p1_cores: Vec<usize>,
+    /// Logical cores P2 would bind, empty if it would not bind.
+    pub p2_cores: Vec<usize>,
+    /// Pairs of a P1 and a P2 core which are SMT siblings on the same physical core.
+    pub smt_conflicts: Vec<(usize, usize)>,
+    /// Planned cores which are not in the cpuset of this process.
+    pub outside_cpuset: Vec<usize>,
+    /// Estimated nodes one core labels per second, for layers after the first.
+    pub node_rate_per_core: f64,
+    pub warnings: Vec<String>,
+}
+
+impl PreflightReport {
+    /// Returns true if nothing was flagged.
+    pub fn is_ok(&self) -> bool {
+        self.smt_conflicts.is_empty() && self.outside_cpuset.is_empty() && self.warnings.is_empty()
+    }
+
+    /// The planned cores, e.g. to render them with `render_core_layout`.
+    pub fn plan(&self) -> BindingPlan {
+        BindingPlan {
+            p1_cores: self.p1_cores.clone(),
+            p2_cores: self.p2_cores.clone(),
+            reserved_cores: Vec::new(),
+        }
+    }
+}
+
+/// One line of the `FIL_PROOFS_BINDING_LOG_JSON` audit trail: how one seal was bound and how it
+/// went.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BindingSummary {
+    /// Seconds since the Unix epoch when the phase ended.
+    pub timestamp: u64,
+    pub phase: String,
+    /// The binding configuration, as the environment variables it corresponds to.
+    pub config: Vec<(String, String)>,
+    /// Logical cores the phase was bound to, empty if it was not bound.
+    pub cores: Vec<usize>,
+    /// `ok`, or the error the phase failed with.
+    pub outcome: String,
+}
+
+impl BindingSummary {
+    pub fn new<T>(phase: Phase, cores: &[CoreIndex], res: &Result<T>) -> Self {
+        BindingSummary {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+            phase: phase.name().to_string(),
+            config: BindingConfig::from_env().to_env_vars(),
+            cores: cores.iter().map(|core| core.0).collect(),
+            outcome: match res {
+                Ok(_) => "ok".to_string(),
+                Err(err) => format!("{:#}", err),
+            },
+        }
+    }
+
+    pub fn to_json_line(&self) -> Result<String> {
+        serde_json::to_string(self).context("failed to serialize binding summary")
+    }
+}
+
+/// Appends `summary` to the file set with `FIL_PROOFS_BINDING_LOG_JSON`, if any. The file is
+/// opened for every line, so it may be rotated in between. Failures are only logged, the audit
+/// trail must not fail a seal.
+pub fn record_binding_summary(summary: &BindingSummary) {
+    let path = match binding_log_json() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let res = summary.to_json_line().and_then(|line| {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        // A single write, so concurrent seals appending to the same file do not interleave.
+        file.write_all(format!("{}\n", line).as_bytes())
+            .with_context(|| format!("failed to write {}", path.display()))
+    });
+    if let Err(err) = res {
+        warn!("failed to record binding summary: {:?}", err);
+    }
+}
+
+/// What the core binding of this build supports on this host, see `binding_capabilities`. A
+/// feature that is not working is either not compiled in or not supported by the platform.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BindingCapabilities {
+    /// Version of `storage-proofs-porep`.
+    pub version: String,
+    /// The cargo features of `storage-proofs-porep` this build was compiled with.
+    pub features: Vec<String>,
+    /// hwloc detected the cores, so threads can be bound to them.
+    pub thread_binding: bool,
+    /// Thread binding uses the Windows thread handles.
+    pub windows_affinity: bool,
+    /// NUMA nodes reported by hwloc, `0` if none.
+    pub numa_nodes: usize,
+    /// Labeling memory can be placed on a NUMA node, see `FIL_PROOFS_NUMA_NODE`. Linux only.
+    pub numa_memory: bool,
+    /// Labeling buffers can be backed by huge pages, see `FIL_PROOFS_LABEL_HUGE_PAGES`: the
+    /// kernel allows transparent huge pages or a hugetlbfs is mounted. Linux only.
+    pub huge_pages: bool,
+    /// The cores have SMT siblings in the topology, so P1 can bind whole cores and `preflight`
+    /// reports P1 and P2 sharing a physical core.
+    pub sibling_aware: bool,
+    /// Logical cores per physical core, as used for binding.
+    pub pus_per_core: usize,
+    /// The CPU count follows the affinity mask of the process, and thereby its cpuset or
+    /// cgroup, see `logical_cpu_count`. Linux only.
+    pub cgroup_aware: bool,
+    /// Labeling hashes with the CPU's SHA-256 instructions.
+    pub sha_acceleration: bool,
+}
+
+/// The cargo features which change the behavior of labeling or binding.
+const REPORTED_FEATURES: &[(&str, bool)] = &[
+    ("gpu", cfg!(feature = "gpu")),
+    ("single-threaded", cfg!(feature = "single-threaded")),
+    ("labeling-stats", cfg!(feature = "labeling-stats")),
+    ("labeling-trace", cfg!(feature = "labeling-trace")),
+    ("phase-memory", cfg!(feature = "phase-memory")),
+    ("bench-helpers", cfg!(feature = "bench-helpers")),
+];
+
+/// Reports the binding features of this build and what was detected on this host, e.g. to
+/// attach to a bug report about binding.
+pub fn binding_capabilities() -> BindingCapabilities {
+    let numa_nodes = TOPOLOGY
+        .lock()
+        .expect("poisoned lock")
+        .objects_with_type(&ObjectType::NUMANode)
+        .map(|nodes| nodes.len())
+        .unwrap_or(0);
+    let thread_binding = CORE_GROUPS.is_some();
+    // Only known once the core groups are planned.
+    let pus_per_core = *PU_PER_CORE.lock().expect("poisoned lock");
+
+    BindingCapabilities {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: REPORTED_FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name.to_string())
+            .collect(),
+        thread_binding,
+        windows_affinity: cfg!(target_os = "windows"),
+        numa_nodes,
+        numa_memory: cfg!(target_os = "linux") && numa_nodes > 0,
+        huge_pages: huge_pages_available(),
+        sibling_aware: thread_binding && pus_per_core > 1,
+        pus_per_core,
+        cgroup_aware: affinity_cpus().is_some(),
+        sha_acceleration: sha_acceleration_active(),
+    }
+}
+
+/// Whether the kernel provides transparent huge pages or a hugetlbfs mount.
+#[cfg(target_os = "linux")]
+fn huge_pages_available() -> bool {
+    let thp = std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
+        .map(|setting| thp_enabled(&setting))
+        .unwrap_or(false);
+    thp || std::fs::read_to_string("/proc/mounts")
+        .map(|mounts| has_hugetlbfs_mount(&mounts))
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn huge_pages_available() -> bool {
+    false
+}
+
+/// Whether the THP setting, like `always [madvise] never`, selects anything but `never`.
+fn thp_enabled(setting: &str) -> bool {
+    setting
+        .split_whitespace()
+        .find(|mode| mode.starts_with('['))
+        .map_or(false, |mode| mode != "[never]")
+}
+
+/// Whether the mount table, in the format of `/proc/mounts`, has a hugetlbfs.
+fn has_hugetlbfs_mount(mounts: &str) -> bool {
+    mounts
+        .lines()
+        .any(|line| line.split_whitespace().nth(2) == Some("hugetlbfs"))
+}
+
+/// Which logical cores (hwloc PU indexes) are bound for what.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BindingPlan {
+    pub p1_cores: Vec<usize>,
+    pub p2_cores: Vec<usize>,
+    /// Cores kept free of labeling and tree building.
+    pub reserved_cores: Vec<usize>,
+}
+
+impl BindingPlan {
+    /// Reserved cores which are also planned for P1 or P2, in ascending order.
+    pub fn reserved_overlap(&self) -> Vec<usize> {
+        let mut overlap: Vec<usize> = self
+            .reserved_cores
+            .iter()
+            .copied()
+            .filter(|core| self.p1_cores.contains(core) || self.p2_cores.contains(core))
+            .collect();
+        overlap.sort_unstable();
+        overlap.dedup();
+        overlap
+    }
+
+    /// Fails if any reserved core is also bound, which would bring back the contention the
+    /// cores are reserved to avoid. Callers may log the error instead of failing.
+    pub fn check_reserved_overlap(&self) -> Result<()> {
+        let overlap = self.reserved_overlap();
+        if overlap.is_empty() {
+            return Ok(());
+        }
+        Err(format_err!("reserved cores {:?} are also bound", overlap))
+    }
+}
+
+/// The logical cores of a host, grouped by NUMA node and, within those, by L3 cache domain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoreLayout {
+    pub numa_nodes: Vec<Vec<Vec<usize>>>,
+}
+
+impl CoreLayout {
+    /// Reads the layout from `topo`. Without NUMA nodes or L3 caches, all cores are put in a
+    /// single node or domain respectively.
+    pub fn from_topology(topo: &Topology) -> Self {
+        let cpusets = |ty: ObjectType| -> Vec<CpuSet> {
+            topo.objects_with_type(&ty)
+                .map(|objs| objs.iter().filter_map(|obj| obj.cpuset()).collect())
+                .unwrap_or_default()
+        };
+        let numa_sets = cpusets(ObjectType::NUMANode);
+        let l3_sets = cpusets(ObjectType::L3Cache);
+        let position = |sets: &[CpuSet], os_index: u32| {
+            sets.iter().position(|set| set.is_set(os_index)).unwrap_or(0)
+        };
+
+        let mut layout = BTreeMap::<usize, BTreeMap<usize, Vec<usize>>>::new();
+        if let Ok(pus) = topo.objects_with_type(&ObjectType::PU) {
+            for (index, pu) in pus.iter().enumerate() {
+                let os_index = pu.os_index();
+                layout
+                    .entry(position(&numa_sets, os_index))
+                    .or_default()
+                    .entry(position(&l3_sets, os_index))
+                    .or_default()
+                    .push(index);
+            }
+        }
+
+        CoreLayout {
+            numa_nodes: layout
+                .into_iter()
+                .map(|(_, domains)| domains.into_iter().map(|(_, cores)| cores).collect())
+                .collect(),
+        }
+    }
+
+    /// The layout of the detected topology.
+    pub fn detected() -> Self {
+        Self::from_topology(&TOPOLOGY.lock().expect("poisoned lock"))
+    }
+
+    /// Serializes the layout, e.g. to keep the layout of known hardware as a golden file or to
+    /// attach it to a bug report.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("failed to serialize core layout")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).context("invalid core layout")
+    }
+
+    /// Describes how this layout differs from `expected`, one entry per difference. Empty if
+    /// the layouts are the same.
+    pub fn differences(&self, expected: &CoreLayout) -> Vec<String> {
+        let mut diffs = Vec::new();
+        if self.numa_nodes.len() != expected.numa_nodes.len() {
+            diffs.push(format!(
+                "{} NUMA nodes, expected {}",
+                self.numa_nodes.len(),
+                expected.numa_nodes.len()
+            ));
+        }
+        for (node, (domains, expected_domains)) in
+            self.numa_nodes.iter().zip(&expected.numa_nodes).enumerate()
+        {
+            if domains.len() != expected_domains.len() {
+                diffs.push(format!(
+                    "NUMA {}: {} L3 domains, expected {}",
+                    node,
+                    domains.len(),
+                    expected_domains.len()
+                ));
+            }
+            for (l3, (cores, expected_cores)) in domains.iter().zip(expected_domains).enumerate() {
+                if cores != expected_cores {
+                    diffs.push(format!(
+                        "NUMA {} L3 {}: cores {:?}, expected {:?}",
+                        node, l3, cores, expected_cores
+                    ));
+                }
+            }
+        }
+        diffs
+    }
+
+    /// Renders the layout as a diagram, marking the cores `plan` binds, one line per L3 domain:
+    ///
+    /// ```text
+    /// NUMA 0
+    ///   L3 0: 0:P1 1:P1 2:P2 3:--
+    /// ```
+    ///
+    /// A core planned for several uses is marked with all of them, e.g. `3:P1+P2`.
+    pub fn render(&self, plan: &BindingPlan) -> String {
+        let mut out = String::new();
+        let mut l3 = 0;
+        for (node, domains) in self.numa_nodes.iter().enumerate() {
+            out.push_str(&format!("NUMA {}\n", node));
+            for cores in domains {
+                let cores = cores
+                    .iter()
+                    .map(|core| {
+                        let uses = [
+                            (&plan.p1_cores, "P1"),
+                            (&plan.p2_cores, "P2"),
+                            (&plan.reserved_cores, "R"),
+                        ]
+                        .iter()
+                        .filter(|(planned, _)| planned.contains(core))
+                        .map(|(_, name)| *name)
+                        .collect::<Vec<_>>();
+                        if uses.is_empty() {
+                            format!("{}:--", core)
+                        } else {
+                            format!("{}:{}", core, uses.join("+"))
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                out.push_str(&format!("  L3 {}: {}\n", l3, cores.join(" ")));
+                l3 += 1;
+            }
+        }
+        out.push_str("P1 = labeling, P2 = tree building, R = reserved, -- = not bound\n");
+        out
+    }
+}
+
+/// Checks the detected core layout against the golden file at `path`, written by
+/// `CoreLayout::to_json` on the same hardware, to catch topology detection regressions.
+pub fn verify_core_layout(path: &Path) -> Result<()> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read core layout from {}", path.display()))?;
+    let expected = CoreLayout::from_json(&json)?;
+
+    let diffs = CoreLayout::detected().differences(&expected);
+    if !diffs.is_empty() {
+        return Err(format_err!(
+            "detected core layout differs from {}: {}",
+            path.display(),
+            diffs.join("; ")
+        ));
+    }
+    Ok(())
+}
+
+/// Renders the NUMA nodes and L3 domains of `topo` as ASCII, marking the cores `plan` binds,
+/// for pasting into bug reports. See `CoreLayout::render` for the format.
+pub fn render_core_layout(topo: &Topology, plan: &BindingPlan) -> String {
+    CoreLayout::from_topology(topo).render(plan)
+}
+
+/// Synthetic nodes hashed to estimate the labeling throughput.
+const PREFLIGHT_NODES: usize = 4096;
+
+/// Checks `config` against the detected topology and cpuset, without binding anything or
+/// checking out core groups: plans which cores P1 and P2 would bind if both ran at once,
+/// flags P1 and P2 cores sharing a physical core and cores outside the cpuset, and estimates
+/// the labeling throughput of a single core. The plan only depends on the topology and
+/// `config`, the core groups are built as if no seal ran, with the locality of `config` and
+/// sized for P1 with the configured number of multicore SDR producers.
+pub fn preflight(config: &BindingConfig) -> PreflightReport {
+    let mut warnings = Vec::new();
+
+    let p1_size = multicore_sdr_producers() + 1;
+    let planned_groups = {
+        let topo = TOPOLOGY.lock().expect("poisoned lock");
+        plan_core_groups(&topo, p1_size, config.binding_use_locality)
+    };
+    let (groups, pu_per_core) = match planned_groups {
+        Some((groups, pu_per_core)) => (groups, pu_per_core.max(1)),
+        None => {
+            warnings.push("failed to enumerate core groups".to_string());
+            (Vec::new(), 1)
+        }
+    };
+    let BindingPlan {
+        p1_cores, p2_cores, ..
+    } = plan_preflight(
+        &groups,
+        pu_per_core,
+        p1_size,
+        config,
+        bind_seed(),
+        &mut warnings,
+    );
+
+    // hwloc numbers PUs so that the siblings of a core are adjacent.
+    let smt_conflicts = p1_cores
+        .iter()
+        .flat_map(|&p1| {
+            p2_cores
+                .iter()
+                .filter(move |&&p2| p1 / pu_per_core == p2 / pu_per_core)
+                .map(move |&p2| (p1, p2))
+        })
+        .collect();
+
+    let outside_cpuset = match affinity_cpus() {
+        Some(cpus) => {
+            let topo = TOPOLOGY.lock().expect("poisoned lock");
+            p1_cores
+                .iter()
+                .chain(p2_cores.iter())
+                .copied()
+                .filter(|&core| match get_core_by_index(&topo, CoreIndex(core), true) {
+                    Ok(pu) => !cpus.contains(&(pu.os_index() as usize)),
+                    Err(_) => true,
+                })
+                .collect()
+        }
+        None => {
+            warnings.push("could not read the cpuset of this process".to_string());
+            Vec::new()
+        }
+    };
+
+    PreflightReport {
+        config: config.to_env_vars(),
+        p1_cores,
+        p2_cores,
+        smt_conflicts,
+        outside_cpuset,
+        node_rate_per_core: estimate_node_rate(PREFLIGHT_NODES),
+        warnings,
+    }
+}
+
+/// The cores P1 and P2 would bind with `config` if both ran at once, given the core `groups`
+/// of a topology with `pu_per_core` PUs per core and P1 units of `p1_size` cores. Adds a
+/// warning for each use which would get fewer cores than configured.
+fn plan_preflight(
+    groups: &[CoreGroup],
+    pu_per_core: usize,
+    p1_size: usize,
+    config: &BindingConfig,
+    seed: Option<u64>,
+    warnings: &mut Vec<String>,
+) -> BindingPlan {
+    let order = core_group_order(groups.len(), seed);
+
+    // P1, mirroring `try_get_p1_core_group`.
+    let p1_step = match config.p1_binding_policy {
+        P1BoundPolicy::Default | P1BoundPolicy::Core => pu_per_core,
+        P1BoundPolicy::ProcessingUnit => 1,
+    };
+    let mut p1_cores = Vec::new();
+    let mut planned = 0;
+    let mut next_group = 0;
+    while planned < p1_size && next_group < order.len() {
+        let group = &groups[order[next_group]];
+        p1_cores.extend(group.iter().step_by(p1_step).map(|core| core.0));
+        planned += group.len();
+        next_group += 1;
+    }
+    if planned < p1_size {
+        warnings.push(format!("P1 would only get {} of {} cores", planned, p1_size));
+    }
+
+    // P2 takes the next groups, mirroring `try_get_p2_core_group`.
+    let mut p2_cores = Vec::new();
+    if config.p2_binding_policy != P2BoundPolicy::NoBinding {
+        while p2_cores.len() < config.p2_bound_cores && next_group < order.len() {
+            p2_cores.extend(groups[order[next_group]].iter().map(|core| core.0));
+            next_group += 1;
+        }
+        if p2_cores.len() < config.p2_bound_cores {
+            warnings.push(format!(
+                "only {} of {} cores free for P2",
+                p2_cores.len(),
+                config.p2_bound_cores
+            ));
+            if config.p2_binding_policy == P2BoundPolicy::Strict {
+                p2_cores.clear();
+            }
+        }
+    }
+
+    BindingPlan {
+        p1_cores,
+        p2_cores,
+        reserved_cores: Vec::new(),
+    }
 }
 
 #[cfg(test)]
@@ -366,4 +1541,303 @@ mod tests {
             _ => panic!("failed to get two checkouts"),
         }
     }
+
+    #[test]
+    fn test_logical_cpu_count() {
+        let count = logical_cpu_count();
+        assert!(count > 0);
+
+        // Not compared to `num_cpus::get`, which also honors CPU quotas and may be lower.
+        let topo = TOPOLOGY.lock().expect("poisoned lock");
+        let pu_count = topo
+            .objects_with_type(&ObjectType::PU)
+            .expect("objects_with_type failed")
+            .len();
+        assert!(count <= pu_count);
+    }
+
+    #[test]
+    fn test_suggest_p2_group_size() {
+        let topo = TOPOLOGY.lock().expect("poisoned lock");
+        let size = suggest_p2_group_size(&topo);
+        assert!(size > 0);
+
+        let pu_count = topo
+            .objects_with_type(&ObjectType::PU)
+            .expect("objects_with_type failed")
+            .len();
+        assert!(size == P2_GROUP_SIZE || size <= pu_count);
+    }
+
+    #[test]
+    fn test_plan_preflight() {
+        // Three L3 domains of two cores with two PUs each.
+        let groups: Vec<CoreGroup> = (0..3)
+            .map(|group| (group * 4..group * 4 + 4).map(CoreIndex).collect())
+            .collect();
+        let config = BindingConfig {
+            p1_binding_policy: P1BoundPolicy::Core,
+            p2_binding_policy: P2BoundPolicy::Weak,
+            p2_bound_cores: 4,
+            ..BindingConfig::default()
+        };
+
+        let mut warnings = Vec::new();
+        let plan = plan_preflight(&groups, 2, 3, &config, None, &mut warnings);
+        assert_eq!(plan.p1_cores, vec![0, 2]);
+        assert_eq!(plan.p2_cores, vec![4, 5, 6, 7]);
+        assert!(warnings.is_empty());
+
+        // P1 on every PU of its group.
+        let per_pu = BindingConfig {
+            p1_binding_policy: P1BoundPolicy::ProcessingUnit,
+            ..config.clone()
+        };
+        let plan = plan_preflight(&groups, 2, 3, &per_pu, None, &mut warnings);
+        assert_eq!(plan.p1_cores, vec![0, 1, 2, 3]);
+
+        // Strict P2 does not bind with fewer cores than configured.
+        let strict = BindingConfig {
+            p2_binding_policy: P2BoundPolicy::Strict,
+            p2_bound_cores: 12,
+            ..config.clone()
+        };
+        let plan = plan_preflight(&groups, 2, 3, &strict, None, &mut warnings);
+        assert!(plan.p2_cores.is_empty());
+        assert_eq!(warnings, vec!["only 8 of 12 cores free for P2".to_string()]);
+
+        let unbound = BindingConfig {
+            p2_binding_policy: P2BoundPolicy::NoBinding,
+            ..config.clone()
+        };
+        assert!(plan_preflight(&groups, 2, 3, &unbound, None, &mut warnings)
+            .p2_cores
+            .is_empty());
+
+        // Without locality every core group is a single PU, so P1 is planned one per PU.
+        let single: Vec<CoreGroup> = (0..12).map(|pu| vec![CoreIndex(pu)]).collect();
+        let plan = plan_preflight(&single, 2, 3, &per_pu, None, &mut warnings);
+        assert_eq!(plan.p1_cores, vec![0, 1, 2]);
+        assert_eq!(plan.p2_cores, vec![3, 4, 5, 6]);
+
+        // Too few cores for P1.
+        let mut warnings = Vec::new();
+        let plan = plan_preflight(&groups[..1], 2, 6, &config, None, &mut warnings);
+        assert_eq!(plan.p1_cores, vec![0, 2]);
+        assert_eq!(warnings[0], "P1 would only get 4 of 6 cores");
+    }
+
+    #[test]
+    fn test_render_core_layout() {
+        let layout = CoreLayout {
+            numa_nodes: vec![vec![vec![0, 1, 2, 3], vec![4, 5]], vec![vec![6, 7]]],
+        };
+        let plan = BindingPlan {
+            p1_cores: vec![0, 1],
+            p2_cores: vec![1, 4, 5],
+            reserved_cores: vec![7],
+        };
+
+        assert_eq!(
+            layout.render(&plan),
+            "NUMA 0\n\
+             \x20 L3 0: 0:P1 1:P1+P2 2:-- 3:--\n\
+             \x20 L3 1: 4:P2 5:P2\n\
+             NUMA 1\n\
+             \x20 L3 2: 6:-- 7:R\n\
+             P1 = labeling, P2 = tree building, R = reserved, -- = not bound\n"
+        );
+
+        let topo = TOPOLOGY.lock().expect("poisoned lock");
+        let rendered = render_core_layout(&topo, &BindingPlan::default());
+        assert!(rendered.starts_with("NUMA 0\n"));
+    }
+
+    #[test]
+    fn test_core_layout_json() {
+        let layout = CoreLayout {
+            numa_nodes: vec![vec![vec![0, 1], vec![2, 3]], vec![vec![4, 5]]],
+        };
+        let json = layout.to_json().expect("to_json failed");
+        let parsed = CoreLayout::from_json(&json).expect("from_json failed");
+        assert_eq!(parsed, layout);
+        assert!(parsed.differences(&layout).is_empty());
+
+        let mut moved = layout.clone();
+        moved.numa_nodes[0][1] = vec![2];
+        moved.numa_nodes.pop();
+        assert_eq!(
+            moved.differences(&layout),
+            vec![
+                "1 NUMA nodes, expected 2".to_string(),
+                "NUMA 0 L3 1: cores [2], expected [2, 3]".to_string(),
+            ]
+        );
+        assert!(CoreLayout::from_json("{}").is_err());
+
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let golden = dir.path().join("layout.json");
+        std::fs::write(&golden, CoreLayout::detected().to_json().expect("to_json failed"))
+            .expect("failed to write golden file");
+        verify_core_layout(&golden).expect("detected layout does not match itself");
+
+        std::fs::write(&golden, json).expect("failed to write golden file");
+        if CoreLayout::detected() != layout {
+            assert!(verify_core_layout(&golden).is_err());
+        }
+    }
+
+    #[test]
+    fn test_reserved_overlap() {
+        let mut plan = BindingPlan {
+            p1_cores: vec![0, 1],
+            p2_cores: vec![4, 5],
+            reserved_cores: vec![2, 3],
+        };
+        assert!(plan.reserved_overlap().is_empty());
+        assert!(plan.check_reserved_overlap().is_ok());
+
+        plan.reserved_cores = vec![5, 3, 1, 5];
+        assert_eq!(plan.reserved_overlap(), vec![1, 5]);
+        let err = plan.check_reserved_overlap().expect_err("overlap not detected");
+        assert_eq!(err.to_string(), "reserved cores [1, 5] are also bound");
+    }
+
+    #[test]
+    fn test_core_group_order() {
+        assert_eq!(core_group_order(5, None), vec![0, 1, 2, 3, 4]);
+        assert!(core_group_order(0, Some(7)).is_empty());
+
+        let order = core_group_order(16, Some(42));
+        assert_eq!(order, core_group_order(16, Some(42)));
+
+        // All groups, from the seeded one on, wrapping around.
+        assert_eq!(order.len(), 16);
+        for (i, group) in order.iter().enumerate() {
+            assert_eq!(*group, (order[0] + i) % 16);
+        }
+    }
+
+    #[test]
+    fn test_cores_numa_node() {
+        let topo = TOPOLOGY.lock().expect("poisoned lock");
+        let nodes = topo
+            .objects_with_type(&ObjectType::NUMANode)
+            .map(|nodes| nodes.iter().map(|node| node.os_index()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        assert_eq!(cores_numa_node(&topo, &[]), None);
+
+        let mut all_cores = Vec::new();
+        for &node in &nodes {
+            let cores = numa_node_cores(&topo, node);
+            if cores.is_empty() {
+                continue;
+            }
+            let expected = if nodes.len() > 1 { Some(node) } else { None };
+            assert_eq!(cores_numa_node(&topo, &cores), expected);
+            all_cores.extend(cores);
+        }
+        if nodes.len() > 1 {
+            assert_eq!(cores_numa_node(&topo, &all_cores), None);
+        }
+    }
+
+    #[test]
+    fn test_binding_capabilities() {
+        let caps = binding_capabilities();
+        assert_eq!(caps.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(caps.features.contains(&"gpu".to_string()), cfg!(feature = "gpu"));
+        if !cfg!(target_os = "linux") {
+            assert!(!caps.huge_pages);
+        }
+        if caps.thread_binding {
+            assert!(caps.pus_per_core >= 1);
+            assert_eq!(caps.sibling_aware, caps.pus_per_core > 1);
+        } else {
+            assert!(!caps.sibling_aware);
+        }
+
+        let json = serde_json::to_string(&caps).expect("failed to serialize");
+        let parsed: BindingCapabilities = serde_json::from_str(&json).expect("failed to parse");
+        assert_eq!(parsed, caps);
+    }
+
+    #[test]
+    fn test_huge_pages_probes() {
+        assert!(thp_enabled("always [madvise] never\n"));
+        assert!(thp_enabled("[always] madvise never\n"));
+        assert!(!thp_enabled("always madvise [never]\n"));
+        assert!(!thp_enabled(""));
+
+        let mounts = "sysfs /sys sysfs rw,nosuid 0 0\n\
+                      hugetlbfs /dev/hugepages hugetlbfs rw,relatime,pagesize=2M 0 0\n";
+        assert!(has_hugetlbfs_mount(mounts));
+        assert!(!has_hugetlbfs_mount("sysfs /sys sysfs rw,nosuid 0 0\n"));
+    }
+
+    #[test]
+    fn test_parse_pci_bus() {
+        assert_eq!(parse_pci_bus("0000:3b:00.0"), Some(0x3b));
+        assert_eq!(parse_pci_bus("0001:00:1f.2"), Some(0));
+        assert_eq!(parse_pci_bus("not a device"), None);
+    }
+
+    #[test]
+    fn test_retry_checkout() {
+        let mut attempts = 0;
+        let res: Option<()> = retry_checkout(CoreGroupWait::Spin, Duration::from_secs(60), false, || {
+            attempts += 1;
+            None
+        });
+        assert!(res.is_none());
+        assert_eq!(attempts, 1, "an impossible checkout is not retried");
+
+        let mut attempts = 0;
+        let res = retry_checkout(CoreGroupWait::Yield, Duration::from_secs(60), true, || {
+            attempts += 1;
+            if attempts == 3 {
+                Some(attempts)
+            } else {
+                None
+            }
+        });
+        assert_eq!(res, Some(3));
+
+        let res: Option<()> =
+            retry_checkout(CoreGroupWait::Yield, Duration::from_millis(10), true, || None);
+        assert!(res.is_none(), "waiting gives up after the timeout");
+    }
+
+    #[test]
+    fn test_p2_slot_capacity() {
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+        let first = P2Slot::acquire(&RUNNING, Some(1)).expect("below capacity");
+        assert_eq!(
+            P2Slot::acquire(&RUNNING, Some(1)).expect_err("above capacity"),
+            CheckoutError::AtCapacity { max: 1 }
+        );
+        drop(first);
+        assert_eq!(RUNNING.load(SeqCst), 0);
+        let _second = P2Slot::acquire(&RUNNING, Some(1)).expect("slot was released");
+        let _unbounded = P2Slot::acquire(&RUNNING, None).expect("no limit");
+        assert_eq!(RUNNING.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn test_binding_summary_json() {
+        let ok = BindingSummary::new(Phase::Labeling, &[CoreIndex(2), CoreIndex(3)], &Ok(()));
+        assert_eq!(ok.phase, "labeling");
+        assert_eq!(ok.cores, vec![2, 3]);
+        assert_eq!(ok.outcome, "ok");
+
+        let line = ok.to_json_line().expect("failed to serialize");
+        assert!(!line.contains('\n'));
+        let parsed: BindingSummary = serde_json::from_str(&line).expect("failed to parse");
+        assert_eq!(parsed, ok);
+
+        let failed = BindingSummary::new::<()>(Phase::Labeling, &[], &Err(format_err!("cancelled")));
+        assert!(failed.cores.is_empty());
+        assert_eq!(failed.outcome, "cancelled");
+    }
 }
@@ -0,0 +1,326 @@
+//! Labeling a batch of sectors at once.
+//!
+//! The sectors of a batch are labeled in lockstep. The parents of a window of nodes are read
+//! from the parents cache once, by the calling thread, and then used by one hashing thread per
+//! sector while the parents of the next window are read. The hashing threads are bound to
+//! consecutive PUs of the P1 core group, which hwloc numbers so that SMT siblings are adjacent,
+//! so each pair of sectors shares a physical core and their SHA-256 rounds interleave on it,
+//! each hiding the parent reads of the other. Every sector still keeps two layers in memory,
+//! so a batch of `n` sectors needs `2 * n` sector sizes.
+
+use std::convert::TryFrom;
+use std::mem;
+use std::ops::Range;
+use std::sync::{Barrier, RwLock};
+
+use anyhow::{ensure, Context, Result};
+use log::info;
+use mapr::MmapMut;
+use merkletree::store::StoreConfig;
+use storage_proofs_core::{
+    drgraph::{Graph, BASE_DEGREE},
+    merkle::MerkleTreeTrait,
+    progress::{self, ProgressEvent},
+    util::NODE_SIZE,
+};
+
+use crate::stacked::vanilla::{
+    cache::ParentCache,
+    cores::{bind_core, get_p1_core_group_for, CoreIndex},
+    create_label::{multi::label_node, prepare_layers, read_layer, write_layer},
+    graph::{StackedBucketGraph, DEGREE},
+    memory_handling::allocate_layer_near,
+    params::Labels,
+    proof::LayerState,
+    utils::ReplicaId,
+};
+
+/// Number of nodes whose parents are read at once and then labeled by all sectors of a batch.
+const BATCH_WINDOW_NODES: usize = 4096;
+
+/// Labels all `layers` of each sector of a batch, `replica_ids[i]` into `configs[i]`, see the
+/// module documentation. Returns the labels of each sector, in the order of `replica_ids`. A
+/// layer already on disk for all sectors is not labeled again; one missing for any sector is
+/// labeled for all of them.
+#[allow(clippy::type_complexity)]
+pub fn create_labels_for_encoding_batch<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &mut ParentCache,
+    layers: usize,
+    replica_ids: &[T],
+    configs: Vec<StoreConfig>,
+) -> Result<Vec<(Labels<Tree>, Vec<LayerState>)>> {
+    ensure!(!replica_ids.is_empty(), "a batch needs at least one sector");
+    ensure!(
+        replica_ids.len() == configs.len(),
+        "{} replica ids for {} configs",
+        replica_ids.len(),
+        configs.len()
+    );
+    info!("create labels for a batch of {} sectors", replica_ids.len());
+
+    let replica_ids = replica_ids
+        .iter()
+        .map(|replica_id| ReplicaId::try_from(replica_id.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let layer_states = configs
+        .iter()
+        .map(|config| prepare_layers::<Tree>(graph, config, layers))
+        .collect::<Vec<_>>();
+
+    let node_count = graph.size();
+    let sector_size = node_count * NODE_SIZE;
+    let (_core_guard, core_group) =
+        get_p1_core_group_for((sector_size * replica_ids.len()) as u64);
+
+    let mut buffers = (0..replica_ids.len())
+        .map(|i| {
+            // Next to the core hashing the sector, see `label_layer_batch`.
+            let cores = core_group
+                .as_deref()
+                .and_then(|group| group.get(i % group.len()))
+                .map(std::slice::from_ref);
+            Ok((
+                allocate_layer_near(sector_size, cores)?,
+                allocate_layer_near(sector_size, cores)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for layer in 1..=layers {
+        info!("Layer {}", layer);
+        progress::report(ProgressEvent::LayerStarted { layer, layers });
+
+        if layer_states.iter().all(|states| states[layer - 1].generated) {
+            info!("skipping layer {}, already generated", layer);
+            for ((_, exp_labels), states) in buffers.iter_mut().zip(&layer_states) {
+                read_layer(&states[layer - 1].config, exp_labels)?;
+            }
+            continue;
+        }
+
+        parents_cache.reset()?;
+        label_layer_batch(
+            parents_cache,
+            &replica_ids,
+            &mut buffers,
+            layer,
+            node_count,
+            core_group.as_deref(),
+        )?;
+
+        info!("  storing labels on disk");
+        for ((layer_labels, exp_labels), states) in buffers.iter_mut().zip(&layer_states) {
+            write_layer(layer_labels, &states[layer - 1].config)
+                .context("failed to store labels")?;
+            mem::swap(layer_labels, exp_labels);
+        }
+    }
+
+    Ok(layer_states
+        .into_iter()
+        .map(|states| {
+            (
+                Labels::<Tree>::new(states.iter().map(|s| s.config.clone()).collect()),
+                states,
+            )
+        })
+        .collect())
+}
+
+/// Labels `layer` of all sectors into the first buffer of each of `buffers`, the second one
+/// holding the previous layer.
+fn label_layer_batch(
+    parents_cache: &mut ParentCache,
+    replica_ids: &[ReplicaId],
+    buffers: &mut [(MmapMut, MmapMut)],
+    layer: usize,
+    node_count: usize,
+    core_group: Option<&[CoreIndex]>,
+) -> Result<()> {
+    let degree = if layer == 1 { BASE_DEGREE } else { DEGREE };
+    let window_nodes = BATCH_WINDOW_NODES.min(node_count);
+    let window_count = (node_count + window_nodes - 1) / window_nodes;
+    let window_range = |window: usize| {
+        let start = window * window_nodes;
+        start..std::cmp::min(start + window_nodes, node_count)
+    };
+
+    // While the sectors label from one window, the parents of the next are read into the other.
+    let windows = [
+        RwLock::new(vec![[0u32; DEGREE]; window_nodes]),
+        RwLock::new(vec![[0u32; DEGREE]; window_nodes]),
+    ];
+    read_parents(
+        parents_cache,
+        window_range(0),
+        &mut windows[0].write().expect("poisoned lock"),
+    )?;
+    let barrier = Barrier::new(replica_ids.len() + 1);
+
+    let progress = progress::current();
+    let report_every = (window_count / 100).max(1);
+
+    crossbeam::thread::scope(|s| {
+        for (i, (replica_id, (layer_labels, exp_labels))) in
+            replica_ids.iter().zip(buffers.iter_mut()).enumerate()
+        {
+            let windows = &windows;
+            let barrier = &barrier;
+            let core_index = core_group.and_then(|group| group.get(i % group.len()));
+
+            s.spawn(move |_| {
+                // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
+                let _cleanup_handle = core_index.map(|core_index| bind_core(*core_index));
+
+                for window in 0..window_count {
+                    {
+                        let parents = windows[window % 2].read().expect("poisoned lock");
+                        let nodes = window_range(window);
+                        for (node, node_parents) in nodes.zip(parents.iter()) {
+                            // Node 0 has no parents.
+                            let node_degree = if node == 0 { 0 } else { degree };
+                            let label = {
+                                let current: &[u8] = layer_labels;
+                                let previous: &[u8] = exp_labels;
+                                let parent_labels = node_parents[..node_degree]
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(k, &parent)| {
+                                        let labels = if k < BASE_DEGREE { current } else { previous };
+                                        let start = parent as usize * NODE_SIZE;
+                                        &labels[start..start + NODE_SIZE]
+                                    })
+                                    .collect::<Vec<_>>();
+                                label_node(replica_id, layer as u32, node as u64, &parent_labels)
+                            };
+                            layer_labels[node * NODE_SIZE..(node + 1) * NODE_SIZE]
+                                .copy_from_slice(&label);
+                        }
+                    }
+                    barrier.wait();
+                }
+            });
+        }
+
+        // Keep meeting the hashing threads at the barrier after a failed read, so they finish.
+        let mut res = Ok(());
+        for window in 0..window_count {
+            if res.is_ok() && window + 1 < window_count {
+                res = read_parents(
+                    parents_cache,
+                    window_range(window + 1),
+                    &mut windows[(window + 1) % 2].write().expect("poisoned lock"),
+                );
+            }
+            barrier.wait();
+
+            let labeled = window_range(window).end;
+            if window % report_every == 0 || labeled == node_count {
+                progress.report(ProgressEvent::NodesLabeled {
+                    layer,
+                    labeled: labeled as u64,
+                    nodes: node_count as u64,
+                });
+            }
+        }
+        res
+    })
+    .expect("crossbeam scope failure")
+}
+
+/// Reads the parents of `nodes` from `parents_cache` into `window`.
+fn read_parents(
+    parents_cache: &mut ParentCache,
+    nodes: Range<usize>,
+    window: &mut [[u32; DEGREE]],
+) -> Result<()> {
+    for (node, parents) in nodes.zip(window.iter_mut()) {
+        *parents = parents_cache.read(node as u32)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use filecoin_hashers::poseidon::PoseidonHasher;
+    use generic_array::typenum::{U0, U2, U8};
+    use merkletree::store::Store;
+    use storage_proofs_core::{api_version::ApiVersion, cache_key::CacheKey, merkle::LCTree};
+    use tempfile::tempdir;
+
+    use crate::stacked::vanilla::{create_label::multi::create_labels_for_decoding, EXP_DEGREE};
+
+    type Tree = LCTree<PoseidonHasher, U8, U0, U2>;
+
+    #[test]
+    fn test_create_labels_batch_matches_single() {
+        let layers = 3;
+        let nodes = 64;
+        let replica_ids = [[3u8; 32], [7u8; 32], [11u8; 32]];
+
+        let graph = StackedBucketGraph::<PoseidonHasher>::new(
+            None,
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [123; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("stacked bucket graph new failed");
+        let mut cache = graph.parent_cache().expect("parent_cache failed");
+
+        let cache_dirs = replica_ids
+            .iter()
+            .map(|_| tempdir().expect("tempdir failure"))
+            .collect::<Vec<_>>();
+        let config = |dir: &tempfile::TempDir| {
+            StoreConfig::new(
+                dir.path(),
+                CacheKey::CommDTree.to_string(),
+                nodes.trailing_zeros() as usize,
+            )
+        };
+
+        let batch = create_labels_for_encoding_batch::<Tree, _>(
+            &graph,
+            &mut cache,
+            layers,
+            &replica_ids,
+            cache_dirs.iter().map(config).collect(),
+        )
+        .expect("create_labels_for_encoding_batch failed");
+        assert_eq!(batch.len(), replica_ids.len());
+
+        for ((replica_id, (labels, states)), dir) in
+            replica_ids.iter().zip(batch.iter()).zip(&cache_dirs)
+        {
+            assert_eq!(states.len(), layers);
+            let single_dir = tempdir().expect("tempdir failure");
+            let expected = create_labels_for_decoding::<Tree, _>(
+                &graph,
+                &cache,
+                layers,
+                replica_id,
+                config(&single_dir),
+            )
+            .expect("create_labels_for_decoding failed");
+
+            for layer in 1..=layers {
+                let store = labels.labels_for_layer(layer).expect("labels_for_layer failed");
+                assert_eq!(
+                    store.read_range(0..nodes).expect("read_range failed"),
+                    expected
+                        .labels_for_layer(layer)
+                        .read_range(0..nodes)
+                        .expect("read_range failed"),
+                    "layer {} of the sector in {:?} differs",
+                    layer,
+                    dir.path()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Helpers to benchmark labeling without setting up full sealing inputs.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::ensure;
+use filecoin_hashers::poseidon::PoseidonHasher;
+use lazy_static::lazy_static;
+use storage_proofs_core::{
+    api_version::ApiVersion, drgraph::BASE_DEGREE, error::Result, settings::SETTINGS,
+    util::NODE_SIZE,
+};
+
+use crate::stacked::vanilla::{
+    create_label::{multi::create_labels_with_producers, MemoryLayerSink},
+    graph::{StackedBucketGraph, EXP_DEGREE},
+};
+
+/// Replica id used for all benchmark runs, so repeated runs label the same data.
+pub const BENCH_REPLICA_ID: [u8; 32] = [0x5a; 32];
+
+/// Porep id of the synthetic benchmark graph.
+pub const BENCH_POREP_ID: [u8; 32] = [0xa5; 32];
+
+/// Labels `layers` layers of a synthetic graph with `sector_nodes` nodes in memory, using
+/// `workers` threads: one hashing thread and `workers - 1` producers. The threads are bound as
+/// configured for P1. Returns the time spent labeling, building the graph and its parent
+/// cache is not included.
+pub fn bench_label(sector_nodes: usize, layers: usize, workers: usize) -> Result<Duration> {
+    ensure!(workers > 1, "labeling needs at least two workers");
+    ensure!(layers > 0, "at least one layer must be labeled");
+
+    let graph = StackedBucketGraph::<PoseidonHasher>::new(
+        None,
+        sector_nodes,
+        BASE_DEGREE,
+        EXP_DEGREE,
+        BENCH_POREP_ID,
+        ApiVersion::V1_1_0,
+    )?;
+    let parents_cache = graph.parent_cache()?;
+    let mut sink = MemoryLayerSink::new(sector_nodes * NODE_SIZE)?;
+
+    let start = Instant::now();
+    create_labels_with_producers(
+        &graph,
+        &parents_cache,
+        layers,
+        BENCH_REPLICA_ID,
+        &mut sink,
+        workers - 1,
+        None,
+    )?;
+    Ok(start.elapsed())
+}
+
+/// Nodes per layer labeled by `calibrate_labeling`. Their labels take 1 MiB, the default
+/// `FIL_PROOFS_MIN_BIND_BYTES`, so the calibration is bound like a real sector. With a larger
+/// threshold it runs unbound.
+pub const CALIBRATION_NODES: usize = 1 << 15;
+
+/// Layers labeled by `calibrate_labeling`. The first layer has no expander parents, so at
+/// least two are needed to cover the cost of the later layers.
+pub const CALIBRATION_LAYERS: usize = 2;
+
+/// The measured labeling speed of this host, see `calibrate_labeling`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LabelingCalibration {
+    /// Nodes per layer in the calibration run.
+    pub nodes: usize,
+    /// Layers in the calibration run.
+    pub layers: usize,
+    /// Time to label a single node of a single layer, in nanoseconds.
+    pub nanos_per_node: f64,
+}
+
+impl LabelingCalibration {
+    /// Extrapolates the time to label `layers` layers of `sector_nodes` nodes each.
+    pub fn estimate(&self, sector_nodes: usize, layers: usize) -> Duration {
+        let nanos = self.nanos_per_node * sector_nodes as f64 * layers as f64;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+lazy_static! {
+    static ref CALIBRATION: Mutex<Option<LabelingCalibration>> = Mutex::new(None);
+}
+
+/// Measures how long labeling a node takes on this host, with the configured number of
+/// producers and the P1 binding from the environment. The first call labels
+/// `CALIBRATION_LAYERS` layers of `CALIBRATION_NODES` nodes, later calls return the cached
+/// result. Concurrent callers wait for the first one rather than competing for the cores.
+pub fn calibrate_labeling() -> Result<LabelingCalibration> {
+    let mut cached = CALIBRATION.lock().expect("poisoned lock");
+    if let Some(calibration) = *cached {
+        return Ok(calibration);
+    }
+
+    let workers = SETTINGS.multicore_sdr_producers + 1;
+    let elapsed = bench_label(CALIBRATION_NODES, CALIBRATION_LAYERS, workers)?;
+    let calibration = LabelingCalibration {
+        nodes: CALIBRATION_NODES,
+        layers: CALIBRATION_LAYERS,
+        nanos_per_node: elapsed.as_nanos() as f64 / (CALIBRATION_NODES * CALIBRATION_LAYERS) as f64,
+    };
+    *cached = Some(calibration);
+
+    Ok(calibration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench_label() {
+        bench_label(64, 2, 3).expect("bench_label failed");
+        assert!(bench_label(64, 2, 1).is_err());
+    }
+
+    #[test]
+    fn test_calibrate_labeling() {
+        let calibration = calibrate_labeling().expect("calibration failed");
+        assert_eq!(calibration.nodes, CALIBRATION_NODES);
+        assert_eq!(calibration.layers, CALIBRATION_LAYERS);
+        assert!(calibration.nanos_per_node > 0.0);
+        assert_eq!(
+            calibrate_labeling().expect("calibration failed"),
+            calibration
+        );
+
+        let one = calibration.estimate(1 << 20, 1);
+        let eleven = calibration.estimate(1 << 20, 11);
+        assert!(one > Duration::from_nanos(0));
+        assert!((eleven.as_secs_f64() / one.as_secs_f64() - 11.0).abs() < 1e-6);
+    }
+}
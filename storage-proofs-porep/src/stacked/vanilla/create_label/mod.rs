@@ -1,18 +1,309 @@
-use std::fs::{self, create_dir_all, remove_file, rename, File};
-use std::io::{self, BufReader};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
+use blake2b_simd::Params as Blake2bParams;
+use byte_slice_cast::{AsByteSlice, AsMutByteSlice};
 use filecoin_hashers::Hasher;
 use log::{info, warn};
 use merkletree::{merkle::Element, store::StoreConfig};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use storage_proofs_core::{
-    cache_key::CacheKey, drgraph::Graph, error::Result, merkle::MerkleTreeTrait,
+    cache_key::CacheKey, drgraph::Graph, error::Result, merkle::MerkleTreeTrait, util::NODE_SIZE,
 };
 
-use crate::stacked::vanilla::{proof::LayerState, StackedBucketGraph};
+use crate::stacked::vanilla::{
+    proof::LayerState,
+    utils::{poison_labels, ReplicaId},
+    StackedBucketGraph,
+};
 
+pub mod batch;
+#[cfg(feature = "bench-helpers")]
+pub mod bench;
 pub mod multi;
 pub mod single;
+pub mod trace;
+
+/// Errors specific to labeling, returned wrapped in an `anyhow::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelingError {
+    /// The cancellation flag was set before labeling completed.
+    Cancelled,
+}
+
+impl fmt::Display for LabelingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelingError::Cancelled => write!(f, "labeling was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for LabelingError {}
+
+/// Decides where the layers produced by `multi::create_labels_with_sink` live, e.g. to stream
+/// every completed layer to disk and reuse its buffer for the next one.
+pub trait LayerSink {
+    /// Returns the buffer layer `idx` (starting at 1) is labeled into. It must hold exactly one
+    /// label per node and be aligned to 4 bytes, or labeling fails. Its previous content is
+    /// irrelevant.
+    fn begin_layer(&mut self, idx: usize) -> Result<&mut [u8]>;
+
+    /// Called once layer `idx` is complete. The buffer handed out by `begin_layer` is not
+    /// accessed by the labeling afterwards, so it may be persisted and reused.
+    fn end_layer(&mut self, idx: usize) -> Result<()>;
+
+    /// Returns layer `idx` if it is complete and still held, which `multi::resume_labels_with_sink`
+    /// needs for the layer before the one it resumes from. By default no layers are held.
+    fn finished_layer(&self, _idx: usize) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// A `LayerSink` keeping all layers in memory.
+#[derive(Debug)]
+pub struct MemoryLayerSink {
+    layer_size: usize,
+    // Stored as `u32`, so the buffers are suitably aligned.
+    layers: Vec<Vec<u32>>,
+}
+
+impl MemoryLayerSink {
+    /// Creates a sink for layers of `layer_size` bytes each.
+    pub fn new(layer_size: usize) -> Result<Self> {
+        ensure!(
+            layer_size % std::mem::size_of::<u32>() == 0,
+            "layer size must be a multiple of 4 bytes"
+        );
+        Ok(MemoryLayerSink {
+            layer_size,
+            layers: Vec::new(),
+        })
+    }
+
+    /// Creates a sink holding `layers` as its first layers, e.g. restored from a checkpoint to
+    /// resume labeling after them with `multi::resume_labels_with_sink`.
+    pub fn from_layers(layer_size: usize, layers: &[&[u8]]) -> Result<Self> {
+        let mut sink = Self::new(layer_size)?;
+        for (idx, layer) in (1..).zip(layers) {
+            ensure!(
+                layer.len() == layer_size,
+                "layer {} holds {} bytes, expected {}",
+                idx,
+                layer.len(),
+                layer_size
+            );
+            sink.begin_layer(idx)?.copy_from_slice(layer);
+        }
+        Ok(sink)
+    }
+
+    /// Returns layer `idx` (starting at 1), if it was labeled.
+    pub fn layer(&self, idx: usize) -> Option<&[u8]> {
+        idx.checked_sub(1)
+            .and_then(|i| self.layers.get(i))
+            .map(|layer| layer.as_byte_slice())
+    }
+
+    /// Number of layers labeled so far.
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Appends `layer`, labeled elsewhere, as the next layer, without copying it.
+    pub(crate) fn push_layer(&mut self, layer: Vec<u32>) -> Result<()> {
+        ensure!(
+            layer.len() * std::mem::size_of::<u32>() == self.layer_size,
+            "layer holds {} bytes, expected {}",
+            layer.len() * std::mem::size_of::<u32>(),
+            self.layer_size
+        );
+        self.layers.push(layer);
+        Ok(())
+    }
+}
+
+impl LayerSink for MemoryLayerSink {
+    fn begin_layer(&mut self, idx: usize) -> Result<&mut [u8]> {
+        ensure!(idx == self.layers.len() + 1, "layers must be labeled in order");
+        self.layers
+            .push(vec![0u32; self.layer_size / std::mem::size_of::<u32>()]);
+        let layer = self
+            .layers
+            .last_mut()
+            .expect("layer was just added")
+            .as_mut_byte_slice();
+        poison_labels(layer);
+        Ok(layer)
+    }
+
+    fn end_layer(&mut self, _idx: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn finished_layer(&self, idx: usize) -> Option<&[u8]> {
+        self.layer(idx)
+    }
+}
+
+/// A `LayerSink` keeping only the most recent layers that fit into a memory budget resident, and
+/// spilling older ones to a temporary file, from which they are read back on demand. This
+/// allows labeling sectors whose layers do not all fit into memory, at the cost of the writes
+/// and of slower reads of the spilled layers.
+#[derive(Debug)]
+pub struct SpillingLayerSink {
+    layer_size: usize,
+    max_resident: usize,
+    // Resident layers with their index, oldest first, stored as `u32` so they are aligned.
+    resident: VecDeque<(usize, Vec<u32>)>,
+    num_layers: usize,
+    spill_path: PathBuf,
+    spill: File,
+}
+
+impl SpillingLayerSink {
+    /// Creates a sink for layers of `layer_size` bytes each, keeping as many layers resident as
+    /// fit into `max_resident_bytes`, which must hold at least the one being labeled. Spilled
+    /// layers go to a new file in `temp_dir`, which is removed when the sink is dropped.
+    pub fn new(layer_size: usize, max_resident_bytes: usize, temp_dir: &Path) -> Result<Self> {
+        ensure!(
+            layer_size > 0 && layer_size % std::mem::size_of::<u32>() == 0,
+            "layer size must be a positive multiple of 4 bytes"
+        );
+        ensure!(
+            max_resident_bytes >= layer_size,
+            "a budget of {} bytes does not hold a layer of {} bytes",
+            max_resident_bytes,
+            layer_size
+        );
+        static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+        let spill_path = temp_dir.join(format!(
+            "labels-spill-{}-{}",
+            std::process::id(),
+            SPILL_FILES.fetch_add(1, SeqCst)
+        ));
+        let spill = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&spill_path)
+            .with_context(|| format!("failed to create {}", spill_path.display()))?;
+
+        Ok(SpillingLayerSink {
+            layer_size,
+            max_resident: max_resident_bytes / layer_size,
+            resident: VecDeque::new(),
+            num_layers: 0,
+            spill_path,
+            spill,
+        })
+    }
+
+    /// Number of layers labeled so far.
+    pub fn num_layers(&self) -> usize {
+        self.num_layers
+    }
+
+    /// Returns layer `idx` (starting at 1) if it is resident.
+    pub fn resident_layer(&self, idx: usize) -> Option<&[u8]> {
+        self.resident
+            .iter()
+            .find(|(i, _)| *i == idx)
+            .map(|(_, layer)| layer.as_byte_slice())
+    }
+
+    /// Copies the `out.len()` bytes at `offset` into layer `idx` to `out`, reading them back
+    /// from the spill file if the layer is not resident.
+    pub fn read_layer_range(&mut self, idx: usize, offset: usize, out: &mut [u8]) -> Result<()> {
+        ensure!(
+            idx >= 1 && idx <= self.num_layers,
+            "layer {} was not labeled",
+            idx
+        );
+        ensure!(
+            offset + out.len() <= self.layer_size,
+            "range {}..{} out of layer",
+            offset,
+            offset + out.len()
+        );
+        if let Some(layer) = self.resident_layer(idx) {
+            out.copy_from_slice(&layer[offset..offset + out.len()]);
+            return Ok(());
+        }
+
+        self.spill
+            .seek(SeekFrom::Start(((idx - 1) * self.layer_size + offset) as u64))
+            .and_then(|_| self.spill.read_exact(out))
+            .with_context(|| format!("failed to read back layer {}", idx))
+    }
+
+    /// Copies the label of `node` from each layer into `out`, in layer order, like
+    /// `gather_column` does for resident layers. `out` must hold one label per layer.
+    pub fn read_column(&mut self, node: usize, out: &mut [u8]) -> Result<()> {
+        ensure!(
+            out.len() == self.num_layers * NODE_SIZE,
+            "column buffer must hold one label per layer"
+        );
+        for (idx, label) in (1..).zip(out.chunks_exact_mut(NODE_SIZE)) {
+            self.read_layer_range(idx, node * NODE_SIZE, label)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the oldest resident layer to the spill file and returns its buffer for reuse.
+    fn spill_oldest(&mut self) -> Result<Vec<u32>> {
+        let (idx, layer) = self.resident.pop_front().expect("no resident layer");
+        self.spill
+            .seek(SeekFrom::Start(((idx - 1) * self.layer_size) as u64))
+            .and_then(|_| self.spill.write_all(layer.as_byte_slice()))
+            .with_context(|| format!("failed to spill layer {}", idx))?;
+        Ok(layer)
+    }
+}
+
+impl LayerSink for SpillingLayerSink {
+    fn begin_layer(&mut self, idx: usize) -> Result<&mut [u8]> {
+        ensure!(idx == self.num_layers + 1, "layers must be labeled in order");
+        let layer = if self.resident.len() >= self.max_resident {
+            self.spill_oldest()?
+        } else {
+            vec![0u32; self.layer_size / std::mem::size_of::<u32>()]
+        };
+        self.resident.push_back((idx, layer));
+        self.num_layers = idx;
+
+        let layer = self
+            .resident
+            .back_mut()
+            .expect("layer was just added")
+            .1
+            .as_mut_byte_slice();
+        poison_labels(layer);
+        Ok(layer)
+    }
+
+    fn end_layer(&mut self, _idx: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn finished_layer(&self, idx: usize) -> Option<&[u8]> {
+        self.resident_layer(idx)
+    }
+}
+
+impl Drop for SpillingLayerSink {
+    fn drop(&mut self) {
+        if let Err(err) = remove_file(&self.spill_path) {
+            warn!("failed to remove {}: {:?}", self.spill_path.display(), err);
+        }
+    }
+}
 
 /// Prepares the necessary `StoreConfig`s with which the layers are stored.
 /// Also checks for already existing layers and marks them as such.
@@ -100,3 +391,257 @@ pub fn is_layer_written<Tree: 'static + MerkleTreeTrait>(
 
     Ok(true)
 }
+
+/// Name of the labeling checkpoint in the cache directory, see `LabelingCheckpoint`.
+pub const CHECKPOINT_FILE: &str = "p1-checkpoint.json";
+
+/// Size of the chunks `layer_fingerprint` hashes independently, in bytes.
+const FINGERPRINT_CHUNK_SIZE: usize = 1 << 20;
+
+fn chunk_digest(chunk: &[u8]) -> [u8; 32] {
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(
+        Blake2bParams::new()
+            .hash_length(32)
+            .hash(chunk)
+            .as_bytes(),
+    );
+    digest
+}
+
+/// Digest over the length of `layer` and all of its labels, hashed in chunks of
+/// `FINGERPRINT_CHUNK_SIZE` bytes in parallel, so that any changed label is noticed.
+pub fn layer_fingerprint(layer: &[u8]) -> [u8; 32] {
+    let digests = layer
+        .par_chunks(FINGERPRINT_CHUNK_SIZE)
+        .map(chunk_digest)
+        .collect::<Vec<_>>();
+
+    let mut hasher = Blake2bParams::new().hash_length(32).to_state();
+    hasher.update(&(layer.len() as u64).to_le_bytes());
+    for digest in &digests {
+        hasher.update(digest);
+    }
+
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(hasher.finalize().as_bytes());
+    fingerprint
+}
+
+/// `layer_fingerprint` of the layer stored with `config`, reading it chunk by chunk.
+fn stored_layer_fingerprint(config: &StoreConfig, nodes: usize) -> Result<[u8; 32]> {
+    let data_path = StoreConfig::data_path(&config.path, &config.id);
+    let mut file = BufReader::new(File::open(data_path).context("failed to open layer")?);
+
+    let len = nodes * NODE_SIZE;
+    let mut hasher = Blake2bParams::new().hash_length(32).to_state();
+    hasher.update(&(len as u64).to_le_bytes());
+    let mut chunk = vec![0u8; FINGERPRINT_CHUNK_SIZE.min(len)];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = &mut chunk[..FINGERPRINT_CHUNK_SIZE.min(len - offset)];
+        file.read_exact(chunk).context("failed to read layer")?;
+        hasher.update(&chunk_digest(chunk));
+        offset += chunk.len();
+    }
+
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(hasher.finalize().as_bytes());
+    Ok(fingerprint)
+}
+
+/// Progress of labeling a replica, stored as `CHECKPOINT_FILE` next to the layers after each
+/// layer, so that an interrupted P1 can continue after the last completed layer instead of
+/// starting over, see `resume_layers`. There are no checkpoints within a layer: an interrupted
+/// layer is labeled again from its first node, as the parents cache is read in windows from the
+/// start of the layer and the labels of a partial layer are not persisted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelingCheckpoint {
+    /// The replica id, hex encoded.
+    pub replica_id: String,
+    /// Nodes per layer.
+    pub nodes: u64,
+    /// `layer_fingerprint` of each completed layer, hex encoded, starting with layer 1.
+    pub layers: Vec<String>,
+}
+
+impl LabelingCheckpoint {
+    /// A checkpoint without any completed layer.
+    pub fn new(replica_id: &ReplicaId, nodes: u64) -> Self {
+        LabelingCheckpoint {
+            replica_id: hex::encode(&replica_id.0),
+            nodes,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Loads the checkpoint from `dir`, `None` if there is none or it can not be read.
+    pub fn load(dir: &Path) -> Option<Self> {
+        let path = dir.join(CHECKPOINT_FILE);
+        let data = fs::read(&path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(checkpoint) => Some(checkpoint),
+            Err(err) => {
+                warn!("ignoring invalid checkpoint {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Stores the checkpoint in `dir`, atomically like `write_layer`.
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(CHECKPOINT_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let data = serde_json::to_vec(self).context("failed to serialize checkpoint")?;
+        fs::write(&tmp_path, data).context("failed to write checkpoint")?;
+        rename(tmp_path, path).context("failed to rename tmp checkpoint")?;
+
+        Ok(())
+    }
+
+    /// Whether the checkpoint is about the labeling of `replica_id` with `nodes` per layer.
+    pub fn matches(&self, replica_id: &ReplicaId, nodes: u64) -> bool {
+        self.replica_id == hex::encode(&replica_id.0) && self.nodes == nodes
+    }
+
+    /// Records `data` as the next completed layer, `layer`, which must follow the last one.
+    pub fn record_layer(&mut self, layer: usize, data: &[u8]) {
+        assert_eq!(layer, self.layers.len() + 1, "layers must be recorded in order");
+        self.layers.push(hex::encode(layer_fingerprint(data)));
+    }
+}
+
+/// Like `prepare_layers`, but for resuming labeling: a layer on disk only counts as generated
+/// if the checkpoint of `replica_id` in the cache directory lists it with the fingerprint of
+/// the stored data. The first layer failing this and all after it are labeled again, as each
+/// layer depends on the previous one. Also returns the checkpoint to continue recording into.
+/// Without `resume` all layers are labeled again.
+pub fn resume_layers<Tree: 'static + MerkleTreeTrait>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    config: &StoreConfig,
+    layers: usize,
+    replica_id: &ReplicaId,
+    resume: bool,
+) -> (Vec<LayerState>, LabelingCheckpoint) {
+    let nodes = graph.size();
+    let mut checkpoint = LabelingCheckpoint::new(replica_id, nodes as u64);
+    let previous = if resume {
+        LabelingCheckpoint::load(&config.path).filter(|c| c.matches(replica_id, nodes as u64))
+    } else {
+        None
+    };
+
+    let mut states = prepare_layers::<Tree>(graph, config, layers);
+    let mut valid = previous.is_some();
+    for (idx, state) in states.iter_mut().enumerate() {
+        let recorded = previous.as_ref().and_then(|c| c.layers.get(idx));
+        valid = valid
+            && state.generated
+            && match (recorded, stored_layer_fingerprint(&state.config, nodes)) {
+                (Some(recorded), Ok(stored)) => *recorded == hex::encode(stored),
+                _ => false,
+            };
+        if valid {
+            checkpoint.layers.push(recorded.expect("recorded layer").clone());
+        } else {
+            if resume && state.generated {
+                warn!("layer {} on disk does not match the checkpoint, labeling it again", idx + 1);
+            }
+            state.generated = false;
+        }
+    }
+    if resume {
+        info!("resuming labeling after layer {}", checkpoint.layers.len());
+    }
+
+    (states, checkpoint)
+}
+
+/// Digest over all `layers`, in the given order, to check cheaply that two implementations or
+/// versions produced identical labels. Each layer is preceded by its length in bytes (as a
+/// little-endian `u64`), so different splits of the same bytes into layers do not collide.
+pub fn labeling_commitment(layers: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Blake2bParams::new().hash_length(32).to_state();
+    for layer in layers {
+        hasher.update(&(layer.len() as u64).to_le_bytes());
+        hasher.update(layer);
+    }
+
+    let mut commitment = [0u8; 32];
+    commitment.copy_from_slice(hasher.finalize().as_bytes());
+    commitment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labeling_commitment() {
+        let a = [1u8; 64];
+        let b = [2u8; 64];
+        let commitment = labeling_commitment(&[&a, &b]);
+
+        let mut expected = Blake2bParams::new().hash_length(32).to_state();
+        expected.update(&64u64.to_le_bytes());
+        expected.update(&a);
+        expected.update(&64u64.to_le_bytes());
+        expected.update(&b);
+        assert_eq!(&commitment[..], expected.finalize().as_bytes());
+
+        assert_eq!(commitment, labeling_commitment(&[&a, &b]));
+        assert_ne!(commitment, labeling_commitment(&[&b, &a]));
+        assert_ne!(commitment, labeling_commitment(&[&a[..32], &a[32..], &b]));
+    }
+
+    #[test]
+    fn test_layer_fingerprint() {
+        let nodes = FINGERPRINT_CHUNK_SIZE / NODE_SIZE * 2 + 3;
+        let layer: Vec<u8> = (0..nodes * NODE_SIZE).map(|i| (i % 251) as u8).collect();
+        let fingerprint = layer_fingerprint(&layer);
+
+        // Every label is covered.
+        for node in &[0, 1, FINGERPRINT_CHUNK_SIZE / NODE_SIZE, nodes - 1] {
+            let mut changed = layer.clone();
+            changed[node * NODE_SIZE + 7] ^= 1;
+            assert_ne!(layer_fingerprint(&changed), fingerprint);
+        }
+        assert_ne!(layer_fingerprint(&layer[..layer.len() - NODE_SIZE]), fingerprint);
+
+        // The same as read from disk.
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let config = StoreConfig::new(dir.path(), CacheKey::label_layer(1), 0);
+        fs::write(StoreConfig::data_path(&config.path, &config.id), &layer)
+            .expect("failed to write layer");
+        assert_eq!(
+            stored_layer_fingerprint(&config, nodes).expect("failed to read layer"),
+            fingerprint
+        );
+        assert!(stored_layer_fingerprint(&config, nodes + 1).is_err());
+    }
+
+    #[test]
+    fn test_labeling_checkpoint() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let replica_id = ReplicaId([3u8; 32]);
+        assert_eq!(LabelingCheckpoint::load(dir.path()), None);
+
+        let mut checkpoint = LabelingCheckpoint::new(&replica_id, 8);
+        checkpoint.record_layer(1, &[1u8; 8 * NODE_SIZE]);
+        checkpoint.record_layer(2, &[2u8; 8 * NODE_SIZE]);
+        checkpoint.store(dir.path()).expect("failed to store checkpoint");
+
+        let loaded = LabelingCheckpoint::load(dir.path()).expect("checkpoint stored");
+        assert_eq!(loaded, checkpoint);
+        assert_eq!(loaded.layers.len(), 2);
+        assert!(loaded.matches(&replica_id, 8));
+        assert!(!loaded.matches(&ReplicaId([4u8; 32]), 8));
+        assert!(!loaded.matches(&replica_id, 16));
+    }
+
+    #[test]
+    #[should_panic(expected = "layers must be recorded in order")]
+    fn test_labeling_checkpoint_order() {
+        LabelingCheckpoint::new(&ReplicaId([3u8; 32]), 8).record_layer(2, &[0u8; 8 * NODE_SIZE]);
+    }
+}
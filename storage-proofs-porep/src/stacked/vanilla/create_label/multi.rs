@@ -1,46 +1,76 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::marker::PhantomData;
 use std::mem::{self, size_of};
+use std::ops::Range;
+use std::path::Path;
 use std::sync::{
-    atomic::{AtomicU64, Ordering::SeqCst},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering::SeqCst},
+    Arc, Mutex,
 };
 use std::thread;
 use std::time::{Instant, Duration};
 
-use anyhow::{Context, Result};
-use byte_slice_cast::{AsByteSlice, AsMutSliceOf};
+use anyhow::{ensure, Context, Result};
+use bellperson::bls::Fr;
+use byte_slice_cast::{AsByteSlice, AsMutByteSlice, AsMutSliceOf, AsSliceOf};
 use filecoin_hashers::Hasher;
+use fr32::bytes_into_fr;
 use generic_array::{
     typenum::{Unsigned, U64},
     GenericArray,
 };
-use log::{debug, info};
-use mapr::MmapMut;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
 use merkletree::store::{DiskStore, Store, StoreConfig};
 use storage_proofs_core::{
     cache_key::CacheKey,
     drgraph::{Graph, BASE_DEGREE},
     merkle::MerkleTreeTrait,
+    progress::{self, ProgressEvent},
     settings::SETTINGS,
     util::NODE_SIZE,
 };
 
 use crate::stacked::vanilla::{
     cache::ParentCache,
-    cores::{bind_core, get_p1_core_group, CoreIndex},
-    create_label::{prepare_layers, read_layer, write_layer},
+    cores::{
+        bind_core, get_p1_core_group_for, logical_cpu_count, record_binding_summary,
+        BindingSummary, CoreGroup, CoreIndex,
+    },
+    create_label::{
+        prepare_layers, read_layer, resume_layers,
+        trace::{self, Stage},
+        LabelingError, LayerSink, MemoryLayerSink, SpillingLayerSink,
+        write_layer,
+    },
     graph::{StackedBucketGraph, DEGREE, EXP_DEGREE},
-    memory_handling::{setup_create_label_memory, CacheReader},
+    hash::hash_single_column,
+    memory_handling::{allocate_layer_near, setup_create_label_memory, CacheReader},
     params::{Labels, LabelsCache},
+    phase::Phase,
     proof::LayerState,
-    utils::{memset, prepare_block, BitMask, RingBuf, UnsafeSlice},
+    utils::{
+        clamp_label_chunk, debug_assert_labeled, label_chunk, memset, multicore_sdr_lookahead,
+        multicore_sdr_producer_stride, multicore_sdr_producers, parent_cache_prefetch,
+        poison_labels, prefetch_depth, prefetch_slots, prepare_block, producers_for_nodes,
+        ring_slots_for_nodes, throttle, BitMask, LabelingThreads, ReplicaId, RingBuf, SlotBuffer,
+        UnsafeSlice,
+    },
 };
 
 const MIN_BASE_PARENT_NODE: u64 = 2000;
 
+/// Number of labels of the final layer `create_labels_with_column_hashes` collects before handing
+/// them to a hashing thread.
+const COLUMN_HASH_BATCH: usize = 1 << 12;
+
+/// Callback receiving `(node, layer, label)` for every label as soon as it is computed, see
+/// `create_labels_with_callback`.
+pub type OnLabel<'a> = &'a (dyn Fn(u64, u32, &[u8; NODE_SIZE]) + Sync);
+
 const NODE_WORDS: usize = NODE_SIZE / size_of::<u32>();
 const SHA_BLOCK_SIZE: usize = 64;
+const BYTES_PER_NODE: usize = (NODE_SIZE * DEGREE) + SHA_BLOCK_SIZE;
 
 const SHA256_INITIAL_DIGEST: [u32; 8] = [
     0x6a09_e667,
@@ -53,13 +83,120 @@ const SHA256_INITIAL_DIGEST: [u32; 8] = [
     0x5be0_cd19,
 ];
 
+/// Counts how well the producers kept ahead of the hashing thread.
+///
+/// A slot is a "hit" if the producers had already filled it when the hashing thread got to it,
+/// and a "miss" if the hashing thread had to wait for it. Base parents which the producers could
+/// not prefetch (because they were not labeled yet) are read by the hashing thread itself and
+/// counted as `parents_missing`. Note that the last base parent, which is always the preceding
+/// node, can never be prefetched.
+#[cfg(feature = "labeling-stats")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PrefetchStats {
+    pub slot_hits: u64,
+    pub slot_misses: u64,
+    pub parents_prefetched: u64,
+    pub parents_missing: u64,
+}
+
+#[cfg(feature = "labeling-stats")]
+impl PrefetchStats {
+    fn add(&mut self, other: &PrefetchStats) {
+        self.slot_hits += other.slot_hits;
+        self.slot_misses += other.slot_misses;
+        self.parents_prefetched += other.parents_prefetched;
+        self.parents_missing += other.parents_missing;
+    }
+}
+
+lazy_static! {
+    /// Whether `FIL_PROOFS_SHA_IMPL` forces a SHA-256 implementation, read once per process.
+    static ref SHA_IMPL_FORCED: bool = {
+        let forced = sha2raw::ShaImpl::from_env() != sha2raw::ShaImpl::Auto;
+        if forced {
+            info!("labeling with the {} SHA-256 implementation", sha2raw::implementation_name());
+        }
+        forced
+    };
+}
+
+/// SHA-256 compression of `blocks` into `state`. Unless an implementation is forced with
+/// `FIL_PROOFS_SHA_IMPL`, this is the `sha2` crate's, which picks the fastest one on its own.
+#[inline(always)]
+fn compress_blocks(state: &mut [u32; 8], blocks: &[GenericArray<u8, U64>]) {
+    if *SHA_IMPL_FORCED {
+        for block in blocks {
+            sha2raw::compress256(state, &[&block[..32], &block[32..]]);
+        }
+    } else {
+        sha2::compress256(state, blocks);
+    }
+}
+
+lazy_static! {
+    static ref SHA_ACCELERATION: bool = if *SHA_IMPL_FORCED {
+        sha2raw::implementation_name() == "sha-ni"
+    } else {
+        cpu_has_sha_extensions()
+    };
+}
+
+/// Whether the labeling hash uses the CPU's SHA-256 instructions, SHA-NI on x86 or the ARMv8
+/// crypto extensions, e.g. to explain slow labeling in diagnostics. Detected once per process,
+/// taking `FIL_PROOFS_SHA_IMPL` into account. Always `false` on other platforms.
+pub fn sha_acceleration_active() -> bool {
+    *SHA_ACCELERATION
+}
+
+/// Whether the CPU has the instructions the `sha2` crate needs for its accelerated backend.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn cpu_has_sha_extensions() -> bool {
+    is_x86_feature_detected!("sha")
+        && is_x86_feature_detected!("sse2")
+        && is_x86_feature_detected!("ssse3")
+        && is_x86_feature_detected!("sse4.1")
+}
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+fn cpu_has_sha_extensions() -> bool {
+    // `HWCAP_SHA2` of the Linux aarch64 ABI.
+    const HWCAP_SHA2: libc::c_ulong = 1 << 6;
+    unsafe { libc::getauxval(libc::AT_HWCAP) & HWCAP_SHA2 != 0 }
+}
+
+#[cfg(not(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    all(target_arch = "aarch64", target_os = "linux")
+)))]
+fn cpu_has_sha_extensions() -> bool {
+    false
+}
+
+#[cfg(feature = "labeling-stats")]
+lazy_static! {
+    static ref PREFETCH_STATS: Mutex<PrefetchStats> = Mutex::new(PrefetchStats::default());
+}
+
+/// Returns the prefetch statistics accumulated over all layers labeled so far in this process.
+#[cfg(feature = "labeling-stats")]
+pub fn prefetch_stats() -> PrefetchStats {
+    *PREFETCH_STATS.lock().expect("poisoned lock")
+}
+
+/// Resets the accumulated prefetch statistics.
+#[cfg(feature = "labeling-stats")]
+pub fn reset_prefetch_stats() {
+    *PREFETCH_STATS.lock().expect("poisoned lock") = PrefetchStats::default();
+}
+
 #[inline]
 fn fill_buffer(
     cur_node: u64,
     parents_cache: &CacheReader<u32>,
     mut cur_parent: &[u32], // parents for this node
     layer_labels: &UnsafeSlice<'_, u32>,
-    exp_labels: Option<&UnsafeSlice<'_, u32>>, // None for layer0
+    exp_labels: Option<&[u32]>, // None for layer0
     buf: &mut [u8],
     base_parent_missing: &mut BitMask,
 ) {
@@ -93,6 +230,7 @@ fn fill_buffer(
                         let offset = cur_parent[0] as usize * NODE_WORDS;
                         &layer_labels.as_slice()[offset..offset + NODE_WORDS]
                     };
+                    debug_assert_labeled(cur_parent[0] as u64, parent_data.as_byte_slice());
                     let a = SHA_BLOCK_SIZE + (NODE_SIZE * k);
                     buf[a..a + NODE_SIZE].copy_from_slice(parent_data.as_byte_slice());
                 };
@@ -111,10 +249,9 @@ fn fill_buffer(
     if let Some(exp_labels) = exp_labels {
         // Read from each of the expander parent nodes
         for k in BASE_DEGREE..DEGREE {
-            let parent_data = unsafe {
-                let offset = cur_parent[0] as usize * NODE_WORDS;
-                &exp_labels.as_slice()[offset..offset + NODE_WORDS]
-            };
+            let offset = cur_parent[0] as usize * NODE_WORDS;
+            let parent_data = &exp_labels[offset..offset + NODE_WORDS];
+            debug_assert_labeled(cur_parent[0] as u64, parent_data.as_byte_slice());
             let a = SHA_BLOCK_SIZE + (NODE_SIZE * k);
             buf[a..a + NODE_SIZE].copy_from_slice(parent_data.as_byte_slice());
             cur_parent = &cur_parent[1..];
@@ -122,6 +259,193 @@ fn fill_buffer(
     }
 }
 
+/// Hashes the parents placed in `buf` into the node state `cur_node_ptr`, which must already
+/// contain the state after hashing the first block (`replica_id || layer || node`), and
+/// finalizes the label.
+#[inline]
+fn hash_node(cur_node_ptr: &mut [u32], buf: &mut [u8], cur_layer: u32) {
+    if cur_layer == 1 {
+        // Six rounds of all base parents
+        for _j in 0..6 {
+            compress256!(cur_node_ptr, &buf[64..], 3);
+        }
+
+        // round 7 is only first parent
+        memset(&mut buf[96..128], 0); // Zero out upper half of last block
+        buf[96] = 0x80; // Padding
+        buf[126] = 0x27;
+        // Length (0x2700 = 9984 bits -> 1248 bytes)
+        compress256!(cur_node_ptr, &buf[64..], 1);
+    } else {
+        // Two rounds of all parents
+        let blocks = [
+            *GenericArray::<u8, U64>::from_slice(&buf[64..128]),
+            *GenericArray::<u8, U64>::from_slice(&buf[128..192]),
+            *GenericArray::<u8, U64>::from_slice(&buf[192..256]),
+            *GenericArray::<u8, U64>::from_slice(&buf[256..320]),
+            *GenericArray::<u8, U64>::from_slice(&buf[320..384]),
+            *GenericArray::<u8, U64>::from_slice(&buf[384..448]),
+            *GenericArray::<u8, U64>::from_slice(&buf[448..512]),
+        ];
+        compress_blocks(
+            (&mut cur_node_ptr[..8])
+                .try_into()
+                .expect("compress failed"),
+            &blocks,
+        );
+        compress_blocks(
+            (&mut cur_node_ptr[..8])
+                .try_into()
+                .expect("compress failed"),
+            &blocks,
+        );
+
+        // Final round is only nine parents
+        memset(&mut buf[352..384], 0); // Zero out upper half of last block
+        buf[352] = 0x80; // Padding
+        buf[382] = 0x27;
+        // Length (0x2700 = 9984 bits -> 1248 bytes)
+        compress256!(cur_node_ptr, &buf[64..], 5);
+    }
+
+    // Fix endianess
+    cur_node_ptr[..8].iter_mut().for_each(|x| *x = x.to_be());
+
+    cur_node_ptr[7] &= 0x3FFF_FFFF; // Strip last two bits to fit in Fr
+}
+
+/// Computes the label of a single node, using the same hashing as the multicore labeling.
+///
+/// `parents` are the labels of the node's parents, in the order given by the parents cache:
+/// the `BASE_DEGREE` base parents from the current layer, followed (for all but the first layer)
+/// by the `EXP_DEGREE` expander parents from the previous layer. Node 0 has no parents.
+pub fn label_node(
+    replica_id: &ReplicaId,
+    layer: u32,
+    node: u64,
+    parents: &[&[u8]],
+) -> [u8; NODE_SIZE] {
+    label_node_from(replica_id, layer, node, parents)
+}
+
+/// `label_node` for parent labels that are already known, e.g. in known-answer tests or when
+/// relabeling a node whose parents were looked up before, so no graph is needed.
+pub fn label_node_with_parents(
+    replica_id: &ReplicaId,
+    layer: u32,
+    node: u64,
+    parent_labels: &[[u8; NODE_SIZE]],
+) -> [u8; NODE_SIZE] {
+    label_node_from(replica_id, layer, node, parent_labels)
+}
+
+fn label_node_from<P: AsRef<[u8]>>(
+    replica_id: &ReplicaId,
+    layer: u32,
+    node: u64,
+    parents: &[P],
+) -> [u8; NODE_SIZE] {
+    let mut buf = [0u8; BYTES_PER_NODE];
+    prepare_block(replica_id, layer, &mut buf);
+
+    let mut state = [0u32; NODE_WORDS];
+    state.copy_from_slice(&SHA256_INITIAL_DIGEST);
+
+    if node == 0 {
+        assert!(parents.is_empty(), "node 0 has no parents");
+        compress256!(state, buf, 2);
+
+        // Fix endianess
+        state.iter_mut().for_each(|x| *x = x.to_be());
+
+        state[7] &= 0x3FFF_FFFF; // Strip last two bits to ensure in Fr
+    } else {
+        let degree = if layer == 1 { BASE_DEGREE } else { DEGREE };
+        assert_eq!(parents.len(), degree, "invalid number of parents for layer {}", layer);
+
+        buf[36..44].copy_from_slice(&node.to_be_bytes());
+        compress256!(state, buf, 1);
+
+        for (k, parent) in parents.iter().enumerate() {
+            let start = SHA_BLOCK_SIZE + (NODE_SIZE * k);
+            buf[start..start + NODE_SIZE].copy_from_slice(parent.as_ref());
+        }
+        hash_node(&mut state, &mut buf, layer);
+    }
+
+    let mut label = [0u8; NODE_SIZE];
+    label.copy_from_slice(state.as_byte_slice());
+    label
+}
+
+/// Recomputes the labels of `nodes` in `layer_labels`, e.g. to repair a corrupted region.
+///
+/// All labels of `layer_labels` before `nodes.start` must be correct, as well as all labels of
+/// `exp_labels` (the previous layer), which is required for all but the first layer. Nodes are
+/// relabeled in order, so parents inside of `nodes` are picked up after they have been repaired.
+pub fn relabel_range<H: Hasher>(
+    graph: &StackedBucketGraph<H>,
+    replica_id: &[u8],
+    layer: usize,
+    nodes: Range<usize>,
+    layer_labels: &mut [u8],
+    exp_labels: Option<&[u8]>,
+) -> Result<()> {
+    ensure!(layer > 0, "layers start at 1");
+    let replica_id = ReplicaId::try_from(replica_id)?;
+    ensure!(
+        nodes.end <= graph.size(),
+        "node range {:?} out of bounds for {} nodes",
+        nodes,
+        graph.size()
+    );
+    ensure!(
+        layer_labels.len() == graph.size() * NODE_SIZE,
+        "invalid layer size"
+    );
+    let exp_labels = if layer == 1 {
+        None
+    } else {
+        let exp_labels = exp_labels.context("previous layer is required for expander parents")?;
+        ensure!(
+            exp_labels.len() == graph.size() * NODE_SIZE,
+            "invalid previous layer size"
+        );
+        Some(exp_labels)
+    };
+    let node_label = |labels: &[u8], node: u32| {
+        let start = node as usize * NODE_SIZE;
+        labels[start..start + NODE_SIZE].to_vec()
+    };
+
+    let mut parents = vec![0u32; DEGREE];
+    for node in nodes {
+        let parent_labels = if node == 0 {
+            Vec::new()
+        } else {
+            graph.parents(node, &mut parents)?;
+            let mut parent_labels = parents[..BASE_DEGREE]
+                .iter()
+                .map(|&parent| node_label(layer_labels, parent))
+                .collect::<Vec<_>>();
+            if let Some(exp_labels) = exp_labels {
+                parent_labels.extend(
+                    parents[BASE_DEGREE..]
+                        .iter()
+                        .map(|&parent| node_label(exp_labels, parent)),
+                );
+            }
+            parent_labels
+        };
+        let parent_labels = parent_labels.iter().map(Vec::as_slice).collect::<Vec<_>>();
+
+        let label = label_node(&replica_id, layer as u32, node as u64, &parent_labels);
+        layer_labels[node * NODE_SIZE..(node + 1) * NODE_SIZE].copy_from_slice(&label);
+    }
+
+    Ok(())
+}
+
 // This implements a producer, i.e. a thread that pre-fills the buffer
 // with parent node data.
 // - cur_consumer - The node currently being processed (consumed) by the
@@ -138,21 +462,27 @@ fn fill_buffer(
 //                         be filled in. This is an array of size lookahead.
 // - is_layer0    - Indicates first (no expander parents) or subsequent layer
 #[allow(clippy::too_many_arguments)]
-fn create_label_runner(
+fn create_label_runner<B: SlotBuffer>(
     parents_cache: &CacheReader<u32>,
     layer_labels: &UnsafeSlice<'_, u32>,
-    exp_labels: Option<&UnsafeSlice<'_, u32>>, // None for layer 0
+    exp_labels: Option<&[u32]>, // None for layer 0
     num_nodes: u64,
     cur_producer: &AtomicU64,
     cur_awaiting: &AtomicU64,
     stride: u64,
     lookahead: u64,
-    ring_buf: &RingBuf,
+    ring_buf: &B,
     base_parent_missing: &UnsafeSlice<'_, BitMask>,
+    cancel: &AtomicBool,
 ) {
     info!("created label runner");
+    debug_assert_eq!(lookahead as usize, ring_buf.num_slots());
     // Label data bytes per node
     loop {
+        if cancel.load(SeqCst) {
+            break;
+        }
+        throttle();
         // Get next work items
         let work = cur_awaiting.fetch_add(stride, SeqCst);
         if work >= num_nodes {
@@ -172,6 +502,9 @@ fn create_label_runner(
 
             // Don't overrun the buffer
             while cur_node > (parents_cache.get_consumer() + lookahead - 1) {
+                if cancel.load(SeqCst) {
+                    return;
+                }
                 thread::sleep(Duration::from_micros(10));
             }
 
@@ -188,10 +521,14 @@ fn create_label_runner(
                 buf,
                 bpm,
             );
+            ring_buf.release_slot(cur_slot as usize);
         }
 
         // Wait for the previous node to finish
         while work > (cur_producer.load(SeqCst) + 1) {
+            if cancel.load(SeqCst) {
+                return;
+            }
             thread::sleep(Duration::from_micros(10));
         }
 
@@ -200,33 +537,147 @@ fn create_label_runner(
     }
 }
 
+/// Panics unless `buf` holds the label of every one of the `parents` of `node`. A parent which
+/// neither a producer filled in nor was marked missing would otherwise be hashed from stale data,
+/// giving a wrong label without any error. Expander parents are not checked in the first layer,
+/// which does not use them.
+#[cfg(debug_assertions)]
+fn assert_parents_filled(
+    node: u64,
+    buf: &[u8],
+    parents: &[u32],
+    layer_labels: &UnsafeSlice<'_, u32>,
+    exp_labels: Option<&[u32]>,
+) {
+    let mut filled = BitMask::default();
+    for (k, &parent) in parents.iter().enumerate() {
+        let start = parent as usize * NODE_WORDS;
+        let expected = if k < BASE_DEGREE {
+            // Safety: parents of `node` are labeled before it and not modified anymore.
+            unsafe { &layer_labels.as_slice()[start..start + NODE_WORDS] }
+        } else {
+            match exp_labels {
+                Some(exp_labels) => &exp_labels[start..start + NODE_WORDS],
+                None => {
+                    filled.set(k);
+                    continue;
+                }
+            }
+        };
+        let offset = SHA_BLOCK_SIZE + NODE_SIZE * k;
+        if &buf[offset..offset + NODE_SIZE] == expected.as_byte_slice() {
+            filled.set(k);
+        }
+    }
+
+    if filled.count_ones() != parents.len() as u32 {
+        let missing: Vec<usize> = (0..parents.len()).filter(|&k| !filled.get(k)).collect();
+        panic!("node {}: parents {:?} were not filled in before hashing", node, missing);
+    }
+}
+
+/// Estimates how many nodes of a layer after the first a single core labels per second, by
+/// hashing `nodes` synthetic nodes on the calling thread. Parent gathering is not included, so
+/// this is an upper bound for the real rate.
+pub(crate) fn estimate_node_rate(nodes: usize) -> f64 {
+    let mut buf = [0u8; BYTES_PER_NODE];
+    prepare_block(&ReplicaId([0x5a; 32]), 2, &mut buf);
+    let mut label = [0u32; NODE_WORDS];
+
+    let start = Instant::now();
+    for node in 0..nodes {
+        buf[36..44].copy_from_slice(&(node as u64).to_be_bytes());
+        label[..8].copy_from_slice(&SHA256_INITIAL_DIGEST);
+        compress256!(label, buf, 1);
+        hash_node(&mut label, &mut buf, 2);
+    }
+    let elapsed = start.elapsed();
+
+    // Keep the hashing from being optimized away.
+    let _ = unsafe { std::ptr::read_volatile(&label[0]) };
+    nodes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+/// The finished label in `cur_node_ptr`, as its bytes.
+#[inline]
+fn node_label(cur_node_ptr: &[u32]) -> &[u8; NODE_SIZE] {
+    cur_node_ptr[..NODE_WORDS]
+        .as_byte_slice()
+        .try_into()
+        .expect("label is NODE_SIZE bytes")
+}
+
+/// Whether `labels` can be labeled into, which accesses it as `u32`s.
+fn is_label_aligned(labels: &[u8]) -> bool {
+    labels.as_ptr() as usize % mem::align_of::<u32>() == 0
+}
+
+/// The stride of the producers and the ring buffer slots for labeling a layer of `num_nodes`
+/// nodes with `num_producers` producers claiming `chunk` nodes at a time. Each producer may run
+/// `depth` strides ahead, up to the `lookahead` setting and the `window_nodes` of the parents
+/// cache window.
+fn producer_ring(
+    num_nodes: u64,
+    num_producers: usize,
+    chunk: u64,
+    depth: usize,
+    lookahead: usize,
+    window_nodes: usize,
+) -> (u64, usize) {
+    // NOTE: Stride must not exceed the number of nodes in parents_cache's window. If it does, the process will deadlock
+    // with producers and consumers waiting for each other.
+    let stride = clamp_label_chunk(chunk, num_nodes, num_producers).min(window_nodes as u64);
+    let slots = ring_slots_for_nodes(
+        num_nodes,
+        prefetch_slots(
+            num_producers,
+            stride as usize,
+            depth,
+            lookahead,
+            window_nodes,
+        ),
+    );
+
+    // Likewise, a producer only hands its stride to the hashing thread once all of it is filled,
+    // so the stride must fit the ring.
+    (stride.min(slots as u64), slots)
+}
+
+/// Labels layer `cur_layer` into `layer_labels`. Returns the number of threads which labeled
+/// it in parallel, the producers spawned and the hashing thread.
+#[allow(clippy::too_many_arguments)]
 fn create_layer_labels(
     parents_cache: &CacheReader<u32>,
-    replica_id: &[u8],
-    layer_labels: &mut MmapMut,
-    exp_labels: Option<&mut MmapMut>,
+    replica_id: &ReplicaId,
+    layer_labels: &mut [u8],
+    exp_labels: Option<&[u8]>,
     num_nodes: u64,
     cur_layer: u32,
     core_group: Arc<Option<Vec<CoreIndex>>>,
-) {
+    num_producers: usize,
+    cancel: &AtomicBool,
+    on_label: Option<OnLabel<'_>>,
+) -> Result<usize> {
     info!("Creating labels for layer {}", cur_layer);
-    // num_producers is the number of producer threads
-    let (lookahead, num_producers, producer_stride) = {
-        let settings = &SETTINGS;
-        let lookahead = settings.multicore_sdr_lookahead;
-        let num_producers = settings.multicore_sdr_producers;
-        // NOTE: Stride must not exceed the number of nodes in parents_cache's window. If it does, the process will deadlock
-        // with producers and consumers waiting for each other.
-        let producer_stride = settings
-            .multicore_sdr_producer_stride
-            .min(parents_cache.window_nodes() as u64);
-
-        (lookahead, num_producers, producer_stride)
-    };
-
-    const BYTES_PER_NODE: usize = (NODE_SIZE * DEGREE) + SHA_BLOCK_SIZE;
+    assert!(num_producers > 0, "labeling needs at least one producer");
+    // Held until the layer is labeled, see `set_max_labeling_threads`.
+    let threads = LabelingThreads::acquire(num_producers + 1);
+    let num_producers = producers_for_nodes(num_nodes, threads.count() - 1);
+    let (producer_stride, num_slots) = producer_ring(
+        num_nodes,
+        num_producers,
+        label_chunk().unwrap_or(multicore_sdr_producer_stride()),
+        prefetch_depth(),
+        multicore_sdr_lookahead(),
+        parents_cache.window_nodes(),
+    );
+    let mut ring_buf = RingBuf::new_near(BYTES_PER_NODE, num_slots, (*core_group).as_deref());
+    let lookahead = ring_buf.num_slots();
+    debug!("using a lookahead of {} nodes", lookahead);
+    if parent_cache_prefetch() {
+        parents_cache.set_prefetch_nodes(lookahead);
+    }
 
-    let mut ring_buf = RingBuf::new(BYTES_PER_NODE, lookahead);
     let mut base_parent_missing = vec![BitMask::default(); lookahead];
 
     // Fill in the fixed portion of all buffers
@@ -245,17 +696,17 @@ fn create_layer_labels(
             .as_mut_slice_of::<u32>()
             .expect("failed as mut slice of"),
     );
-    let exp_labels = exp_labels.map(|m| {
-        UnsafeSlice::from_slice(m.as_mut_slice_of::<u32>().expect("failed as mut slice of"))
-    });
+    let exp_labels = exp_labels.map(|m| m.as_slice_of::<u32>().expect("failed as slice of"));
     let base_parent_missing = UnsafeSlice::from_slice(&mut base_parent_missing);
 
+    let progress = progress::current();
+    let report_every = (num_nodes / 100).max(1);
+
     crossbeam::thread::scope(|s| {
         let mut runners = Vec::with_capacity(num_producers);
 
         for i in 0..num_producers {
             let layer_labels = &layer_labels;
-            let exp_labels = exp_labels.as_ref();
             let cur_producer = &cur_producer;
             let cur_awaiting = &cur_awaiting;
             let ring_buf = &ring_buf;
@@ -271,20 +722,24 @@ fn create_layer_labels(
                 // It will be logged as a warning by `bind_core`.
                 debug!("binding core in producer thread {}", i);
                 // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
-                let _cleanup_handle = core_index.map(|c| bind_core(*c));
-
-                create_label_runner(
-                    parents_cache,
-                    layer_labels,
-                    exp_labels,
-                    num_nodes,
-                    cur_producer,
-                    cur_awaiting,
-                    producer_stride,
-                    lookahead as u64,
-                    ring_buf,
-                    base_parent_missing,
-                )
+                let _cleanup_handle = core_index
+                    .map(|c| trace::time(Some(cur_layer), Stage::Bind, || bind_core(*c)));
+
+                trace::time(Some(cur_layer), Stage::Gather, || {
+                    create_label_runner(
+                        parents_cache,
+                        layer_labels,
+                        exp_labels,
+                        num_nodes,
+                        cur_producer,
+                        cur_awaiting,
+                        producer_stride,
+                        lookahead as u64,
+                        ring_buf,
+                        base_parent_missing,
+                        cancel,
+                    )
+                })
             }));
         }
         let mut cur_node_ptr = unsafe { layer_labels.as_mut_slice() };
@@ -304,29 +759,42 @@ fn create_layer_labels(
         cur_node_ptr[..8].iter_mut().for_each(|x| *x = x.to_be());
 
         cur_node_ptr[7] &= 0x3FFF_FFFF; // Strip last two bits to ensure in Fr
+        if let Some(on_label) = on_label {
+            on_label(0, cur_layer, node_label(cur_node_ptr));
+        }
 
         // Keep track of which node slot in the ring_buffer to use
         let mut cur_slot = 0;
         let mut count_not_ready = 0;
+        #[cfg(feature = "labeling-stats")]
+        let mut stats = PrefetchStats::default();
 
         // Calculate nodes 1 to n
 
         // Skip first node.
         parents_cache.store_consumer(1);
+        let hash_start = Instant::now();
         let mut i = 1;
+        let mut next_report = report_every;
         while i < num_nodes {
             // Ensure next buffer is ready
-            let mut counted = false;
             let mut producer_val = cur_producer.load(SeqCst);
 
-            while producer_val < i {
-                if !counted {
-                    counted = true;
-                    count_not_ready += 1;
-                }
-                thread::sleep(Duration::from_micros(10));
-                producer_val = cur_producer.load(SeqCst);
+            if producer_val < i {
+                count_not_ready += 1;
+                producer_val = trace::time(Some(cur_layer), Stage::Wait, || {
+                    while producer_val < i && !cancel.load(SeqCst) {
+                        thread::sleep(Duration::from_micros(10));
+                        producer_val = cur_producer.load(SeqCst);
+                    }
+                    producer_val
+                });
+            }
+            // Only checked once per batch of ready nodes, to keep the hashing loop clean.
+            if cancel.load(SeqCst) {
+                break;
             }
+            throttle();
 
             // Process as many nodes as are ready
             let ready_count = producer_val - i + 1;
@@ -340,18 +808,30 @@ fn create_layer_labels(
                     }
                 }
 
+                #[cfg(debug_assertions)]
+                let node_parents = &cur_parent_ptr[..DEGREE];
+
                 cur_node_ptr = &mut cur_node_ptr[8..];
                 // Grab the current slot of the ring_buf
                 let buf = unsafe { ring_buf.slot_mut(cur_slot) };
                 // Fill in the base parents
                 for k in 0..BASE_DEGREE {
                     let bpm = unsafe { base_parent_missing.get(cur_slot) };
+                    #[cfg(feature = "labeling-stats")]
+                    {
+                        if bpm.get(k) {
+                            stats.parents_missing += 1;
+                        } else {
+                            stats.parents_prefetched += 1;
+                        }
+                    }
                     if bpm.get(k) {
                         let source = unsafe {
                             let start = cur_parent_ptr[0] as usize * NODE_WORDS;
                             let end = start + NODE_WORDS;
                             &layer_labels.as_slice()[start..end]
                         };
+                        debug_assert_labeled(cur_parent_ptr[0] as u64, source.as_byte_slice());
 
                         buf[64 + (NODE_SIZE * k)..64 + (NODE_SIZE * (k + 1))]
                             .copy_from_slice(source.as_byte_slice());
@@ -363,55 +843,15 @@ fn create_layer_labels(
                 cur_parent_ptr = &cur_parent_ptr[EXP_DEGREE..];
                 cur_parent_ptr_offset += EXP_DEGREE;
 
-                if cur_layer == 1 {
-                    // Six rounds of all base parents
-                    for _j in 0..6 {
-                        compress256!(cur_node_ptr, &buf[64..], 3);
-                    }
+                #[cfg(debug_assertions)]
+                assert_parents_filled(i, buf, node_parents, &layer_labels, exp_labels);
 
-                    // round 7 is only first parent
-                    memset(&mut buf[96..128], 0); // Zero out upper half of last block
-                    buf[96] = 0x80; // Padding
-                    buf[126] = 0x27;
-                    // Length (0x2700 = 9984 bits -> 1248 bytes)
-                    compress256!(cur_node_ptr, &buf[64..], 1);
-                } else {
-                    // Two rounds of all parents
-                    let blocks = [
-                        *GenericArray::<u8, U64>::from_slice(&buf[64..128]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[128..192]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[192..256]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[256..320]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[320..384]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[384..448]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[448..512]),
-                    ];
-                    sha2::compress256(
-                        (&mut cur_node_ptr[..8])
-                            .try_into()
-                            .expect("compress failed"),
-                        &blocks,
-                    );
-                    sha2::compress256(
-                        (&mut cur_node_ptr[..8])
-                            .try_into()
-                            .expect("compress failed"),
-                        &blocks,
-                    );
-
-                    // Final round is only nine parents
-                    memset(&mut buf[352..384], 0); // Zero out upper half of last block
-                    buf[352] = 0x80; // Padding
-                    buf[382] = 0x27;
-                    // Length (0x2700 = 9984 bits -> 1248 bytes)
-                    compress256!(cur_node_ptr, &buf[64..], 5);
+                hash_node(cur_node_ptr, buf, cur_layer);
+                ring_buf.release_slot(cur_slot);
+                if let Some(on_label) = on_label {
+                    on_label(i, cur_layer, node_label(cur_node_ptr));
                 }
 
-                // Fix endianess
-                cur_node_ptr[..8].iter_mut().for_each(|x| *x = x.to_be());
-
-                cur_node_ptr[7] &= 0x3FFF_FFFF; // Strip last two bits to fit in Fr
-
                 // Safety:
                 // It's possible that this increment will trigger moving the cache window.
                 // In that case, we must not access `parents_cache` again but instead replace it.
@@ -423,15 +863,38 @@ fn create_layer_labels(
                 i += 1;
                 cur_slot = (cur_slot + 1) % lookahead;
             }
+            if i >= next_report || i == num_nodes {
+                progress.report(ProgressEvent::NodesLabeled {
+                    layer: cur_layer as usize,
+                    labeled: i,
+                    nodes: num_nodes,
+                });
+                next_report = i + report_every;
+            }
         }
 
+        trace::record(Some(cur_layer), Stage::Hash, hash_start.elapsed());
+
         debug!("PRODUCER NOT READY: {} times", count_not_ready);
+        #[cfg(feature = "labeling-stats")]
+        {
+            stats.slot_misses = count_not_ready;
+            stats.slot_hits = num_nodes.saturating_sub(1) - count_not_ready;
+            debug!("layer {} prefetch stats: {:?}", cur_layer, stats);
+            PREFETCH_STATS.lock().expect("poisoned lock").add(&stats);
+        }
 
         for runner in runners {
             runner.join().expect("join failed");
         }
     })
     .expect("crossbeam scope failure");
+
+    if cancel.load(SeqCst) {
+        return Err(LabelingError::Cancelled.into());
+    }
+
+    Ok(num_producers + 1)
 }
 
 #[allow(clippy::type_complexity)]
@@ -441,10 +904,94 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
     layers: usize,
     replica_id: T,
     config: StoreConfig,
+) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+    create_labels_for_encoding_cancellable(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        config,
+        &AtomicBool::new(false),
+    )
+}
+
+/// Like `create_labels_for_encoding`, but stops early with `LabelingError::Cancelled` once
+/// `cancel` is set. The flag is only checked between batches of nodes, so labeling stops
+/// shortly after, but not immediately. Layers completed before are kept on disk, but are only
+/// reused when resuming with `create_labels_for_encoding_resumable`.
+#[allow(clippy::type_complexity)]
+pub fn create_labels_for_encoding_cancellable<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    config: StoreConfig,
+    cancel: &AtomicBool,
+) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+    create_labels_for_encoding_resumable(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        config,
+        cancel,
+        false,
+    )
+}
+
+/// Like `create_labels_for_encoding_cancellable`, choosing whether to `resume` an interrupted
+/// labeling. Each stored layer is recorded in a `LabelingCheckpoint` in the cache directory.
+/// With `resume`, layers on disk which match the checkpoint are kept and labeling continues
+/// after the last of them, see `resume_layers`; without, all layers are labeled again.
+#[allow(clippy::type_complexity)]
+pub fn create_labels_for_encoding_resumable<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    config: StoreConfig,
+    cancel: &AtomicBool,
+    resume: bool,
 ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
     info!("create labels");
+    let replica_id = ReplicaId::try_from(replica_id.as_ref())?;
 
-    let layer_states = prepare_layers::<Tree>(graph, &config, layers);
+    let sector_size = graph.size() * NODE_SIZE;
+    let (_core_guard, core_group) = get_p1_core_group_for(sector_size as u64);
+    let core_group = Arc::new(core_group);
+
+    let res = create_labels_for_encoding_on::<Tree>(
+        graph,
+        parents_cache,
+        layers,
+        &replica_id,
+        config,
+        cancel,
+        resume,
+        core_group.clone(),
+    );
+    record_binding_summary(&BindingSummary::new(
+        Phase::Labeling,
+        (*core_group).as_deref().unwrap_or(&[]),
+        &res,
+    ));
+    res
+}
+
+/// `create_labels_for_encoding_cancellable` on the cores of `core_group`.
+#[allow(clippy::type_complexity)]
+fn create_labels_for_encoding_on<Tree: 'static + MerkleTreeTrait>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: &ReplicaId,
+    config: StoreConfig,
+    cancel: &AtomicBool,
+    resume: bool,
+    core_group: Arc<Option<CoreGroup>>,
+) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+    let (layer_states, mut checkpoint) =
+        resume_layers::<Tree>(graph, &config, layers, replica_id, resume);
 
     let sector_size = graph.size() * NODE_SIZE;
     let node_count = graph.size() as u64;
@@ -452,15 +999,14 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
     let default_cache_size = DEGREE * 4 * cache_window_nodes;
 
-    let (_core_guard, core_group) = get_p1_core_group();
-    let core_group = Arc::new(core_group);
-
     // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
     let _cleanup_handle = (*core_group).as_ref().map(|group| {
         // This could fail, but we will ignore the error if so.
         // It will be logged as a warning by `bind_core`.
         debug!("binding core in main thread");
-        group.get(0).map(|core_index| bind_core(*core_index))
+        group
+            .get(0)
+            .map(|core_index| trace::time(None, Stage::Bind, || bind_core(*core_index)))
     });
 
     // NOTE: this means we currently keep 2x sector size around, to improve speed
@@ -469,10 +1015,14 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         DEGREE,
         Some(default_cache_size as usize),
         &parents_cache.path,
+        (*core_group).as_deref(),
     )?;
 
+    // Stays 0 if no layer is labeled, e.g. as all of them are resumed.
+    let mut effective_workers = 0;
     for (layer, layer_state) in (1..=layers).zip(layer_states.iter()) {
         info!("Layer {}", layer);
+        progress::report(ProgressEvent::LayerStarted { layer, layers });
 
         if layer_state.generated {
             info!("skipping layer {}, already generated", layer);
@@ -488,19 +1038,23 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             parents_cache.finish_reset()?;
         }
 
-        create_layer_labels(
+        let workers = create_layer_labels(
             &parents_cache,
-            &replica_id.as_ref(),
+            replica_id,
             &mut layer_labels,
             if layer == 1 {
                 None
             } else {
-                Some(&mut exp_labels)
+                Some(&exp_labels[..])
             },
             node_count,
             layer as u32,
             core_group.clone(),
-        );
+            multicore_sdr_producers(),
+            cancel,
+            None,
+        )?;
+        effective_workers = effective_workers.max(workers);
 
         // Cache reset happens in two parts.
         // The first part (the start) happens after each layer but the last.
@@ -513,19 +1067,33 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             let layer_config = &layer_state.config;
 
             info!("  storing labels on disk");
-            write_layer(&exp_labels, layer_config).context("failed to store labels")?;
+            trace::time(Some(layer as u32), Stage::Write, || {
+                write_layer(&exp_labels, layer_config)
+            })
+            .context("failed to store labels")?;
 
             info!(
                 "  generated layer {} store with id {}",
                 layer, layer_config.id
             );
         }
+
+        // Losing the checkpoint only costs resuming, not the seal.
+        checkpoint.record_layer(layer, &exp_labels);
+        if let Err(err) = checkpoint.store(&config.path) {
+            warn!("failed to store labeling checkpoint: {:?}", err);
+        }
+    }
+
+    if let Err(err) = trace::write_trace() {
+        warn!("failed to write labeling trace: {:?}", err);
     }
 
     Ok((
         Labels::<Tree> {
             labels: layer_states.iter().map(|s| s.config.clone()).collect(),
             _h: PhantomData,
+            effective_workers,
         },
         layer_states,
     ))
@@ -540,6 +1108,9 @@ pub fn create_labels_for_encoding_bench<Tree: 'static + MerkleTreeTrait, T: AsRe
     config: StoreConfig,
 ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
     info!("create labels");
+    let replica_id = ReplicaId::try_from(replica_id.as_ref())?;
+    // Never set, this entry point can not be cancelled.
+    let cancel = &AtomicBool::new(false);
 
     let labels_start = Instant::now();
 
@@ -551,7 +1122,7 @@ pub fn create_labels_for_encoding_bench<Tree: 'static + MerkleTreeTrait, T: AsRe
 
     let default_cache_size = DEGREE * 4 * cache_window_nodes;
 
-    let (_core_guard, core_group) = get_p1_core_group();
+    let (_core_guard, core_group) = get_p1_core_group_for(sector_size as u64);
     let core_group = Arc::new(core_group);
 
     // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
@@ -559,7 +1130,9 @@ pub fn create_labels_for_encoding_bench<Tree: 'static + MerkleTreeTrait, T: AsRe
         // This could fail, but we will ignore the error if so.
         // It will be logged as a warning by `bind_core`.
         debug!("binding core in main thread");
-        group.get(0).map(|core_index| bind_core(*core_index))
+        group
+            .get(0)
+            .map(|core_index| trace::time(None, Stage::Bind, || bind_core(*core_index)))
     });
 
     // NOTE: this means we currently keep 2x sector size around, to improve speed
@@ -568,8 +1141,11 @@ pub fn create_labels_for_encoding_bench<Tree: 'static + MerkleTreeTrait, T: AsRe
         DEGREE,
         Some(default_cache_size as usize),
         &parents_cache.path,
+        (*core_group).as_deref(),
     )?;
 
+    // Stays 0 if no layer is labeled, e.g. as all of them are resumed.
+    let mut effective_workers = 0;
     for (layer, layer_state) in (1..=layers).zip(layer_states.iter()) {
         info!("Layer {}", layer);
 
@@ -587,19 +1163,23 @@ pub fn create_labels_for_encoding_bench<Tree: 'static + MerkleTreeTrait, T: AsRe
             parents_cache.finish_reset()?;
         }
 
-        create_layer_labels(
+        let workers = create_layer_labels(
             &parents_cache,
-            &replica_id.as_ref(),
+            &replica_id,
             &mut layer_labels,
             if layer == 1 {
                 None
             } else {
-                Some(&mut exp_labels)
+                Some(&exp_labels[..])
             },
             node_count,
             layer as u32,
             core_group.clone(),
-        );
+            multicore_sdr_producers(),
+            cancel,
+            None,
+        )?;
+        effective_workers = effective_workers.max(workers);
 
         // Cache reset happens in two parts.
         // The first part (the start) happens after each layer but the last.
@@ -612,7 +1192,10 @@ pub fn create_labels_for_encoding_bench<Tree: 'static + MerkleTreeTrait, T: AsRe
             let layer_config = &layer_state.config;
 
             info!("  storing labels on disk");
-            write_layer(&exp_labels, layer_config).context("failed to store labels")?;
+            trace::time(Some(layer as u32), Stage::Write, || {
+                write_layer(&exp_labels, layer_config)
+            })
+            .context("failed to store labels")?;
 
             info!(
                 "  generated layer {} store with id {}",
@@ -624,10 +1207,15 @@ pub fn create_labels_for_encoding_bench<Tree: 'static + MerkleTreeTrait, T: AsRe
     let labels_time = labels_start.elapsed();
     info!("encodint labels time: {:?}", labels_time);
 
+    if let Err(err) = trace::write_trace() {
+        warn!("failed to write labeling trace: {:?}", err);
+    }
+
     Ok((
         Labels::<Tree> {
             labels: layer_states.iter().map(|s| s.config.clone()).collect(),
             _h: PhantomData,
+            effective_workers,
         },
         layer_states,
     ))
@@ -642,6 +1230,9 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
     config: StoreConfig,
 ) -> Result<LabelsCache<Tree>> {
     info!("create labels");
+    let replica_id = ReplicaId::try_from(replica_id.as_ref())?;
+    // Never set, this entry point can not be cancelled.
+    let cancel = &AtomicBool::new(false);
 
     // For now, we require it due to changes in encodings structure.
     let mut labels: Vec<DiskStore<<Tree::Hasher as Hasher>::Domain>> = Vec::with_capacity(layers);
@@ -653,7 +1244,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
     let default_cache_size = DEGREE * 4 * cache_window_nodes;
 
-    let (_core_guard, core_group) = get_p1_core_group();
+    let (_core_guard, core_group) = get_p1_core_group_for(sector_size as u64);
     let core_group = Arc::new(core_group);
 
     // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
@@ -661,7 +1252,9 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         // This could fail, but we will ignore the error if so.
         // It will be logged as a warning by `bind_core`.
         debug!("binding core in main thread");
-        group.get(0).map(|core_index| bind_core(*core_index))
+        group
+            .get(0)
+            .map(|core_index| trace::time(None, Stage::Bind, || bind_core(*core_index)))
     });
 
     // NOTE: this means we currently keep 2x sector size around, to improve speed
@@ -670,6 +1263,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         DEGREE,
         Some(default_cache_size as usize),
         &parents_cache.path,
+        (*core_group).as_deref(),
     )?;
 
     for layer in 1..=layers {
@@ -683,17 +1277,20 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
         create_layer_labels(
             &parents_cache,
-            &replica_id.as_ref(),
+            &replica_id,
             &mut layer_labels,
             if layer == 1 {
                 None
             } else {
-                Some(&mut exp_labels)
+                Some(&exp_labels[..])
             },
             node_count,
             layer as u32,
             core_group.clone(),
-        );
+            multicore_sdr_producers(),
+            cancel,
+            None,
+        )?;
 
         // Cache reset happens in two parts.
         // The first part (the start) happens after each layer but the last.
@@ -732,63 +1329,592 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         "Invalid amount of layers encoded expected"
     );
 
+    if let Err(err) = trace::write_trace() {
+        warn!("failed to write labeling trace: {:?}", err);
+    }
+
     Ok(LabelsCache::<Tree> { labels })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use bellperson::bls::{Fr, FrRepr};
-    use ff::PrimeField;
-    use filecoin_hashers::poseidon::PoseidonHasher;
-    use generic_array::typenum::{U0, U2, U8};
-    use storage_proofs_core::{api_version::ApiVersion, merkle::LCTree};
-    use tempfile::tempdir;
+/// Labels all `layers`, handing each one to the caller's `sink` instead of writing it to disk.
+/// Only one extra layer, the previous one needed for the expander parents, is kept internally.
+pub fn create_labels_with_sink<H: Hasher, T: AsRef<[u8]>, S: LayerSink>(
+    graph: &StackedBucketGraph<H>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    sink: &mut S,
+) -> Result<()> {
+    create_labels_with_producers(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        sink,
+        multicore_sdr_producers(),
+        None,
+    )
+}
 
-    #[test]
-    fn test_create_labels() {
-        let layers = 11;
-        let nodes_2k = 1 << 11;
-        let nodes_4k = 1 << 12;
-        let replica_id = [9u8; 32];
+/// Labels all `layers` into `sink` like `create_labels_with_sink`, additionally passing every
+/// label to `on_label` as soon as it is computed, e.g. to hash or stream the labels without
+/// waiting for a layer to be complete. The labels are still written to `sink`, as the following
+/// nodes and layers are labeled from them.
+///
+/// `on_label` is called on the calling thread, by the loop hashing the labels while the producer
+/// threads gather the parents of the following nodes. Within a layer it is called exactly once
+/// per node, in increasing node order, and for one layer after another, never concurrently.
+/// Hashing waits for it to return, so it should be cheap.
+pub fn create_labels_with_callback<H: Hasher, T: AsRef<[u8]>, S: LayerSink>(
+    graph: &StackedBucketGraph<H>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    sink: &mut S,
+    on_label: OnLabel<'_>,
+) -> Result<()> {
+    create_labels_with_producers(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        sink,
+        multicore_sdr_producers(),
+        Some(on_label),
+    )
+}
 
-        // These PoRepIDs are only useful to distinguish legacy/new sectors.
-        // They do not correspond to registered proofs of the sizes used here.
-        let legacy_porep_id = [0; 32];
-        let new_porep_id = [123; 32];
-        test_create_labels_aux(
-            nodes_2k,
-            layers,
-            replica_id,
-            legacy_porep_id,
-            ApiVersion::V1_0_0,
-            Fr::from_repr(FrRepr([
-                0xd3faa96b9a0fba04,
-                0xea81a283d106485e,
-                0xe3d51b9afa5ac2b3,
-                0x0462f4f4f1a68d37,
-            ]))
-            .expect("create_labels_aux failed"),
-        );
-        test_create_labels_aux(
-            nodes_4k,
-            layers,
-            replica_id,
-            legacy_porep_id,
-            ApiVersion::V1_0_0,
-            Fr::from_repr(FrRepr([
-                0x7e191e52c4a8da86,
-                0x5ae8a1c9e6fac148,
-                0xce239f3b88a894b8,
-                0x234c00d1dc1d53be,
-            ]))
-            .expect("create_labels_aux failed"),
-        );
+/// Labels all `layers` like `create_labels_with_sink`, keeping only as many of the most recent
+/// layers in memory as fit into `max_resident_bytes`, and spilling the others to a file in
+/// `temp_dir`. The budget includes the copy of the previous layer labeling keeps, so it must
+/// hold at least two layers. The labels are the same as with all layers resident; the returned
+/// sink reads spilled layers back when they are needed, e.g. to gather columns.
+pub fn create_labels_with_budget<H: Hasher, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<H>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    max_resident_bytes: usize,
+    temp_dir: &Path,
+) -> Result<SpillingLayerSink> {
+    let layer_size = graph.size() * NODE_SIZE;
+    ensure!(
+        max_resident_bytes >= 2 * layer_size,
+        "a budget of {} bytes does not hold two layers of {} bytes",
+        max_resident_bytes,
+        layer_size
+    );
+    // One layer of the budget is the copy of the previous layer, see `create_labels_from_layer`.
+    let mut sink = SpillingLayerSink::new(layer_size, max_resident_bytes - layer_size, temp_dir)?;
+    create_labels_with_sink(graph, parents_cache, layers, replica_id, &mut sink)?;
+    Ok(sink)
+}
 
-        test_create_labels_aux(
-            nodes_2k,
-            layers,
+/// Labels layers `resume_from_layer..=layers` into `sink` like `create_labels_with_sink`, e.g.
+/// to continue a seal from its last checkpointed layer after a crash. The layers before are
+/// trusted as they are, of those only layer `resume_from_layer - 1` is read, for the expander
+/// parents, so `sink` must return it from `LayerSink::finished_layer`. Resuming from layer 1
+/// labels all layers.
+pub fn resume_labels_with_sink<H: Hasher, T: AsRef<[u8]>, S: LayerSink>(
+    graph: &StackedBucketGraph<H>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    sink: &mut S,
+    resume_from_layer: usize,
+) -> Result<()> {
+    create_labels_from_layer(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        sink,
+        resume_from_layer,
+        multicore_sdr_producers(),
+        None,
+    )
+}
+
+/// `create_labels_with_callback` with `num_producers` producer threads, instead of the
+/// configured number of `multicore_sdr_producers`, and an optional callback.
+pub(crate) fn create_labels_with_producers<H: Hasher, T: AsRef<[u8]>, S: LayerSink>(
+    graph: &StackedBucketGraph<H>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    sink: &mut S,
+    num_producers: usize,
+    on_label: Option<OnLabel<'_>>,
+) -> Result<()> {
+    create_labels_from_layer(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        sink,
+        1,
+        num_producers,
+        on_label,
+    )
+}
+
+/// `create_labels_with_producers`, starting at layer `resume_from_layer`, see
+/// `resume_labels_with_sink`.
+#[allow(clippy::too_many_arguments)]
+fn create_labels_from_layer<H: Hasher, T: AsRef<[u8]>, S: LayerSink>(
+    graph: &StackedBucketGraph<H>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    sink: &mut S,
+    resume_from_layer: usize,
+    num_producers: usize,
+    on_label: Option<OnLabel<'_>>,
+) -> Result<()> {
+    info!("create labels");
+    let replica_id = ReplicaId::try_from(replica_id.as_ref())?;
+    ensure!(
+        resume_from_layer >= 1 && resume_from_layer <= layers + 1,
+        "can not resume from layer {} of {}",
+        resume_from_layer,
+        layers
+    );
+    // Never set, this entry point can not be cancelled.
+    let cancel = &AtomicBool::new(false);
+
+    let sector_size = graph.size() * NODE_SIZE;
+    let node_count = graph.size() as u64;
+    let cache_window_nodes = SETTINGS.sdr_parents_cache_size as usize;
+
+    let default_cache_size = DEGREE * 4 * cache_window_nodes;
+
+    let (_core_guard, core_group) = get_p1_core_group_for(sector_size as u64);
+    let core_group = Arc::new(core_group);
+
+    // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
+    let _cleanup_handle = (*core_group).as_ref().map(|group| {
+        // This could fail, but we will ignore the error if so.
+        // It will be logged as a warning by `bind_core`.
+        debug!("binding core in main thread");
+        group
+            .get(0)
+            .map(|core_index| trace::time(None, Stage::Bind, || bind_core(*core_index)))
+    });
+
+    let parents_cache = CacheReader::new(&parents_cache.path, Some(default_cache_size), DEGREE)?;
+    let mut exp_labels = allocate_layer_near(sector_size, (*core_group).as_deref())?;
+    if resume_from_layer > 1 {
+        let prev_layer = resume_from_layer - 1;
+        let prev_labels = sink
+            .finished_layer(prev_layer)
+            .with_context(|| format!("layer {} to resume after is not available", prev_layer))?;
+        ensure!(
+            prev_labels.len() == sector_size,
+            "layer {} holds {} bytes, expected {}",
+            prev_layer,
+            prev_labels.len(),
+            sector_size
+        );
+        info!("resuming after layer {}", prev_layer);
+        exp_labels.copy_from_slice(prev_labels);
+    }
+
+    for layer in resume_from_layer..=layers {
+        info!("Layer {}", layer);
+        progress::report(ProgressEvent::LayerStarted { layer, layers });
+
+        // Cache reset happens in two parts.
+        // The second part (the finish) happens before each layer but the first.
+        if layers != 1 {
+            parents_cache.finish_reset()?;
+        }
+
+        let layer_labels = sink.begin_layer(layer)?;
+        ensure!(
+            layer_labels.len() == sector_size,
+            "layer sink returned {} bytes for layer {}, expected {}",
+            layer_labels.len(),
+            layer,
+            sector_size
+        );
+        ensure!(
+            is_label_aligned(layer_labels),
+            "layer sink returned a buffer for layer {} which is not aligned to 4 bytes",
+            layer
+        );
+
+        create_layer_labels(
+            &parents_cache,
+            &replica_id,
+            layer_labels,
+            if layer == 1 {
+                None
+            } else {
+                Some(&exp_labels[..])
+            },
+            node_count,
+            layer as u32,
+            core_group.clone(),
+            num_producers,
+            cancel,
+            on_label,
+        )?;
+
+        // Cache reset happens in two parts.
+        // The first part (the start) happens after each layer but the last.
+        if layer != layers {
+            parents_cache.start_reset()?;
+            // Keep this layer for the expander parents of the next one.
+            exp_labels.copy_from_slice(layer_labels);
+        }
+
+        sink.end_layer(layer)?;
+    }
+
+    if let Err(err) = trace::write_trace() {
+        warn!("failed to write labeling trace: {:?}", err);
+    }
+
+    Ok(())
+}
+
+/// Labels all `layers` into `sink` like `create_labels_with_sink`, hashing the column of every
+/// node while the final layer is labeled, instead of in a second pass over all layers once
+/// labeling is done. Returns the column hashes of all nodes, the same as `hash_single_column`
+/// over the finished layers, so `layers` must be a supported column arity.
+///
+/// The labels of the final layer are copied out as they are written, in batches of
+/// `COLUMN_HASH_BATCH`, to hashing threads on the cores the producers leave free. They read
+/// the other labels of a column from the layers already in `sink`, which are not modified
+/// anymore, while the final layer is labeled into a buffer of its own and only added to `sink`
+/// once it is complete.
+pub fn create_labels_with_column_hashes<H: Hasher, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<H>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    sink: &mut MemoryLayerSink,
+) -> Result<Vec<Fr>> {
+    ensure!(layers > 1, "column hashes need at least two layers");
+    ensure!(sink.num_layers() == 0, "layer sink must be empty");
+    let replica_id = replica_id.as_ref();
+    let node_count = graph.size();
+
+    create_labels_with_sink(graph, parents_cache, layers - 1, replica_id, sink)?;
+
+    let prev_layers: Vec<&[u8]> = (1..layers)
+        .map(|layer| sink.layer(layer).expect("layer was labeled"))
+        .collect();
+    let mut last_sink = FinalLayerSink {
+        prev_idx: layers - 1,
+        prev: prev_layers[layers - 2],
+        layer: Vec::new(),
+    };
+
+    let num_producers = multicore_sdr_producers();
+    let num_hashers = logical_cpu_count().saturating_sub(num_producers + 1).max(1);
+    info!(
+        "Layer {}, hashing columns on {} threads",
+        layers, num_hashers
+    );
+
+    let (batch_tx, batch_rx) =
+        crossbeam::channel::bounded::<(usize, Vec<[u8; NODE_SIZE]>)>(2 * num_hashers);
+    let (hash_tx, hash_rx) = crossbeam::channel::unbounded::<(usize, Result<Vec<Fr>>)>();
+
+    let mut batches = crossbeam::scope(|s| -> Result<Vec<(usize, Vec<Fr>)>> {
+        for _ in 0..num_hashers {
+            let batch_rx = batch_rx.clone();
+            let hash_tx = hash_tx.clone();
+            let prev_layers = &prev_layers;
+            s.spawn(move |_| {
+                for (start, labels) in batch_rx.iter() {
+                    let hashes = (start..)
+                        .zip(&labels)
+                        .map(|(node, label)| -> Result<Fr> {
+                            let range = node * NODE_SIZE..(node + 1) * NODE_SIZE;
+                            let mut column = Vec::with_capacity(layers);
+                            for layer in prev_layers.iter() {
+                                column.push(bytes_into_fr(&layer[range.clone()])?);
+                            }
+                            column.push(bytes_into_fr(label)?);
+                            Ok(hash_single_column(&column))
+                        })
+                        .collect();
+                    if hash_tx.send((start, hashes)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(batch_rx);
+        drop(hash_tx);
+
+        let pending = Mutex::new(Vec::with_capacity(COLUMN_HASH_BATCH));
+        let on_label = |node: u64, _layer: u32, label: &[u8; NODE_SIZE]| {
+            let mut pending = pending.lock().expect("pending labels poisoned");
+            pending.push(*label);
+            if pending.len() == COLUMN_HASH_BATCH {
+                let labels = mem::replace(&mut *pending, Vec::with_capacity(COLUMN_HASH_BATCH));
+                let start = node as usize + 1 - COLUMN_HASH_BATCH;
+                // Only fails if all hashing threads panicked, which the scope reports.
+                let _ = batch_tx.send((start, labels));
+            }
+        };
+        let res = create_labels_from_layer(
+            graph,
+            parents_cache,
+            layers,
+            replica_id,
+            &mut last_sink,
+            layers,
+            num_producers,
+            Some(&on_label),
+        );
+        let labels = pending.into_inner().expect("pending labels poisoned");
+        if res.is_ok() && !labels.is_empty() {
+            let _ = batch_tx.send((node_count - labels.len(), labels));
+        }
+        // Closing the channel stops the hashing threads once they are done.
+        drop(batch_tx);
+        res?;
+
+        hash_rx
+            .iter()
+            .map(|(start, hashes)| hashes.map(|hashes| (start, hashes)))
+            .collect()
+    })
+    .expect("failed to join the column hashing threads")?;
+
+    batches.sort_unstable_by_key(|(start, _)| *start);
+    let hashes: Vec<Fr> = batches.into_iter().flat_map(|(_, hashes)| hashes).collect();
+    ensure!(
+        hashes.len() == node_count,
+        "hashed {} of {} columns",
+        hashes.len(),
+        node_count
+    );
+
+    sink.push_layer(last_sink.layer)?;
+    Ok(hashes)
+}
+
+/// The `LayerSink` the final layer of `create_labels_with_column_hashes` is labeled into, kept
+/// apart from the earlier layers, which the hashing threads read meanwhile.
+struct FinalLayerSink<'a> {
+    prev_idx: usize,
+    prev: &'a [u8],
+    // Stored as `u32`, so the buffer is suitably aligned.
+    layer: Vec<u32>,
+}
+
+impl LayerSink for FinalLayerSink<'_> {
+    fn begin_layer(&mut self, idx: usize) -> Result<&mut [u8]> {
+        ensure!(idx == self.prev_idx + 1, "only the final layer is labeled");
+        self.layer = vec![0u32; self.prev.len() / size_of::<u32>()];
+        let layer = self.layer.as_mut_byte_slice();
+        poison_labels(layer);
+        Ok(layer)
+    }
+
+    fn end_layer(&mut self, _idx: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn finished_layer(&self, idx: usize) -> Option<&[u8]> {
+        if idx == self.prev_idx {
+            Some(self.prev)
+        } else {
+            None
+        }
+    }
+}
+
+/// Labels all `layers` alternating between the two `buffers`, each of which must hold one layer,
+/// so that a finished layer can be consumed while the next one is labeled into the other buffer.
+///
+/// `on_layer_ready(layer, labels)` signals that `layer` (starting at 1) is complete. It is
+/// called on a separate thread while the following layer is being labeled, which only reads
+/// the finished layer for its expander parents. Labeling of the layer after that, which reuses
+/// the buffer, waits for the call to return, so `labels` must not be kept beyond it. The final
+/// layer is handed over once labeling is done, and the first error returned stops labeling.
+pub fn create_labels_double_buffered<H, T, F>(
+    graph: &StackedBucketGraph<H>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    buffers: [&mut [u8]; 2],
+    mut on_layer_ready: F,
+) -> Result<()>
+where
+    H: Hasher,
+    T: AsRef<[u8]>,
+    F: FnMut(usize, &[u8]) -> Result<()> + Send,
+{
+    info!("create labels, double buffered");
+    let replica_id = ReplicaId::try_from(replica_id.as_ref())?;
+    // Never set, this entry point can not be cancelled.
+    let cancel = &AtomicBool::new(false);
+
+    let sector_size = graph.size() * NODE_SIZE;
+    let node_count = graph.size() as u64;
+    let cache_window_nodes = SETTINGS.sdr_parents_cache_size as usize;
+
+    let default_cache_size = DEGREE * 4 * cache_window_nodes;
+
+    for buffer in buffers.iter() {
+        ensure!(
+            buffer.len() == sector_size,
+            "layer buffer holds {} bytes, expected {}",
+            buffer.len(),
+            sector_size
+        );
+        ensure!(
+            is_label_aligned(buffer),
+            "layer buffer is not aligned to 4 bytes"
+        );
+    }
+    let [mut front, mut back] = buffers;
+
+    let (_core_guard, core_group) = get_p1_core_group_for(sector_size as u64);
+    let core_group = Arc::new(core_group);
+
+    // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
+    let _cleanup_handle = (*core_group).as_ref().map(|group| {
+        // This could fail, but we will ignore the error if so.
+        // It will be logged as a warning by `bind_core`.
+        debug!("binding core in main thread");
+        group
+            .get(0)
+            .map(|core_index| trace::time(None, Stage::Bind, || bind_core(*core_index)))
+    });
+
+    let parents_cache = CacheReader::new(&parents_cache.path, Some(default_cache_size), DEGREE)?;
+
+    for layer in 1..=layers {
+        info!("Layer {}", layer);
+        progress::report(ProgressEvent::LayerStarted { layer, layers });
+
+        // Cache reset happens in two parts.
+        // The second part (the finish) happens before each layer but the first.
+        if layers != 1 {
+            parents_cache.finish_reset()?;
+        }
+
+        // `back` holds the previous layer, which is consumed while this one is labeled.
+        let prev_labels: &[u8] = back;
+        let on_layer_ready = &mut on_layer_ready;
+        crossbeam::thread::scope(|s| {
+            let consumer = if layer > 1 {
+                Some(s.spawn(move |_| on_layer_ready(layer - 1, prev_labels)))
+            } else {
+                None
+            };
+
+            let res = create_layer_labels(
+                &parents_cache,
+                &replica_id,
+                front,
+                if layer == 1 { None } else { Some(prev_labels) },
+                node_count,
+                layer as u32,
+                core_group.clone(),
+                multicore_sdr_producers(),
+                cancel,
+                None,
+            );
+
+            // The previous layer must be consumed before its buffer is labeled into again.
+            match consumer {
+                Some(consumer) => res.and(consumer.join().expect("join failed")),
+                None => res.map(drop),
+            }
+        })
+        .expect("crossbeam scope failure")?;
+
+        // Cache reset happens in two parts.
+        // The first part (the start) happens after each layer but the last.
+        if layer != layers {
+            parents_cache.start_reset()?;
+        }
+
+        mem::swap(&mut front, &mut back);
+    }
+
+    if layers > 0 {
+        on_layer_ready(layers, back)?;
+    }
+
+    if let Err(err) = trace::write_trace() {
+        warn!("failed to write labeling trace: {:?}", err);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellperson::bls::{Fr, FrRepr};
+    use byte_slice_cast::AsMutByteSlice;
+    use ff::PrimeField;
+    use filecoin_hashers::poseidon::PoseidonHasher;
+    use generic_array::typenum::{U0, U2, U8};
+    use storage_proofs_core::{api_version::ApiVersion, merkle::LCTree};
+    use tempfile::tempdir;
+
+    use crate::stacked::vanilla::{
+        create_label::{
+            single::{create_label, create_label_exp},
+            MemoryLayerSink,
+        },
+        utils::gather_column,
+    };
+
+    #[test]
+    fn test_create_labels() {
+        let layers = 11;
+        let nodes_2k = 1 << 11;
+        let nodes_4k = 1 << 12;
+        let replica_id = [9u8; 32];
+
+        // These PoRepIDs are only useful to distinguish legacy/new sectors.
+        // They do not correspond to registered proofs of the sizes used here.
+        let legacy_porep_id = [0; 32];
+        let new_porep_id = [123; 32];
+        test_create_labels_aux(
+            nodes_2k,
+            layers,
+            replica_id,
+            legacy_porep_id,
+            ApiVersion::V1_0_0,
+            Fr::from_repr(FrRepr([
+                0xd3faa96b9a0fba04,
+                0xea81a283d106485e,
+                0xe3d51b9afa5ac2b3,
+                0x0462f4f4f1a68d37,
+            ]))
+            .expect("create_labels_aux failed"),
+        );
+        test_create_labels_aux(
+            nodes_4k,
+            layers,
+            replica_id,
+            legacy_porep_id,
+            ApiVersion::V1_0_0,
+            Fr::from_repr(FrRepr([
+                0x7e191e52c4a8da86,
+                0x5ae8a1c9e6fac148,
+                0xce239f3b88a894b8,
+                0x234c00d1dc1d53be,
+            ]))
+            .expect("create_labels_aux failed"),
+        );
+
+        test_create_labels_aux(
+            nodes_2k,
+            layers,
             replica_id,
             new_porep_id,
             ApiVersion::V1_1_0,
@@ -859,4 +1985,541 @@ mod tests {
         dbg!(&last_label);
         assert_eq!(expected_last_label.into_repr(), last_label.0);
     }
+
+    /// The graph of a sector with `nodes` nodes and its parent cache.
+    fn graph_and_cache(nodes: usize) -> (StackedBucketGraph<PoseidonHasher>, ParentCache) {
+        let graph = StackedBucketGraph::<PoseidonHasher>::new(
+            None,
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [123; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("stacked bucket graph new failed");
+        let cache = graph.parent_cache().expect("parent_cache failed");
+        (graph, cache)
+    }
+
+    /// `layers` layers labeled one node at a time by the single core `create_label`, the
+    /// reference the multicore labeling is checked against.
+    fn reference_labels(
+        graph: &StackedBucketGraph<PoseidonHasher>,
+        layers: usize,
+        replica_id: [u8; 32],
+    ) -> Vec<Vec<u8>> {
+        let mut labels: Vec<Vec<u8>> = Vec::with_capacity(layers);
+        for layer in 1..=layers {
+            let mut layer_labels = vec![0u8; graph.size() * NODE_SIZE];
+            for node in 0..graph.size() {
+                match labels.last() {
+                    None => create_label(graph, None, replica_id, &mut layer_labels, layer, node),
+                    Some(exp_labels) => create_label_exp(
+                        graph,
+                        None,
+                        replica_id,
+                        exp_labels,
+                        &mut layer_labels,
+                        layer,
+                        node,
+                    ),
+                }
+                .expect("create_label failed");
+            }
+            labels.push(layer_labels);
+        }
+        labels
+    }
+
+    #[test]
+    fn test_label_node_matches_bulk() {
+        let layers = 2;
+        let nodes = 64;
+        let replica_id = [9u8; 32];
+
+        let (graph, _) = graph_and_cache(nodes);
+        let layer_labels = reference_labels(&graph, layers, replica_id);
+        let label_at = |layer: usize, node: u32| {
+            &layer_labels[layer - 1][node as usize * NODE_SIZE..(node as usize + 1) * NODE_SIZE]
+        };
+
+        let mut parents = vec![0u32; DEGREE];
+        for layer in 1..=layers {
+            for node in 0..nodes {
+                graph.parents(node, &mut parents).expect("parents failed");
+
+                let mut parent_labels = Vec::new();
+                if node > 0 {
+                    let base = &parents[..BASE_DEGREE];
+                    parent_labels.extend(base.iter().map(|&p| label_at(layer, p)));
+                    if layer > 1 {
+                        let exp = &parents[BASE_DEGREE..];
+                        parent_labels.extend(exp.iter().map(|&p| label_at(layer - 1, p)));
+                    }
+                }
+
+                let label = label_node(
+                    &ReplicaId(replica_id),
+                    layer as u32,
+                    node as u64,
+                    &parent_labels,
+                );
+                assert_eq!(
+                    &label[..],
+                    label_at(layer, node as u32),
+                    "mismatch at layer {} node {}",
+                    layer,
+                    node
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_label_node_with_parents() {
+        let replica_id = ReplicaId([9u8; 32]);
+        let parents = (0..DEGREE)
+            .map(|i| [i as u8; NODE_SIZE])
+            .collect::<Vec<_>>();
+        let parent_refs = parents.iter().map(|p| &p[..]).collect::<Vec<_>>();
+
+        // Node 0, a node of the first layer, and one of a later layer.
+        for &degree in &[0, BASE_DEGREE, DEGREE] {
+            assert_eq!(
+                label_node_with_parents(&replica_id, 2, 7, &parents[..degree]),
+                label_node(&replica_id, 2, 7, &parent_refs[..degree]),
+                "{} parents",
+                degree
+            );
+        }
+    }
+
+    #[test]
+    fn test_create_labels_tiny_sectors() {
+        let layers = 2;
+        let replica_id = ReplicaId([9u8; 32]);
+
+        // 1 to 8 nodes, and a 2KiB sector, with more producers than there is work for.
+        for &nodes in &[1, 2, 8, 64] {
+            let (graph, cache) = graph_and_cache(nodes);
+            let mut sink = MemoryLayerSink::new(nodes * NODE_SIZE).expect("invalid layer size");
+            create_labels_with_producers(&graph, &cache, layers, &replica_id, &mut sink, 4, None)
+                .expect("create_labels_with_producers failed");
+
+            let expected = reference_labels(&graph, layers, replica_id.0);
+            for layer in 1..=layers {
+                assert_eq!(
+                    sink.layer(layer),
+                    Some(&expected[layer - 1][..]),
+                    "mismatch for {} nodes at layer {}",
+                    nodes,
+                    layer
+                );
+            }
+        }
+
+        // A 2KiB layer is labeled by a single producer and the hashing thread.
+        let nodes = 64;
+        let (graph, cache) = graph_and_cache(nodes);
+        let cache_dir = tempdir().expect("tempdir failure");
+        let (labels, _) = create_labels_for_encoding::<LCTree<PoseidonHasher, U8, U0, U2>, _>(
+            &graph,
+            &cache,
+            layers,
+            replica_id,
+            StoreConfig::new(
+                cache_dir.path(),
+                CacheKey::CommDTree.to_string(),
+                nodes.trailing_zeros() as usize,
+            ),
+        )
+        .expect("create_labels_for_encoding failed");
+        assert_eq!(labels.effective_workers, 2);
+    }
+
+    #[test]
+    fn test_relabel_range() {
+        let replica_id = [5u8; 32];
+
+        let (graph, _) = graph_and_cache(64);
+        let labels = reference_labels(&graph, 2, replica_id);
+        let (layer1, layer2) = (&labels[0][..], &labels[1][..]);
+
+        // Repair the first layer, including node 0.
+        let mut damaged = layer1.to_vec();
+        memset(&mut damaged[..20 * NODE_SIZE], 0xFF);
+        relabel_range(&graph, &replica_id, 1, 0..20, &mut damaged, None)
+            .expect("relabel_range failed");
+        assert_eq!(damaged, layer1);
+
+        // Repair a range in the middle of the second layer.
+        let mut damaged = layer2.to_vec();
+        memset(&mut damaged[30 * NODE_SIZE..50 * NODE_SIZE], 0);
+        relabel_range(&graph, &replica_id, 2, 30..50, &mut damaged, Some(layer1))
+            .expect("relabel_range failed");
+        assert_eq!(damaged, layer2);
+
+        assert!(relabel_range(&graph, &replica_id, 2, 0..1, &mut damaged, None).is_err());
+        assert!(relabel_range(&graph, &replica_id, 1, 60..65, &mut damaged, None).is_err());
+    }
+
+    #[test]
+    fn test_create_labels_cancelled() {
+        let nodes = 64;
+        let (graph, cache) = graph_and_cache(nodes);
+        let cache_dir = tempdir().expect("tempdir failure");
+
+        let cancel = AtomicBool::new(true);
+        let err = create_labels_for_encoding_cancellable::<LCTree<PoseidonHasher, U8, U0, U2>, _>(
+            &graph,
+            &cache,
+            2,
+            [9u8; 32],
+            StoreConfig::new(
+                cache_dir.path(),
+                CacheKey::CommDTree.to_string(),
+                nodes.trailing_zeros() as usize,
+            ),
+            &cancel,
+        )
+        .expect_err("labeling was not cancelled");
+        assert_eq!(
+            err.downcast_ref::<LabelingError>(),
+            Some(&LabelingError::Cancelled)
+        );
+    }
+
+    #[test]
+    fn test_effective_workers() {
+        let nodes = 64;
+        let (graph, cache) = graph_and_cache(nodes);
+        let cache_dir = tempdir().expect("tempdir failure");
+        let config = StoreConfig::new(
+            cache_dir.path(),
+            CacheKey::CommDTree.to_string(),
+            nodes.trailing_zeros() as usize,
+        );
+
+        let label = |resume| {
+            create_labels_for_encoding_resumable::<LCTree<PoseidonHasher, U8, U0, U2>, _>(
+                &graph,
+                &cache,
+                2,
+                [9u8; 32],
+                config.clone(),
+                &AtomicBool::new(false),
+                resume,
+            )
+            .expect("create_labels_for_encoding_resumable failed")
+            .0
+        };
+
+        // At least one producer and the hashing thread, never more than configured.
+        let labels = label(false);
+        assert!(labels.effective_workers >= 2);
+        assert!(labels.effective_workers <= multicore_sdr_producers() + 1);
+
+        // No layer is labeled when all of them are resumed.
+        assert_eq!(label(true).effective_workers, 0);
+    }
+
+    #[test]
+    fn test_producer_ring_holds_a_stride() {
+        let nodes = 1 << 20;
+
+        // A chunk larger than the lookahead grows the ring to hold it.
+        let (stride, slots) = producer_ring(nodes, 3, 1024, 4, 800, 1 << 16);
+        assert_eq!(stride, 1024);
+        assert!(slots >= stride as usize);
+
+        // The stride never exceeds the parents cache window.
+        let (stride, slots) = producer_ring(nodes, 3, 1024, 4, 800, 512);
+        assert_eq!(stride, 512);
+        assert_eq!(slots, 512);
+
+        // Nor the nodes of a small layer.
+        let (stride, slots) = producer_ring(64, 1, 1024, 4, 800, 1 << 16);
+        assert_eq!(stride, 63);
+        assert_eq!(slots, 63);
+    }
+
+    #[test]
+    fn test_sha2raw_compress_matches_sha2() {
+        let data: Vec<u8> = (0..192).map(|i| (i * 31 % 256) as u8).collect();
+        let blocks: Vec<_> = data
+            .chunks(64)
+            .map(|block| *GenericArray::<u8, U64>::from_slice(block))
+            .collect();
+
+        let mut expected = SHA256_INITIAL_DIGEST;
+        sha2::compress256(&mut expected, &blocks);
+
+        let mut state = SHA256_INITIAL_DIGEST;
+        for block in &blocks {
+            sha2raw::compress256(&mut state, &[&block[..32], &block[32..]]);
+        }
+        assert_eq!(state, expected);
+    }
+
+    #[test]
+    fn test_create_labels_with_sink() {
+        let layers = 3;
+        let nodes = 64;
+        let replica_id = [9u8; 32];
+
+        let (graph, cache) = graph_and_cache(nodes);
+        let cache_dir = tempdir().expect("tempdir failure");
+        let labels = create_labels_for_decoding::<LCTree<PoseidonHasher, U8, U0, U2>, _>(
+            &graph,
+            &cache,
+            layers,
+            replica_id,
+            StoreConfig::new(
+                cache_dir.path(),
+                CacheKey::CommDTree.to_string(),
+                nodes.trailing_zeros() as usize,
+            ),
+        )
+        .expect("create_labels_for_decoding failed");
+
+        let mut sink = MemoryLayerSink::new(nodes * NODE_SIZE).expect("invalid layer size");
+        create_labels_with_sink(&graph, &cache, layers, replica_id, &mut sink)
+            .expect("create_labels_with_sink failed");
+        assert_eq!(sink.num_layers(), layers);
+
+        for layer in 1..=layers {
+            let expected = labels
+                .labels_for_layer(layer)
+                .read_range(0..nodes)
+                .expect("read_range failed");
+            let actual = sink.layer(layer).expect("missing layer");
+            for (node, label) in expected.iter().enumerate() {
+                assert_eq!(
+                    &actual[node * NODE_SIZE..(node + 1) * NODE_SIZE],
+                    AsRef::<[u8]>::as_ref(label),
+                    "layer {} node {}",
+                    layer,
+                    node
+                );
+            }
+        }
+
+        // A sink handing out a misaligned buffer is an error, not a panic.
+        struct MisalignedSink(Vec<u32>, usize);
+        impl LayerSink for MisalignedSink {
+            fn begin_layer(&mut self, _idx: usize) -> Result<&mut [u8]> {
+                let layer_size = self.1;
+                Ok(&mut self.0.as_mut_byte_slice()[1..=layer_size])
+            }
+            fn end_layer(&mut self, _idx: usize) -> Result<()> {
+                Ok(())
+            }
+        }
+        let mut sink = MisalignedSink(vec![0u32; nodes * NODE_SIZE / 4 + 1], nodes * NODE_SIZE);
+        let err = create_labels_with_sink(&graph, &cache, layers, replica_id, &mut sink)
+            .expect_err("misaligned buffer accepted");
+        assert!(err.to_string().contains("not aligned"));
+    }
+
+    #[test]
+    fn test_resume_labels_with_sink() {
+        let layers = 4;
+        let nodes = 64;
+        let replica_id = [9u8; 32];
+
+        let (graph, cache) = graph_and_cache(nodes);
+        let full = reference_labels(&graph, layers, replica_id);
+
+        let checkpoint = [&full[0][..]];
+        let mut resumed =
+            MemoryLayerSink::from_layers(nodes * NODE_SIZE, &checkpoint).expect("invalid layers");
+        resume_labels_with_sink(&graph, &cache, layers, replica_id, &mut resumed, 2)
+            .expect("resume_labels_with_sink failed");
+
+        assert_eq!(resumed.num_layers(), layers);
+        for layer in 1..=layers {
+            assert_eq!(
+                resumed.layer(layer),
+                Some(&full[layer - 1][..]),
+                "layer {}",
+                layer
+            );
+        }
+
+        // The layer before the resumed one is required.
+        let mut empty = MemoryLayerSink::new(nodes * NODE_SIZE).expect("invalid layer size");
+        assert!(resume_labels_with_sink(&graph, &cache, layers, replica_id, &mut empty, 3).is_err());
+    }
+
+    #[test]
+    fn test_create_labels_with_budget() {
+        let layers = 4;
+        let nodes = 64;
+        let replica_id = [9u8; 32];
+
+        let (graph, cache) = graph_and_cache(nodes);
+        let resident = reference_labels(&graph, layers, replica_id);
+
+        let temp_dir = tempdir().expect("tempdir failure");
+        let mut spilling = create_labels_with_budget(
+            &graph,
+            &cache,
+            layers,
+            replica_id,
+            2 * nodes * NODE_SIZE,
+            temp_dir.path(),
+        )
+        .expect("create_labels_with_budget failed");
+        assert_eq!(spilling.num_layers(), layers);
+        assert!(spilling.resident_layer(1).is_none());
+        assert!(spilling.resident_layer(layers).is_some());
+
+        let mut layer = vec![0u8; nodes * NODE_SIZE];
+        for idx in 1..=layers {
+            spilling
+                .read_layer_range(idx, 0, &mut layer)
+                .expect("read_layer_range failed");
+            assert_eq!(layer, resident[idx - 1], "layer {}", idx);
+        }
+
+        let resident_layers: Vec<&[u8]> = resident.iter().map(Vec::as_slice).collect();
+        let mut expected = vec![0u8; layers * NODE_SIZE];
+        let mut column = vec![0u8; layers * NODE_SIZE];
+        for node in 0..nodes {
+            gather_column(&resident_layers, node, &mut expected);
+            spilling.read_column(node, &mut column).expect("read_column failed");
+            assert_eq!(column, expected, "node {}", node);
+        }
+
+        drop(spilling);
+        assert_eq!(std::fs::read_dir(temp_dir.path()).expect("read_dir failed").count(), 0);
+
+        // The layer being labeled and the copy of the previous one do not fit into one layer.
+        assert!(create_labels_with_budget(
+            &graph,
+            &cache,
+            layers,
+            replica_id,
+            2 * nodes * NODE_SIZE - 1,
+            temp_dir.path(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_create_labels_with_callback() {
+        let layers = 2;
+        let nodes = 64;
+
+        let (graph, cache) = graph_and_cache(nodes);
+
+        let caller = thread::current().id();
+        let seen = Mutex::new(Vec::new());
+        let on_label = |node: u64, layer: u32, label: &[u8; NODE_SIZE]| {
+            assert_eq!(thread::current().id(), caller, "on_label runs on another thread");
+            seen.lock().expect("poisoned lock").push((layer, node, *label));
+        };
+        let mut sink = MemoryLayerSink::new(nodes * NODE_SIZE).expect("invalid layer size");
+        create_labels_with_callback(&graph, &cache, layers, [9u8; 32], &mut sink, &on_label)
+            .expect("create_labels_with_callback failed");
+
+        // Every label once, in order, as written to the sink.
+        let seen = seen.into_inner().expect("poisoned lock");
+        assert_eq!(seen.len(), layers * nodes);
+        for (i, (layer, node, label)) in seen.iter().enumerate() {
+            assert_eq!(*layer as usize, i / nodes + 1);
+            assert_eq!(*node as usize, i % nodes);
+            let layer = sink.layer(*layer as usize).expect("missing layer");
+            let start = *node as usize * NODE_SIZE;
+            assert_eq!(&layer[start..start + NODE_SIZE], &label[..]);
+        }
+    }
+
+    #[test]
+    fn test_create_labels_with_column_hashes() {
+        let layers = 2;
+        let replica_id = [5u8; 32];
+
+        // Fewer nodes than one batch, and enough for several.
+        for &nodes in &[64, 4 * COLUMN_HASH_BATCH] {
+            let (graph, cache) = graph_and_cache(nodes);
+
+            let mut sink = MemoryLayerSink::new(nodes * NODE_SIZE).expect("invalid layer size");
+            let hashes =
+                create_labels_with_column_hashes(&graph, &cache, layers, replica_id, &mut sink)
+                    .expect("create_labels_with_column_hashes failed");
+
+            let expected = reference_labels(&graph, layers, replica_id);
+            assert_eq!(sink.num_layers(), layers);
+            for (idx, labels) in (1..).zip(&expected) {
+                assert_eq!(sink.layer(idx), Some(&labels[..]), "layer {}", idx);
+            }
+
+            // The same as hashing the columns once all layers are labeled.
+            let expected_layers: Vec<&[u8]> = expected.iter().map(Vec::as_slice).collect();
+            let mut column = vec![0u8; layers * NODE_SIZE];
+            assert_eq!(hashes.len(), nodes);
+            for (node, hash) in hashes.iter().enumerate() {
+                gather_column(&expected_layers, node, &mut column);
+                let column: Vec<Fr> = column
+                    .chunks(NODE_SIZE)
+                    .map(|label| bytes_into_fr(label).expect("invalid label"))
+                    .collect();
+                assert_eq!(*hash, hash_single_column(&column), "node {}", node);
+            }
+        }
+
+        let (graph, cache) = graph_and_cache(64);
+        let mut sink = MemoryLayerSink::new(64 * NODE_SIZE).expect("invalid layer size");
+        assert!(
+            create_labels_with_column_hashes(&graph, &cache, 1, replica_id, &mut sink).is_err()
+        );
+    }
+
+    #[test]
+    fn test_create_labels_double_buffered() {
+        let layers = 3;
+        let nodes = 64;
+        let replica_id = [9u8; 32];
+
+        let (graph, cache) = graph_and_cache(nodes);
+        let expected = reference_labels(&graph, layers, replica_id);
+
+        let mut front = vec![0u8; nodes * NODE_SIZE];
+        let mut back = vec![0u8; nodes * NODE_SIZE];
+        let mut ready = Vec::new();
+        create_labels_double_buffered(
+            &graph,
+            &cache,
+            layers,
+            replica_id,
+            [&mut front[..], &mut back[..]],
+            |layer, labels| {
+                ready.push((layer, labels.to_vec()));
+                Ok(())
+            },
+        )
+        .expect("create_labels_double_buffered failed");
+
+        assert_eq!(ready.len(), layers);
+        for (i, (layer, labels)) in ready.iter().enumerate() {
+            assert_eq!(*layer, i + 1);
+            assert_eq!(labels, &expected[*layer - 1]);
+        }
+
+        // Misaligned buffers are rejected instead of panicking.
+        let mut aligned = vec![0u32; nodes * NODE_SIZE / 4 + 1];
+        let misaligned = &mut aligned.as_mut_byte_slice()[1..=nodes * NODE_SIZE];
+        let mut back = vec![0u8; nodes * NODE_SIZE];
+        let err = create_labels_double_buffered(
+            &graph,
+            &cache,
+            layers,
+            replica_id,
+            [misaligned, &mut back[..]],
+            |_, _| Ok(()),
+        )
+        .expect_err("misaligned buffer accepted");
+        assert!(err.to_string().contains("not aligned"));
+    }
 }
@@ -93,6 +93,7 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         Labels::<Tree> {
             labels: layer_states.iter().map(|s| s.config.clone()).collect(),
             _h: PhantomData,
+            effective_workers: 1,
         },
         layer_states,
     ))
@@ -0,0 +1,177 @@
+//! Per-stage labeling timings, for profiling without an external profiler.
+//!
+//! With the `labeling-trace` feature enabled, the time spent in each stage is accumulated per
+//! layer and appended to the file named by `FIL_PROOFS_LABELING_TRACE_FILE` once labeling is
+//! done. The output is in the folded stack format, which `inferno-flamegraph` renders directly.
+//! Without the feature all of this compiles down to calling the timed closures.
+
+#[cfg(feature = "labeling-trace")]
+use std::collections::BTreeMap;
+#[cfg(feature = "labeling-trace")]
+use std::fs::OpenOptions;
+#[cfg(feature = "labeling-trace")]
+use std::io::Write;
+#[cfg(feature = "labeling-trace")]
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(feature = "labeling-trace")]
+use std::time::Instant;
+
+use anyhow::Result;
+#[cfg(feature = "labeling-trace")]
+use anyhow::Context;
+#[cfg(feature = "labeling-trace")]
+use lazy_static::lazy_static;
+
+pub const TRACE_FILE_ENV: &str = "FIL_PROOFS_LABELING_TRACE_FILE";
+
+/// The stages labeling time is attributed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Binding threads to cores.
+    Bind,
+    /// Producers filling the ring buffer with parent labels, summed over all producer threads.
+    Gather,
+    /// The hashing thread, including the time it waits for the producers.
+    Hash,
+    /// The hashing thread waiting for the producers, nested in `Hash`.
+    Wait,
+    /// Writing a finished layer to disk.
+    Write,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Bind => "bind",
+            Stage::Gather => "gather",
+            Stage::Hash => "hash",
+            Stage::Wait => "wait",
+            Stage::Write => "write",
+        }
+    }
+
+    /// The stage whose time includes the time of this stage.
+    pub fn parent(&self) -> Option<Stage> {
+        match self {
+            Stage::Wait => Some(Stage::Hash),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "labeling-trace")]
+lazy_static! {
+    static ref TRACE: Mutex<BTreeMap<(Option<u32>, Stage), Duration>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Adds `elapsed` to the time spent in `stage` of `layer`, `None` if the stage is not part of
+/// a single layer.
+#[cfg(feature = "labeling-trace")]
+pub fn record(layer: Option<u32>, stage: Stage, elapsed: Duration) {
+    *TRACE
+        .lock()
+        .expect("poisoned lock")
+        .entry((layer, stage))
+        .or_default() += elapsed;
+}
+
+#[cfg(not(feature = "labeling-trace"))]
+#[inline(always)]
+pub fn record(_layer: Option<u32>, _stage: Stage, _elapsed: Duration) {}
+
+/// Runs `f`, attributing its duration to `stage` of `layer`.
+#[cfg(feature = "labeling-trace")]
+pub fn time<T, F: FnOnce() -> T>(layer: Option<u32>, stage: Stage, f: F) -> T {
+    let start = Instant::now();
+    let res = f();
+    record(layer, stage, start.elapsed());
+    res
+}
+
+#[cfg(not(feature = "labeling-trace"))]
+#[inline(always)]
+pub fn time<T, F: FnOnce() -> T>(_layer: Option<u32>, _stage: Stage, f: F) -> T {
+    f()
+}
+
+/// Formats the recorded timings as folded stacks, in microseconds. Nested stages are reported
+/// below their parent, whose count is reduced to its self time.
+#[cfg(feature = "labeling-trace")]
+fn folded(trace: &BTreeMap<(Option<u32>, Stage), Duration>) -> String {
+    let mut self_time = trace.clone();
+    for (&(layer, stage), elapsed) in trace {
+        if let Some(parent) = stage.parent() {
+            if let Some(parent_time) = self_time.get_mut(&(layer, parent)) {
+                *parent_time = parent_time.checked_sub(*elapsed).unwrap_or_default();
+            }
+        }
+    }
+
+    self_time
+        .iter()
+        .map(|(&(layer, stage), elapsed)| {
+            let mut frames = vec!["labeling".to_string()];
+            if let Some(layer) = layer {
+                frames.push(format!("layer_{}", layer));
+            }
+            if let Some(parent) = stage.parent() {
+                frames.push(parent.name().to_string());
+            }
+            frames.push(stage.name().to_string());
+
+            format!("{} {}\n", frames.join(";"), elapsed.as_micros())
+        })
+        .collect()
+}
+
+/// Appends the timings recorded so far to the trace file, if one is configured, and resets them.
+#[cfg(feature = "labeling-trace")]
+pub fn write_trace() -> Result<()> {
+    let trace = std::mem::take(&mut *TRACE.lock().expect("poisoned lock"));
+    let path = match std::env::var(TRACE_FILE_ENV) {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("failed to open labeling trace file {}", path))?;
+    file.write_all(folded(&trace).as_bytes())
+        .with_context(|| format!("failed to write labeling trace file {}", path))?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "labeling-trace"))]
+#[inline(always)]
+pub fn write_trace() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, feature = "labeling-trace"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folded() {
+        let mut trace = BTreeMap::new();
+        trace.insert((Some(2), Stage::Hash), Duration::from_micros(30));
+        trace.insert((Some(1), Stage::Hash), Duration::from_micros(20));
+        trace.insert((Some(1), Stage::Wait), Duration::from_micros(8));
+        trace.insert((Some(1), Stage::Write), Duration::from_millis(1));
+        trace.insert((None, Stage::Bind), Duration::from_micros(5));
+
+        assert_eq!(
+            folded(&trace),
+            "labeling;bind 5\n\
+             labeling;layer_1;hash 12\n\
+             labeling;layer_1;hash;wait 8\n\
+             labeling;layer_1;write 1000\n\
+             labeling;layer_2;hash 30\n"
+        );
+    }
+}
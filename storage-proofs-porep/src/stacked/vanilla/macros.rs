@@ -68,14 +68,14 @@ macro_rules! prefetch {
 macro_rules! compress256 {
     ($state:expr, $buf:expr, 1) => {
         let blocks = [*GenericArray::<u8, U64>::from_slice(&$buf[..64])];
-        sha2::compress256((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
+        compress_blocks((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
     };
     ($state:expr, $buf:expr, 2) => {
         let blocks = [
             *GenericArray::<u8, U64>::from_slice(&$buf[..64]),
             *GenericArray::<u8, U64>::from_slice(&$buf[64..128]),
         ];
-        sha2::compress256((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
+        compress_blocks((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
     };
     ($state:expr, $buf:expr, 3) => {
         let blocks = [
@@ -83,7 +83,7 @@ macro_rules! compress256 {
             *GenericArray::<u8, U64>::from_slice(&$buf[64..128]),
             *GenericArray::<u8, U64>::from_slice(&$buf[128..192]),
         ];
-        sha2::compress256((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
+        compress_blocks((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
     };
     ($state:expr, $buf:expr, 5) => {
         let blocks = [
@@ -93,6 +93,6 @@ macro_rules! compress256 {
             *GenericArray::<u8, U64>::from_slice(&$buf[192..256]),
             *GenericArray::<u8, U64>::from_slice(&$buf[256..320]),
         ];
-        sha2::compress256((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
+        compress_blocks((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
     };
 }
@@ -1,17 +1,28 @@
 use std::cell::UnsafeCell;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
 use std::fs::File;
+#[cfg(target_os = "linux")]
+use std::fs::{self, OpenOptions};
 use std::hint::spin_loop;
 use std::marker::{PhantomData, Sync};
 use std::mem::size_of;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::slice;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+#[cfg(target_os = "linux")]
+use anyhow::{ensure, Context};
 use byte_slice_cast::{AsSliceOf, FromByteSlice};
 use log::{info, warn};
 use mapr::{Mmap, MmapMut, MmapOptions};
 
+use super::cores::{bind_memory_to_node, numa_node_of_cores, prefer_memory_node, CoreIndex};
+use super::utils::{forced_numa_node, label_huge_pages, poison_labels, HugePages};
+
 pub struct CacheReader<T> {
     file: File,
     bufs: UnsafeCell<[Mmap; 2]>,
@@ -20,6 +31,8 @@ pub struct CacheReader<T> {
     window_size: usize,
     cursor: IncrementingCursor,
     consumer: AtomicU64,
+    /// Bytes read ahead of a newly mapped window, see `set_prefetch_nodes`.
+    prefetch: AtomicUsize,
     _t: PhantomData<T>,
 }
 
@@ -126,6 +139,7 @@ impl<T: FromByteSlice> CacheReader<T> {
             // The furthest window from which the cache has yet been read.
             cursor: IncrementingCursor::new(0),
             consumer: AtomicU64::new(0),
+            prefetch: AtomicUsize::new(0),
             _t: PhantomData::<T>,
         })
     }
@@ -145,6 +159,22 @@ impl<T: FromByteSlice> CacheReader<T> {
         self.consumer.fetch_add(1, Ordering::SeqCst);
     }
 
+    /// Has the kernel read the cache ahead of the producers, the window mapped next and the
+    /// parents of `nodes` more nodes past it, so that a cold cache does not stall them on the
+    /// disk. Sized to the lookahead of the consumer; `0` turns reading ahead off.
+    pub fn set_prefetch_nodes(&self, nodes: usize) {
+        self.prefetch
+            .store(nodes * self.degree * size_of::<T>(), Ordering::SeqCst);
+        self.read_ahead(0);
+    }
+
+    fn read_ahead(&self, offset: u64) {
+        let prefetch = self.prefetch.load(Ordering::SeqCst);
+        if prefetch > 0 {
+            read_ahead(&self.file, offset, self.window_size + prefetch);
+        }
+    }
+
     pub fn store_consumer(&self, val: u64) {
         self.consumer.store(val, Ordering::SeqCst);
     }
@@ -174,6 +204,7 @@ impl<T: FromByteSlice> CacheReader<T> {
 
     pub fn start_reset(&self) -> Result<()> {
         let buf0 = Self::map_buf(0, self.window_size, &self.file)?;
+        self.read_ahead(0);
         let bufs = unsafe { self.get_mut_bufs() };
         bufs[0] = buf0;
         Ok(())
@@ -262,12 +293,10 @@ impl<T: FromByteSlice> CacheReader<T> {
 
         let replace_idx = (new_window % 2) as usize;
 
-        let new_buf = Self::map_buf(
-            (new_window * self.window_size) as u64,
-            self.window_size as usize,
-            &self.file,
-        )
-        .expect("map_buf failed");
+        let offset = (new_window * self.window_size) as u64;
+        let new_buf =
+            Self::map_buf(offset, self.window_size as usize, &self.file).expect("map_buf failed");
+        self.read_ahead(offset);
 
         unsafe {
             self.get_mut_bufs()[replace_idx] = new_buf;
@@ -275,7 +304,132 @@ impl<T: FromByteSlice> CacheReader<T> {
     }
 }
 
-fn allocate_layer(sector_size: usize) -> Result<MmapMut> {
+/// The NUMA node memory is placed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPlacement {
+    /// Only on this node, forced with `FIL_PROOFS_NUMA_NODE`.
+    Bind(u32),
+    /// Preferably on this node, the one of the cores using the memory. Once it is full, the
+    /// memory goes to other nodes instead.
+    Prefer(u32),
+}
+
+impl MemoryPlacement {
+    /// Where memory used by `cores` goes: on the node forced with `FIL_PROOFS_NUMA_NODE`, or else
+    /// on the node holding all of `cores`. `None` leaves the placement to the OS, e.g. on a
+    /// single node machine or if `cores` span several nodes.
+    pub fn near(cores: Option<&[CoreIndex]>) -> Option<Self> {
+        if let Some(node) = forced_numa_node() {
+            return Some(MemoryPlacement::Bind(node));
+        }
+        cores
+            .and_then(numa_node_of_cores)
+            .map(MemoryPlacement::Prefer)
+    }
+
+    /// Places the `len` bytes at `addr`, the `what` of the log message if that fails.
+    pub fn apply(&self, addr: *mut u8, len: usize, what: &str) {
+        let (node, res) = match *self {
+            MemoryPlacement::Bind(node) => (node, bind_memory_to_node(addr, len, node)),
+            MemoryPlacement::Prefer(node) => (node, prefer_memory_node(addr, len, node)),
+        };
+        if let Err(err) = res {
+            warn!("failed to place {} on NUMA node {}: {:?}", what, node, err);
+        }
+    }
+}
+
+/// Asks the kernel to back the `len` bytes at `addr` with transparent huge pages.
+#[cfg(target_os = "linux")]
+pub fn advise_huge_pages(addr: *mut u8, len: usize) {
+    // Safety: only changes how the kernel backs the caller's mapping, not its contents.
+    let res = unsafe { libc::madvise(addr as *mut libc::c_void, len, libc::MADV_HUGEPAGE) };
+    if res != 0 {
+        warn!(
+            "failed to request transparent huge pages: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn advise_huge_pages(_addr: *mut u8, _len: usize) {}
+
+/// Asks the kernel to read the pages of the mapped `data` in the background, before they are
+/// touched.
+#[cfg(target_os = "linux")]
+pub fn advise_will_need(data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    // madvise wants a page aligned address, mappings at an unaligned offset start mid-page.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = data.as_ptr() as usize / page_size * page_size;
+    let len = data.as_ptr() as usize + data.len() - start;
+    // Safety: only a hint about the caller's mapping, its contents are not changed.
+    let res = unsafe { libc::madvise(start as *mut libc::c_void, len, libc::MADV_WILLNEED) };
+    if res != 0 {
+        warn!(
+            "failed to advise read ahead: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn advise_will_need(_data: &[u8]) {}
+
+/// Asks the kernel to read the `len` bytes of `file` from `offset` on in the background, e.g.
+/// before they are mapped.
+#[cfg(target_os = "linux")]
+pub fn read_ahead(file: &File, offset: u64, len: usize) {
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: only a hint about the open file.
+    let res = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        )
+    };
+    if res != 0 {
+        warn!(
+            "failed to advise read ahead: {}",
+            std::io::Error::from_raw_os_error(res)
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_ahead(_file: &File, _offset: u64, _len: usize) {}
+
+/// Allocates a layer of labels used by `cores`, placed according to `MemoryPlacement::near` and
+/// backed by huge pages as set with `FIL_PROOFS_LABEL_HUGE_PAGES`.
+pub fn allocate_layer_near(sector_size: usize, cores: Option<&[CoreIndex]>) -> Result<MmapMut> {
+    let mut layer = match label_huge_pages() {
+        HugePages::Off => allocate_layer_unplaced(sector_size)?,
+        HugePages::Transparent => allocate_layer_transparent(sector_size)?,
+        HugePages::Hugetlbfs(dir) => match allocate_layer_hugetlbfs(&dir, sector_size) {
+            Ok(layer) => layer,
+            Err(err) => {
+                warn!(
+                    "failed to allocate layer from the huge pages in {:?}, falling back to transparent huge pages: {:?}",
+                    dir, err
+                );
+                allocate_layer_transparent(sector_size)?
+            }
+        },
+    };
+    if let Some(placement) = MemoryPlacement::near(cores) {
+        placement.apply(layer.as_mut_ptr(), layer.len(), "layer");
+    }
+    poison_labels(&mut layer);
+    Ok(layer)
+}
+
+fn allocate_layer_unplaced(sector_size: usize) -> Result<MmapMut> {
     match MmapOptions::new()
         .len(sector_size)
         .private()
@@ -296,15 +450,93 @@ fn allocate_layer(sector_size: usize) -> Result<MmapMut> {
     }
 }
 
+/// Like `allocate_layer_unplaced`, but advised to be backed by transparent huge pages before
+/// the pages are locked, and thereby faulted in.
+fn allocate_layer_transparent(sector_size: usize) -> Result<MmapMut> {
+    let mut layer = MmapOptions::new().len(sector_size).private().map_anon()?;
+    advise_huge_pages(layer.as_mut_ptr(), layer.len());
+    if let Err(err) = layer.mlock() {
+        // fallback to not locked if permissions are not available
+        warn!("failed to lock map {:?}, falling back", err);
+    }
+    Ok(layer)
+}
+
+/// Number of layers allocated from a hugetlbfs so far, to name their files.
+#[cfg(target_os = "linux")]
+static HUGETLBFS_LAYERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocates a layer from the huge pages of the hugetlbfs mounted at `dir`. The pages are
+/// reserved when mapping, so a lack of free huge pages is an error here rather than a crash
+/// on first use. Huge pages are never swapped, so the layer is not locked.
+#[cfg(target_os = "linux")]
+fn allocate_layer_hugetlbfs(dir: &Path, sector_size: usize) -> Result<MmapMut> {
+    let page_size = hugetlbfs_page_size(dir)?;
+    // The mapping could not be unmapped otherwise.
+    ensure!(
+        sector_size % page_size == 0,
+        "layer of {} bytes is not a whole number of {} byte huge pages",
+        sector_size,
+        page_size
+    );
+
+    let path = dir.join(format!(
+        "fil-proofs-layer-{}-{}",
+        std::process::id(),
+        HUGETLBFS_LAYERS.fetch_add(1, Ordering::SeqCst)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("could not create {:?}", path))?;
+    // The mapping keeps the pages, the file is only needed to create it.
+    fs::remove_file(&path).with_context(|| format!("could not remove {:?}", path))?;
+    file.set_len(sector_size as u64)?;
+
+    let layer = unsafe { MmapOptions::new().len(sector_size).map_mut(&file)? };
+    Ok(layer)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate_layer_hugetlbfs(dir: &Path, _sector_size: usize) -> Result<MmapMut> {
+    bail!("hugetlbfs at {:?} is only supported on Linux", dir)
+}
+
+/// The size of the huge pages of the hugetlbfs mounted at `dir`.
+#[cfg(target_os = "linux")]
+fn hugetlbfs_page_size(dir: &Path) -> Result<usize> {
+    const HUGETLBFS_MAGIC: i64 = 0x9584_58f6;
+
+    let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // Safety: `c_dir` is a valid C string and `stat` is large enough for the result.
+    if unsafe { libc::statfs(c_dir.as_ptr(), &mut stat) } != 0 {
+        bail!(
+            "statfs of {:?} failed: {}",
+            dir,
+            std::io::Error::last_os_error()
+        );
+    }
+    ensure!(
+        stat.f_type as i64 == HUGETLBFS_MAGIC,
+        "{:?} is not a hugetlbfs mount",
+        dir
+    );
+    Ok(stat.f_bsize as usize)
+}
+
 pub fn setup_create_label_memory(
     sector_size: usize,
     degree: usize,
     window_size: Option<usize>,
     cache_path: &Path,
+    cores: Option<&[CoreIndex]>,
 ) -> Result<(CacheReader<u32>, MmapMut, MmapMut)> {
     let parents_cache = CacheReader::new(cache_path, window_size, degree)?;
-    let layer_labels = allocate_layer(sector_size)?;
-    let exp_labels = allocate_layer(sector_size)?;
+    let layer_labels = allocate_layer_near(sector_size, cores)?;
+    let exp_labels = allocate_layer_near(sector_size, cores)?;
 
     Ok((parents_cache, layer_labels, exp_labels))
 }
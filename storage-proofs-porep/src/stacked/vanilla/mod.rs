@@ -14,17 +14,39 @@ mod graph;
 mod labeling_proof;
 mod memory_handling;
 mod params;
+mod phase;
 mod porep;
 mod proof;
 mod proof_scheme;
+mod pure;
+mod synthetic;
+mod tree_d;
 mod utils;
 
-pub use challenges::{ChallengeRequirements, LayerChallenges};
+pub use challenges::{
+    ChallengeMode, ChallengeRequirements, LayerChallenges, SYNTHETIC_CHALLENGE_COUNT,
+};
 pub use column::Column;
 pub use column_proof::ColumnProof;
 pub use encoding_proof::EncodingProof;
 pub use graph::{StackedBucketGraph, StackedGraph, EXP_DEGREE};
 pub use labeling_proof::LabelingProof;
 pub use params::*;
-pub use proof::{StackedDrg, TOTAL_PARENTS, get_core_pool};
-pub use cores::{checkout_core_group, get_p1_core_group};
\ No newline at end of file
+pub use proof::{StackedDrg, TOTAL_PARENTS, get_core_pool, gpu_throughput, RebindPool};
+pub use phase::{current_rss, set_phase_observer, Phase, PhaseObserver, RSS_SAMPLE_INTERVAL};
+pub use tree_d::build_tree_d;
+pub use pure::{buffers_equal, memset, xor_into, BitMask, BitMaskError};
+pub use cores::{
+    admit_p2, bind_thread_near_device, bind_thread_near_gpu, binding_capabilities,
+    checkout_core_group, get_p1_core_group, preflight, record_binding_summary,
+    render_core_layout, run_binding_selftest, suggest_p2_group_size, verify_core_layout,
+    BindingCapabilities, BindingPlan, BindingSelftestReport, BindingSelftestStep, BindingSummary,
+    CheckoutError, CoreLayout, P2Slot, PreflightReport,
+};
+pub use utils::{
+    dump_fil_proofs_env, max_labeling_threads, numa_sub_arenas, partition_nodes,
+    render_fil_proofs_env, set_max_labeling_threads, set_throttle_level, split_ranges,
+    throttle_level, unrecognized_fil_proofs_env, BindingConfig, CoreGroupWait, NumaArena,
+    P1BoundPolicy, P2BoundPolicy, ReplicaId, ReplicaIdLengthError, SealSettings, ThrottleLevel,
+    FIL_PROOFS_ENV_VARS,
+};
\ No newline at end of file
@@ -15,6 +15,7 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use storage_proofs_core::{
     api_version::ApiVersion,
+    artifact_io::{Artifact, ArtifactReader},
     drgraph::Graph,
     error::Result,
     merkle::{
@@ -23,7 +24,7 @@ use storage_proofs_core::{
         MerkleProofTrait, MerkleTreeTrait,
     },
     parameter_cache::ParameterSetMetadata,
-    util::data_at_node,
+    util::{data_at_node, NODE_SIZE},
 };
 
 use crate::stacked::vanilla::{
@@ -129,10 +130,11 @@ impl<T: Domain, S: Domain> PublicInputs<T, S> {
         layer_challenges: &LayerChallenges,
         leaves: usize,
         partition_k: Option<usize>,
-    ) -> Vec<usize> {
+    ) -> Result<Vec<usize>> {
         let k = partition_k.unwrap_or(0);
+        let comm_r = self.tau.as_ref().map(|tau| &tau.comm_r);
 
-        layer_challenges.derive::<T>(leaves, &self.replica_id, &self.seed, k as u8)
+        layer_challenges.derive::<T>(leaves, &self.replica_id, comm_r, &self.seed, k as u8)
     }
 }
 
@@ -594,6 +596,11 @@ pub struct Labels<Tree: MerkleTreeTrait> {
     ))]
     pub labels: Vec<StoreConfig>,
     pub _h: PhantomData<Tree>,
+    /// Number of threads which labeled a layer in parallel, the producers spawned and the
+    /// hashing thread, the most of any layer. Only known right after labeling, it is not
+    /// persisted and `0` otherwise, or if no layer was labeled, e.g. as all were resumed.
+    #[serde(skip)]
+    pub effective_workers: usize,
 }
 
 impl<Tree: MerkleTreeTrait> Clone for Labels<Tree> {
@@ -601,6 +608,7 @@ impl<Tree: MerkleTreeTrait> Clone for Labels<Tree> {
         Self {
             labels: self.labels.clone(),
             _h: Default::default(),
+            effective_workers: self.effective_workers,
         }
     }
 }
@@ -610,6 +618,7 @@ impl<Tree: MerkleTreeTrait> Labels<Tree> {
         Labels {
             labels,
             _h: PhantomData,
+            effective_workers: 0,
         }
     }
 
@@ -695,18 +704,26 @@ impl<Tree: MerkleTreeTrait> Labels<Tree> {
 #[derive(Debug)]
 pub struct LabelsCache<Tree: MerkleTreeTrait> {
     pub labels: Vec<DiskStore<<Tree::Hasher as Hasher>::Domain>>,
+    /// Read ranges of the layers for building the trees, see `Artifact::Layers`.
+    readers: Vec<ArtifactReader>,
 }
 
 impl<Tree: MerkleTreeTrait> LabelsCache<Tree> {
     pub fn new(labels: &Labels<Tree>) -> Result<Self> {
         let mut disk_store_labels: Vec<DiskStore<<Tree::Hasher as Hasher>::Domain>> =
             Vec::with_capacity(labels.len());
-        for i in 0..labels.len() {
+        let mut readers = Vec::with_capacity(labels.len());
+        for (i, config) in labels.labels.iter().enumerate() {
             disk_store_labels.push(labels.labels_for_layer(i + 1)?);
+            readers.push(ArtifactReader::open(
+                StoreConfig::data_path(&config.path, &config.id),
+                Artifact::Layers,
+            )?);
         }
 
         Ok(LabelsCache {
             labels: disk_store_labels,
+            readers,
         })
     }
 
@@ -731,6 +748,28 @@ impl<Tree: MerkleTreeTrait> LabelsCache<Tree> {
         &self.labels[row_index]
     }
 
+    /// Reads the labels of the nodes `start..end` of a layer into `buf`.
+    pub fn read_layer_range_into(
+        &self,
+        layer: usize,
+        start: usize,
+        end: usize,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        assert!(layer != 0, "Layer cannot be 0");
+        assert!(
+            layer <= self.layers(),
+            "Layer {} is not available (only {} layers available)",
+            layer,
+            self.layers()
+        );
+
+        self.readers[layer - 1].read_at(
+            (start * NODE_SIZE) as u64,
+            &mut buf[..(end - start) * NODE_SIZE],
+        )
+    }
+
     /// Returns the labels on the last layer.
     pub fn labels_for_last_layer(&self) -> Result<&DiskStore<<Tree::Hasher as Hasher>::Domain>> {
         Ok(&self.labels[self.labels.len() - 1])
@@ -0,0 +1,189 @@
+//! Reporting the end of each replication phase, for sizing hosts.
+//!
+//! An observer registered with `set_phase_observer` is told how long labeling (P1) and tree
+//! building (P2) took. With the `phase-memory` feature enabled the resident set size is also
+//! sampled every `RSS_SAMPLE_INTERVAL` while a phase runs and its peak is reported along with
+//! the duration. Without the feature, or on platforms other than Linux, the peak is `None`.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+/// How often the resident set size is sampled while a phase runs. Phases take minutes to
+/// hours, so a coarse interval is plenty and keeps the overhead negligible.
+pub const RSS_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The replication phases reported to a `PhaseObserver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Generating the labels of all layers (P1).
+    Labeling,
+    /// Building the column and replica trees (P2).
+    TreeBuilding,
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Labeling => "labeling",
+            Phase::TreeBuilding => "tree_building",
+        }
+    }
+}
+
+pub trait PhaseObserver: Send + Sync {
+    /// Called once `phase` finished, successfully or not, on the thread which ran it.
+    /// `peak_rss` is the highest resident set size seen while it ran, in bytes, if it was
+    /// sampled.
+    fn on_phase_end(&self, phase: Phase, elapsed: Duration, peak_rss: Option<u64>);
+}
+
+lazy_static! {
+    static ref OBSERVER: RwLock<Option<Arc<dyn PhaseObserver>>> = RwLock::new(None);
+}
+
+/// Registers the observer notified at the end of each phase, replacing any previous one.
+/// `None` removes it.
+pub fn set_phase_observer(observer: Option<Arc<dyn PhaseObserver>>) {
+    *OBSERVER.write().expect("poisoned lock") = observer;
+}
+
+/// Runs `f` as `phase`, reporting it to the registered observer, if any.
+pub(crate) fn observe<T, F: FnOnce() -> T>(phase: Phase, f: F) -> T {
+    let observer = match OBSERVER.read().expect("poisoned lock").clone() {
+        Some(observer) => observer,
+        None => return f(),
+    };
+
+    let start = Instant::now();
+    let sampler = RssSampler::start();
+    let res = f();
+    let peak_rss = sampler.stop();
+    observer.on_phase_end(phase, start.elapsed(), peak_rss);
+
+    res
+}
+
+/// Samples the resident set size on a background thread until stopped.
+#[cfg(feature = "phase-memory")]
+struct RssSampler {
+    stop: std::sync::mpsc::Sender<()>,
+    handle: std::thread::JoinHandle<Option<u64>>,
+}
+
+#[cfg(feature = "phase-memory")]
+impl RssSampler {
+    fn start() -> Self {
+        use std::sync::mpsc::{channel, RecvTimeoutError};
+
+        let (stop, stopped) = channel();
+        let handle = std::thread::spawn(move || {
+            let mut peak = current_rss();
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(RSS_SAMPLE_INTERVAL) {
+                peak = peak.max(current_rss());
+            }
+            peak.max(current_rss())
+        });
+
+        RssSampler { stop, handle }
+    }
+
+    fn stop(self) -> Option<u64> {
+        // The sampler only exits once told to, or if the receiver is gone, so sending can
+        // only fail if it already stopped.
+        let _ = self.stop.send(());
+        self.handle.join().unwrap_or(None)
+    }
+}
+
+#[cfg(not(feature = "phase-memory"))]
+struct RssSampler;
+
+#[cfg(not(feature = "phase-memory"))]
+impl RssSampler {
+    #[inline(always)]
+    fn start() -> Self {
+        RssSampler
+    }
+
+    #[inline(always)]
+    fn stop(self) -> Option<u64> {
+        None
+    }
+}
+
+/// The resident set size of this process in bytes, `None` if it can't be determined.
+#[cfg(target_os = "linux")]
+pub fn current_rss() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+
+    parse_statm_resident(&statm).map(|pages| pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_rss() -> Option<u64> {
+    None
+}
+
+/// The resident size in pages, the second field of `/proc/self/statm`.
+#[cfg(any(test, target_os = "linux"))]
+fn parse_statm_resident(statm: &str) -> Option<u64> {
+    statm.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::thread::{self, ThreadId};
+
+    #[test]
+    fn test_parse_statm_resident() {
+        assert_eq!(parse_statm_resident("1234 567 89 1 0 321 0\n"), Some(567));
+        assert_eq!(parse_statm_resident("1234"), None);
+        assert_eq!(parse_statm_resident("1234 abc"), None);
+    }
+
+    #[test]
+    fn test_phase_observer() {
+        // The observer is global, and other tests replicating in parallel report to it too. It
+        // is called on the thread which ran the phase, so only phases of this test's thread
+        // are recorded.
+        struct Recorder(ThreadId, Mutex<Vec<(Phase, Option<u64>)>>);
+
+        impl PhaseObserver for Recorder {
+            fn on_phase_end(&self, phase: Phase, _elapsed: Duration, peak_rss: Option<u64>) {
+                if thread::current().id() != self.0 {
+                    return;
+                }
+                self.1
+                    .lock()
+                    .expect("poisoned lock")
+                    .push((phase, peak_rss));
+            }
+        }
+
+        let recorder = Arc::new(Recorder(thread::current().id(), Mutex::new(Vec::new())));
+        set_phase_observer(Some(recorder.clone()));
+        assert_eq!(observe(Phase::Labeling, || 1), 1);
+        assert_eq!(observe(Phase::TreeBuilding, || 2), 2);
+        set_phase_observer(None);
+        assert_eq!(observe(Phase::Labeling, || 3), 3);
+
+        let events = recorder.1.lock().expect("poisoned lock");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].0, Phase::Labeling);
+        assert_eq!(events[1].0, Phase::TreeBuilding);
+
+        let sampled = cfg!(all(feature = "phase-memory", target_os = "linux"));
+        for (_, peak_rss) in events.iter() {
+            assert_eq!(peak_rss.is_some(), sampled);
+        }
+    }
+}
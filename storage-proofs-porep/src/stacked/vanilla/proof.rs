@@ -1,8 +1,10 @@
 use std::fs;
+use std::io::{Read, Seek, SeekFrom};
 use std::marker::PhantomData;
+use std::ops::Range;
 use std::panic::panic_any;
 use std::path::{Path, PathBuf};
-use std::sync::{Mutex};
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
 
 use anyhow::{ensure, Context};
 use bincode::deserialize;
@@ -27,7 +29,7 @@ use storage_proofs_core::{
     measurements::{measure_op, Operation},
     merkle::{
         create_lc_tree, get_base_tree_count, split_config,
-        split_config_and_replica, BinaryMerkleTree, DiskTree, LCTree, MerkleProofTrait, MerkleTree,
+        split_config_and_replica, BinaryMerkleTree, DiskTree, LCTree, MerkleProofTrait,
         MerkleTreeTrait,
     },
     settings::SETTINGS,
@@ -40,24 +42,29 @@ use crate::{
     stacked::vanilla::{
         challenges::LayerChallenges,
         column::Column,
+        cores::{admit_p2, P2Slot},
         create_label,
         graph::StackedBucketGraph,
         params::{
-            get_node, Labels, LabelsCache, PersistentAux, Proof, PublicInputs, PublicParams,
+            Labels, LabelsCache, PersistentAux, Proof, PublicInputs, PublicParams,
             ReplicaColumnProof, Tau, TemporaryAux, TemporaryAuxCache, TransformedLayers,
             BINARY_ARITY,
         },
+        phase::{self, Phase},
+        tree_d::build_tree_d,
         EncodingProof, LabelingProof,
     },
     PoRep,
 };
 
+mod gpu_queue;
 mod tree_c_proof;
 mod tree_r_proof;
 mod tree_building_parallel;
 mod utils;
 
-pub use utils::get_core_pool;
+pub use gpu_queue::gpu_throughput;
+pub use utils::{get_core_pool, RebindPool};
 
 pub const TOTAL_PARENTS: usize = 37;
 
@@ -106,6 +113,35 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             t_aux.tree_d.root()
         );
 
+        (0..partition_count)
+            .map(|k| -> Result<Vec<Proof<Tree, G>>> {
+                trace!("proving partition {}/{}", k + 1, partition_count);
+
+                // Derive the set of challenges we are proving over.
+                let challenges = pub_inputs.challenges(layer_challenges, graph_size, Some(k))?;
+
+                // Stacked commitment specifics
+                challenges
+                    .into_par_iter()
+                    .enumerate()
+                    .map(|(challenge_index, challenge)| {
+                        trace!(" challenge {} ({})", challenge, challenge_index);
+                        Self::prove_challenge(graph, pub_inputs, p_aux, t_aux, layers, challenge)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Proves a single `challenge` against all `layers`.
+    pub(crate) fn prove_challenge(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        pub_inputs: &PublicInputs<<Tree::Hasher as Hasher>::Domain, <G as Hasher>::Domain>,
+        p_aux: &PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+        t_aux: &TemporaryAuxCache<Tree, G>,
+        layers: usize,
+        challenge: usize,
+    ) -> Result<Proof<Tree, G>> {
         let get_drg_parents_columns = |x: usize| -> Result<Vec<Column<Tree::Hasher>>> {
             let base_degree = graph.base_graph().degree();
 
@@ -136,148 +172,130 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 .collect()
         };
 
-        (0..partition_count)
-            .map(|k| {
-                trace!("proving partition {}/{}", k + 1, partition_count);
+        assert!(challenge < graph.size(), "Invalid challenge");
+        assert!(challenge > 0, "Invalid challenge");
+
+        // Initial data layer openings (c_X in Comm_D)
+        let comm_d_proof = t_aux.tree_d.gen_proof(challenge)?;
+        assert!(comm_d_proof.validate(challenge));
+
+        // Stacked replica column openings
+        let rcp = {
+            let (c_x, drg_parents, exp_parents) = {
+                assert_eq!(p_aux.comm_c, t_aux.tree_c.root());
+                let tree_c = &t_aux.tree_c;
+
+                // All labels in C_X
+                trace!("  c_x");
+                let c_x = t_aux.column(challenge as u32)?.into_proof(tree_c)?;
+
+                // All labels in the DRG parents.
+                trace!("  drg_parents");
+                let drg_parents = get_drg_parents_columns(challenge)?
+                    .into_iter()
+                    .map(|column| column.into_proof(tree_c))
+                    .collect::<Result<_>>()?;
+
+                // Labels for the expander parents
+                trace!("  exp_parents");
+                let exp_parents = get_exp_parents_columns(challenge)?
+                    .into_iter()
+                    .map(|column| column.into_proof(tree_c))
+                    .collect::<Result<_>>()?;
+
+                (c_x, drg_parents, exp_parents)
+            };
 
-                // Derive the set of challenges we are proving over.
-                let challenges = pub_inputs.challenges(layer_challenges, graph_size, Some(k));
+            ReplicaColumnProof {
+                c_x,
+                drg_parents,
+                exp_parents,
+            }
+        };
 
-                // Stacked commitment specifics
-                challenges
+        // Final replica layer openings
+        trace!("final replica layer openings");
+        let comm_r_last_proof = t_aux.tree_r_last.gen_cached_proof(
+            challenge,
+            Some(t_aux.tree_r_last_config_rows_to_discard),
+        )?;
+
+        debug_assert!(comm_r_last_proof.validate(challenge));
+
+        // Labeling Proofs Layer 1..l
+        let mut labeling_proofs = Vec::with_capacity(layers);
+        let mut encoding_proof = None;
+
+        for layer in 1..=layers {
+            trace!("  encoding proof layer {}", layer,);
+            let parents_data: Vec<<Tree::Hasher as Hasher>::Domain> = if layer == 1 {
+                let mut parents = vec![0; graph.base_graph().degree()];
+                graph.base_parents(challenge, &mut parents)?;
+
+                parents
+                    .into_par_iter()
+                    .map(|parent| t_aux.domain_node_at_layer(layer, parent))
+                    .collect::<Result<_>>()?
+            } else {
+                let mut parents = vec![0; graph.degree()];
+                graph.parents(challenge, &mut parents)?;
+                let base_parents_count = graph.base_graph().degree();
+
+                parents
                     .into_par_iter()
                     .enumerate()
-                    .map(|(challenge_index, challenge)| {
-                        trace!(" challenge {} ({})", challenge, challenge_index);
-                        assert!(challenge < graph.size(), "Invalid challenge");
-                        assert!(challenge > 0, "Invalid challenge");
-
-                        // Initial data layer openings (c_X in Comm_D)
-                        let comm_d_proof = t_aux.tree_d.gen_proof(challenge)?;
-                        assert!(comm_d_proof.validate(challenge));
-
-                        // Stacked replica column openings
-                        let rcp = {
-                            let (c_x, drg_parents, exp_parents) = {
-                                assert_eq!(p_aux.comm_c, t_aux.tree_c.root());
-                                let tree_c = &t_aux.tree_c;
-
-                                // All labels in C_X
-                                trace!("  c_x");
-                                let c_x = t_aux.column(challenge as u32)?.into_proof(tree_c)?;
-
-                                // All labels in the DRG parents.
-                                trace!("  drg_parents");
-                                let drg_parents = get_drg_parents_columns(challenge)?
-                                    .into_iter()
-                                    .map(|column| column.into_proof(tree_c))
-                                    .collect::<Result<_>>()?;
-
-                                // Labels for the expander parents
-                                trace!("  exp_parents");
-                                let exp_parents = get_exp_parents_columns(challenge)?
-                                    .into_iter()
-                                    .map(|column| column.into_proof(tree_c))
-                                    .collect::<Result<_>>()?;
-
-                                (c_x, drg_parents, exp_parents)
-                            };
-
-                            ReplicaColumnProof {
-                                c_x,
-                                drg_parents,
-                                exp_parents,
-                            }
-                        };
-
-                        // Final replica layer openings
-                        trace!("final replica layer openings");
-                        let comm_r_last_proof = t_aux.tree_r_last.gen_cached_proof(
-                            challenge,
-                            Some(t_aux.tree_r_last_config_rows_to_discard),
-                        )?;
-
-                        debug_assert!(comm_r_last_proof.validate(challenge));
-
-                        // Labeling Proofs Layer 1..l
-                        let mut labeling_proofs = Vec::with_capacity(layers);
-                        let mut encoding_proof = None;
-
-                        for layer in 1..=layers {
-                            trace!("  encoding proof layer {}", layer,);
-                            let parents_data: Vec<<Tree::Hasher as Hasher>::Domain> = if layer == 1
-                            {
-                                let mut parents = vec![0; graph.base_graph().degree()];
-                                graph.base_parents(challenge, &mut parents)?;
-
-                                parents
-                                    .into_par_iter()
-                                    .map(|parent| t_aux.domain_node_at_layer(layer, parent))
-                                    .collect::<Result<_>>()?
-                            } else {
-                                let mut parents = vec![0; graph.degree()];
-                                graph.parents(challenge, &mut parents)?;
-                                let base_parents_count = graph.base_graph().degree();
-
-                                parents
-                                    .into_par_iter()
-                                    .enumerate()
-                                    .map(|(i, parent)| {
-                                        if i < base_parents_count {
-                                            // parents data for base parents is from the current layer
-                                            t_aux.domain_node_at_layer(layer, parent)
-                                        } else {
-                                            // parents data for exp parents is from the previous layer
-                                            t_aux.domain_node_at_layer(layer - 1, parent)
-                                        }
-                                    })
-                                    .collect::<Result<_>>()?
-                            };
-
-                            // repeat parents
-                            let mut parents_data_full = vec![Default::default(); TOTAL_PARENTS];
-                            for chunk in parents_data_full.chunks_mut(parents_data.len()) {
-                                chunk.copy_from_slice(&parents_data[..chunk.len()]);
-                            }
-
-                            let proof = LabelingProof::<Tree::Hasher>::new(
-                                layer as u32,
-                                challenge as u64,
-                                parents_data_full.clone(),
-                            );
-
-                            {
-                                let labeled_node = rcp.c_x.get_node_at_layer(layer)?;
-                                assert!(
-                                    proof.verify(&pub_inputs.replica_id, &labeled_node),
-                                    "Invalid encoding proof generated at layer {}",
-                                    layer,
-                                );
-                                trace!("Valid encoding proof generated at layer {}", layer);
-                            }
-
-                            labeling_proofs.push(proof);
-
-                            if layer == layers {
-                                encoding_proof = Some(EncodingProof::new(
-                                    layer as u32,
-                                    challenge as u64,
-                                    parents_data_full,
-                                ));
-                            }
+                    .map(|(i, parent)| {
+                        if i < base_parents_count {
+                            // parents data for base parents is from the current layer
+                            t_aux.domain_node_at_layer(layer, parent)
+                        } else {
+                            // parents data for exp parents is from the previous layer
+                            t_aux.domain_node_at_layer(layer - 1, parent)
                         }
-
-                        Ok(Proof {
-                            comm_d_proofs: comm_d_proof,
-                            replica_column_proofs: rcp,
-                            comm_r_last_proof,
-                            labeling_proofs,
-                            encoding_proof: encoding_proof.expect("invalid tapering"),
-                        })
                     })
-                    .collect()
-            })
-            .collect()
+                    .collect::<Result<_>>()?
+            };
+
+            // repeat parents
+            let mut parents_data_full = vec![Default::default(); TOTAL_PARENTS];
+            for chunk in parents_data_full.chunks_mut(parents_data.len()) {
+                chunk.copy_from_slice(&parents_data[..chunk.len()]);
+            }
+
+            let proof = LabelingProof::<Tree::Hasher>::new(
+                layer as u32,
+                challenge as u64,
+                parents_data_full.clone(),
+            );
+
+            {
+                let labeled_node = rcp.c_x.get_node_at_layer(layer)?;
+                assert!(
+                    proof.verify(&pub_inputs.replica_id, &labeled_node),
+                    "Invalid encoding proof generated at layer {}",
+                    layer,
+                );
+                trace!("Valid encoding proof generated at layer {}", layer);
+            }
+
+            labeling_proofs.push(proof);
+
+            if layer == layers {
+                encoding_proof = Some(EncodingProof::new(
+                    layer as u32,
+                    challenge as u64,
+                    parents_data_full,
+                ));
+            }
+        }
+
+        Ok(Proof {
+            comm_d_proofs: comm_d_proof,
+            replica_column_proofs: rcp,
+            comm_r_last_proof,
+            labeling_proofs,
+            encoding_proof: encoding_proof.expect("invalid tapering"),
+        })
     }
 
     pub(crate) fn extract_and_invert_transform_layers(
@@ -298,8 +316,124 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         let last_layer_labels = labels.labels_for_last_layer()?;
         let size = Store::len(last_layer_labels);
 
+        Self::decode_nodes(last_layer_labels, 0..size, data)
+    }
+
+    /// Like `extract_and_invert_transform_layers`, but only decodes the `nodes` of the replica
+    /// read from `sealed`, handing them to `sink` `window_nodes` at a time. If the cache
+    /// directory of `config` still holds the last layer, the key is read from it alongside the
+    /// replica, so only one window of each is held in memory. Otherwise the labels are generated
+    /// again, as by `extract_and_invert_transform_layers`, and memory is no longer bounded.
+    #[allow(clippy::too_many_arguments)]
+    pub fn extract_range<R, F>(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        layer_challenges: &LayerChallenges,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        mut sealed: R,
+        nodes: Range<usize>,
+        window_nodes: usize,
+        config: StoreConfig,
+        mut sink: F,
+    ) -> Result<()>
+    where
+        R: Read + Seek,
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        trace!("extract_range {:?}", nodes);
+        ensure!(window_nodes > 0, "window must hold at least one node");
+        ensure!(
+            nodes.start <= nodes.end && nodes.end <= graph.size(),
+            "nodes {:?} out of range for {} nodes",
+            nodes,
+            graph.size()
+        );
+
+        // The last layer is the key, read in place if it was kept.
+        let last_layer_path = StoreConfig::data_path(
+            &config.path,
+            &CacheKey::label_layer(layer_challenges.layers()),
+        );
+        let stored_key = match fs::metadata(&last_layer_path) {
+            Ok(metadata) if metadata.len() == (graph.size() * NODE_SIZE) as u64 => Some(
+                fs::File::open(&last_layer_path)
+                    .with_context(|| format!("failed to open {:?}", last_layer_path))?,
+            ),
+            _ => None,
+        };
+        let labels = match stored_key {
+            Some(_) => None,
+            None => {
+                info!(
+                    "extract_range: no last layer at {:?}, generating the labels",
+                    last_layer_path
+                );
+                Some(Self::generate_labels_for_decoding(
+                    graph,
+                    layer_challenges,
+                    replica_id,
+                    config,
+                )?)
+            }
+        };
+        let mut key = match stored_key {
+            Some(mut file) => {
+                file.seek(SeekFrom::Start((nodes.start * NODE_SIZE) as u64))?;
+                Some((file, vec![0u8; window_nodes.min(nodes.len()) * NODE_SIZE]))
+            }
+            None => None,
+        };
+
+        sealed.seek(SeekFrom::Start((nodes.start * NODE_SIZE) as u64))?;
+        let mut window = vec![0u8; window_nodes.min(nodes.len()) * NODE_SIZE];
+        let mut start = nodes.start;
+        while start < nodes.end {
+            let end = std::cmp::min(start + window_nodes, nodes.end);
+            let data = &mut window[..(end - start) * NODE_SIZE];
+            sealed
+                .read_exact(data)
+                .with_context(|| format!("failed to read sealed nodes {}..{}", start, end))?;
+            match (&mut key, &labels) {
+                (Some((file, key_window)), _) => {
+                    let key_window = &mut key_window[..data.len()];
+                    file.read_exact(key_window)
+                        .with_context(|| format!("failed to read key nodes {}..{}", start, end))?;
+                    Self::decode_window(key_window, data)?;
+                }
+                (None, Some(labels)) => {
+                    Self::decode_nodes(labels.labels_for_last_layer()?, start..end, data)?
+                }
+                (None, None) => unreachable!("labels are generated without a stored key"),
+            }
+            sink(data)?;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    /// Decodes `data` in place with the `key` nodes of the same range.
+    fn decode_window(key: &[u8], data: &mut [u8]) -> Result<()> {
+        for (key_bytes, encoded_node_bytes) in key.chunks(NODE_SIZE).zip(data.chunks_mut(NODE_SIZE))
+        {
+            let key = <Tree::Hasher as Hasher>::Domain::try_from_bytes(key_bytes)?;
+            let encoded_node =
+                <Tree::Hasher as Hasher>::Domain::try_from_bytes(encoded_node_bytes)?;
+            let data_node = decode::<<Tree::Hasher as Hasher>::Domain>(key, encoded_node);
+
+            encoded_node_bytes.copy_from_slice(AsRef::<[u8]>::as_ref(&data_node));
+        }
+
+        Ok(())
+    }
+
+    /// Decodes `data`, the encoded `nodes`, in place with the keys in `last_layer_labels`.
+    fn decode_nodes<S: Store<<Tree::Hasher as Hasher>::Domain>>(
+        last_layer_labels: &S,
+        nodes: Range<usize>,
+        data: &mut [u8],
+    ) -> Result<()> {
         for (key, encoded_node_bytes) in last_layer_labels
-            .read_range(0..size)?
+            .read_range(nodes)?
             .into_iter()
             .zip(data.chunks_mut(NODE_SIZE))
         {
@@ -343,6 +477,97 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         }
     }
 
+    /// Like `generate_labels_for_encoding`, but stops early with
+    /// `create_label::LabelingError::Cancelled` once `cancel` is set, e.g. on shutdown.
+    /// The multicore implementation checks the flag between batches of nodes, the single core
+    /// implementation only before it starts.
+    pub fn generate_labels_for_encoding_cancellable(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        layer_challenges: &LayerChallenges,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+        let mut parent_cache = graph.parent_cache()?;
+
+        if SETTINGS.use_multicore_sdr {
+            create_label::multi::create_labels_for_encoding_cancellable(
+                graph,
+                &parent_cache,
+                layer_challenges.layers(),
+                replica_id,
+                config,
+                &cancel,
+            )
+        } else {
+            if cancel.load(Ordering::SeqCst) {
+                return Err(create_label::LabelingError::Cancelled.into());
+            }
+            info!("single core replication");
+            create_label::single::create_labels_for_encoding(
+                graph,
+                &mut parent_cache,
+                layer_challenges.layers(),
+                replica_id,
+                config,
+            )
+        }
+    }
+
+    /// Like `generate_labels_for_encoding`, choosing whether to `resume` from the layers a
+    /// previous, interrupted run left in the cache directory, see
+    /// `create_label::multi::create_labels_for_encoding_resumable`. The single core
+    /// implementation keeps no checkpoint and always reuses complete layers on disk.
+    pub fn generate_labels_for_encoding_resumable(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        layer_challenges: &LayerChallenges,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        resume: bool,
+    ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+        let mut parent_cache = graph.parent_cache()?;
+
+        if SETTINGS.use_multicore_sdr {
+            create_label::multi::create_labels_for_encoding_resumable(
+                graph,
+                &parent_cache,
+                layer_challenges.layers(),
+                replica_id,
+                config,
+                &AtomicBool::new(false),
+                resume,
+            )
+        } else {
+            info!("single core replication");
+            create_label::single::create_labels_for_encoding(
+                graph,
+                &mut parent_cache,
+                layer_challenges.layers(),
+                replica_id,
+                config,
+            )
+        }
+    }
+
+    /// Generates the layers of a batch of sectors at once, see
+    /// `create_label::batch::create_labels_for_encoding_batch`.
+    pub fn generate_labels_for_encoding_batch(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        layer_challenges: &LayerChallenges,
+        replica_ids: &[<Tree::Hasher as Hasher>::Domain],
+        configs: Vec<StoreConfig>,
+    ) -> Result<Vec<(Labels<Tree>, Vec<LayerState>)>> {
+        let mut parent_cache = graph.parent_cache()?;
+
+        create_label::batch::create_labels_for_encoding_batch(
+            graph,
+            &mut parent_cache,
+            layer_challenges.layers(),
+            replica_ids,
+            configs,
+        )
+    }
+
     /// Generates the layers as needed for encoding.
     pub fn generate_labels_for_encoding_bench(
         graph: &StackedBucketGraph<Tree::Hasher>,
@@ -409,14 +634,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         let leafs = tree_data.len() / NODE_SIZE;
         assert_eq!(tree_data.len() % NODE_SIZE, 0);
 
-        let tree = MerkleTree::from_par_iter_with_config(
-            (0..leafs)
-                .into_par_iter()
-                // TODO: proper error handling instead of `unwrap()`
-                .map(|i| get_node::<K>(tree_data, i).expect("get_node failure")),
-            config,
-        )?;
-        Ok(tree)
+        build_tree_d::<K>(config, leafs, tree_data)
     }
 
     fn generate_tree_c<ColumnArity, TreeArity>(
@@ -425,6 +643,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         tree_count: usize,
         configs: Vec<StoreConfig>,
         labels: &LabelsCache<Tree>,
+        p2_slot: &P2Slot,
     ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: 'static + PoseidonArity,
@@ -437,6 +656,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 tree_count,
                 configs,
                 labels,
+                p2_slot,
             )
         } else {
             Self::generate_tree_c_cpu::<ColumnArity, TreeArity>(
@@ -445,6 +665,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 tree_count,
                 configs,
                 labels,
+                p2_slot,
             )
         }
     }
@@ -456,6 +677,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         tree_r_last_config: StoreConfig,
         replica_path: PathBuf,
         labels: &LabelsCache<Tree>,
+        p2_slot: &P2Slot,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
@@ -468,6 +690,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 tree_r_last_config,
                 replica_path,
                 labels,
+                p2_slot,
             )
         } else {
             Self::generate_tree_r_last_cpu::<TreeArity>(
@@ -477,6 +700,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 tree_r_last_config,
                 replica_path,
                 labels,
+                p2_slot,
             )
         }
     }
@@ -499,6 +723,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         })?
         .0;
 
+        let p2_slot = admit_p2()?;
         if  get_gpu_for_parallel_tree_r() == 0 {
             Self::transform_and_replicate_layers_inner(
                 graph,
@@ -508,6 +733,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 config,
                 replica_path,
                 labels,
+                &p2_slot,
             )
             .context("failed to transform")
         } else {
@@ -519,6 +745,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 config,
                 replica_path,
                 labels,
+                &p2_slot,
             )
             .context("failed to transform")
         }
@@ -532,6 +759,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         config: StoreConfig,
         replica_path: PathBuf,
         label_configs: Labels<Tree>,
+        p2_slot: &P2Slot,
     ) -> Result<TransformedLayers<Tree, G>> {
         trace!("transform_and_replicate_layers");
         let nodes_count = graph.size();
@@ -616,6 +844,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     tree_count,
                     configs,
                     &labels,
+                    p2_slot,
                 )?;
                 tree_c.root()
             }
@@ -626,6 +855,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     tree_count,
                     configs,
                     &labels,
+                    p2_slot,
                 )?;
                 tree_c.root()
             }
@@ -636,6 +866,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     tree_count,
                     configs,
                     &labels,
+                    p2_slot,
                 )?;
                 tree_c.root()
             }
@@ -677,6 +908,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 tree_r_last_config.clone(),
                 replica_path.clone(),
                 &labels,
+                p2_slot,
             )
             .context("failed to generate tree_r_last")
         })?;
@@ -715,18 +947,60 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         pp: &'a PublicParams<Tree>,
         replica_id: &<Tree::Hasher as Hasher>::Domain,
         config: StoreConfig,
+    ) -> Result<Labels<Tree>> {
+        Self::replicate_phase1_resumable(pp, replica_id, config, true)
+    }
+
+    /// Phase1 of replication, resuming after the layers completed by an interrupted run if
+    /// `resume` is set, see `generate_labels_for_encoding_resumable`.
+    pub fn replicate_phase1_resumable(
+        pp: &'a PublicParams<Tree>,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        resume: bool,
     ) -> Result<Labels<Tree>> {
         info!("replicate_phase1");
 
-        let labels = measure_op(Operation::EncodeWindowTimeAll, || {
-            Self::generate_labels_for_encoding(&pp.graph, &pp.layer_challenges, replica_id, config)
+        let labels = phase::observe(Phase::Labeling, || {
+            measure_op(Operation::EncodeWindowTimeAll, || {
+                Self::generate_labels_for_encoding_resumable(
+                    &pp.graph,
+                    &pp.layer_challenges,
+                    replica_id,
+                    config,
+                    resume,
+                )
+            })
         })?
         .0;
 
         Ok(labels)
     }
 
-    /// Phase2 of replication.
+    /// Phase1 of replication for a batch of sectors, labeled together, see
+    /// `generate_labels_for_encoding_batch`. Returns the labels in the order of `replica_ids`.
+    pub fn replicate_phase1_batch(
+        pp: &'a PublicParams<Tree>,
+        replica_ids: &[<Tree::Hasher as Hasher>::Domain],
+        configs: Vec<StoreConfig>,
+    ) -> Result<Vec<Labels<Tree>>> {
+        info!("replicate_phase1_batch");
+
+        let labels = phase::observe(Phase::Labeling, || {
+            measure_op(Operation::EncodeWindowTimeAll, || {
+                Self::generate_labels_for_encoding_batch(
+                    &pp.graph,
+                    &pp.layer_challenges,
+                    replica_ids,
+                    configs,
+                )
+            })
+        })?;
+
+        Ok(labels.into_iter().map(|(labels, _)| labels).collect())
+    }
+
+    /// Phase2 of replication, as the P2 run admitted with `p2_slot`.
     #[allow(clippy::type_complexity)]
     pub fn replicate_phase2(
         pp: &'a PublicParams<Tree>,
@@ -735,21 +1009,25 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         data_tree: BinaryMerkleTree<G>,
         config: StoreConfig,
         replica_path: PathBuf,
+        p2_slot: &P2Slot,
     ) -> Result<(
         <Self as PoRep<'a, Tree::Hasher, G>>::Tau,
         <Self as PoRep<'a, Tree::Hasher, G>>::ProverAux,
     )> {
         info!("replicate_phase2");
 
-        let (tau, paux, taux) = Self::transform_and_replicate_layers_inner(
-            &pp.graph,
-            &pp.layer_challenges,
-            data,
-            Some(data_tree),
-            config,
-            replica_path,
-            label_configs,
-        )?;
+        let (tau, paux, taux) = phase::observe(Phase::TreeBuilding, || {
+            Self::transform_and_replicate_layers_inner(
+                &pp.graph,
+                &pp.layer_challenges,
+                data,
+                Some(data_tree),
+                config,
+                replica_path,
+                label_configs,
+                p2_slot,
+            )
+        })?;
 
         Ok((tau, (paux, taux)))
     }
@@ -1184,6 +1462,47 @@ mod tests {
 
         assert_ne!(data, &mmapped_data[..], "replication did not change data");
 
+        // Decoding a range a few nodes at a time gives the same data, also when the range and
+        // the windows are not aligned.
+        let mut extracted = Vec::new();
+        StackedDrg::<Tree, Blake2sHasher>::extract_range(
+            &pp.graph,
+            &layer_challenges,
+            &replica_id,
+            std::fs::File::open(&replica_path).expect("failed to open replica"),
+            3..nodes - 5,
+            7,
+            config.clone(),
+            |window| {
+                assert!(window.len() <= 7 * NODE_SIZE);
+                extracted.extend_from_slice(window);
+                Ok(())
+            },
+        )
+        .expect("failed to extract range");
+        assert_eq!(&data[3 * NODE_SIZE..(nodes - 5) * NODE_SIZE], &extracted[..]);
+
+        // Without the last layer the key is generated again, with the same result.
+        let last_layer = &label_states[label_states.len() - 1].config;
+        std::fs::remove_file(StoreConfig::data_path(&last_layer.path, &last_layer.id))
+            .expect("failed to delete last layer");
+        let mut regenerated = Vec::new();
+        StackedDrg::<Tree, Blake2sHasher>::extract_range(
+            &pp.graph,
+            &layer_challenges,
+            &replica_id,
+            std::fs::File::open(&replica_path).expect("failed to open replica"),
+            3..nodes - 5,
+            7,
+            config.clone(),
+            |window| {
+                regenerated.extend_from_slice(window);
+                Ok(())
+            },
+        )
+        .expect("failed to extract range");
+        assert_eq!(extracted, regenerated);
+
         let decoded_data = StackedDrg::<Tree, Blake2sHasher>::extract_all(
             &pp,
             &replica_id,
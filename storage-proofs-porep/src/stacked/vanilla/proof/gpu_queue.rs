@@ -0,0 +1,102 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+/// The trees of a P2 tree building waiting for a GPU. Instead of splitting them among the GPUs
+/// up front, each GPU takes the next one whenever it has memory to build it, so a faster GPU,
+/// which frees its memory sooner, builds more of them and a dual-GPU box is not held back by
+/// its slower card.
+pub(crate) struct GpuQueue<T> {
+    pending: Mutex<VecDeque<(usize, T)>>,
+}
+
+impl<T> GpuQueue<T> {
+    /// Queues `items`, each taken along with its index.
+    pub(crate) fn new(items: Vec<T>) -> Self {
+        GpuQueue {
+            pending: Mutex::new(items.into_iter().enumerate().collect()),
+        }
+    }
+
+    /// Takes the next tree, `None` once all are taken.
+    pub(crate) fn take(&self) -> Option<(usize, T)> {
+        self.pending.lock().expect("poisoned lock").pop_front()
+    }
+}
+
+lazy_static! {
+    /// Nodes per second each GPU built its last tree with, by bus id.
+    static ref GPU_THROUGHPUT: Mutex<HashMap<u32, f64>> = Mutex::new(HashMap::new());
+}
+
+/// Weight of a new measurement in the smoothed throughput of a GPU.
+const THROUGHPUT_SMOOTHING: f64 = 0.5;
+
+/// Records that the GPU at `bus_id` built a tree of `nodes` in `elapsed`, and returns its
+/// smoothed throughput in nodes per second.
+pub(crate) fn record_gpu_throughput(bus_id: u32, nodes: usize, elapsed: Duration) -> f64 {
+    let rate = nodes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    let mut throughput = GPU_THROUGHPUT.lock().expect("poisoned lock");
+    let smoothed = match throughput.get(&bus_id) {
+        Some(previous) => previous + THROUGHPUT_SMOOTHING * (rate - previous),
+        None => rate,
+    };
+    throughput.insert(bus_id, smoothed);
+    smoothed
+}
+
+/// The smoothed throughput of each GPU which built a tree in this process, in nodes per
+/// second, by bus id.
+pub fn gpu_throughput() -> HashMap<u32, f64> {
+    GPU_THROUGHPUT.lock().expect("poisoned lock").clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_gpu_queue_balances() {
+        let queue = GpuQueue::new((0..12).collect::<Vec<usize>>());
+
+        // A GPU three times as fast as the other takes about three times as many trees.
+        let taken: Vec<Vec<usize>> = crossbeam::scope(|s| {
+            let workers: Vec<_> = [1u64, 3]
+                .iter()
+                .map(|&millis| {
+                    let queue = &queue;
+                    s.spawn(move |_| {
+                        let mut taken = Vec::new();
+                        while let Some((i, item)) = queue.take() {
+                            assert_eq!(i, item);
+                            taken.push(i);
+                            thread::sleep(Duration::from_millis(millis * 10));
+                        }
+                        taken
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().expect("join failed")).collect()
+        })
+        .expect("crossbeam scope failure");
+
+        assert!(queue.take().is_none());
+        assert_eq!(taken[0].len() + taken[1].len(), 12);
+        assert!(taken[0].len() > taken[1].len(), "{:?}", taken);
+    }
+
+    #[test]
+    fn test_record_gpu_throughput() {
+        // A bus id no real device uses, the throughput is process-wide.
+        let bus_id = u32::MAX;
+        let first = record_gpu_throughput(bus_id, 1000, Duration::from_secs(1));
+        assert!((first - 1000.0).abs() < 1e-6);
+        let second = record_gpu_throughput(bus_id, 3000, Duration::from_secs(1));
+        assert!((second - 2000.0).abs() < 1e-6);
+        assert_eq!(gpu_throughput().get(&bus_id), Some(&second));
+    }
+}
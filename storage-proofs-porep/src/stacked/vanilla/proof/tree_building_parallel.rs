@@ -1,7 +1,7 @@
 use std::marker::PhantomData;
 use std::path::{PathBuf};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use filecoin_hashers::{HashFunction, Hasher};
 use generic_array::typenum::{self, Unsigned};
 use log::*;
@@ -20,12 +20,14 @@ use storage_proofs_core::{
         Operation::{CommD, GenerateTreeRLast},
     },
     merkle::*,
+    progress,
     util::{default_rows_to_discard, NODE_SIZE},
 };
 use typenum::{U11, U2, U8};
 
 use super::super::{
     challenges::LayerChallenges,
+    cores::P2Slot,
     graph::StackedBucketGraph,
     params::{
         Labels, LabelsCache, PersistentAux,
@@ -45,6 +47,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         config: StoreConfig,
         replica_path: PathBuf,
         label_configs: Labels<Tree>,
+        p2_slot: &P2Slot,
     ) -> Result<TransformedLayers<Tree, G>> {
         trace!("transform_and_replicate_layers");
         let nodes_count = graph.size();
@@ -122,108 +125,117 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         };
 
         // FIXME-Ryan: The following 2 steps in P2 can be parallel
-        let mut tree_c_root: <Tree::Hasher as Hasher>::Domain = <Tree::Hasher as Hasher>::Domain::default();
-        let mut tree_d_root: <G as filecoin_hashers::Hasher>::Domain = <G as filecoin_hashers::Hasher>::Domain::default();
-        let mut tree_r_last_root: <Tree::Hasher as Hasher>::Domain = <Tree::Hasher as Hasher>::Domain::default();
+        let mut tree_c_root: Result<<Tree::Hasher as Hasher>::Domain> =
+            Err(anyhow!("tree_c was not built"));
+        let mut tree_d_and_r_last_roots: Result<(
+            <G as filecoin_hashers::Hasher>::Domain,
+            <Tree::Hasher as Hasher>::Domain,
+        )> = Err(anyhow!("tree_r_last was not built"));
 
+        // The spawned builders report to the progress of this thread.
+        let progress = progress::current();
         rayon::scope(|s| {
 
             // capture a shadowed version of datas.
             let tree_c_root = &mut tree_c_root;
-            let tree_d_root = &mut tree_d_root;
-            let tree_r_last_root = &mut tree_r_last_root;
+            let tree_d_and_r_last_roots = &mut tree_d_and_r_last_roots;
 
             let labels = &labels;
             let tree_d_config = &mut tree_d_config;
             let tree_r_last_config = &tree_r_last_config;
 
             // 1)[gpu] Column Hash calculation
-            s.spawn(move |_| {
+            let progress_c = progress.clone();
+            s.spawn(move |_| progress_c.scope(|| {
                 info!("[tree_c] building tree_c in parallel with tree_r");
                 *tree_c_root = match layers {
-                    2 => {
-                        let tree_c = Self::generate_tree_c::<U2, Tree::Arity>(
-                            layers,
-                            nodes_count,
-                            tree_count,
-                            configs,
-                            &labels,
-                        ).expect("failed to generate_tree_c U2");
-                        tree_c.root()
-                    }
-                    8 => {
-                        let tree_c = Self::generate_tree_c::<U8, Tree::Arity>(
-                            layers,
-                            nodes_count,
-                            tree_count,
-                            configs,
-                            &labels,
-                        ).expect("failed to generate_tree_c U8");
-                        tree_c.root()
-                    }
-                    11 => {
-                        let tree_c = Self::generate_tree_c::<U11, Tree::Arity>(
-                            layers,
-                            nodes_count,
-                            tree_count,
-                            configs,
-                            &labels,
-                        ).expect("failed to generate_tree_c U11");
-                        tree_c.root()
-                    }
-                    _ => panic!("Unsupported column arity"),
-                };
-                info!("tree_c done");
-            });
-
-            s.spawn(move |_| {
-                // 2) [cpu] Build the MerkleTree over the original data (if needed).
-                let tree_d = match data_tree {
-                    Some(t) => {
-                        trace!("using existing original data merkle tree");
-                        assert_eq!(t.len(), 2 * (data.len() / NODE_SIZE) - 1);
-
-                        t
-                    }
-                    None => {
-                        trace!("building merkle tree for the original data");
-                        data.ensure_data().expect("failed to data.ensure_data");
-                        measure_op(CommD, || {
-                            Self::build_binary_tree::<G>(data.as_ref(), tree_d_config.clone())
-                        }).expect("failed to tree_d measure_op")
-                    }
-                };
-                tree_d_config.size = Some(tree_d.len());
-                assert_eq!(
-                    tree_d_config.size.expect("config size failure"),
-                    tree_d.len()
-                );
-                *tree_d_root = tree_d.root();
-                drop(tree_d);
-
-                // You have to wait for the second step to be executed before the third step
-                // 3) [gpu] Encode original data into the last layer.
-                info!("[tree_r_last] building tree_r_last in parallel with tree_c");
-                let tree_r_last = measure_op(GenerateTreeRLast, || {
-                    Self::generate_tree_r_last::<Tree::Arity>(
-                        &mut data,
+                    2 => Self::generate_tree_c::<U2, Tree::Arity>(
+                        layers,
                         nodes_count,
                         tree_count,
-                        tree_r_last_config.clone(),
-                        replica_path.clone(),
+                        configs,
                         &labels,
+                        p2_slot,
                     )
-                    .context("failed to generate tree_r_last")
-                }).expect("failed to generate tree_r_last");
-                info!("tree_r_last done");
+                    .context("failed to generate_tree_c U2")
+                    .map(|tree_c| tree_c.root()),
+                    8 => Self::generate_tree_c::<U8, Tree::Arity>(
+                        layers,
+                        nodes_count,
+                        tree_count,
+                        configs,
+                        &labels,
+                        p2_slot,
+                    )
+                    .context("failed to generate_tree_c U8")
+                    .map(|tree_c| tree_c.root()),
+                    11 => Self::generate_tree_c::<U11, Tree::Arity>(
+                        layers,
+                        nodes_count,
+                        tree_count,
+                        configs,
+                        &labels,
+                        p2_slot,
+                    )
+                    .context("failed to generate_tree_c U11")
+                    .map(|tree_c| tree_c.root()),
+                    _ => panic!("Unsupported column arity"),
+                };
+                info!("tree_c done");
+            }));
 
-                *tree_r_last_root = tree_r_last.root();
-                drop(tree_r_last);
+            let progress_r = progress.clone();
+            s.spawn(move |_| progress_r.scope(|| {
+                *tree_d_and_r_last_roots = (|| -> Result<_> {
+                    // 2) [cpu] Build the MerkleTree over the original data (if needed).
+                    let tree_d = match data_tree {
+                        Some(t) => {
+                            trace!("using existing original data merkle tree");
+                            assert_eq!(t.len(), 2 * (data.len() / NODE_SIZE) - 1);
 
-                data.drop_data().unwrap(); 
-            });
+                            t
+                        }
+                        None => {
+                            trace!("building merkle tree for the original data");
+                            data.ensure_data().context("failed to data.ensure_data")?;
+                            measure_op(CommD, || {
+                                Self::build_binary_tree::<G>(data.as_ref(), tree_d_config.clone())
+                            }).context("failed to tree_d measure_op")?
+                        }
+                    };
+                    tree_d_config.size = Some(tree_d.len());
+                    let tree_d_root = tree_d.root();
+                    drop(tree_d);
+
+                    // You have to wait for the second step to be executed before the third step
+                    // 3) [gpu] Encode original data into the last layer.
+                    info!("[tree_r_last] building tree_r_last in parallel with tree_c");
+                    let tree_r_last = measure_op(GenerateTreeRLast, || {
+                        Self::generate_tree_r_last::<Tree::Arity>(
+                            &mut data,
+                            nodes_count,
+                            tree_count,
+                            tree_r_last_config.clone(),
+                            replica_path.clone(),
+                            &labels,
+                            p2_slot,
+                        )
+                        .context("failed to generate tree_r_last")
+                    })?;
+                    info!("tree_r_last done");
+
+                    let tree_r_last_root = tree_r_last.root();
+                    drop(tree_r_last);
+
+                    data.drop_data()?;
+                    Ok((tree_d_root, tree_r_last_root))
+                })();
+            }));
         });
 
+        let tree_c_root = tree_c_root?;
+        let (tree_d_root, tree_r_last_root) = tree_d_and_r_last_roots?;
+
         // comm_r = H(comm_c || comm_r_last)
         let comm_r: <Tree::Hasher as Hasher>::Domain =
             <Tree::Hasher as Hasher>::Function::hash2(&tree_c_root, &tree_r_last_root);
@@ -1,7 +1,7 @@
 use std::sync::{mpsc, Arc, RwLock, Mutex,
     atomic::{AtomicU64, Ordering::SeqCst}};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use bellperson::bls::Fr;
 use filecoin_hashers::{Hasher, PoseidonArity};
@@ -16,6 +16,7 @@ use storage_proofs_core::{
         Operation::{GenerateTreeC},
     },
     merkle::*,
+    progress::{self, ProgressEvent},
     settings,
     util::{NODE_SIZE},
 };
@@ -26,10 +27,11 @@ use super::super::{
         LabelsCache
     },
     proof::StackedDrg,
-    cores::{get_p2_core_group, CoreIndex, Cleanup, bind_core_set},
-    utils::{P2BoundPolicy, p2_binding_policy, p2_binding_use_same_set}
+    cores::{get_p2_core_group, logical_cpu_count, CoreIndex, Cleanup, bind_core_set, bind_thread_near_gpu, P2Slot},
+    utils::{gather_column, P2BoundPolicy, p2_binding_policy, p2_binding_use_same_set}
 };
 
+use super::gpu_queue::{record_gpu_throughput, GpuQueue};
 use super::utils::{get_memory_padding, get_gpu_for_parallel_tree_r, get_core_pool};
 
 use generic_array::{GenericArray};
@@ -49,6 +51,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         tree_count: usize,
         configs: Vec<StoreConfig>,
         labels: &LabelsCache<Tree>,
+        p2_slot: &P2Slot,
     ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: 'static + PoseidonArity,
@@ -92,7 +95,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 last_idx = bus_num - tree_r_gpu;
             }
 
-            let trees_per_gpu: usize = std::env::var("FIL_PROOFS_TREE_PER_GPU")
+            let trees_per_gpu: usize = settings::var("FIL_PROOFS_TREE_PER_GPU")
                 .and_then(|v| match v.parse() {
                     Ok(val) => Ok(val),
                     Err(_) => {
@@ -114,7 +117,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             }
 
             // ================= CPU POOL ===============
-            let groups = get_p2_core_group();
+            let groups = get_p2_core_group(p2_slot);
             let mut core_group: Vec<CoreIndex> = vec![];
             let mut core_group_usize: Vec<usize> = vec![];
             let use_same_set = p2_binding_use_same_set();
@@ -160,19 +163,18 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
 
 
-            let mut builders_rx_by_gpu = Vec::new();
+            let mut builders_rx = Vec::new();
             let mut builders_tx = Vec::new();
-            for _i in 0..bus_num {
-                builders_rx_by_gpu.push(Vec::new());
-            }
 
-            for config_idx in 0..configs.len() {
+            for _config_idx in 0..configs.len() {
                 // This channel will receive batches of columns and add them to the ColumnTreeBuilder.
                 // Each config has own channel
                 let (builder_tx, builder_rx) = mpsc::sync_channel(0);
                 builders_tx.push(builder_tx);
-                builders_rx_by_gpu[config_idx % bus_num].push(builder_rx);
+                builders_rx.push(builder_rx);
             }
+            // Each GPU takes the next config once it has memory for it, see `GpuQueue`.
+            let builders_rx = GpuQueue::new(builders_rx);
 
             let bus_num = batchertype_gpus.len();
             assert!(bus_num > 0);
@@ -193,6 +195,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             let gpu_memory_padding = get_memory_padding();
 
             let configs =  Arc::new(configs);
+            let progress = progress::current();
             crossbeam::scope(|s| {
                 let mut main_threads = Vec::new();
                 // This channel will receive the finished tree data to be written to disk.
@@ -244,31 +247,35 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                             layer_data.iter_mut().enumerate()
                                         {
                                             let labels = labels.lock().unwrap();
-                                            let store = labels.labels_for_layer(layer_index + 1);
                                             let start = (i * nodes_count) + node_index;
                                             let end = start + chunked_nodes_count;
 
-                                            store
-                                                .read_range_into(start, end, &mut layer_bytes)
+                                            labels
+                                                .read_layer_range_into(layer_index + 1, start, end, &mut layer_bytes)
                                                 .expect("failed to read store range");
                                         }
 
-                                        let pool = get_core_pool(core_group_usize.clone());
+                                        let pool = get_core_pool("p2", core_group_usize.clone());
                                         pool.install(|| {
+                                            let layer_data = layer_data
+                                                .iter()
+                                                .map(|layer_bytes| layer_bytes.as_slice())
+                                                .collect::<Vec<_>>();
                                             let res = (0..chunked_nodes_count)
                                                 .into_par_iter()
-                                                .map(|index| {
-                                                    (0..layers)
-                                                        .map(|layer_index| {
-                                                            bytes_into_fr(
-                                                            &layer_data[layer_index][std::mem::size_of::<Fr>()
-                                                                * index
-                                                                ..std::mem::size_of::<Fr>() * (index + 1)],
-                                                        )
-                                                        .expect("Could not create Fr from bytes.")
-                                                        })
-                                                        .collect::<GenericArray<Fr, ColumnArity>>()
-                                                })
+                                                .map_init(
+                                                    || vec![0u8; layers * NODE_SIZE],
+                                                    |column, index| {
+                                                        gather_column(&layer_data, index, column);
+                                                        column
+                                                            .chunks(NODE_SIZE)
+                                                            .map(|label| {
+                                                                bytes_into_fr(label)
+                                                                    .expect("Could not create Fr from bytes.")
+                                                            })
+                                                            .collect::<GenericArray<Fr, ColumnArity>>()
+                                                    },
+                                                )
                                                 .collect();
                                             res
                                         })
@@ -297,6 +304,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 })); // spawn
                 
                 let batchertype_gpus = &batchertype_gpus;
+                let builders_rx = &builders_rx;
                 let gpu_indexes: Vec<usize> = (0.. bus_num).collect();
 
                 //Parallel tuning GPU computing
@@ -308,8 +316,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
                         let writers_tx = Arc::new(writers_tx);
 
-                        for (&gpu_index, builders_rx) in gpu_indexes.iter()
-                            .zip(builders_rx_by_gpu.into_iter())
+                        for &gpu_index in gpu_indexes.iter()
                             {
                                 let writers_tx = writers_tx.clone();
 
@@ -348,14 +355,28 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                     assert!(locked_gpu >= 0);
                                     let locked_gpu: usize = locked_gpu as usize;
 
+                                    // Unbound, the thread feeding the GPU at least runs on its NUMA node.
+                                    let _cleanup_handle_near_gpu = match &batchertype_gpus[locked_gpu] {
+                                        BatcherType::CustomGPU(selector) if _cleanup_handle_gpu_i.is_none() => {
+                                            bind_thread_near_gpu(selector.get_device().and_then(|device| device.bus_id()))
+                                        }
+                                        _ => None,
+                                    };
+
                                     let mut mem_total: u64 = 0;
                                     let mem_used = AtomicU64::new(0);
+                                    let bus_id = match &batchertype_gpus[locked_gpu] {
+                                        BatcherType::CustomGPU(selector) => {
+                                            selector.get_device().and_then(|device| device.bus_id())
+                                        }
+                                        _ => None,
+                                    };
 
                                     match &batchertype_gpus[locked_gpu] {
                                         BatcherType::CustomGPU(selector) => {
                                             mem_total = selector.get_device().unwrap().memory();
 
-                                            info!("[tree_c] Run ColumnTreeBuilder over indexes i % gpu_num = {} on {} (buis_id: {}, memory: {})",
+                                            info!("[tree_c] Run ColumnTreeBuilder in GPU slot {} on {} (buis_id: {}, memory: {})",
                                             gpu_index,
                                             selector.get_device().unwrap().name(),
                                             selector.get_device().unwrap().bus_id().unwrap(),
@@ -367,18 +388,27 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                         }
                                     }
 
-                                    // Loop until all trees for all configs have been built.
-                                    let config_ids: Vec<_> = (gpu_index..config_count).step_by(bus_num).collect();
-
-                                    
+                                    // Loop until all trees for all configs have been built, taking
+                                    // the next one whenever there is memory for it.
                                     crossbeam::scope(|s3| {
                                         let mut config_threads = Vec::new();
 
                                         let writers_tx = Arc::new(writers_tx);
                                         let mem_used = Arc::new(mem_used);
-                                        for (&i, builder_rx) in config_ids.iter()
-                                            .zip(builders_rx.into_iter())
-                                            {
+                                        loop {
+                                            let mut printed = false;
+                                            while (mem_used.load(SeqCst) + mem_column_add) as f64 >= (1.0 - gpu_memory_padding) * (mem_total as f64) {
+                                                if !printed {
+                                                    info!("gpu memory shortage on {}, waiting...", locked_gpu);
+                                                    printed = true;
+                                                }
+                                                thread::sleep(Duration::from_secs(1));
+                                            }
+                                            let (i, builder_rx) = match builders_rx.take() {
+                                                Some(config) => config,
+                                                None => break,
+                                            };
+                                            mem_used.fetch_add(mem_column_add, SeqCst);
                                             if i != 0 {
                                                 thread::sleep(Duration::from_secs(5));
                                             }
@@ -386,21 +416,11 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                             let mem_used = mem_used.clone();
                                             config_threads.push(s3.spawn(move |_| {
                                                 let _cleanup_handle_gpu_inner = bind_thread();
-                                                let mut printed = false;
-                                                let mut mem_used_val = mem_used.load(SeqCst);
-                                                while (mem_used_val + mem_column_add) as f64 >= (1.0 - gpu_memory_padding) * (mem_total as f64) {
-                                                    if !printed {
-                                                        info!("gpu memory shortage on {}, waiting ({})...", locked_gpu, i);
-                                                        printed = true;
-                                                    }
-                                                    thread::sleep(Duration::from_secs(1));
-                                                    mem_used_val = mem_used.load(SeqCst);
-                                                }
-                                                mem_used.fetch_add(mem_column_add, SeqCst);
                                                 if printed {
                                                     info!("continue on {} ({})", locked_gpu, i);
                                                     thread::sleep(Duration::from_secs(i as u64));
                                                 }
+                                                let started = Instant::now();
 
                                                 let mut column_tree_builder = ColumnTreeBuilder::<ColumnArity, TreeArity>::new(
                                                     Some(batchertype_gpus[locked_gpu].clone()),
@@ -433,6 +453,11 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
                                                     let tree_len = base_data.len() + tree_data.len();
 
+                                                    if let Some(bus_id) = bus_id {
+                                                        let rate = record_gpu_throughput(bus_id, nodes_count, started.elapsed());
+                                                        info!("[tree_c] GPU {} builds {:.0} nodes/s", bus_id, rate);
+                                                    }
+
                                                     info!(
                                                         "persisting base tree_c {}/{} of length {}",
                                                         i + 1,
@@ -468,11 +493,12 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 }));
 
                 let configs = configs.clone();
+                let progress = progress.clone();
                 main_threads.push(s.spawn(move |_| {
                     let _cleanup_handle_write = bind_thread();
                     configs.iter().enumerate()
                         .zip(writers_rx.iter())
-                        .for_each(|((_i, config), writer_rx)| {
+                        .for_each(|((i, config), writer_rx)| {
                         let (base_data, tree_data) = writer_rx
                             .recv()
                             .expect("failed to receive base_data, tree_data for tree_c");
@@ -529,6 +555,10 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                             .sync()
                             .expect("store sync failure");
                         trace!("done writing tree_c store data");
+                        progress.report(ProgressEvent::TreeCBuilt {
+                            config: i + 1,
+                            configs: configs.len(),
+                        });
                     });
                 }));
 
@@ -549,6 +579,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         tree_count: usize,
         configs: Vec<StoreConfig>,
         labels: &LabelsCache<Tree>,
+        p2_slot: &P2Slot,
     ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: PoseidonArity,
@@ -557,7 +588,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         info!("generating tree c using the CPU");
         measure_op(GenerateTreeC, || {
             // ================= CPU POOL ===============
-            let groups = get_p2_core_group();
+            let groups = get_p2_core_group(p2_slot);
             let mut core_group: Vec<CoreIndex> = vec![];
             let mut core_group_usize: Vec<usize> = vec![];
 
@@ -593,7 +624,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             
             info!("Building column hashes");
 
-            let pool = get_core_pool(core_group_usize.clone());
+            let pool = get_core_pool("p2", core_group_usize.clone());
+            let progress = progress::current();
             pool.install(|| {
 
                 let mut trees = Vec::with_capacity(tree_count);
@@ -602,7 +634,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                         vec![<Tree::Hasher as Hasher>::Domain::default(); nodes_count];
 
                     rayon::scope(|s| {
-                        let n = num_cpus::get();
+                        let n = logical_cpu_count();
 
                         // only split if we have at least two elements per thread
                         let num_chunks = if n > nodes_count * 2 { 1 } else { n };
@@ -641,6 +673,10 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     >::from_par_iter_with_config(
                         hashes.into_par_iter(), config.clone()
                     ));
+                    progress.report(ProgressEvent::TreeCBuilt {
+                        config: i + 1,
+                        configs: tree_count,
+                    });
                 }
 
                 assert_eq!(tree_count, trees.len());
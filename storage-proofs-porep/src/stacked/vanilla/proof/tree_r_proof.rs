@@ -1,10 +1,8 @@
-use std::fs::OpenOptions;
-use std::io::Write;
 use std::path::{PathBuf};
 use std::sync::{mpsc, Arc, Mutex,
     atomic::{AtomicU64, Ordering::SeqCst}};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use bellperson::bls::Fr;
@@ -17,9 +15,11 @@ use merkletree::merkle::{
 use merkletree::store::{StoreConfig};
 use rayon::prelude::*;
 use storage_proofs_core::{
+    artifact_io::{self, Artifact},
     data::Data,
     error::Result,
     merkle::*,
+    progress::{self, ProgressEvent},
     settings,
     util::{NODE_SIZE},
 };
@@ -29,7 +29,7 @@ use super::super::{
         LabelsCache,
     },
     proof::StackedDrg,
-    cores::{bind_core_set, get_p2_core_group, CoreIndex, Cleanup},
+    cores::{bind_core_set, bind_thread_near_gpu, get_p2_core_group, CoreIndex, Cleanup, P2Slot},
     utils::{P2BoundPolicy, p2_binding_policy, p2_binding_use_same_set}
 };
 
@@ -42,6 +42,7 @@ use rust_gpu_tools::opencl;
 use crate::encode::{encode};
 
 use bellperson::gpu::{scheduler};
+use super::gpu_queue::{record_gpu_throughput, GpuQueue};
 use super::utils::{get_memory_padding, get_gpu_for_parallel_tree_r, get_core_pool};
 
 impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tree, G> { 
@@ -52,6 +53,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         tree_r_last_config: StoreConfig,
         replica_path: PathBuf,
         labels: &LabelsCache<Tree>,
+        p2_slot: &P2Slot,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
@@ -64,7 +66,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         )?;
 
         data.ensure_data()?;
-        let last_layer_labels = labels.labels_for_last_layer()?;
+        let last_layer = labels.len();
 
         info!("[tree_r_last] generating tree r last using the GPU");
         let max_gpu_tree_batch_size = settings::SETTINGS.max_gpu_tree_batch_size as usize;
@@ -90,7 +92,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
         let mut bus_num = bus_num - start_idx;
 
-        let trees_per_gpu: usize = std::env::var("FIL_PROOFS_TREE_PER_GPU")
+        let trees_per_gpu: usize = settings::var("FIL_PROOFS_TREE_PER_GPU")
                 .and_then(|v| match v.parse() {
                     Ok(val) => Ok(val),
                     Err(_) => {
@@ -112,7 +114,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         }
 
         // ================= CPU POOL ===============
-        let groups = get_p2_core_group();
+        let groups = get_p2_core_group(p2_slot);
         let mut core_group: Vec<CoreIndex> = vec![];
         let mut core_group_usize: Vec<usize> = vec![];
         
@@ -156,19 +158,18 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         };
         // =====
         
-        let mut builders_rx_by_gpu = Vec::new();
+        let mut builders_rx = Vec::new();
         let mut builders_tx = Vec::new();
-        for _i in 0..bus_num {
-            builders_rx_by_gpu.push(Vec::new());
-        }
 
-        for config_idx in 0..configs.len() {
+        for _config_idx in 0..configs.len() {
             // This channel will receive batches of columns and add them to the ColumnTreeBuilder.
             // Each config has own channel
             let (builder_tx, builder_rx) = mpsc::sync_channel(0);
             builders_tx.push(builder_tx);
-            builders_rx_by_gpu[config_idx % bus_num].push(builder_rx);
+            builders_rx.push(builder_rx);
         }
+        // Each GPU takes the next config once it has memory for it, see `GpuQueue`.
+        let builders_rx = GpuQueue::new(builders_rx);
 
 
         let bus_num = batchertype_gpus.len();
@@ -177,11 +178,12 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         let mem_one_thread = 800000000;
         let gpu_memory_padding = get_memory_padding();
 
-        let last_layer_labels = Arc::new(Mutex::new(last_layer_labels));
+        let last_layer_labels = Arc::new(Mutex::new(labels));
 
         let config_count = configs.len(); // Don't move config into closure below.
         let configs = &configs;
         let tree_r_last_config = &tree_r_last_config;
+        let progress = progress::current();
         crossbeam::scope(|s| {
             let mut main_threads = Vec::new();
 
@@ -238,11 +240,11 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                         let labels_start = i * nodes_count + node_index;
                                         let labels_end = labels_start + chunked_nodes_count;
                                         last_layer_labels
-                                            .read_range_into(labels_start, labels_end, &mut layer_bytes)
+                                            .read_layer_range_into(last_layer, labels_start, labels_end, &mut layer_bytes)
                                             .expect("failed to read layer bytes");
                                     }
 
-                                    let pool = get_core_pool(core_group_usize.clone());
+                                    let pool = get_core_pool("p2", core_group_usize.clone());
                                     pool.install(|| {
                                         let res = layer_bytes
                                             .into_par_iter() // TODO CROSSBEAM
@@ -299,6 +301,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 }).unwrap(); // scope s2
             })); // spawn
             let batchertype_gpus = &batchertype_gpus;
+            let builders_rx = &builders_rx;
             let gpu_indexes: Vec<usize> = (0.. bus_num).collect();
 
             //Parallel tuning GPU computing
@@ -309,8 +312,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
                     let writers_tx = Arc::new(writers_tx);
 
-                    for (&gpu_index, builders_rx) in gpu_indexes.iter()
-                        .zip(builders_rx_by_gpu.into_iter())
+                    for &gpu_index in gpu_indexes.iter()
                         {
 
                         let writers_tx = writers_tx.clone();
@@ -350,8 +352,22 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                             assert!(locked_gpu >= 0);
                             let locked_gpu: usize = locked_gpu as usize;
 
+                            // Unbound, the thread feeding the GPU at least runs on its NUMA node.
+                            let _cleanup_handle_near_gpu = match &batchertype_gpus[locked_gpu] {
+                                BatcherType::CustomGPU(selector) if _cleanup_handle_gpu_i.is_none() => {
+                                    bind_thread_near_gpu(selector.get_device().and_then(|device| device.bus_id()))
+                                }
+                                _ => None,
+                            };
+
                             let mut mem_total: u64 = 0;
                             let mem_used = AtomicU64::new(0);
+                            let bus_id = match &batchertype_gpus[locked_gpu] {
+                                BatcherType::CustomGPU(selector) => {
+                                    selector.get_device().and_then(|device| device.bus_id())
+                                }
+                                _ => None,
+                            };
                             
                             let tree_r_last_config = &tree_r_last_config;
                             let batchertype_gpus = &batchertype_gpus;
@@ -360,7 +376,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                 BatcherType::CustomGPU(selector) => {
                                     mem_total = selector.get_device().unwrap().memory();
 
-                                    info!("[tree_r_last] Run TreeBuilder over indexes i % gpu_num = {} on {} (buis_id: {})",
+                                    info!("[tree_r_last] Run TreeBuilder in GPU slot {} on {} (buis_id: {})",
                                     gpu_index,
                                     selector.get_device().unwrap().name(),
                                     selector.get_device().unwrap().bus_id().unwrap(),
@@ -371,38 +387,37 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                 }
                             }
 
-                            // Loop until all trees for all configs have been built.
-                            let config_ids: Vec<_> = (gpu_index..config_count).step_by(bus_num).collect();
-
                             crossbeam::scope(|s3| {
                                 let mut config_threads = Vec::new();
                                 let writers_tx = Arc::new(writers_tx);
                                 let mem_used = Arc::new(mem_used);
 
-                                // Loop until all trees for all configs have been built.
-                                for (&i, builder_rx) in config_ids.iter()
-                                    .zip(builders_rx.into_iter())
-                                    {
+                                // Loop until all trees for all configs have been built, taking
+                                // the next one whenever there is memory for it.
+                                loop {
+                                    let mut printed = false;
+                                    while (mem_used.load(SeqCst) + mem_one_thread) as f64 >= (1.0 - gpu_memory_padding) * (mem_total as f64) {
+                                        if !printed {
+                                            info!("gpu memory shortage on {}, waiting...", locked_gpu);
+                                            printed = true;
+                                        }
+                                        thread::sleep(Duration::from_secs(1));
+                                    }
+                                    let (i, builder_rx) = match builders_rx.take() {
+                                        Some(config) => config,
+                                        None => break,
+                                    };
+                                    mem_used.fetch_add(mem_one_thread, SeqCst);
+
                                     let writers_tx  = writers_tx.clone();
                                     let mem_used = mem_used.clone();
                                     
                                     config_threads.push(s3.spawn(move |_| {
                                         let _cleanup_handle_gpu_inner = bind_thread();
-                                        let mut printed = false;
-                                        let mut mem_used_val = mem_used.load(SeqCst);
-                                        while (mem_used_val + mem_one_thread) as f64 >= (1.0 - gpu_memory_padding) * (mem_total as f64) {
-                                            if !printed {
-                                                info!("gpu memory shortage on {}, waiting ({})...", locked_gpu, i);
-                                                printed = true;
-                                            }
-                                            thread::sleep(Duration::from_secs(1));
-                                            mem_used_val = mem_used.load(SeqCst);
-                                        }
-                                        mem_used.fetch_add(mem_one_thread, SeqCst);
-
                                         if printed {
                                             info!("continue on {} ({})", locked_gpu, i);
                                         }
+                                        let started = Instant::now();
 
                                         let mut tree_builder = TreeBuilder::<Tree::Arity>::new(
                                             Some(batchertype_gpus[locked_gpu].clone()),
@@ -428,6 +443,11 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                             let (_, tree_data) = tree_builder
                                                 .add_final_leaves(&encoded)
                                                 .expect("failed to add final leaves");
+
+                                            if let Some(bus_id) = bus_id {
+                                                let rate = record_gpu_throughput(bus_id, nodes_count, started.elapsed());
+                                                info!("[tree_r_last] GPU {} builds {:.0} nodes/s", bus_id, rate);
+                                            }
                     
     
                                             mem_used.fetch_sub(mem_one_thread, SeqCst);
@@ -453,11 +473,12 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 }).unwrap(); // scope s2
             }));
 
+            let progress = &progress;
             main_threads.push(s.spawn(move |_| {
                 let _cleanup_handle_write = bind_thread();
                 configs.iter().enumerate()
                     .zip(writers_rx.iter())
-                    .for_each(|((_i, config), writer_rx)| {
+                    .for_each(|((i, config), writer_rx)| {
 
                     let tree_data = writer_rx
                         .recv()
@@ -489,13 +510,16 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                         config.rows_to_discard,
                         tree_r_last_path
                     );
-                    let mut f = OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .open(&tree_r_last_path)
-                        .expect("failed to open file for tree_r_last");
-                    f.write_all(&flat_tree_data)
-                        .expect("failed to wrote tree_r_last data");
+                    artifact_io::write_file(
+                        &tree_r_last_path,
+                        &flat_tree_data,
+                        Artifact::Tree.io_strategy(),
+                    )
+                    .expect("failed to write tree_r_last data");
+                    progress.report(ProgressEvent::TreeRLastBuilt {
+                        config: i + 1,
+                        configs: config_count,
+                    });
                 });
             })); //spawn
 
@@ -518,6 +542,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         tree_r_last_config: StoreConfig,
         replica_path: PathBuf,
         labels: &LabelsCache<Tree>,
+        p2_slot: &P2Slot,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
@@ -525,7 +550,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         info!("generating tree r last using the CPU");
 
         // ================= CPU POOL ===============
-        let groups = get_p2_core_group();
+        let groups = get_p2_core_group(p2_slot);
         let mut core_group: Vec<CoreIndex> = vec![];
         let mut core_group_usize: Vec<usize> = vec![];
         
@@ -559,7 +584,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         let core_group_usize = Arc::new(core_group_usize);
         // =====
 
-        let pool = get_core_pool(core_group_usize.clone());
+        let pool = get_core_pool("p2", core_group_usize.clone());
+        let progress = progress::current();
         pool.install(|| {
 
             let (configs, replica_config) = split_config_and_replica(
@@ -570,17 +596,21 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             )?;
 
             data.ensure_data()?;
-            let last_layer_labels = labels.labels_for_last_layer()?;
-
-            let size = Store::len(last_layer_labels);
+            let last_layer = labels.len();
+            let size = Store::len(labels.labels_for_last_layer()?);
 
             let mut start = 0;
             let mut end = size / tree_count;
 
             for (i, config) in configs.iter().enumerate() {
-                let encoded_data = last_layer_labels
-                    .read_range(start..end)?
-                    .into_par_iter()
+                let mut layer_bytes = vec![0u8; (end - start) * NODE_SIZE];
+                labels.read_layer_range_into(last_layer, start, end, &mut layer_bytes)?;
+                let encoded_data = layer_bytes
+                    .par_chunks(NODE_SIZE)
+                    .map(|key_bytes| {
+                        <Tree::Hasher as Hasher>::Domain::try_from_bytes(key_bytes)
+                            .expect("try from bytes failed")
+                    })
                     .zip(
                         data.as_mut()[(start * NODE_SIZE)..(end * NODE_SIZE)]
                             .par_chunks_mut(NODE_SIZE),
@@ -602,6 +632,10 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     tree_count
                 );
                 LCTree::<Tree::Hasher, Tree::Arity, typenum::U0, typenum::U0>::from_par_iter_with_config(encoded_data, config.clone()).with_context(|| format!("failed tree_r_last CPU {}/{}", i + 1, tree_count))?;
+                progress.report(ProgressEvent::TreeRLastBuilt {
+                    config: i + 1,
+                    configs: tree_count,
+                });
 
                 start = end;
                 end += size / tree_count;
@@ -1,12 +1,20 @@
 use log::*;
-use std::sync::{Arc};
-use storage_proofs_core::settings::SETTINGS;
-use num_cpus;
+use std::cell::RefCell;
+use std::sync::{Arc, Barrier, MutexGuard};
+use storage_proofs_core::settings::{self, SETTINGS};
+
+use super::super::{
+    cores::{
+        bind_core_set, get_p2_core_group, logical_cpu_count, Cleanup, CoreGroup, CoreIndex,
+        P2Slot,
+    },
+    utils::worker_stack_bytes,
+};
 
 const MEMORY_PADDING: f64 = 0.35f64;
 
 pub fn get_memory_padding() -> f64 {
-    std::env::var("FIL_PROOFS_GPU_MEMORY_PADDING")
+    settings::var("FIL_PROOFS_GPU_MEMORY_PADDING")
         .and_then(|v| match v.parse() {
             Ok(val) => Ok(val),
             Err(_) => {
@@ -20,7 +28,7 @@ pub fn get_memory_padding() -> f64 {
 }
 
 pub fn get_gpu_for_parallel_tree_r() -> usize {
-    std::env::var("FIL_PROOFS_GPU_FOR_PARALLEL_TREE_R")
+    settings::var("FIL_PROOFS_GPU_FOR_PARALLEL_TREE_R")
                 .and_then(|v| match v.parse() {
                     Ok(val) => Ok(val),
                     Err(_) => {
@@ -31,13 +39,230 @@ pub fn get_gpu_for_parallel_tree_r() -> usize {
                 .unwrap_or(SETTINGS.gpu_for_parallel_tree_r) as usize
 }
 
-pub fn get_core_pool(core_group: Arc<Vec<usize>>) -> rayon::ThreadPool {
+/// Builds a pool for `phase`, `p1` or `p2`, which names its workers, see `worker_thread_name`.
+/// With cores in `core_group`, one worker is bound to each of them, otherwise there is one
+/// unbound worker per CPU.
+pub fn get_core_pool(phase: &'static str, core_group: Arc<Vec<usize>>) -> rayon::ThreadPool {
     let pool;
+    let stack_bytes = worker_stack_bytes();
     if core_group.len() > 0 {
-        pool = thread_binder::ThreadPoolBuilder::new_with_core_set(core_group.clone()).build().expect("failed creating core pool");
+        pool = bound_pool(phase, core_group.clone(), stack_bytes);
     } else {
-        let cpus = num_cpus::get();
-        pool = rayon::ThreadPoolBuilder::new().num_threads(cpus).build().expect("failed creating core pool");
+        let cpus = logical_cpu_count();
+        let mut builder = rayon::ThreadPoolBuilder::new()
+            .num_threads(cpus)
+            .thread_name(move |i| worker_thread_name(phase, i, None));
+        if let Some(stack_bytes) = stack_bytes {
+            builder = builder.stack_size(stack_bytes);
+        }
+        pool = builder.build().expect("failed creating core pool");
     }
     pool
 }
+
+/// The name of worker `worker` of a pool for `phase`, e.g. `fil-p2-w3-core12`, so profilers
+/// show which worker runs on which core.
+pub(crate) fn worker_thread_name(phase: &str, worker: usize, core: Option<usize>) -> String {
+    match core {
+        Some(core) => format!("fil-{}-w{}-core{}", phase, worker, core),
+        None => format!("fil-{}-w{}", phase, worker),
+    }
+}
+
+/// Builds a pool for `phase` with one worker per core in `core_group`, each bound to its core,
+/// optionally with a custom stack size. The binding is kept for the lifetime of the worker.
+fn bound_pool(
+    phase: &'static str,
+    core_group: Arc<Vec<usize>>,
+    stack_bytes: Option<usize>,
+) -> rayon::ThreadPool {
+    let names = core_group.clone();
+    let cores = core_group.clone();
+    let mut builder = rayon::ThreadPoolBuilder::new()
+        .num_threads(core_group.len())
+        .thread_name(move |i| worker_thread_name(phase, i, Some(names[i % names.len()])))
+        .start_handler(move |i| bind_worker(i, CoreIndex(cores[i % cores.len()])));
+    if let Some(stack_bytes) = stack_bytes {
+        builder = builder.stack_size(stack_bytes);
+    }
+    builder.build().expect("failed creating core pool")
+}
+
+thread_local! {
+    /// The binding of a pool worker, restored when the worker is rebound, unbound or exits.
+    static WORKER_BINDING: RefCell<Option<Cleanup>> = RefCell::new(None);
+}
+
+/// Binds the calling worker `i` of a pool to `core`, replacing its previous binding.
+fn bind_worker(i: usize, core: CoreIndex) {
+    // The previous binding is restored first, so the restored binding in the end is the one
+    // from before the first.
+    unbind_worker();
+    match bind_core_set(Arc::new(vec![core])) {
+        Ok(cleanup) => WORKER_BINDING.with(|binding| *binding.borrow_mut() = Some(cleanup)),
+        Err(err) => warn!("failed to bind worker {} to core {}: {:?}", i, core.0, err),
+    }
+}
+
+/// Restores the binding the calling worker of a pool had before `bind_worker`.
+fn unbind_worker() {
+    WORKER_BINDING.with(|binding| drop(binding.borrow_mut().take()));
+}
+
+/// Moving the workers of a pool from `get_core_pool` to other cores, e.g. when a pool built for
+/// P1 is reused for P2.
+pub trait RebindPool {
+    /// Binds worker `i` to core `core_group[i % core_group.len()]`, like `get_core_pool` does.
+    /// Every worker has to take part, so the pool must not run anything else meanwhile.
+    fn rebind(&self, core_group: &[usize]);
+
+    /// Hands the pool over from P1 to P2. Releases `p1_groups`, the core groups the workers were
+    /// bound to, then checks out core groups for the P2 run admitted with `p2_slot`, like
+    /// `get_p2_core_group` does, and binds the workers to them. The groups are returned, and
+    /// have to be held as long as the pool works on them. `None` if P2 does not bind, e.g. with
+    /// the `NoBinding` policy, or no group is available; the workers are then unbound.
+    fn rebind_for_p2(
+        &self,
+        p1_groups: Option<Vec<MutexGuard<'static, CoreGroup>>>,
+        p2_slot: &P2Slot,
+    ) -> Option<Vec<MutexGuard<'static, CoreGroup>>>;
+}
+
+impl RebindPool for rayon::ThreadPool {
+    fn rebind(&self, core_group: &[usize]) {
+        if core_group.is_empty() {
+            return;
+        }
+
+        on_every_worker(self, |i| bind_worker(i, CoreIndex(core_group[i % core_group.len()])));
+    }
+
+    fn rebind_for_p2(
+        &self,
+        p1_groups: Option<Vec<MutexGuard<'static, CoreGroup>>>,
+        p2_slot: &P2Slot,
+    ) -> Option<Vec<MutexGuard<'static, CoreGroup>>> {
+        // P2 may be given the very groups P1 held.
+        drop(p1_groups);
+
+        match get_p2_core_group(p2_slot) {
+            Some(groups) => {
+                let cores = groups
+                    .iter()
+                    .flat_map(|group| group.iter().map(|core| core.0))
+                    .collect::<Vec<_>>();
+                debug!("rebinding pool to P2 cores {:?}", cores);
+                self.rebind(&cores);
+                Some(groups)
+            }
+            None => {
+                debug!("no P2 core groups, unbinding pool");
+                on_every_worker(self, |_| unbind_worker());
+                None
+            }
+        }
+    }
+}
+
+/// Runs `f` once on each worker of `pool`, with the index of the worker.
+fn on_every_worker<F: Fn(usize) + Sync>(pool: &rayon::ThreadPool, f: F) {
+    // A worker blocks in the barrier once it ran `f`, so every one of them picks up exactly
+    // one of the jobs.
+    let workers = pool.current_num_threads();
+    let barrier = Barrier::new(workers);
+    pool.scope(|s| {
+        for _ in 0..workers {
+            let (barrier, f) = (&barrier, &f);
+            s.spawn(move |_| {
+                f(rayon::current_thread_index().expect("not running on the pool"));
+                barrier.wait();
+            });
+        }
+    });
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use super::super::super::cores::{admit_p2, affinity_cpus};
+
+    /// The CPUs a thread bound to `core` may run on.
+    fn core_affinity(core: usize) -> Option<Vec<usize>> {
+        std::thread::spawn(move || {
+            let _cleanup = bind_core_set(Arc::new(vec![CoreIndex(core)])).ok()?;
+            affinity_cpus()
+        })
+        .join()
+        .expect("binding thread panicked")
+    }
+
+    fn pool_affinities(pool: &rayon::ThreadPool) -> Vec<Option<Vec<usize>>> {
+        let affinities = Mutex::new(Vec::new());
+        on_every_worker(pool, |_| {
+            affinities.lock().expect("poisoned lock").push(affinity_cpus());
+        });
+        affinities.into_inner().expect("poisoned lock")
+    }
+
+    #[test]
+    fn test_worker_thread_names() {
+        assert_eq!(worker_thread_name("p2", 3, Some(12)), "fil-p2-w3-core12");
+        assert_eq!(worker_thread_name("p1", 0, None), "fil-p1-w0");
+
+        let pool = get_core_pool("p2", Arc::new(vec![0]));
+        let name = pool.install(|| std::thread::current().name().map(str::to_string));
+        assert_eq!(name.as_deref(), Some("fil-p2-w0-core0"));
+
+        let pool = get_core_pool("p1", Arc::new(vec![]));
+        let name = pool.install(|| std::thread::current().name().map(str::to_string));
+        assert_eq!(name.as_deref().map(|name| name.starts_with("fil-p1-w")), Some(true));
+    }
+
+    #[test]
+    fn test_rebind_pool() {
+        let (p1_core, p2_core) = (0, 1);
+        let (p1_set, p2_set) = match (core_affinity(p1_core), core_affinity(p2_core)) {
+            (Some(p1_set), Some(p2_set)) if p1_set != p2_set => (p1_set, p2_set),
+            // A single CPU, or binding is not possible here.
+            _ => return,
+        };
+
+        let pool = get_core_pool("p1", Arc::new(vec![p1_core]));
+        let affinities = pool_affinities(&pool);
+        assert_eq!(affinities.len(), pool.current_num_threads());
+        assert!(affinities.iter().all(|cpus| cpus.as_ref() == Some(&p1_set)));
+
+        pool.rebind(&[p2_core]);
+        let affinities = pool_affinities(&pool);
+        assert_eq!(affinities.len(), pool.current_num_threads());
+        assert!(affinities.iter().all(|cpus| cpus.as_ref() == Some(&p2_set)));
+    }
+
+    #[test]
+    fn test_rebind_for_p2() {
+        let unbound = std::thread::spawn(affinity_cpus)
+            .join()
+            .expect("thread panicked");
+        let pool = get_core_pool("p1", Arc::new(vec![0]));
+
+        let p2_slot = admit_p2().expect("failed to admit P2");
+        let groups = pool.rebind_for_p2(None, &p2_slot);
+        let affinities = pool_affinities(&pool);
+        assert_eq!(affinities.len(), pool.current_num_threads());
+        match groups {
+            // Each worker on one of the P2 cores.
+            Some(groups) => {
+                let core_sets = groups
+                    .iter()
+                    .flat_map(|group| group.iter().map(|core| core_affinity(core.0)))
+                    .collect::<Vec<_>>();
+                assert!(affinities.iter().all(|cpus| core_sets.contains(cpus)));
+            }
+            // Not bound to the P1 core any more.
+            None => assert!(affinities.iter().all(|cpus| *cpus == unbound)),
+        }
+    }
+}
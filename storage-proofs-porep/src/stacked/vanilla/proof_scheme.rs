@@ -108,7 +108,10 @@ for StackedDrg<'c, Tree, G>
             }
 
             let challenges =
-                pub_inputs.challenges(&pub_params.layer_challenges, graph.size(), Some(k));
+                match pub_inputs.challenges(&pub_params.layer_challenges, graph.size(), Some(k)) {
+                    Ok(challenges) => challenges,
+                    Err(_) => return false,
+                };
 
             proofs.par_iter().enumerate().all(|(i, proof)| {
                 trace!("verify challenge {}/{}", i + 1, challenges.len());
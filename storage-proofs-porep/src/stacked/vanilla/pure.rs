@@ -0,0 +1,239 @@
+//! Pure computational helpers of the labeling, which only depend on `core` so they can be reused
+//! in `no_std` builds, e.g. in an enclave. They are re-exported from `utils` for everyone else;
+//! anything touching the environment or binding stays there.
+
+use core::fmt;
+
+/// Set all values in the given slice to the provided value.
+///
+/// The loop is lowered to a call to the platform `memset`, which already writes whole words
+/// for aligned buffers, so there is no need for a hand written fast path; `benches/memset.rs`
+/// compares aligned and unaligned inputs.
+#[inline]
+pub fn memset(slice: &mut [u8], value: u8) {
+    for v in slice.iter_mut() {
+        *v = value;
+    }
+}
+
+/// Fills `slice` with repetitions of `pattern`, the last one cut short if the length of `slice`
+/// is not a multiple of 32.
+#[inline]
+pub fn memset_pattern32(slice: &mut [u8], pattern: &[u8; 32]) {
+    for chunk in slice.chunks_mut(pattern.len()) {
+        chunk.copy_from_slice(&pattern[..chunk.len()]);
+    }
+}
+
+/// XORs `src` into `dst`, byte by byte. Both must have the same length.
+#[inline]
+pub fn xor_into(dst: &mut [u8], src: &[u8]) {
+    assert_eq!(dst.len(), src.len(), "xor operands must have the same length");
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= *s;
+    }
+}
+
+/// Bytes compared at once by `buffers_equal` before looking for the differing byte.
+const COMPARE_CHUNK: usize = 64;
+
+/// Index of the first byte in which `a` and `b` differ, `None` if they are equal. If one is a
+/// prefix of the other, that is the length of the shorter one. Meant for comparing whole layers
+/// in tests, where the index points at the first divergent node.
+///
+/// Whole chunks are compared with slice equality, which is lowered to a vectorized `memcmp`,
+/// and only the first unequal chunk is searched byte by byte; `benches/buffers_equal.rs`
+/// compares this with a plain byte loop.
+pub fn buffers_equal(a: &[u8], b: &[u8]) -> Option<usize> {
+    let len = a.len().min(b.len());
+    let mismatch = a[..len]
+        .chunks(COMPARE_CHUNK)
+        .zip(b[..len].chunks(COMPARE_CHUNK))
+        .position(|(a, b)| a != b)
+        .map(|chunk| {
+            let start = chunk * COMPARE_CHUNK;
+            let end = (start + COMPARE_CHUNK).min(len);
+            let offset = a[start..end]
+                .iter()
+                .zip(&b[start..end])
+                .position(|(a, b)| a != b)
+                .expect("chunks differ");
+            start + offset
+        });
+
+    match mismatch {
+        Some(index) => Some(index),
+        None if a.len() != b.len() => Some(len),
+        None => None,
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitMask(u32);
+
+/// Errors returned by the fallible `BitMask` operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitMaskError {
+    /// More bits were requested than the mask can hold.
+    TooManyBits(u8),
+}
+
+impl fmt::Display for BitMaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitMaskError::TooManyBits(n) => write!(
+                f,
+                "cannot set {} bits, a bit mask only holds {}",
+                n,
+                BitMask::BITS
+            ),
+        }
+    }
+}
+
+impl BitMask {
+    /// Number of bits a mask holds.
+    pub const BITS: u8 = 32;
+
+    /// Sets the full mask for the first `n` bits.
+    /// Panics if `n` exceeds `BitMask::BITS`, see `try_set_upto` for a fallible version.
+    #[inline]
+    pub fn set_upto(&mut self, n: u8) {
+        if let Err(err) = self.try_set_upto(n) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Sets the full mask for the first `n` bits, or returns an error if `n` exceeds `BitMask::BITS`.
+    #[inline]
+    pub fn try_set_upto(&mut self, n: u8) -> Result<(), BitMaskError> {
+        if n > Self::BITS {
+            return Err(BitMaskError::TooManyBits(n));
+        }
+        self.0 |= ((1u64 << n) - 1) as u32;
+        Ok(())
+    }
+
+    /// Sets the ith bit.
+    #[inline]
+    pub fn set(&mut self, i: usize) {
+        self.0 |= 1 << i
+    }
+
+    /// Returns true if the ith bit is set, false otherwise.
+    #[inline(always)]
+    pub fn get(self, i: usize) -> bool {
+        self.0 & (1 << i) != 0
+    }
+
+    /// Number of bits set.
+    #[inline]
+    pub fn count_ones(self) -> u32 {
+        self.0.count_ones()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memset() {
+        let mut buf = [1u8; 9];
+        memset(&mut buf[2..5], 7);
+        assert_eq!(buf, [1, 1, 7, 7, 7, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_memset_unaligned() {
+        // Every offset and length over a few words, aligned or not.
+        for start in 0..9 {
+            for end in start..40 {
+                let mut buf = [1u8; 40];
+                memset(&mut buf[start..end], 0xab);
+                for (i, &b) in buf.iter().enumerate() {
+                    let expected = if (start..end).contains(&i) { 0xab } else { 1 };
+                    assert_eq!(b, expected, "start {}, end {}, byte {}", start, end, i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_memset_pattern32() {
+        let mut pattern = [0u8; 32];
+        pattern.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+
+        let mut buf = [0xffu8; 70];
+        memset_pattern32(&mut buf, &pattern);
+        assert_eq!(&buf[..32], &pattern[..]);
+        assert_eq!(&buf[32..64], &pattern[..]);
+        assert_eq!(&buf[64..], &pattern[..6]);
+    }
+
+    #[test]
+    fn test_xor_into() {
+        let mut dst = [0b1010u8, 0xff, 0];
+        xor_into(&mut dst, &[0b0110, 0xff, 3]);
+        assert_eq!(dst, [0b1100, 0, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "xor operands must have the same length")]
+    fn test_xor_into_length_mismatch() {
+        xor_into(&mut [0u8; 2], &[0u8; 3]);
+    }
+
+    #[test]
+    fn test_buffers_equal() {
+        let a: [u8; 200] = {
+            let mut a = [0u8; 200];
+            a.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+            a
+        };
+        assert_eq!(buffers_equal(&a, &a), None);
+        assert_eq!(buffers_equal(&[], &[]), None);
+
+        // Differences at the start, within and at the edges of a chunk, and in the tail.
+        for &index in &[0, 1, 63, 64, 100, 191, 199] {
+            let mut b = a;
+            b[index] ^= 1;
+            b[199] = b[199].wrapping_add(if index == 199 { 0 } else { 1 });
+            assert_eq!(buffers_equal(&a, &b), Some(index), "index {}", index);
+        }
+
+        assert_eq!(buffers_equal(&a[..100], &a), Some(100));
+        assert_eq!(buffers_equal(&a, &a[..70]), Some(70));
+    }
+
+    #[test]
+    fn test_bitmask_try_set_upto() {
+        let mut mask = BitMask::default();
+        assert_eq!(mask.try_set_upto(6), Ok(()));
+        assert!((0..6).all(|i| mask.get(i)));
+        assert!(!mask.get(6));
+
+        let mut full = BitMask::default();
+        assert_eq!(full.try_set_upto(32), Ok(()));
+        assert!((0..32).all(|i| full.get(i)));
+
+        let mut mask = BitMask::default();
+        assert_eq!(mask.try_set_upto(33), Err(BitMaskError::TooManyBits(33)));
+        assert_eq!(mask, BitMask::default());
+    }
+
+    #[test]
+    fn test_bitmask_count_ones() {
+        let mut mask = BitMask::default();
+        assert_eq!(mask.count_ones(), 0);
+        mask.set_upto(6);
+        mask.set(20);
+        assert_eq!(mask.count_ones(), 7);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot set 33 bits")]
+    fn test_bitmask_set_upto_panics() {
+        BitMask::default().set_upto(33);
+    }
+}
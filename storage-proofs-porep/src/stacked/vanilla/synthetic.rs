@@ -0,0 +1,269 @@
+//! Proving synthetic challenges, see `ChallengeMode::Synthetic`.
+//!
+//! Right after precommit, all synthetic challenges of a sector are proven and the proofs are
+//! written to a file. Once the seed is known, the proofs of the challenges of each partition are
+//! read back from that file, so the layers and tree_c can be discarded as soon as the file is
+//! written.
+//!
+//! The file holds the number of proofs and the offset of each proof, as little endian `u64`s,
+//! followed by the bincode serialized proofs.
+
+use std::cmp::min;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{ensure, Context};
+use filecoin_hashers::Hasher;
+use log::info;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use storage_proofs_core::{drgraph::Graph, error::Result, merkle::MerkleTreeTrait};
+
+use crate::stacked::vanilla::{
+    challenges::{derive_synthetic_challenge, synthetic_count, ChallengeMode},
+    params::{PrivateInputs, Proof, PublicInputs, PublicParams},
+    proof::StackedDrg,
+};
+
+/// Number of synthetic challenges proven at once, before their proofs are written.
+const SYNTHETIC_PROVING_CHUNK: usize = 4096;
+
+impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tree, G> {
+    /// Proves all synthetic challenges of the sector and writes the proofs to `path`. The file
+    /// only appears at `path` once it is complete.
+    pub fn prove_synthetic(
+        pub_params: &PublicParams<Tree>,
+        pub_inputs: &PublicInputs<<Tree::Hasher as Hasher>::Domain, <G as Hasher>::Domain>,
+        priv_inputs: &PrivateInputs<Tree, G>,
+        path: &Path,
+    ) -> Result<()> {
+        ensure!(
+            pub_params.layer_challenges.mode() == ChallengeMode::Synthetic,
+            "synthetic proofs need synthetic challenges"
+        );
+        let graph = &pub_params.graph;
+        let layers = pub_params.layer_challenges.layers();
+        let t_aux = &priv_inputs.t_aux;
+        ensure!(
+            t_aux.labels.len() == layers,
+            "{} layers for {} challenged layers",
+            t_aux.labels.len(),
+            layers
+        );
+        let tau = pub_inputs.tau.as_ref().context("missing tau")?;
+        ensure!(
+            tau.comm_d == t_aux.tree_d.root(),
+            "comm_d does not match tree_d"
+        );
+
+        let leaves = graph.size();
+        let count = synthetic_count(leaves);
+        info!("proving {} synthetic challenges", count);
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::new(
+            File::create(&tmp_path).with_context(|| format!("could not create {:?}", tmp_path))?,
+        );
+        // The offsets are filled in once all proofs are written.
+        file.write_all(&(count as u64).to_le_bytes())?;
+        file.write_all(&vec![0u8; count * 8])?;
+
+        let mut offsets = Vec::with_capacity(count);
+        let mut offset = (count as u64 + 1) * 8;
+        for chunk_start in (0..count).step_by(SYNTHETIC_PROVING_CHUNK) {
+            let proofs = (chunk_start..min(chunk_start + SYNTHETIC_PROVING_CHUNK, count))
+                .into_par_iter()
+                .map(|i| {
+                    let challenge =
+                        derive_synthetic_challenge(leaves, &pub_inputs.replica_id, &tau.comm_r, i);
+                    Self::prove_challenge(
+                        graph,
+                        pub_inputs,
+                        &priv_inputs.p_aux,
+                        t_aux,
+                        layers,
+                        challenge,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            for proof in &proofs {
+                let bytes = bincode::serialize(proof)?;
+                offsets.push(offset);
+                offset += bytes.len() as u64;
+                file.write_all(&bytes)?;
+            }
+        }
+
+        let mut file = file
+            .into_inner()
+            .context("failed to write synthetic proofs")?;
+        let offset_bytes = offsets
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes())
+            .collect::<Vec<u8>>();
+        file.seek(SeekFrom::Start(8))?;
+        file.write_all(&offset_bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("could not move synthetic proofs to {:?}", path))?;
+
+        info!("synthetic proofs written to {:?}", path);
+        Ok(())
+    }
+
+    /// Reads the proofs of the challenges of `partition_count` partitions from the synthetic
+    /// proofs at `path`, see `prove_synthetic`. Equal to what `prove_all_partitions` would
+    /// return, without needing the layers or tree_c.
+    pub fn read_synthetic_proofs(
+        pub_params: &PublicParams<Tree>,
+        pub_inputs: &PublicInputs<<Tree::Hasher as Hasher>::Domain, <G as Hasher>::Domain>,
+        path: &Path,
+        partition_count: usize,
+    ) -> Result<Vec<Vec<Proof<Tree, G>>>> {
+        ensure!(
+            pub_params.layer_challenges.mode() == ChallengeMode::Synthetic,
+            "synthetic proofs need synthetic challenges"
+        );
+        let leaves = pub_params.graph.size();
+        let mut file =
+            BufReader::new(File::open(path).with_context(|| format!("could not open {:?}", path))?);
+        let count = read_u64(&mut file)? as usize;
+        ensure!(
+            count == synthetic_count(leaves),
+            "{:?} holds {} synthetic proofs, expected {}",
+            path,
+            count,
+            synthetic_count(leaves)
+        );
+
+        let mut partition_proofs = Vec::with_capacity(partition_count);
+        for k in 0..partition_count {
+            let indexes = pub_params.layer_challenges.derive_synthetic_indexes(
+                leaves,
+                &pub_inputs.replica_id,
+                &pub_inputs.seed,
+                k as u8,
+            );
+            let mut proofs = Vec::with_capacity(indexes.len());
+            for i in indexes {
+                file.seek(SeekFrom::Start((i as u64 + 1) * 8))?;
+                let offset = read_u64(&mut file)?;
+                file.seek(SeekFrom::Start(offset))?;
+                proofs.push(
+                    bincode::deserialize_from(&mut file)
+                        .with_context(|| format!("invalid synthetic proof {}", i))?,
+                );
+            }
+            partition_proofs.push(proofs);
+        }
+
+        Ok(partition_proofs)
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellperson::bls::Fr;
+    use ff::Field;
+    use filecoin_hashers::{blake2s::Blake2sHasher, poseidon::PoseidonHasher, Domain};
+    use fr32::fr_into_bytes;
+    use generic_array::typenum::{U0, U2, U8};
+    use merkletree::store::StoreConfig;
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+    use storage_proofs_core::{
+        api_version::ApiVersion, cache_key::CacheKey, drgraph::BASE_DEGREE, merkle::DiskTree,
+        proof::ProofScheme, test_helper::setup_replica, util::default_rows_to_discard, TEST_SEED,
+    };
+
+    use crate::stacked::{
+        LayerChallenges, SetupParams, TemporaryAux, TemporaryAuxCache, BINARY_ARITY, EXP_DEGREE,
+    };
+    use crate::PoRep;
+
+    type Tree = DiskTree<PoseidonHasher, U8, U2, U0>;
+
+    #[test]
+    fn test_read_synthetic_proofs_matches_prove() {
+        let nodes = 64 * 2;
+        let partitions = 2;
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+
+        let replica_id = <PoseidonHasher as Hasher>::Domain::random(rng);
+        let data: Vec<u8> = (0..nodes)
+            .flat_map(|_| fr_into_bytes(&Fr::random(rng)))
+            .collect();
+
+        let cache_dir = tempfile::tempdir().expect("tempdir failure");
+        let config = StoreConfig::new(
+            cache_dir.path(),
+            CacheKey::CommDTree.to_string(),
+            default_rows_to_discard(nodes, BINARY_ARITY),
+        );
+        let replica_path = cache_dir.path().join("replica-path");
+        let mut mmapped_data = setup_replica(&data, &replica_path);
+
+        let sp = SetupParams {
+            nodes,
+            degree: BASE_DEGREE,
+            expansion_degree: EXP_DEGREE,
+            porep_id: [92; 32],
+            layer_challenges: LayerChallenges::new_synthetic(2, 3),
+            api_version: ApiVersion::V1_1_0,
+        };
+        let pp = StackedDrg::<Tree, Blake2sHasher>::setup(&sp).expect("setup failed");
+        let (tau, (p_aux, t_aux)) = StackedDrg::<Tree, Blake2sHasher>::replicate(
+            &pp,
+            &replica_id,
+            (mmapped_data.as_mut()).into(),
+            None,
+            config,
+            replica_path.clone(),
+        )
+        .expect("replication failed");
+
+        let pub_inputs = PublicInputs {
+            replica_id,
+            seed: rng.gen(),
+            tau: Some(tau),
+            k: None,
+        };
+        let t_aux_orig = t_aux.clone();
+        let t_aux = TemporaryAuxCache::<Tree, Blake2sHasher>::new(&t_aux, replica_path)
+            .expect("failed to restore contents of t_aux");
+        let priv_inputs = PrivateInputs { p_aux, t_aux };
+
+        let synthetic_path = cache_dir.path().join("synthetic-proofs");
+        StackedDrg::prove_synthetic(&pp, &pub_inputs, &priv_inputs, &synthetic_path)
+            .expect("prove_synthetic failed");
+        let expected = StackedDrg::prove_all_partitions(&pp, &pub_inputs, &priv_inputs, partitions)
+            .expect("prove_all_partitions failed");
+
+        // The layers are not needed anymore once the synthetic proofs are written.
+        drop(priv_inputs);
+        TemporaryAux::<Tree, Blake2sHasher>::clear_temp(t_aux_orig).expect("t_aux delete failed");
+
+        let proofs = StackedDrg::<Tree, Blake2sHasher>::read_synthetic_proofs(
+            &pp,
+            &pub_inputs,
+            &synthetic_path,
+            partitions,
+        )
+        .expect("read_synthetic_proofs failed");
+        assert_eq!(
+            bincode::serialize(&proofs).expect("serialize failed"),
+            bincode::serialize(&expected).expect("serialize failed")
+        );
+        assert!(StackedDrg::verify_all_partitions(&pp, &pub_inputs, &proofs)
+            .expect("verify_all_partitions failed"));
+    }
+}
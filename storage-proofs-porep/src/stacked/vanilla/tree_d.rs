@@ -0,0 +1,405 @@
+//! Building tree_d, the binary Sha256 tree over the data of a sector, on the GPU.
+//!
+//! The data is split into batches of `max_gpu_tree_d_batch_size` leaves, read from disk by one
+//! thread while the GPU hashes the previous batch. The GPU hashes all levels of the subtree over
+//! a batch, each level written to its place in the tree file right away, and the few levels
+//! above the subtree roots are hashed on the CPU. Without a usable GPU, or for other hashers,
+//! the tree is built on the CPU like any other.
+
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{ensure, Context};
+use bellperson::gpu::scheduler;
+use filecoin_hashers::{sha256::Sha256Hasher, Domain, HashFunction, Hasher};
+use log::{info, warn};
+use merkletree::{
+    merkle::get_merkle_tree_len,
+    store::{DiskStore, StoreConfig},
+};
+use rust_gpu_tools::opencl;
+use storage_proofs_core::{
+    error::Result,
+    merkle::{create_base_merkle_tree, BinaryMerkleTree},
+    settings::SETTINGS,
+    util::NODE_SIZE,
+};
+
+use crate::stacked::vanilla::params::BINARY_ARITY;
+
+/// Hashes two 32 byte children into their parent, like `Sha256Function::node`, for each work
+/// item.
+const SHA256_LEVEL_KERNEL: &str = r#"
+__constant uint K[64] = {
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+};
+
+#define ROTR(x, n) rotate((x), (uint)(32 - (n)))
+
+void sha256_compress(uint *state, uint *w) {
+    for (int t = 16; t < 64; t++) {
+        uint s0 = ROTR(w[t - 15], 7) ^ ROTR(w[t - 15], 18) ^ (w[t - 15] >> 3);
+        uint s1 = ROTR(w[t - 2], 17) ^ ROTR(w[t - 2], 19) ^ (w[t - 2] >> 10);
+        w[t] = w[t - 16] + s0 + w[t - 7] + s1;
+    }
+
+    uint a = state[0], b = state[1], c = state[2], d = state[3];
+    uint e = state[4], f = state[5], g = state[6], h = state[7];
+    for (int t = 0; t < 64; t++) {
+        uint t1 = h + (ROTR(e, 6) ^ ROTR(e, 11) ^ ROTR(e, 25)) + ((e & f) ^ (~e & g)) + K[t] + w[t];
+        uint t2 = (ROTR(a, 2) ^ ROTR(a, 13) ^ ROTR(a, 22)) + ((a & b) ^ (a & c) ^ (b & c));
+        h = g; g = f; f = e; e = d + t1;
+        d = c; c = b; b = a; a = t1 + t2;
+    }
+
+    state[0] += a; state[1] += b; state[2] += c; state[3] += d;
+    state[4] += e; state[5] += f; state[6] += g; state[7] += h;
+}
+
+__kernel void sha256_level(__global const uchar *children, __global uchar *parents, uint count) {
+    uint i = get_global_id(0);
+    if (i >= count) {
+        return;
+    }
+
+    uint state[8] = {
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+        0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+    };
+    uint w[64];
+
+    __global const uchar *in = children + 64 * (ulong)i;
+    for (int t = 0; t < 16; t++) {
+        w[t] = ((uint)in[4 * t] << 24) | ((uint)in[4 * t + 1] << 16)
+            | ((uint)in[4 * t + 2] << 8) | (uint)in[4 * t + 3];
+    }
+    sha256_compress(state, w);
+
+    // The padding of a 64 byte message.
+    w[0] = 0x80000000;
+    for (int t = 1; t < 15; t++) {
+        w[t] = 0;
+    }
+    w[15] = 512;
+    sha256_compress(state, w);
+
+    __global uchar *out = parents + 32 * (ulong)i;
+    for (int t = 0; t < 8; t++) {
+        out[4 * t] = state[t] >> 24;
+        out[4 * t + 1] = state[t] >> 16;
+        out[4 * t + 2] = state[t] >> 8;
+        out[4 * t + 3] = state[t];
+    }
+    // Strip the last two bits, to stay in Fr.
+    out[31] &= 0x3f;
+}
+"#;
+
+const LOCAL_WORK_SIZE: usize = 64;
+
+/// Builds tree_d over `data` of `size` leaves, persisted with `config`. Sha256 trees are built on
+/// the GPU if `use_gpu_tree_d_builder` is set, falling back to the CPU if that fails.
+pub fn build_tree_d<G: Hasher>(
+    config: StoreConfig,
+    size: usize,
+    data: &[u8],
+) -> Result<BinaryMerkleTree<G>> {
+    if SETTINGS.use_gpu_tree_d_builder && G::name() == Sha256Hasher::name() {
+        match build_tree_d_gpu::<G>(&config, size, data) {
+            Ok(tree) => return Ok(tree),
+            Err(err) => warn!(
+                "failed to build tree_d on the GPU, using the CPU: {:#}",
+                err
+            ),
+        }
+    }
+
+    create_base_merkle_tree::<BinaryMerkleTree<G>>(Some(config), size, data)
+}
+
+fn build_tree_d_gpu<G: Hasher>(
+    config: &StoreConfig,
+    size: usize,
+    data: &[u8],
+) -> Result<BinaryMerkleTree<G>> {
+    ensure!(
+        !opencl::Device::all().is_empty(),
+        "no OpenCL device available"
+    );
+    let batch_size = batch_size(size, SETTINGS.max_gpu_tree_d_batch_size as usize);
+
+    let (guard, device) = scheduler::get_next_device_second_pool();
+    let _lock = guard.lock().expect("failed to get gpu lock");
+    info!(
+        "building tree_d on {} in batches of {} leaves",
+        device.device().name(),
+        batch_size
+    );
+    let mut hasher = GpuSubtreeHasher::new(device.device().clone(), batch_size)?;
+    write_tree_d::<G, _>(config, size, data, batch_size, &mut hasher)?;
+
+    open_tree_d(config, size)
+}
+
+/// The number of leaves of each batch, the largest power of two which is at most `max_batch_size`
+/// and `size`.
+fn batch_size(size: usize, max_batch_size: usize) -> usize {
+    let max_batch_size = max_batch_size.max(2).min(size);
+    let batch_size = max_batch_size.next_power_of_two();
+    if batch_size > max_batch_size {
+        batch_size / 2
+    } else {
+        batch_size
+    }
+}
+
+fn open_tree_d<G: Hasher>(config: &StoreConfig, size: usize) -> Result<BinaryMerkleTree<G>> {
+    let tree_len = get_merkle_tree_len(size, BINARY_ARITY)?;
+    let store = DiskStore::new_from_disk(tree_len, BINARY_ARITY, config)?;
+    BinaryMerkleTree::<G>::from_data_store(store, size)
+}
+
+/// Hashes the subtree over a batch of leaves.
+trait SubtreeHasher {
+    /// Returns the levels of the subtree over `leaves` above them, from the bottom to the root.
+    fn hash_subtree(&mut self, leaves: &[u8]) -> Result<Vec<Vec<u8>>>;
+}
+
+struct GpuSubtreeHasher {
+    program: opencl::Program,
+    children: opencl::Buffer<u8>,
+    parents: opencl::Buffer<u8>,
+}
+
+impl GpuSubtreeHasher {
+    fn new(device: opencl::Device, batch_size: usize) -> Result<Self> {
+        let program = opencl::Program::from_opencl(device, SHA256_LEVEL_KERNEL)
+            .context("failed to compile the sha256 kernel")?;
+        let children = program.create_buffer::<u8>(batch_size * NODE_SIZE)?;
+        let parents = program.create_buffer::<u8>(batch_size * NODE_SIZE)?;
+
+        Ok(GpuSubtreeHasher {
+            program,
+            children,
+            parents,
+        })
+    }
+}
+
+impl SubtreeHasher for GpuSubtreeHasher {
+    fn hash_subtree(&mut self, leaves: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.children.write_from(0, leaves)?;
+
+        let mut levels = Vec::new();
+        let mut count = leaves.len() / NODE_SIZE / 2;
+        while count > 0 {
+            let global_work_size =
+                (count + LOCAL_WORK_SIZE - 1) / LOCAL_WORK_SIZE * LOCAL_WORK_SIZE;
+            self.program
+                .create_kernel("sha256_level", global_work_size, Some(LOCAL_WORK_SIZE))
+                .arg(&self.children)
+                .arg(&self.parents)
+                .arg(&(count as u32))
+                .run()?;
+
+            let mut level = vec![0u8; count * NODE_SIZE];
+            self.parents.read_into(0, &mut level)?;
+            levels.push(level);
+
+            std::mem::swap(&mut self.children, &mut self.parents);
+            count /= 2;
+        }
+
+        Ok(levels)
+    }
+}
+
+/// Writes tree_d over `data` of `size` leaves to the file of `config`, in the layout of a
+/// `DiskStore`. The subtrees over batches of `batch_size` leaves are hashed by `hasher` while the
+/// next batch is read, the levels above them on the CPU.
+fn write_tree_d<G: Hasher, S: SubtreeHasher>(
+    config: &StoreConfig,
+    size: usize,
+    data: &[u8],
+    batch_size: usize,
+    hasher: &mut S,
+) -> Result<()> {
+    ensure!(
+        data.len() == size * NODE_SIZE,
+        "expected {} bytes of data, got {}",
+        size * NODE_SIZE,
+        data.len()
+    );
+    ensure!(
+        size.is_power_of_two() && batch_size.is_power_of_two() && size % batch_size == 0,
+        "invalid tree_d of {} leaves in batches of {}",
+        size,
+        batch_size
+    );
+
+    let path = StoreConfig::data_path(&config.path, &config.id);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .with_context(|| format!("could not create tree_d at {:?}", path))?;
+    file.set_len((get_merkle_tree_len(size, BINARY_ARITY)? * NODE_SIZE) as u64)?;
+
+    // The offset of the first node of `level` in the tree, in nodes.
+    let level_offset = |level: u32| 2 * (size - (size >> level));
+    let write_nodes = |offset: usize, nodes: &[u8]| -> Result<()> {
+        file.write_all_at(nodes, (offset * NODE_SIZE) as u64)
+            .with_context(|| format!("failed to write tree_d at {:?}", path))
+    };
+
+    let batch_bytes = batch_size * NODE_SIZE;
+    let batches = size / batch_size;
+    let mut roots = Vec::with_capacity(batches * NODE_SIZE);
+    crossbeam::scope(|s| -> Result<()> {
+        let (batch_tx, batch_rx) = crossbeam::channel::bounded::<Vec<u8>>(1);
+        s.spawn(move |_| {
+            for batch in data.chunks(batch_bytes) {
+                // Copying the batch pages the data in from disk.
+                if batch_tx.send(batch.to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        for (i, leaves) in batch_rx.iter().enumerate() {
+            write_nodes(i * batch_size, &leaves)?;
+            let levels = hasher.hash_subtree(&leaves)?;
+            for (level, nodes) in levels.iter().enumerate() {
+                let level = level as u32 + 1;
+                write_nodes(level_offset(level) + i * (batch_size >> level), nodes)?;
+            }
+            match levels.last() {
+                Some(root) => roots.extend_from_slice(root),
+                None => roots.extend_from_slice(&leaves),
+            }
+        }
+
+        Ok(())
+    })
+    .expect("failed to join the tree_d reader")?;
+    ensure!(
+        roots.len() == batches * NODE_SIZE,
+        "tree_d data ended after {} of {} batches",
+        roots.len() / NODE_SIZE,
+        batches
+    );
+
+    let mut level = batch_size.trailing_zeros();
+    let mut nodes = roots;
+    while nodes.len() > NODE_SIZE {
+        level += 1;
+        let mut parents = Vec::with_capacity(nodes.len() / 2);
+        for children in nodes.chunks(2 * NODE_SIZE) {
+            let left = G::Domain::try_from_bytes(&children[..NODE_SIZE])?;
+            let right = G::Domain::try_from_bytes(&children[NODE_SIZE..])?;
+            parents.extend(G::Function::hash2(&left, &right).into_bytes());
+        }
+        write_nodes(level_offset(level), &parents)?;
+        nodes = parents;
+    }
+
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellperson::bls::Fr;
+    use ff::Field;
+    use fr32::fr_into_bytes;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+    use storage_proofs_core::{util::default_rows_to_discard, TEST_SEED};
+    use tempfile::tempdir;
+
+    /// Hashes subtrees like the GPU, on the CPU.
+    struct CpuSubtreeHasher;
+
+    impl SubtreeHasher for CpuSubtreeHasher {
+        fn hash_subtree(&mut self, leaves: &[u8]) -> Result<Vec<Vec<u8>>> {
+            let mut levels = Vec::new();
+            let mut children = leaves.to_vec();
+            while children.len() > NODE_SIZE {
+                let mut parents = Vec::new();
+                for pair in children.chunks(2 * NODE_SIZE) {
+                    let left =
+                        <Sha256Hasher as Hasher>::Domain::try_from_bytes(&pair[..NODE_SIZE])?;
+                    let right =
+                        <Sha256Hasher as Hasher>::Domain::try_from_bytes(&pair[NODE_SIZE..])?;
+                    parents.extend(
+                        <Sha256Hasher as Hasher>::Function::hash2(&left, &right).into_bytes(),
+                    );
+                }
+                levels.push(parents.clone());
+                children = parents;
+            }
+            Ok(levels)
+        }
+    }
+
+    #[test]
+    fn test_batch_size() {
+        assert_eq!(batch_size(1 << 20, 700_000), 1 << 19);
+        assert_eq!(batch_size(1 << 10, 1 << 22), 1 << 10);
+        assert_eq!(batch_size(2, 0), 2);
+    }
+
+    #[test]
+    fn test_write_tree_d_matches_cpu_tree() {
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+        let size = 256;
+        let data: Vec<u8> = (0..size)
+            .flat_map(|_| fr_into_bytes(&Fr::random(rng)))
+            .collect();
+
+        let cache_dir = tempdir().expect("tempdir failure");
+        let rows_to_discard = default_rows_to_discard(size, BINARY_ARITY);
+        let cpu_config = StoreConfig::new(cache_dir.path(), "cpu-tree-d", rows_to_discard);
+        let cpu_tree = create_base_merkle_tree::<BinaryMerkleTree<Sha256Hasher>>(
+            Some(cpu_config.clone()),
+            size,
+            &data,
+        )
+        .expect("failed to build tree_d");
+
+        for &batch_size in &[2, 16, size] {
+            let config = StoreConfig::new(
+                cache_dir.path(),
+                format!("tree-d-{}", batch_size),
+                rows_to_discard,
+            );
+            write_tree_d::<Sha256Hasher, _>(
+                &config,
+                size,
+                &data,
+                batch_size,
+                &mut CpuSubtreeHasher,
+            )
+            .expect("failed to write tree_d");
+            let tree = open_tree_d::<Sha256Hasher>(&config, size).expect("failed to open tree_d");
+
+            assert_eq!(tree.root(), cpu_tree.root());
+            assert_eq!(
+                std::fs::read(StoreConfig::data_path(&config.path, &config.id))
+                    .expect("failed to read tree_d"),
+                std::fs::read(StoreConfig::data_path(&cpu_config.path, &cpu_config.id))
+                    .expect("failed to read tree_d")
+            );
+        }
+    }
+}
@@ -1,12 +1,38 @@
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
 use std::cell::UnsafeCell;
-use std::slice::{self, ChunksExactMut};
-use log::error;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hint::spin_loop;
+use std::ops::{Deref, Range};
+use std::path::PathBuf;
+use std::ptr::NonNull;
+use std::slice::{self, ChunksExact, ChunksExactMut};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+#[cfg(debug_assertions)]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::RwLock;
+use anyhow::{format_err, Result};
+use lazy_static::lazy_static;
+use log::{error, warn};
+use storage_proofs_core::{
+    settings::{self, SETTINGS},
+    util::NODE_SIZE,
+};
 use enum_derive::*;
 use custom_derive::*;
 
+use super::cores::{
+    bind_memory_to_node, logical_cpu_count, numa_node_exists, suggested_p2_group_size, CoreIndex,
+};
+use super::memory_handling::{advise_huge_pages, MemoryPlacement};
+pub use super::pure::{buffers_equal, memset, memset_pattern32, xor_into, BitMask, BitMaskError};
+
 /// A slice type which can be shared between threads, but must be fully managed by the caller.
 /// Any synchronization must be ensured by the caller, which is why all access is `unsafe`.
-#[derive(Debug)]
 pub struct UnsafeSlice<'a, T> {
     // holds the data to ensure lifetime correctness
     data: UnsafeCell<&'a mut [T]>,
@@ -18,6 +44,22 @@ pub struct UnsafeSlice<'a, T> {
 
 unsafe impl<'a, T> Sync for UnsafeSlice<'a, T> {}
 
+/// Moving an `UnsafeSlice` to another thread moves the exclusive borrow `&'a mut [T]` it was
+/// created from, which is `Send` exactly when `T: Send`. The handle does not own or drop any
+/// elements, so this adds no obligations beyond those of the `unsafe` accessors: callers must
+/// still ensure that no two threads access the same element without synchronization.
+unsafe impl<'a, T: Send> Send for UnsafeSlice<'a, T> {}
+
+impl<'a, T> fmt::Debug for UnsafeSlice<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnsafeSlice")
+            .field("ptr", &self.ptr)
+            .field("len", &self.len)
+            .field("is_empty", &self.is_empty())
+            .finish()
+    }
+}
+
 impl<'a, T> UnsafeSlice<'a, T> {
     /// Takes mutable slice, to ensure that `UnsafeSlice` is the only user of this memory, until it gets dropped.
     pub fn from_slice(source: &'a mut [T]) -> Self {
@@ -27,6 +69,52 @@ impl<'a, T> UnsafeSlice<'a, T> {
         Self { data, ptr, len }
     }
 
+    /// Number of elements in the slice.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the underlying slice, e.g. for a sequential pass once parallel access is done.
+    /// Consuming `self` guarantees that no other thread still holds a reference to this handle.
+    /// References handed out earlier by the `unsafe` accessors must not be used afterwards,
+    /// as that would be an unsynchronized access to the same region.
+    #[inline]
+    pub fn into_slice(self) -> &'a mut [T] {
+        self.data.into_inner()
+    }
+
+    /// Ends the write phase, returning a view with only safe, read-only access, e.g. for the
+    /// labels of a finished layer. Like `into_slice`, consuming `self` proves no writer remains.
+    #[inline]
+    pub fn finish(self) -> SliceView<'a, T> {
+        SliceView(self.into_slice())
+    }
+
+    /// Splits the slice into `workers` disjoint chunks, sized like `partition_nodes`, e.g. to
+    /// hand one to each task of a rayon `for_each`. Consuming `self` is what makes this safe:
+    /// no other access through this handle can overlap with the chunks.
+    pub fn chunks_for_workers(self, workers: usize) -> impl Iterator<Item = &'a mut [T]> + 'a
+    where
+        T: 'a,
+    {
+        assert!(workers > 0, "at least one worker required");
+        let base = self.len / workers;
+        let remainder = self.len % workers;
+
+        (0..workers).scan(self.into_slice(), move |rest, worker| {
+            let len = base + if worker < remainder { 1 } else { 0 };
+            let (chunk, tail) = std::mem::take(rest).split_at_mut(len);
+            *rest = tail;
+            Some(chunk)
+        })
+    }
+
     /// Safety: The caller must ensure that there are no unsynchronized parallel access to the same regions.
     #[inline]
     pub unsafe fn as_mut_slice(&self) -> &'a mut [T] {
@@ -47,110 +135,842 @@ impl<'a, T> UnsafeSlice<'a, T> {
     pub unsafe fn get_mut(&self, index: usize) -> &'a mut T {
         &mut *self.ptr.add(index)
     }
+
+    /// Writes `value` at `index` with a non-temporal store that bypasses the cache, for data that
+    /// is written once and not read again soon, like labels. 32-byte values at 32-byte aligned
+    /// addresses use `_mm_stream_si256` on x86 CPUs with AVX, everything else is a normal store.
+    ///
+    /// Non-temporal stores are weakly ordered: call `nt_store_fence` before the data is read,
+    /// by this or any other thread.
+    ///
+    /// Safety: The caller must ensure that there are no unsynchronized parallel access to the same regions.
+    #[inline]
+    pub unsafe fn write_nt(&self, index: usize, value: T) {
+        assert!(index < self.len, "index {} out of range", index);
+        let dst = self.ptr.add(index);
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if std::mem::size_of::<T>() == 32
+                && dst as usize % 32 == 0
+                && is_x86_feature_detected!("avx")
+            {
+                stream_32(dst as *mut u8, &value as *const T as *const u8);
+                // The bytes were moved into `dst`, which now owns the value.
+                std::mem::forget(value);
+                return;
+            }
+        }
+
+        std::ptr::write(dst, value);
+    }
+}
+
+/// Read-only view of an `UnsafeSlice` that is no longer written, see `UnsafeSlice::finish`.
+#[derive(Debug)]
+pub struct SliceView<'a, T>(&'a [T]);
+
+impl<'a, T> SliceView<'a, T> {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The element at `index`, `None` if it is out of bounds.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&'a T> {
+        self.0.get(index)
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &'a [T] {
+        self.0
+    }
+}
+
+impl<'a, T> Deref for SliceView<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+unsafe fn stream_32(dst: *mut u8, src: *const u8) {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::{__m256i, _mm256_loadu_si256, _mm256_stream_si256};
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::{__m256i, _mm256_loadu_si256, _mm256_stream_si256};
+
+    let value = _mm256_loadu_si256(src as *const __m256i);
+    _mm256_stream_si256(dst as *mut __m256i, value);
+}
+
+/// Orders all non-temporal stores done by `UnsafeSlice::write_nt` on this thread before any
+/// later stores, e.g. the atomic that publishes the written labels to other threads.
+#[inline]
+pub fn nt_store_fence() {
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        std::arch::x86::_mm_sfence()
+    }
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::x86_64::_mm_sfence()
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Types for which the all-zero byte pattern is a valid value.
+///
+/// Safety: Implementors must be valid, and not need dropping, when all their bytes are zero.
+pub unsafe trait Zeroable: Copy {}
+
+unsafe impl Zeroable for u8 {}
+unsafe impl Zeroable for u32 {}
+unsafe impl Zeroable for u64 {}
+unsafe impl Zeroable for usize {}
+unsafe impl Zeroable for BitMask {}
+
+impl<'a, T: Zeroable> UnsafeSlice<'a, T> {
+    /// Allocates `len` zeroed elements and runs `f` with an `UnsafeSlice` over them, returning the
+    /// buffer together with the result of `f`. The handle cannot outlive the call, so the buffer
+    /// is always zeroed before any parallel writes and owned once they are done.
+    pub fn zeroed<R, F>(len: usize, f: F) -> (Box<[T]>, R)
+    where
+        F: FnOnce(UnsafeSlice<'_, T>) -> R,
+    {
+        let mut data = Vec::<T>::with_capacity(len);
+        // Safety: `T: Zeroable`, so `len` zeroed elements are initialized values.
+        unsafe {
+            std::ptr::write_bytes(data.as_mut_ptr(), 0, len);
+            data.set_len(len);
+        }
+        let mut data = data.into_boxed_slice();
+
+        let res = f(UnsafeSlice::from_slice(&mut data));
+        (data, res)
+    }
+}
+
+/// Pattern fresh label buffers are filled with in debug builds, unless overridden with
+/// `FIL_PROOFS_LABEL_POISON`.
+pub const DEFAULT_LABEL_POISON: [u8; NODE_SIZE] = [
+    0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef,
+    0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef,
+];
+
+/// Parses a label poison pattern, given as 32 hex encoded bytes.
+pub fn parse_label_poison(s: &str) -> Option<[u8; NODE_SIZE]> {
+    let bytes = hex::decode(s.trim()).ok()?;
+    if bytes.len() != NODE_SIZE {
+        return None;
+    }
+    let mut poison = [0u8; NODE_SIZE];
+    poison.copy_from_slice(&bytes);
+    Some(poison)
+}
+
+/// The label poison pattern, overridden with `FIL_PROOFS_LABEL_POISON` (32 hex encoded bytes),
+/// e.g. if the default happens to look like real data in a test.
+pub fn label_poison() -> [u8; NODE_SIZE] {
+    settings::var("FIL_PROOFS_LABEL_POISON")
+        .ok()
+        .and_then(|v| match parse_label_poison(&v) {
+            Some(val) => Some(val),
+            None => {
+                error!(
+                    "Invalid FIL_PROOFS_LABEL_POISON! Defaulting to {}",
+                    hex::encode(DEFAULT_LABEL_POISON)
+                );
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_LABEL_POISON)
+}
+
+#[cfg(debug_assertions)]
+lazy_static! {
+    static ref LABEL_POISON: [u8; NODE_SIZE] = label_poison();
+}
+
+/// Fills a fresh label buffer with the poison pattern in debug builds, so that reading a label
+/// before it is written trips `debug_assert_labeled`. Does nothing in release builds.
+#[cfg(debug_assertions)]
+pub fn poison_labels(buf: &mut [u8]) {
+    memset_pattern32(buf, &LABEL_POISON);
+}
+
+#[cfg(not(debug_assertions))]
+#[inline(always)]
+pub fn poison_labels(_buf: &mut [u8]) {}
+
+/// Panics in debug builds if the `label` of `node` still holds the poison pattern, i.e. it is
+/// read before it was written. Does nothing in release builds.
+#[cfg(debug_assertions)]
+#[inline]
+pub fn debug_assert_labeled(node: u64, label: &[u8]) {
+    assert!(
+        label != &LABEL_POISON[..],
+        "label of node {} read before it was written",
+        node
+    );
+}
+
+#[cfg(not(debug_assertions))]
+#[inline(always)]
+pub fn debug_assert_labeled(_node: u64, _label: &[u8]) {}
+
+/// Number of bytes the layer occupies in a labeling block, ending at byte 36.
+pub const LAYER_FIELD_BYTES: usize = 1;
+const LAYER_FIELD_END: usize = 36;
+
+/// Length of a replica id, in bytes.
+pub const REPLICA_ID_BYTES: usize = 32;
+
+/// The replica id hashed into the first block of every label. Its length is checked once on
+/// construction from a slice, so the labeling does not have to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicaId(pub [u8; REPLICA_ID_BYTES]);
+
+impl AsRef<[u8]> for ReplicaId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for ReplicaId {
+    type Error = ReplicaIdLengthError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != REPLICA_ID_BYTES {
+            return Err(ReplicaIdLengthError(bytes.len()));
+        }
+        let mut id = [0u8; REPLICA_ID_BYTES];
+        id.copy_from_slice(bytes);
+        Ok(ReplicaId(id))
+    }
+}
+
+/// Error returned when a `ReplicaId` is created from a slice of the wrong length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicaIdLengthError(pub usize);
+
+impl fmt::Display for ReplicaIdLengthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "replica id must be {} bytes, got {}", REPLICA_ID_BYTES, self.0)
+    }
+}
+
+impl std::error::Error for ReplicaIdLengthError {}
+
+/// Errors returned by `try_prepare_block`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrepareBlockError {
+    /// The layer does not fit into the layer field.
+    LayerOverflow(u32),
+}
+
+impl fmt::Display for PrepareBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrepareBlockError::LayerOverflow(layer) => write!(
+                f,
+                "layer {} does not fit into the {} byte layer field",
+                layer, LAYER_FIELD_BYTES
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PrepareBlockError {}
+
+/// Writes `layer` big-endian into the layer field, refusing layers the field cannot hold
+/// instead of truncating them, which would make two layers produce the same block.
+#[inline]
+fn write_layer_field(layer: u32, buf: &mut [u8]) -> Result<(), PrepareBlockError> {
+    let field_bits = (8 * LAYER_FIELD_BYTES) as u32;
+    if layer.checked_shr(field_bits).unwrap_or(0) != 0 {
+        return Err(PrepareBlockError::LayerOverflow(layer));
+    }
+    let layer_bytes = layer.to_be_bytes();
+    buf[LAYER_FIELD_END - LAYER_FIELD_BYTES..LAYER_FIELD_END]
+        .copy_from_slice(&layer_bytes[layer_bytes.len() - LAYER_FIELD_BYTES..]);
+
+    Ok(())
 }
 
-/// Set all values in the given slice to the provided value.
+/// Fills the fixed portion of a labeling buffer: `replica_id || layer || padding || length`.
+/// All bytes in between the written fields are zeroed, so the result only depends on
+/// `replica_id` and `layer`, regardless of what the buffer held before.
+/// Panics if `layer` does not fit into the layer field, see `try_prepare_block`.
 #[inline]
-pub fn memset(slice: &mut [u8], value: u8) {
-    for v in slice.iter_mut() {
-        *v = value;
+pub fn prepare_block(replica_id: &ReplicaId, layer: u32, buf: &mut [u8]) {
+    if let Err(err) = try_prepare_block(replica_id, layer, buf) {
+        panic!("{}", err);
     }
 }
 
+/// Like `prepare_block`, but returns an error if `layer` does not fit into the layer field.
 #[inline]
-pub fn prepare_block(replica_id: &[u8], layer: u32, buf: &mut [u8]) {
-    buf[..32].copy_from_slice(replica_id);
-    buf[35] = (layer & 0xFF) as u8;
+pub fn try_prepare_block(
+    replica_id: &ReplicaId,
+    layer: u32,
+    buf: &mut [u8],
+) -> Result<(), PrepareBlockError> {
+    write_layer_field(layer, buf)?;
+    buf[..REPLICA_ID_BYTES].copy_from_slice(&replica_id.0);
+    memset(&mut buf[32..LAYER_FIELD_END - LAYER_FIELD_BYTES], 0);
+    memset(&mut buf[36..64], 0);
     buf[64] = 0x80; // Padding
-    buf[126] = 0x02 // Length (512 bits = 64B)
+    memset(&mut buf[65..126], 0);
+    buf[126] = 0x02; // Length (512 bits = 64B)
+    buf[127] = 0;
+
+    Ok(())
 }
 
-#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
-pub struct BitMask(u32);
+/// Fills one block per layer in `layers`, as `prepare_block` would. The shared part of the
+/// blocks is only prepared once, and then copied into the remaining blocks.
+#[inline]
+pub fn prepare_blocks(replica_id: &ReplicaId, layers: Range<u32>, bufs: &mut [[u8; 128]]) {
+    assert_eq!(bufs.len(), layers.len(), "one buffer per layer required");
 
-impl BitMask {
-    /// Sets the full mask for the first `n` bits.
-    #[inline]
-    pub fn set_upto(&mut self, n: u8) {
-        assert!(n <= 32);
-        self.0 |= (1 << n) - 1
+    let (first, rest) = match bufs.split_first_mut() {
+        Some(split) => split,
+        None => return,
+    };
+    prepare_block(replica_id, layers.start, first);
+
+    for (buf, layer) in rest.iter_mut().zip(layers.skip(1)) {
+        buf.copy_from_slice(&first[..]);
+        if let Err(err) = write_layer_field(layer, buf) {
+            panic!("{}", err);
+        }
     }
+}
 
-    /// Sets the ith bit.
-    #[inline]
-    pub fn set(&mut self, i: usize) {
-        self.0 |= 1 << i
+/// Copies the label of `node` from each of the `layers` into `out`, in layer order.
+/// `out` must hold exactly one label per layer.
+pub fn gather_column(layers: &[&[u8]], node: usize, out: &mut [u8]) {
+    assert_eq!(
+        out.len(),
+        layers.len() * NODE_SIZE,
+        "column buffer must hold one label per layer"
+    );
+    let start = node * NODE_SIZE;
+    for (layer, label) in layers.iter().zip(out.chunks_exact_mut(NODE_SIZE)) {
+        assert!(layer.len() >= start + NODE_SIZE, "node {} out of range", node);
+        label.copy_from_slice(&layer[start..start + NODE_SIZE]);
     }
+}
 
-    /// Returns true if the ith bit is set, false otherwise.
-    #[inline(always)]
-    pub fn get(self, i: usize) -> bool {
-        self.0 & (1 << i) != 0
+/// Splits `num_nodes` nodes into `workers` consecutive ranges, e.g. for `relabel_range`. The
+/// remainder of an uneven split is spread over the first ranges one node each, so no two ranges
+/// differ by more than one node and no worker straggles behind the others.
+pub fn partition_nodes(num_nodes: usize, workers: usize) -> Vec<Range<usize>> {
+    assert!(workers > 0, "at least one worker required");
+    let base = num_nodes / workers;
+    let remainder = num_nodes % workers;
+
+    let mut start = 0;
+    (0..workers)
+        .map(|worker| {
+            let len = base + if worker < remainder { 1 } else { 0 };
+            let range = start..start + len;
+            start += len;
+            range
+        })
+        .collect()
+}
+
+/// Splits `slice` into the sub-slices at `ranges`, which have to be ascending and must not
+/// overlap, e.g. the ranges from `partition_nodes`. Gaps between the ranges are skipped.
+pub fn split_ranges<'a, T>(slice: &'a mut [T], ranges: &[Range<usize>]) -> Vec<&'a mut [T]> {
+    let mut rest = slice;
+    let mut offset = 0;
+    ranges
+        .iter()
+        .map(|range| {
+            assert!(
+                range.start >= offset && range.start <= range.end,
+                "range {:?} overlaps the previous one or is reversed",
+                range
+            );
+            let (_, tail) = std::mem::take(&mut rest).split_at_mut(range.start - offset);
+            let (chunk, tail) = tail.split_at_mut(range.len());
+            rest = tail;
+            offset = range.end;
+            chunk
+        })
+        .collect()
+}
+
+/// The part of a layer buffer one worker labels, placed on its NUMA node, see `numa_sub_arenas`.
+#[derive(Debug)]
+pub struct NumaArena<'a> {
+    /// OS index of the NUMA node.
+    pub node: u32,
+    /// The worker running on `node`.
+    pub worker: usize,
+    /// The nodes of the layer whose labels are in this arena, the range `partition_nodes` gives
+    /// the worker.
+    pub nodes: Range<usize>,
+    /// The labels of `nodes`.
+    pub labels: &'a mut [u8],
+}
+
+/// Splits the layer buffer `arena` into one sub-arena per worker in `worker_nodes`, which maps
+/// each worker to the NUMA node it runs on, and places each sub-arena on its worker's node. The
+/// arenas are in worker order, each holding the range `partition_nodes` gives its worker, so
+/// each worker writes node-local memory. Memory that cannot be placed, e.g. for a node not in
+/// the topology, is left where it is.
+pub fn numa_sub_arenas<'a>(arena: &'a mut [u8], worker_nodes: &[u32]) -> Vec<NumaArena<'a>> {
+    assert_eq!(arena.len() % NODE_SIZE, 0, "arena is not a whole number of labels");
+    let ranges = partition_nodes(arena.len() / NODE_SIZE, worker_nodes.len());
+    let byte_ranges = ranges
+        .iter()
+        .map(|range| range.start * NODE_SIZE..range.end * NODE_SIZE)
+        .collect::<Vec<_>>();
+
+    worker_nodes
+        .iter()
+        .enumerate()
+        .zip(ranges)
+        .zip(split_ranges(arena, &byte_ranges))
+        .map(|(((worker, &node), nodes), labels)| {
+            if !numa_node_exists(node) {
+                warn!(
+                    "NUMA node {} does not exist, not placing the labels of worker {}",
+                    node, worker
+                );
+            } else if let Err(err) = bind_memory_to_node(labels.as_mut_ptr(), labels.len(), node) {
+                warn!("failed to place labels on NUMA node {}: {:?}", node, err);
+            }
+            NumaArena { node, worker, nodes, labels }
+        })
+        .collect()
+}
+
+/// The least number of nodes worth spawning a producer for. Producers claim a stride of nodes
+/// at a time, with fewer nodes than this the additional ones mostly wait for each other.
+pub const MIN_NODES_PER_PRODUCER: u64 = 1024;
+
+/// The number of producers worth spawning for a layer of `num_nodes` nodes, at most
+/// `producers`. The producers fill in every node but the first, one producer for every
+/// `MIN_NODES_PER_PRODUCER` of those, but at least one. So a 2KiB test sector is labeled by a
+/// single producer, while an 8MiB one already has work for plenty.
+pub fn producers_for_nodes(num_nodes: u64, producers: usize) -> usize {
+    assert!(producers > 0, "at least one producer required");
+    let worth = num_nodes.saturating_sub(1) / MIN_NODES_PER_PRODUCER;
+    if worth < producers as u64 {
+        (worth as usize).max(1)
+    } else {
+        producers
     }
 }
 
+/// Ring buffer slots needed for a layer of `num_nodes` nodes, given the `slots` a full sized
+/// sector uses. Never more than the nodes the producers fill in, but at least one.
+pub fn ring_slots_for_nodes(num_nodes: u64, slots: usize) -> usize {
+    let fillable = num_nodes.saturating_sub(1).max(1);
+    if fillable < slots as u64 {
+        fillable as usize
+    } else {
+        slots
+    }
+}
+
+/// Ring buffer slots for `producers` producers which each prefetch `depth` strides of `stride`
+/// nodes ahead of the hashing thread. Never more than the `lookahead` setting, nor than the
+/// `window_nodes` of the parents cache window, which the prefetched nodes have to fit in, but
+/// at least one stride: a producer only hands its stride to the hashing thread once all of it
+/// is filled, so with fewer slots both would wait for each other forever.
+pub fn prefetch_slots(
+    producers: usize,
+    stride: usize,
+    depth: usize,
+    lookahead: usize,
+    window_nodes: usize,
+) -> usize {
+    assert!(
+        stride > 0 && stride <= window_nodes,
+        "a stride of {} nodes does not fit the parents cache window of {} nodes",
+        stride,
+        window_nodes
+    );
+    RingBuf::slots_for_workers(producers, stride * depth)
+        .min(lookahead)
+        .min(window_nodes)
+        .max(stride)
+}
+
+impl std::error::Error for BitMaskError {}
+
+/// Alignment of the `RingBuf` allocation, in bytes.
+pub const RING_BUF_ALIGNMENT: usize = 64;
+
+/// Alignment of a `RingBuf` placed on a NUMA node, so that it does not share pages with other
+/// allocations, which would then be moved along with it.
+const NUMA_ALIGNMENT: usize = 4096;
+
+/// A buffer of `num_slots` slots of `slot_size` bytes each, shared between threads.
+///
+/// The backing memory is aligned to `RING_BUF_ALIGNMENT` bytes. If `slot_size` is a multiple of
+/// `RING_BUF_ALIGNMENT`, the start of every slot is aligned as well, so consumers can use aligned
+/// (SIMD) loads on it.
 #[derive(Debug)]
 pub struct RingBuf {
-    data: UnsafeCell<Box<[u8]>>,
+    data: NonNull<u8>,
     slot_size: usize,
     num_slots: usize,
+    memory: RingBufMemory,
+    /// Whether each slot is currently claimed through `slot_mut`, to catch two threads
+    /// claiming the same slot in debug builds.
+    #[cfg(debug_assertions)]
+    in_use: Box<[AtomicBool]>,
+}
+
+/// Where the memory of a `RingBuf` comes from, which determines how it is freed.
+#[derive(Debug)]
+enum RingBufMemory {
+    /// Allocated by the buffer itself, aligned to `align` bytes.
+    Allocated { align: usize },
+    /// Adopted from the caller with `from_boxed_slice`.
+    Boxed,
 }
 
 unsafe impl Sync for RingBuf {}
+unsafe impl Send for RingBuf {}
 
 impl RingBuf {
-    /// Creates a new
+    /// Creates a new, zeroed buffer with `num_slots` slots of `slot_size` bytes each.
     pub fn new(slot_size: usize, num_slots: usize) -> Self {
-        let data = vec![0u8; slot_size * num_slots].into_boxed_slice();
+        Self::with_alignment(slot_size, num_slots, RING_BUF_ALIGNMENT)
+    }
+
+    /// Creates a new, zeroed buffer like `new`, with its memory placed on NUMA node `node`.
+    /// If the memory cannot be bound, it is left where the allocator put it.
+    pub fn new_on_node(slot_size: usize, num_slots: usize, node: u32) -> Self {
+        let buf = Self::with_alignment(slot_size, num_slots, NUMA_ALIGNMENT);
+        if let Err(err) = bind_memory_to_node(buf.data.as_ptr(), buf.len(), node) {
+            warn!("failed to place ring buffer on NUMA node {}: {:?}", node, err);
+        }
+        buf
+    }
+
+    /// Size in bytes of a buffer with `num_slots` slots of `slot_size` bytes each, `None` if it
+    /// overflows `usize`. Lets callers check sizes before constructing a buffer.
+    pub fn capacity_bytes_checked(slot_size: usize, num_slots: usize) -> Option<usize> {
+        slot_size.checked_mul(num_slots)
+    }
+
+    fn with_alignment(slot_size: usize, num_slots: usize, align: usize) -> Self {
+        let len = Self::capacity_bytes_checked(slot_size, num_slots).unwrap_or_else(|| {
+            panic!(
+                "ring buffer of {} slots of {} bytes overflows",
+                num_slots, slot_size
+            )
+        });
+        let data = if len == 0 {
+            // Nothing to allocate, but the pointer still has to be aligned.
+            NonNull::new(align as *mut u8).expect("alignment is not zero")
+        } else {
+            let layout = Self::layout(len, align);
+            // Safety: the layout has a non-zero size.
+            NonNull::new(unsafe { alloc_zeroed(layout) })
+                .unwrap_or_else(|| handle_alloc_error(layout))
+        };
+
+        RingBuf {
+            data,
+            slot_size,
+            num_slots,
+            memory: RingBufMemory::Allocated { align },
+            #[cfg(debug_assertions)]
+            in_use: Self::slot_flags(num_slots),
+        }
+    }
+
+    /// Creates a buffer over the caller's allocation `data`, e.g. one reused from an arena, with
+    /// as many slots of `slot_size` bytes as fit. The contents of `data` are kept and it is
+    /// freed with the buffer. Unlike allocated buffers, its slots are only as aligned as `data`.
+    pub fn from_boxed_slice(data: Box<[u8]>, slot_size: usize) -> Self {
+        assert!(slot_size > 0, "slot size must not be zero");
+        assert_eq!(
+            data.len() % slot_size,
+            0,
+            "buffer length {} is not a multiple of the slot size {}",
+            data.len(),
+            slot_size
+        );
+        let num_slots = data.len() / slot_size;
+        let data = NonNull::new(Box::into_raw(data) as *mut u8).expect("box pointer is not null");
 
         RingBuf {
-            data: UnsafeCell::from(data),
+            data,
             slot_size,
             num_slots,
+            memory: RingBufMemory::Boxed,
+            #[cfg(debug_assertions)]
+            in_use: Self::slot_flags(num_slots),
+        }
+    }
+
+    /// Creates a new, zeroed buffer with `slots_per_worker` slots for each of the `workers`.
+    /// The buffer is placed on the NUMA node forced with `FIL_PROOFS_NUMA_NODE`, if any.
+    pub fn for_workers(slot_size: usize, workers: usize, slots_per_worker: usize) -> Self {
+        Self::new_placed(slot_size, Self::slots_for_workers(workers, slots_per_worker))
+    }
+
+    /// Creates a new, zeroed buffer like `new`, placed on the NUMA node forced with
+    /// `FIL_PROOFS_NUMA_NODE`, if any.
+    pub fn new_placed(slot_size: usize, num_slots: usize) -> Self {
+        Self::new_near(slot_size, num_slots, None)
+    }
+
+    /// Creates a new, zeroed buffer like `new`, used by `cores`. It is placed like a label
+    /// layer, see `MemoryPlacement::near`, and backed by transparent huge pages if
+    /// `FIL_PROOFS_LABEL_HUGE_PAGES` is set and it spans at least one.
+    pub fn new_near(slot_size: usize, num_slots: usize, cores: Option<&[CoreIndex]>) -> Self {
+        let len = Self::capacity_bytes_checked(slot_size, num_slots).unwrap_or(0);
+        let huge = len >= TRANSPARENT_HUGE_PAGE_SIZE && label_huge_pages() != HugePages::Off;
+        let placement = MemoryPlacement::near(cores);
+        if !huge && placement.is_none() {
+            return Self::new(slot_size, num_slots);
         }
+
+        let align = if huge { TRANSPARENT_HUGE_PAGE_SIZE } else { NUMA_ALIGNMENT };
+        let buf = Self::with_alignment(slot_size, num_slots, align);
+        if huge {
+            advise_huge_pages(buf.data.as_ptr(), buf.len());
+        }
+        if let Some(placement) = placement {
+            placement.apply(buf.data.as_ptr(), buf.len(), "ring buffer");
+        }
+        buf
+    }
+
+    /// Number of slots `for_workers` allocates. There is always at least one slot.
+    pub fn slots_for_workers(workers: usize, slots_per_worker: usize) -> usize {
+        (workers.max(1) * slots_per_worker).max(1)
+    }
+
+    fn layout(len: usize, align: usize) -> Layout {
+        Layout::from_size_align(len, align).expect("invalid ring buffer layout")
+    }
+
+    #[cfg(debug_assertions)]
+    fn slot_flags(num_slots: usize) -> Box<[AtomicBool]> {
+        (0..num_slots).map(|_| AtomicBool::new(false)).collect()
     }
 
     #[allow(clippy::mut_from_ref)]
     #[inline(always)]
     unsafe fn slice_mut(&self) -> &mut [u8] {
-        slice::from_raw_parts_mut((*self.data.get()).as_mut_ptr(), self.len())
+        slice::from_raw_parts_mut(self.data.as_ptr(), self.len())
     }
 
     fn len(&self) -> usize {
+        // Checked when the buffer was created.
         self.slot_size * self.num_slots
     }
 
+    /// Claims `slot` for writing until `release_slot` is called for it. In debug builds, claiming
+    /// a slot which is still claimed panics, release builds do not track claims at all.
+    ///
+    /// Safety: The caller must ensure that no two threads access the same slot at the same time.
     #[allow(clippy::mut_from_ref)]
     #[inline(always)]
     pub unsafe fn slot_mut(&self, slot: usize) -> &mut [u8] {
         let start = self.slot_size * slot;
         let end = start + self.slot_size;
 
-        &mut self.slice_mut()[start..end]
+        let buf = &mut self.slice_mut()[start..end];
+        debug_assert!(
+            self.slot_size % RING_BUF_ALIGNMENT != 0
+                || matches!(self.memory, RingBufMemory::Boxed)
+                || buf.as_ptr() as usize % RING_BUF_ALIGNMENT == 0,
+            "misaligned ring buffer slot"
+        );
+        #[cfg(debug_assertions)]
+        assert!(
+            !self.in_use[slot].swap(true, Ordering::AcqRel),
+            "ring buffer slot {} claimed twice",
+            slot
+        );
+        buf
+    }
+
+    /// Ends the claim on `slot` taken by `slot_mut`. The reference returned by it must not be
+    /// used afterwards. A no-op in release builds.
+    #[inline(always)]
+    pub fn release_slot(&self, slot: usize) {
+        #[cfg(debug_assertions)]
+        self.in_use[slot].store(false, Ordering::Release);
+        #[cfg(not(debug_assertions))]
+        let _ = slot;
+    }
+
+    /// Swaps the contents of slots `a` and `b`, e.g. to exchange the roles of the current and the
+    /// next buffer without copying through a separate allocation.
+    pub fn swap_slots(&mut self, a: usize, b: usize) {
+        assert!(a < self.num_slots, "slot {} out of range", a);
+        assert!(b < self.num_slots, "slot {} out of range", b);
+        if a == b {
+            return;
+        }
+
+        let (low, high) = (a.min(b), a.max(b));
+        let slot_size = self.slot_size;
+        // Safety: safe because we are holding &mut self
+        let data = unsafe { self.slice_mut() };
+        let (head, tail) = data.split_at_mut(high * slot_size);
+        head[low * slot_size..(low + 1) * slot_size].swap_with_slice(&mut tail[..slot_size]);
     }
 
     pub fn iter_slot_mut(&mut self) -> ChunksExactMut<'_, u8> {
         // Safety: safe because we are holding &mut self
         unsafe { self.slice_mut().chunks_exact_mut(self.slot_size) }
     }
+
+    /// Iterates over immutable views of all slots, e.g. for a read-only verification pass.
+    ///
+    /// Safety: The caller must ensure that no slot is written to while the returned
+    /// references are alive, as other threads may write through `slot_mut` with only `&self`.
+    pub unsafe fn iter_slot(&self) -> ChunksExact<'_, u8> {
+        slice::from_raw_parts(self.data.as_ptr(), self.len()).chunks_exact(self.slot_size)
+    }
+
+    /// Copies the contents of `slot` into `dst`, e.g. to snapshot a parent buffer, without
+    /// handing out a reference into the buffer. `dst` must be exactly one slot long.
+    ///
+    /// Safety: The caller must ensure that `slot` is not written to during the copy.
+    pub unsafe fn copy_slot_to(&self, slot: usize, dst: &mut [u8]) {
+        assert!(slot < self.num_slots, "slot {} out of range", slot);
+        assert_eq!(dst.len(), self.slot_size, "destination must hold exactly one slot");
+
+        let src = self.data.as_ptr().add(slot * self.slot_size);
+        std::ptr::copy_nonoverlapping(src, dst.as_mut_ptr(), self.slot_size);
+    }
+}
+
+impl Drop for RingBuf {
+    fn drop(&mut self) {
+        let len = self.len();
+        match self.memory {
+            RingBufMemory::Allocated { align } => {
+                if len != 0 {
+                    // Safety: allocated in `with_alignment` with the same layout.
+                    unsafe { dealloc(self.data.as_ptr(), Self::layout(len, align)) };
+                }
+            }
+            RingBufMemory::Boxed => {
+                let data = std::ptr::slice_from_raw_parts_mut(self.data.as_ptr(), len);
+                // Safety: taken from a `Box<[u8]>` of this length in `from_boxed_slice`.
+                drop(unsafe { Box::from_raw(data) });
+            }
+        }
+    }
+}
+
+/// A set of fixed size slots shared between threads, as used by the labeling producers.
+/// `RingBuf` is the production implementation; tests can substitute their own, e.g. one
+/// which checks bounds and records every access.
+pub trait SlotBuffer: Sync {
+    /// Safety: The caller must ensure that no two threads access the same slot at the same time.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slot_mut(&self, slot: usize) -> &mut [u8];
+
+    /// Marks the end of an access to `slot` handed out by `slot_mut`.
+    #[inline(always)]
+    fn release_slot(&self, _slot: usize) {}
+
+    /// Number of slots in the buffer.
+    fn num_slots(&self) -> usize;
+
+    /// Size of a single slot, in bytes.
+    fn slot_size(&self) -> usize;
+}
+
+impl SlotBuffer for RingBuf {
+    #[allow(clippy::mut_from_ref)]
+    #[inline(always)]
+    unsafe fn slot_mut(&self, slot: usize) -> &mut [u8] {
+        RingBuf::slot_mut(self, slot)
+    }
+
+    #[inline(always)]
+    fn release_slot(&self, slot: usize) {
+        RingBuf::release_slot(self, slot)
+    }
+
+    #[inline(always)]
+    fn num_slots(&self) -> usize {
+        self.num_slots
+    }
+
+    #[inline(always)]
+    fn slot_size(&self) -> usize {
+        self.slot_size
+    }
 }
 
-const P2_GROUP_SIZE: usize = 8;
+/// The P2 group size used when the topology does not tell a better one.
+pub const P2_GROUP_SIZE: usize = 8;
 
+/// The number of cores a P2 instance binds. `FIL_PROOFS_P2_BOUND_CORES` takes precedence over
+/// a count embedded in `FIL_PROOFS_P2_BINDING_POLICY` (e.g. `Strict:12`). If neither is set,
+/// the cores of one L3 domain are used, see `suggest_p2_group_size`.
+/// The result never exceeds the number of CPUs the process can run on.
 pub fn env_lock_p2_cores() -> usize {
-    std::env::var("FIL_PROOFS_P2_BOUND_CORES")
+    clamp_p2_bound_cores(env_lock_p2_cores_unclamped())
+}
+
+/// Limits the number of cores P2 binds to the number of CPUs the process can run on.
+fn clamp_p2_bound_cores(cores: usize) -> usize {
+    let available = logical_cpu_count();
+    if cores > available {
+        warn!("only {} CPUs available, P2 binds {} instead of {} cores", available, available, cores);
+        return available;
+    }
+    cores
+}
+
+fn env_lock_p2_cores_unclamped() -> usize {
+    if let Some(cores) = installed_seal_settings(|s| s.binding.p2_bound_cores) {
+        return cores;
+    }
+    settings::var("FIL_PROOFS_P2_BOUND_CORES")
         .and_then(|v| match v.parse() {
             Ok(val) => Ok(val),
             Err(_) => {
-                error!("Invalid FIL_PROOFS_P2_BOUND_CORES! Defaulting to {}", P2_GROUP_SIZE);
-                Ok(P2_GROUP_SIZE)
+                let default = suggested_p2_group_size();
+                error!("Invalid FIL_PROOFS_P2_BOUND_CORES! Defaulting to {}", default);
+                Ok(default)
             }
         })
-        .unwrap_or(P2_GROUP_SIZE) as usize
+        .or_else(|_| {
+            settings::var("FIL_PROOFS_P2_BINDING_POLICY").map(|v| {
+                match parse_p2_binding_policy(&v) {
+                    Ok((_, Some(cores))) => cores,
+                    _ => suggested_p2_group_size(),
+                }
+            })
+        })
+        .unwrap_or_else(|_| suggested_p2_group_size())
 }
 
 custom_derive! {
-    #[derive(Debug, PartialEq, EnumFromStr)]
+    #[derive(Debug, Clone, Copy, PartialEq, EnumFromStr)]
     pub enum P2BoundPolicy
     {
         NoBinding,
@@ -160,7 +980,7 @@ custom_derive! {
 }
 
 custom_derive! {
-    #[derive(Debug, PartialEq, EnumFromStr)]
+    #[derive(Debug, Clone, Copy, PartialEq, EnumFromStr)]
     pub enum P1BoundPolicy
     {
         Default,
@@ -169,10 +989,57 @@ custom_derive! {
     }
 }
 
+// The variant names are also the values accepted by `FromStr`.
+impl fmt::Display for P2BoundPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for P1BoundPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Parses a P1 binding policy, e.g. `Core`, ignoring case.
+pub fn parse_p1_binding_policy(s: &str) -> Result<P1BoundPolicy, String> {
+    [P1BoundPolicy::Default, P1BoundPolicy::ProcessingUnit, P1BoundPolicy::Core]
+        .iter()
+        .copied()
+        .find(|policy| policy.to_string().eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("unknown P1 binding policy: {}", s))
+}
+
+/// Parses a P2 binding policy with an optional number of bound cores, e.g. `Strict` or `Strict:12`,
+/// ignoring the case of the policy.
+pub fn parse_p2_binding_policy(s: &str) -> Result<(P2BoundPolicy, Option<usize>), String> {
+    let mut parts = s.splitn(2, ':');
+    let name = parts.next().unwrap_or_default();
+    let policy = [P2BoundPolicy::NoBinding, P2BoundPolicy::Strict, P2BoundPolicy::Weak]
+        .iter()
+        .copied()
+        .find(|policy| policy.to_string().eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("unknown P2 binding policy: {}", s))?;
+    let cores = match parts.next() {
+        Some(cores) => Some(
+            cores
+                .parse::<usize>()
+                .map_err(|_| format!("invalid number of bound cores: {}", s))?,
+        ),
+        None => None,
+    };
+
+    Ok((policy, cores))
+}
+
 pub fn p2_binding_policy() -> P2BoundPolicy {
-    std::env::var("FIL_PROOFS_P2_BINDING_POLICY")
-        .and_then(|v| match v.parse() {
-            Ok(val) => Ok(val),
+    if let Some(value) = installed_seal_settings(|s| s.binding.p2_binding_policy) {
+        return value;
+    }
+    settings::var("FIL_PROOFS_P2_BINDING_POLICY")
+        .and_then(|v| match parse_p2_binding_policy(&v) {
+            Ok((val, _)) => Ok(val),
             Err(_) => {
                 error!("Invalid FIL_PROOFS_P2_BINDING_POLICY! Defaulting to {:?}", P2BoundPolicy::NoBinding);
                 Ok(P2BoundPolicy::NoBinding)
@@ -181,35 +1048,370 @@ pub fn p2_binding_policy() -> P2BoundPolicy {
         .unwrap_or(P2BoundPolicy::NoBinding)
 }
 
-pub fn p2_binding_use_same_set() -> bool {
-    let res: usize = std::env::var("FIL_PROOFS_P2_BINDING_USE_SAME_SET")
-        .and_then(|v| match v.parse() {
-            Ok(val) => Ok(val),
-            Err(_) => {
-                error!("Invalid FIL_PROOFS_P2_BINDING_USE_SAME_SET! Defaulting to {:?}", 1);
-                Ok(1)
+pub const DEFAULT_PREFETCH_DEPTH: usize = 4;
+
+/// The number of strides each producer may prefetch ahead of the hashing thread.
+pub fn prefetch_depth() -> usize {
+    if let Some(value) = installed_seal_settings(|s| s.prefetch_depth) {
+        return value;
+    }
+    settings::var("FIL_PROOFS_PREFETCH_DEPTH")
+        .and_then(|v| match v.parse::<usize>() {
+            Ok(val) if val > 0 => Ok(val),
+            _ => {
+                error!(
+                    "Invalid FIL_PROOFS_PREFETCH_DEPTH! Defaulting to {}",
+                    DEFAULT_PREFETCH_DEPTH
+                );
+                Ok(DEFAULT_PREFETCH_DEPTH)
             }
         })
-        .unwrap_or(1);
-    res != 0
+        .unwrap_or(DEFAULT_PREFETCH_DEPTH)
 }
 
-pub fn binding_use_locality() -> bool {
-    let res: usize = std::env::var("FIL_PROOFS_BINDING_USE_LOCALITY")
-        .and_then(|v| match v.parse() {
-            Ok(val) => Ok(val),
-            Err(_) => {
-                error!("Invalid FIL_PROOFS_BINDING_USE_LOCALITY! Defaulting to {:?}", 1);
-                Ok(1)
-            }
-        })
+// How much labeling backs off to leave the machine usable, e.g. for background seals on a
+// development machine. See `FIL_PROOFS_THROTTLE`.
+custom_derive! {
+    #[derive(Debug, Clone, Copy, PartialEq, EnumFromStr)]
+    pub enum ThrottleLevel
+    {
+        Off,
+        Light,
+        Heavy,
+    }
+}
+
+impl fmt::Display for ThrottleLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl ThrottleLevel {
+    /// How long a labeling thread sleeps after each chunk of nodes, `None` at full speed.
+    pub fn pause(&self) -> Option<Duration> {
+        match self {
+            ThrottleLevel::Off => None,
+            ThrottleLevel::Light => Some(Duration::from_millis(1)),
+            ThrottleLevel::Heavy => Some(Duration::from_millis(5)),
+        }
+    }
+
+    fn from_u8(level: u8) -> Self {
+        match level {
+            1 => ThrottleLevel::Light,
+            2 => ThrottleLevel::Heavy,
+            _ => ThrottleLevel::Off,
+        }
+    }
+}
+
+fn env_throttle_level() -> ThrottleLevel {
+    settings::var("FIL_PROOFS_THROTTLE")
+        .and_then(|v| match v.parse() {
+            Ok(val) => Ok(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_THROTTLE! Defaulting to {:?}", ThrottleLevel::Off);
+                Ok(ThrottleLevel::Off)
+            }
+        })
+        .unwrap_or(ThrottleLevel::Off)
+}
+
+lazy_static! {
+    static ref THROTTLE_LEVEL: AtomicU8 = AtomicU8::new(env_throttle_level() as u8);
+}
+
+/// The current throttle level, initially read from `FIL_PROOFS_THROTTLE`.
+pub fn throttle_level() -> ThrottleLevel {
+    ThrottleLevel::from_u8(THROTTLE_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Changes the throttle level, which running labeling picks up at its next chunk of nodes.
+pub fn set_throttle_level(level: ThrottleLevel) {
+    THROTTLE_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Sleeps according to the current throttle level. Called at chunk boundaries, never per node.
+#[inline]
+pub fn throttle() {
+    if let Some(pause) = throttle_level().pause() {
+        thread::sleep(pause);
+    }
+}
+
+/// The fewest threads labeling a layer can run with: one producer and the hashing thread.
+const MIN_LABELING_THREADS: usize = 2;
+
+/// Process-wide limit on the threads labeling at once, over all seals, `0` if unlimited.
+static MAX_LABELING_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Threads labeling right now, as handed out by `LabelingThreads::acquire`.
+static LABELING_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// The process-wide limit on labeling threads, if set with `set_max_labeling_threads`.
+pub fn max_labeling_threads() -> Option<usize> {
+    match MAX_LABELING_THREADS.load(Ordering::Relaxed) {
+        0 => None,
+        max => Some(max),
+    }
+}
+
+/// Limits the threads labeling at once in this process, over all concurrent seals, or removes
+/// the limit with `None`. Each seal still uses at most its `multicore_sdr_producers` and one
+/// hashing thread, so the smaller of the two applies. A seal takes its threads when it starts a
+/// layer, so a new limit applies from the next layer on. Limits below two threads are raised to
+/// two. A layer never waits for threads, as its seal holds its P1 cores meanwhile: if not even
+/// a single producer is left, it labels with one producer and the hashing thread regardless,
+/// exceeding the limit by those.
+pub fn set_max_labeling_threads(max: Option<usize>) {
+    MAX_LABELING_THREADS.store(max.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Threads granted to a layer wanting `wanted` of them while `used` are taken, given the limit
+/// `max`. `None` if the minimum of `MIN_LABELING_THREADS` is not available.
+fn grant_labeling_threads(wanted: usize, used: usize, max: Option<usize>) -> Option<usize> {
+    let available = match max {
+        Some(max) => max.max(MIN_LABELING_THREADS).saturating_sub(used),
+        None => wanted,
+    };
+    let granted = available.min(wanted);
+    if granted < MIN_LABELING_THREADS.min(wanted) {
+        return None;
+    }
+    Some(granted)
+}
+
+/// Threads taken from the `set_max_labeling_threads` budget for labeling one layer, returned to
+/// it when dropped.
+#[derive(Debug)]
+pub struct LabelingThreads(usize);
+
+impl LabelingThreads {
+    /// Takes up to `wanted` threads, the producers and the hashing thread. If not even one
+    /// producer and the hashing thread fit into the limit, takes those beyond it instead of
+    /// waiting, see `set_max_labeling_threads`.
+    pub fn acquire(wanted: usize) -> Self {
+        let max = max_labeling_threads();
+        let mut granted = (0, false);
+        LABELING_THREADS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                granted = match grant_labeling_threads(wanted, used, max) {
+                    Some(threads) => (threads, false),
+                    None => (MIN_LABELING_THREADS.min(wanted), true),
+                };
+                Some(used + granted.0)
+            })
+            .expect("threads are always granted");
+
+        let (threads, over_limit) = granted;
+        if over_limit {
+            warn!(
+                "labeling thread limit of {} reached, labeling with {} threads beyond it",
+                max.unwrap_or_default(),
+                threads
+            );
+        }
+        LabelingThreads(threads)
+    }
+
+    /// Number of threads taken.
+    pub fn count(&self) -> usize {
+        self.0
+    }
+}
+
+impl Drop for LabelingThreads {
+    fn drop(&mut self) {
+        LABELING_THREADS.fetch_sub(self.0, Ordering::SeqCst);
+    }
+}
+
+/// The number of nodes a producer claims at a time, if set with `FIL_PROOFS_LABEL_CHUNK`.
+/// If unset, the `multicore_sdr_producer_stride` setting is used.
+pub fn label_chunk() -> Option<u64> {
+    if let Some(value) = installed_seal_settings(|s| s.label_chunk) {
+        return value;
+    }
+    settings::var("FIL_PROOFS_LABEL_CHUNK")
+        .ok()
+        .and_then(|v| match v.parse::<u64>() {
+            Ok(val) if val > 0 => Some(val),
+            _ => {
+                error!(
+                    "Invalid FIL_PROOFS_LABEL_CHUNK! Defaulting to multicore_sdr_producer_stride"
+                );
+                None
+            }
+        })
+}
+
+/// Clamps a chunk of `chunk` nodes to between one node and the share of each of the `producers`
+/// of the `num_nodes - 1` nodes they fill in, so that no producer is left without work.
+pub fn clamp_label_chunk(chunk: u64, num_nodes: u64, producers: usize) -> u64 {
+    assert!(producers > 0, "at least one producer required");
+    let fillable = num_nodes.saturating_sub(1);
+    let per_producer = (fillable + producers as u64 - 1) / producers as u64;
+    chunk.min(per_producer).max(1)
+}
+
+/// How to wait when no core group is free.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoreGroupWait {
+    /// Do not wait, run unbound instead.
+    NoWait,
+    /// Busy-spin until a group is free.
+    Spin,
+    /// Yield the thread to the scheduler between attempts.
+    Yield,
+    /// Park the thread for the given duration between attempts.
+    Park(Duration),
+}
+
+const DEFAULT_PARK_MICROS: u64 = 100;
+
+impl CoreGroupWait {
+    /// Waits once, according to the strategy.
+    #[inline]
+    pub fn wait(&self) {
+        match self {
+            CoreGroupWait::NoWait => {}
+            CoreGroupWait::Spin => spin_loop(),
+            CoreGroupWait::Yield => thread::yield_now(),
+            CoreGroupWait::Park(duration) => thread::park_timeout(*duration),
+        }
+    }
+}
+
+/// Accepts `NoWait`, `Spin`, `Yield`, `Park` and `Park:<microseconds>`, ignoring case.
+impl FromStr for CoreGroupWait {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let arg = parts.next();
+        match (name.as_str(), arg) {
+            ("nowait", None) => Ok(CoreGroupWait::NoWait),
+            ("spin", None) => Ok(CoreGroupWait::Spin),
+            ("yield", None) => Ok(CoreGroupWait::Yield),
+            ("park", None) => Ok(CoreGroupWait::Park(Duration::from_micros(DEFAULT_PARK_MICROS))),
+            ("park", Some(micros)) => micros
+                .parse()
+                .map(|micros| CoreGroupWait::Park(Duration::from_micros(micros)))
+                .map_err(|_| format!("invalid park duration: {}", micros)),
+            _ => Err(format!("unknown core group wait strategy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for CoreGroupWait {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoreGroupWait::NoWait => write!(f, "NoWait"),
+            CoreGroupWait::Spin => write!(f, "Spin"),
+            CoreGroupWait::Yield => write!(f, "Yield"),
+            CoreGroupWait::Park(duration) => write!(f, "Park:{}", duration.as_micros()),
+        }
+    }
+}
+
+pub fn core_group_wait_strategy() -> CoreGroupWait {
+    if let Some(value) = installed_seal_settings(|s| s.binding.core_group_wait) {
+        return value;
+    }
+    settings::var("FIL_PROOFS_CORE_GROUP_WAIT")
+        .and_then(|v| match v.parse() {
+            Ok(val) => Ok(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_CORE_GROUP_WAIT! Defaulting to {:?}", CoreGroupWait::NoWait);
+                Ok(CoreGroupWait::NoWait)
+            }
+        })
+        .unwrap_or(CoreGroupWait::NoWait)
+}
+
+pub fn p2_binding_use_same_set() -> bool {
+    if let Some(value) = installed_seal_settings(|s| s.binding.p2_binding_use_same_set) {
+        return value;
+    }
+    let res: usize = settings::var("FIL_PROOFS_P2_BINDING_USE_SAME_SET")
+        .and_then(|v| match v.parse() {
+            Ok(val) => Ok(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_P2_BINDING_USE_SAME_SET! Defaulting to {:?}", 1);
+                Ok(1)
+            }
+        })
         .unwrap_or(1);
     res != 0
 }
 
-pub fn p1_binding_policy() -> P1BoundPolicy {
-    std::env::var("FIL_PROOFS_P1_BINDING_POLICY")
+pub fn binding_use_locality() -> bool {
+    if let Some(value) = installed_seal_settings(|s| s.binding.binding_use_locality) {
+        return value;
+    }
+    let res: usize = settings::var("FIL_PROOFS_BINDING_USE_LOCALITY")
         .and_then(|v| match v.parse() {
+            Ok(val) => Ok(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_BINDING_USE_LOCALITY! Defaulting to {:?}", 1);
+                Ok(1)
+            }
+        })
+        .unwrap_or(1);
+    res != 0
+}
+
+/// File each labeling run appends its binding decision to, as one JSON line, if set.
+pub fn binding_log_json() -> Option<PathBuf> {
+    settings::var("FIL_PROOFS_BINDING_LOG_JSON")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Whether every bound thread logs its intended cores and the binding it actually got.
+pub fn log_binding() -> bool {
+    if let Some(value) = installed_seal_settings(|s| s.log_binding) {
+        return value;
+    }
+    let res: usize = settings::var("FIL_PROOFS_LOG_BINDING")
+        .and_then(|v| match v.parse() {
+            Ok(val) => Ok(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_LOG_BINDING! Defaulting to {:?}", 0);
+                Ok(0)
+            }
+        })
+        .unwrap_or(0);
+    res != 0
+}
+
+/// Whether the parents cache is generated and verified a chunk at a time, with its digest
+/// computed alongside, and read ahead of the labeling by its lookahead.
+pub fn parent_cache_prefetch() -> bool {
+    if let Some(value) = installed_seal_settings(|s| s.parent_cache_prefetch) {
+        return value;
+    }
+    let res: usize = settings::var("FIL_PROOFS_PARENT_CACHE_PREFETCH")
+        .and_then(|v| match v.parse() {
+            Ok(val) => Ok(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_PARENT_CACHE_PREFETCH! Defaulting to {:?}", 0);
+                Ok(0)
+            }
+        })
+        .unwrap_or(0);
+    res != 0
+}
+
+pub fn p1_binding_policy() -> P1BoundPolicy {
+    if let Some(value) = installed_seal_settings(|s| s.binding.p1_binding_policy) {
+        return value;
+    }
+    settings::var("FIL_PROOFS_P1_BINDING_POLICY")
+        .and_then(|v| match parse_p1_binding_policy(&v) {
             Ok(val) => Ok(val),
             Err(_) => {
                 error!("Invalid FIL_PROOFS_P1_BINDING_POLICY! Defaulting to {:?}", P1BoundPolicy::Default);
@@ -217,4 +1419,1464 @@ pub fn p1_binding_policy() -> P1BoundPolicy {
             }
         })
         .unwrap_or(P1BoundPolicy::Default)
-}
\ No newline at end of file
+}
+
+pub const DEFAULT_MIN_BIND_BYTES: u64 = 1 << 20;
+
+/// The smallest amount of data, in bytes, for which labeling binds its threads to cores.
+/// Below this, setting up the binding costs more than the labeling itself.
+pub fn min_bind_bytes() -> u64 {
+    if let Some(value) = installed_seal_settings(|s| s.binding.min_bind_bytes) {
+        return value;
+    }
+    settings::var("FIL_PROOFS_MIN_BIND_BYTES")
+        .and_then(|v| match v.parse() {
+            Ok(val) => Ok(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_MIN_BIND_BYTES! Defaulting to {}", DEFAULT_MIN_BIND_BYTES);
+                Ok(DEFAULT_MIN_BIND_BYTES)
+            }
+        })
+        .unwrap_or(DEFAULT_MIN_BIND_BYTES)
+}
+
+/// The most P2 runs which may hold core groups at once, if limited. Unset or `0` means no limit
+/// other than the free cores.
+pub fn max_p2_concurrent() -> Option<usize> {
+    settings::var("FIL_PROOFS_MAX_P2_CONCURRENT")
+        .ok()
+        .and_then(|v| match v.parse::<usize>() {
+            Ok(0) => None,
+            Ok(val) => Some(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_MAX_P2_CONCURRENT! Not limiting concurrent P2 runs");
+                None
+            }
+        })
+}
+
+/// Seed for a deterministic choice of core groups, if set. See `FIL_PROOFS_BIND_SEED`.
+pub fn bind_seed() -> Option<u64> {
+    settings::var("FIL_PROOFS_BIND_SEED")
+        .ok()
+        .and_then(|v| match v.parse::<u64>() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_BIND_SEED! Ignoring it");
+                None
+            }
+        })
+}
+
+/// The NUMA node all labeling memory is placed on, if forced with `FIL_PROOFS_NUMA_NODE`.
+/// A node that does not exist is ignored with a warning, leaving placement to the OS.
+pub fn forced_numa_node() -> Option<u32> {
+    if let Some(value) = installed_seal_settings(|s| s.binding.numa_node) {
+        return value;
+    }
+    let node = settings::var("FIL_PROOFS_NUMA_NODE")
+        .ok()
+        .and_then(|v| match v.parse::<u32>() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                error!("Invalid FIL_PROOFS_NUMA_NODE! Ignoring it");
+                None
+            }
+        })?;
+
+    if !numa_node_exists(node) {
+        warn!("NUMA node {} does not exist, ignoring FIL_PROOFS_NUMA_NODE", node);
+        return None;
+    }
+    Some(node)
+}
+
+/// How label buffers are backed by huge pages, set with `FIL_PROOFS_LABEL_HUGE_PAGES`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HugePages {
+    /// Regular pages.
+    Off,
+    /// Transparent huge pages, requested with `madvise`. The kernel backs the buffers with
+    /// (2MiB) huge pages where it can and with regular pages elsewhere.
+    Transparent,
+    /// Pages of the hugetlbfs mounted at this path, e.g. 1GiB pages from a mount with
+    /// `pagesize=1G`. Falls back to `Transparent` if the pages cannot be had.
+    Hugetlbfs(PathBuf),
+}
+
+/// Size of a transparent huge page on x86_64 and aarch64 with 4KiB pages.
+pub const TRANSPARENT_HUGE_PAGE_SIZE: usize = 2 << 20;
+
+fn parse_huge_pages(value: &str) -> Option<HugePages> {
+    match value.trim() {
+        "" | "0" | "off" => Some(HugePages::Off),
+        "1" | "thp" => Some(HugePages::Transparent),
+        path if path.starts_with('/') => Some(HugePages::Hugetlbfs(PathBuf::from(path))),
+        _ => None,
+    }
+}
+
+/// Whether label buffers are backed by huge pages, see `HugePages`. Linux only, ignored with
+/// a warning elsewhere.
+pub fn label_huge_pages() -> HugePages {
+    let huge_pages = installed_seal_settings(|s| s.label_huge_pages.clone()).unwrap_or_else(|| {
+        settings::var("FIL_PROOFS_LABEL_HUGE_PAGES")
+            .ok()
+            .and_then(|v| match parse_huge_pages(&v) {
+                Some(val) => Some(val),
+                None => {
+                    error!("Invalid FIL_PROOFS_LABEL_HUGE_PAGES! Defaulting to off");
+                    None
+                }
+            })
+            .unwrap_or(HugePages::Off)
+    });
+
+    if huge_pages != HugePages::Off && !cfg!(target_os = "linux") {
+        warn!("huge pages are only supported on Linux, ignoring FIL_PROOFS_LABEL_HUGE_PAGES");
+        return HugePages::Off;
+    }
+    huge_pages
+}
+
+/// Stack size for the worker threads of the (bound) thread pools, if set.
+/// If unset, rayon's default stack size is used.
+pub fn worker_stack_bytes() -> Option<usize> {
+    if let Some(value) = installed_seal_settings(|s| s.binding.worker_stack_bytes) {
+        return value;
+    }
+    settings::var("FIL_PROOFS_WORKER_STACK_BYTES")
+        .ok()
+        .and_then(|v| match v.parse::<usize>() {
+            Ok(val) if val > 0 => Some(val),
+            _ => {
+                error!("Invalid FIL_PROOFS_WORKER_STACK_BYTES! Defaulting to rayon's default");
+                None
+            }
+        })
+}
+
+/// Every `FIL_PROOFS_*` environment variable read anywhere in the proofs crates, including the
+/// `storage_proofs_core::settings` fields. Keep it sorted, and add new variables here, so that
+/// `dump_fil_proofs_env` reports them instead of flagging them as unrecognized.
+pub const FIL_PROOFS_ENV_VARS: &[&str] = &[
+    "FIL_PROOFS_ALGORITHM",
+    "FIL_PROOFS_BINDING_LOG_JSON",
+    "FIL_PROOFS_BINDING_USE_LOCALITY",
+    "FIL_PROOFS_BIND_P1_TREE",
+    "FIL_PROOFS_BIND_SEED",
+    "FIL_PROOFS_CACHE_DIR",
+    "FIL_PROOFS_COLUMN_WRITE_BATCH_SIZE",
+    "FIL_PROOFS_CONFIG",
+    "FIL_PROOFS_CORE_GROUP_WAIT",
+    "FIL_PROOFS_GPU_FOR_PARALLEL_TREE_R",
+    "FIL_PROOFS_GPU_MEMORY_PADDING",
+    "FIL_PROOFS_IO_LAYERS",
+    "FIL_PROOFS_IO_PARENT_CACHE",
+    "FIL_PROOFS_IO_REPLICA",
+    "FIL_PROOFS_IO_TREE",
+    "FIL_PROOFS_LABELING_TRACE_FILE",
+    "FIL_PROOFS_LABEL_CHUNK",
+    "FIL_PROOFS_LABEL_HUGE_PAGES",
+    "FIL_PROOFS_LABEL_POISON",
+    "FIL_PROOFS_LOG_BINDING",
+    "FIL_PROOFS_MAX_GPU_COLUMN_BATCH_SIZE",
+    "FIL_PROOFS_MAX_GPU_TREE_BATCH_SIZE",
+    "FIL_PROOFS_MAX_GPU_TREE_D_BATCH_SIZE",
+    "FIL_PROOFS_MAX_P2_CONCURRENT",
+    "FIL_PROOFS_MIN_BIND_BYTES",
+    "FIL_PROOFS_MULTICORE_SDR_LOOKAHEAD",
+    "FIL_PROOFS_MULTICORE_SDR_PRODUCERS",
+    "FIL_PROOFS_MULTICORE_SDR_PRODUCER_STRIDE",
+    "FIL_PROOFS_NUMA_NODE",
+    "FIL_PROOFS_P1_BINDING_POLICY",
+    "FIL_PROOFS_P2_BINDING_POLICY",
+    "FIL_PROOFS_P2_BINDING_USE_SAME_SET",
+    "FIL_PROOFS_P2_BOUND_CORES",
+    "FIL_PROOFS_PARAMETER_CACHE",
+    "FIL_PROOFS_PARENT_CACHE",
+    "FIL_PROOFS_PARENT_CACHE_PREFETCH",
+    "FIL_PROOFS_PREFETCH_DEPTH",
+    "FIL_PROOFS_ROWS_TO_DISCARD",
+    "FIL_PROOFS_SDR_PARENTS_CACHE_SIZE",
+    "FIL_PROOFS_SECTOR_STORE_STAGING",
+    "FIL_PROOFS_SHA_IMPL",
+    "FIL_PROOFS_THROTTLE",
+    "FIL_PROOFS_TREE_PER_GPU",
+    "FIL_PROOFS_USE_GPU_COLUMN_BUILDER",
+    "FIL_PROOFS_USE_GPU_TREE_BUILDER",
+    "FIL_PROOFS_USE_GPU_TREE_D_BUILDER",
+    "FIL_PROOFS_USE_MULTICORE_SDR",
+    "FIL_PROOFS_VERIFY_CACHE",
+    "FIL_PROOFS_VERIFY_PRODUCTION_PARAMS",
+    "FIL_PROOFS_WINDOW_POST_SYNTHESIS_NUM_CPUS",
+    "FIL_PROOFS_WORKER_STACK_BYTES",
+];
+
+/// Splits the `FIL_PROOFS_*` variables among `vars` into those in `FIL_PROOFS_ENV_VARS` and the
+/// others. Variables without the prefix are dropped.
+fn split_fil_proofs_env<I: IntoIterator<Item = (String, String)>>(
+    vars: I,
+) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+    vars.into_iter()
+        .filter(|(name, _)| name.starts_with("FIL_PROOFS_"))
+        .partition(|(name, _)| FIL_PROOFS_ENV_VARS.contains(&name.as_str()))
+}
+
+/// The `FIL_PROOFS_*` variables set in this process which the proofs read, e.g. for a support
+/// request. Variables with the prefix which are not read anywhere, likely typos, are left out and
+/// logged as a warning, see `unrecognized_fil_proofs_env`.
+pub fn dump_fil_proofs_env() -> BTreeMap<String, String> {
+    let (recognized, unrecognized) = split_fil_proofs_env(std::env::vars());
+    for name in unrecognized.keys() {
+        warn!("{} is set, but not a known setting", name);
+    }
+    recognized
+}
+
+/// The `FIL_PROOFS_*` variables set in this process which the proofs do not read.
+pub fn unrecognized_fil_proofs_env() -> BTreeMap<String, String> {
+    split_fil_proofs_env(std::env::vars()).1
+}
+
+/// Renders `vars` one `NAME=value` line each, e.g. the result of `dump_fil_proofs_env`.
+pub fn render_fil_proofs_env(vars: &BTreeMap<String, String>) -> String {
+    vars.iter()
+        .map(|(name, value)| format!("{}={}\n", name, value))
+        .collect()
+}
+
+/// The binding settings, as resolved from the `FIL_PROOFS_*` environment variables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BindingConfig {
+    pub p1_binding_policy: P1BoundPolicy,
+    pub p2_binding_policy: P2BoundPolicy,
+    pub p2_bound_cores: usize,
+    pub p2_binding_use_same_set: bool,
+    pub binding_use_locality: bool,
+    pub core_group_wait: CoreGroupWait,
+    pub min_bind_bytes: u64,
+    pub worker_stack_bytes: Option<usize>,
+    pub numa_node: Option<u32>,
+}
+
+impl BindingConfig {
+    /// Resolves all binding settings from the environment, applying the same defaults as the
+    /// individual accessors.
+    pub fn from_env() -> Self {
+        BindingConfig {
+            p1_binding_policy: p1_binding_policy(),
+            p2_binding_policy: p2_binding_policy(),
+            p2_bound_cores: env_lock_p2_cores(),
+            p2_binding_use_same_set: p2_binding_use_same_set(),
+            binding_use_locality: binding_use_locality(),
+            core_group_wait: core_group_wait_strategy(),
+            min_bind_bytes: min_bind_bytes(),
+            worker_stack_bytes: worker_stack_bytes(),
+            numa_node: forced_numa_node(),
+        }
+    }
+
+    /// Returns the environment variables which `from_env` resolves to this config, e.g. to pass
+    /// the settings on to a child process.
+    pub fn to_env_vars(&self) -> Vec<(String, String)> {
+        let flag = |value: bool| if value { "1" } else { "0" }.to_string();
+        let mut vars = vec![
+            ("FIL_PROOFS_P1_BINDING_POLICY".to_string(), self.p1_binding_policy.to_string()),
+            ("FIL_PROOFS_P2_BINDING_POLICY".to_string(), self.p2_binding_policy.to_string()),
+            ("FIL_PROOFS_P2_BOUND_CORES".to_string(), self.p2_bound_cores.to_string()),
+            ("FIL_PROOFS_P2_BINDING_USE_SAME_SET".to_string(), flag(self.p2_binding_use_same_set)),
+            ("FIL_PROOFS_BINDING_USE_LOCALITY".to_string(), flag(self.binding_use_locality)),
+            ("FIL_PROOFS_CORE_GROUP_WAIT".to_string(), self.core_group_wait.to_string()),
+            ("FIL_PROOFS_MIN_BIND_BYTES".to_string(), self.min_bind_bytes.to_string()),
+        ];
+        if let Some(stack_bytes) = self.worker_stack_bytes {
+            vars.push(("FIL_PROOFS_WORKER_STACK_BYTES".to_string(), stack_bytes.to_string()));
+        }
+        if let Some(node) = self.numa_node {
+            vars.push(("FIL_PROOFS_NUMA_NODE".to_string(), node.to_string()));
+        }
+        vars
+    }
+
+    /// Parses command line style flags on top of the defaults, e.g. for tools wrapping the
+    /// crate. Values take the same form as the environment variables, e.g.
+    /// `--p2-policy Strict --p2-cores 8 --locality`:
+    ///
+    /// * `--p1-policy <policy>`, see `FIL_PROOFS_P1_BINDING_POLICY`
+    /// * `--p2-policy <policy>[:<cores>]`, see `FIL_PROOFS_P2_BINDING_POLICY`
+    /// * `--p2-cores <cores>`, see `FIL_PROOFS_P2_BOUND_CORES`
+    /// * `--same-set`, `--no-same-set`, see `FIL_PROOFS_P2_BINDING_USE_SAME_SET`
+    /// * `--locality`, `--no-locality`, see `FIL_PROOFS_BINDING_USE_LOCALITY`
+    /// * `--core-group-wait <strategy>`, see `FIL_PROOFS_CORE_GROUP_WAIT`
+    /// * `--min-bind-bytes <bytes>`, see `FIL_PROOFS_MIN_BIND_BYTES`
+    /// * `--worker-stack-bytes <bytes>`, see `FIL_PROOFS_WORKER_STACK_BYTES`
+    /// * `--numa-node <node>`, see `FIL_PROOFS_NUMA_NODE`
+    ///
+    /// Values are parsed like in the environment, policies and strategies ignoring case, and
+    /// `--p2-cores` takes precedence over a count in `--p2-policy` wherever it is given. Unlike
+    /// the environment, where invalid values fall back to the default, unknown flags, missing
+    /// and invalid values are errors.
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut config = BindingConfig::default();
+        let mut p2_cores = None;
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let (env_var, switch) = match flag.as_str() {
+                "--p1-policy" => ("FIL_PROOFS_P1_BINDING_POLICY", None),
+                "--p2-policy" => ("FIL_PROOFS_P2_BINDING_POLICY", None),
+                "--p2-cores" => ("FIL_PROOFS_P2_BOUND_CORES", None),
+                "--same-set" => ("FIL_PROOFS_P2_BINDING_USE_SAME_SET", Some("1")),
+                "--no-same-set" => ("FIL_PROOFS_P2_BINDING_USE_SAME_SET", Some("0")),
+                "--locality" => ("FIL_PROOFS_BINDING_USE_LOCALITY", Some("1")),
+                "--no-locality" => ("FIL_PROOFS_BINDING_USE_LOCALITY", Some("0")),
+                "--core-group-wait" => ("FIL_PROOFS_CORE_GROUP_WAIT", None),
+                "--min-bind-bytes" => ("FIL_PROOFS_MIN_BIND_BYTES", None),
+                "--worker-stack-bytes" => ("FIL_PROOFS_WORKER_STACK_BYTES", None),
+                "--numa-node" => ("FIL_PROOFS_NUMA_NODE", None),
+                _ => return Err(format_err!("unknown binding flag: {}", flag)),
+            };
+            let value = match switch {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .ok_or_else(|| format_err!("missing value for {}", flag))?,
+            };
+            // Applied last, so it wins over a count in the P2 policy, as in the environment.
+            if env_var == "FIL_PROOFS_P2_BOUND_CORES" {
+                p2_cores = Some((flag, value));
+                continue;
+            }
+            config
+                .set(env_var, value)
+                .map_err(|err| format_err!("invalid value for {}: {}", flag, err))?;
+        }
+        if let Some((flag, value)) = p2_cores {
+            config
+                .set("FIL_PROOFS_P2_BOUND_CORES", value)
+                .map_err(|err| format_err!("invalid value for {}: {}", flag, err))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Sets the setting configured by the environment variable `env_var` from `value`, which
+    /// takes the same form as in the environment. A count in `FIL_PROOFS_P2_BINDING_POLICY`
+    /// replaces `p2_bound_cores`, so set `FIL_PROOFS_P2_BOUND_CORES` after it to override it.
+    /// Like in the environment, `p2_bound_cores` is limited to the CPUs the process can use.
+    pub fn set(&mut self, env_var: &str, value: &str) -> Result<(), String> {
+        let number = |value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("not a number: {}", value))
+        };
+        match env_var {
+            "FIL_PROOFS_P1_BINDING_POLICY" => {
+                self.p1_binding_policy = parse_p1_binding_policy(value)?
+            }
+            "FIL_PROOFS_P2_BINDING_POLICY" => {
+                let (policy, cores) = parse_p2_binding_policy(value)?;
+                self.p2_binding_policy = policy;
+                if let Some(cores) = cores {
+                    self.p2_bound_cores = clamp_p2_bound_cores(cores);
+                }
+            }
+            "FIL_PROOFS_P2_BOUND_CORES" => {
+                self.p2_bound_cores = clamp_p2_bound_cores(number(value)? as usize)
+            }
+            "FIL_PROOFS_P2_BINDING_USE_SAME_SET" => {
+                self.p2_binding_use_same_set = number(value)? != 0
+            }
+            "FIL_PROOFS_BINDING_USE_LOCALITY" => self.binding_use_locality = number(value)? != 0,
+            "FIL_PROOFS_CORE_GROUP_WAIT" => self.core_group_wait = value.parse()?,
+            "FIL_PROOFS_MIN_BIND_BYTES" => self.min_bind_bytes = number(value)?,
+            "FIL_PROOFS_WORKER_STACK_BYTES" => match number(value)? {
+                0 => return Err("the stack size must be positive".to_string()),
+                bytes => self.worker_stack_bytes = Some(bytes as usize),
+            },
+            "FIL_PROOFS_NUMA_NODE" => {
+                let node = value
+                    .parse::<u32>()
+                    .map_err(|_| format!("not a NUMA node: {}", value))?;
+                if !numa_node_exists(node) {
+                    return Err(format!("NUMA node {} does not exist", node));
+                }
+                self.numa_node = Some(node);
+            }
+            _ => return Err(format!("unknown binding setting: {}", env_var)),
+        }
+
+        Ok(())
+    }
+}
+
+/// The settings used when none of the environment variables are set.
+impl Default for BindingConfig {
+    fn default() -> Self {
+        BindingConfig {
+            p1_binding_policy: P1BoundPolicy::Default,
+            p2_binding_policy: P2BoundPolicy::NoBinding,
+            p2_bound_cores: suggested_p2_group_size(),
+            p2_binding_use_same_set: true,
+            binding_use_locality: true,
+            core_group_wait: CoreGroupWait::NoWait,
+            min_bind_bytes: DEFAULT_MIN_BIND_BYTES,
+            worker_stack_bytes: None,
+            numa_node: None,
+        }
+    }
+}
+
+/// The labeling and binding settings of sealing, for applications which configure them in code
+/// rather than through the process environment. Start from `SealSettings::current`, change
+/// fields, and `install` the result:
+///
+/// ```ignore
+/// SealSettings {
+///     multicore_sdr_producers: 5,
+///     ..SealSettings::current()
+/// }
+/// .install();
+/// ```
+///
+/// While installed, the settings replace the corresponding `FIL_PROOFS_*` variables and
+/// `storage_proofs_core::settings` fields for the whole process; the environment is not
+/// consulted for them until `SealSettings::uninstall`. Running seals pick up a change at their
+/// next layer. Core groups are sized with the producer count once, at first use, so install
+/// the settings before the first seal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SealSettings {
+    pub binding: BindingConfig,
+    /// See `FIL_PROOFS_MULTICORE_SDR_PRODUCERS`.
+    pub multicore_sdr_producers: usize,
+    /// See `FIL_PROOFS_MULTICORE_SDR_PRODUCER_STRIDE`.
+    pub multicore_sdr_producer_stride: u64,
+    /// See `FIL_PROOFS_MULTICORE_SDR_LOOKAHEAD`.
+    pub multicore_sdr_lookahead: usize,
+    /// See `FIL_PROOFS_PREFETCH_DEPTH`.
+    pub prefetch_depth: usize,
+    /// See `FIL_PROOFS_LABEL_CHUNK`.
+    pub label_chunk: Option<u64>,
+    /// See `FIL_PROOFS_LABEL_HUGE_PAGES`.
+    pub label_huge_pages: HugePages,
+    /// See `FIL_PROOFS_PARENT_CACHE_PREFETCH`.
+    pub parent_cache_prefetch: bool,
+    /// See `FIL_PROOFS_LOG_BINDING`.
+    pub log_binding: bool,
+}
+
+lazy_static! {
+    static ref SEAL_SETTINGS: RwLock<Option<SealSettings>> = RwLock::new(None);
+}
+
+/// Reads a field of the installed `SealSettings`, `None` if none are installed.
+fn installed_seal_settings<T>(field: impl FnOnce(&SealSettings) -> T) -> Option<T> {
+    SEAL_SETTINGS.read().expect("poisoned lock").as_ref().map(field)
+}
+
+impl SealSettings {
+    /// The settings in effect: the installed ones, or else those resolved from the environment.
+    pub fn current() -> Self {
+        if let Some(settings) = installed_seal_settings(SealSettings::clone) {
+            return settings;
+        }
+        SealSettings {
+            binding: BindingConfig::from_env(),
+            multicore_sdr_producers: SETTINGS.multicore_sdr_producers,
+            multicore_sdr_producer_stride: SETTINGS.multicore_sdr_producer_stride,
+            multicore_sdr_lookahead: SETTINGS.multicore_sdr_lookahead,
+            prefetch_depth: prefetch_depth(),
+            label_chunk: label_chunk(),
+            label_huge_pages: label_huge_pages(),
+            parent_cache_prefetch: parent_cache_prefetch(),
+            log_binding: log_binding(),
+        }
+    }
+
+    /// Checks the settings, e.g. before installing them. The binding fields are validated by
+    /// `BindingConfig::set`, or come from the environment.
+    pub fn validate(&self) -> Result<()> {
+        if self.multicore_sdr_producers == 0 {
+            return Err(format_err!("at least one producer is required"));
+        }
+        if self.multicore_sdr_producer_stride == 0 {
+            return Err(format_err!("the producer stride must be positive"));
+        }
+        if self.multicore_sdr_lookahead == 0 {
+            return Err(format_err!("the lookahead must be positive"));
+        }
+        if self.multicore_sdr_producer_stride > self.multicore_sdr_lookahead as u64 {
+            return Err(format_err!(
+                "the producer stride {} exceeds the lookahead {}",
+                self.multicore_sdr_producer_stride,
+                self.multicore_sdr_lookahead
+            ));
+        }
+        if self.prefetch_depth == 0 {
+            return Err(format_err!("the prefetch depth must be positive"));
+        }
+        match self.label_chunk {
+            Some(0) => return Err(format_err!("the label chunk must be positive")),
+            Some(chunk) if chunk > self.multicore_sdr_lookahead as u64 => {
+                return Err(format_err!(
+                    "the label chunk {} exceeds the lookahead {}",
+                    chunk,
+                    self.multicore_sdr_lookahead
+                ))
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Makes these the settings of the process, replacing any installed before.
+    pub fn install(self) -> Result<()> {
+        self.validate()?;
+        *SEAL_SETTINGS.write().expect("poisoned lock") = Some(self);
+        Ok(())
+    }
+
+    /// Removes the installed settings, so that the environment applies again.
+    pub fn uninstall() {
+        *SEAL_SETTINGS.write().expect("poisoned lock") = None;
+    }
+}
+
+/// Producers labeling a layer, see `SealSettings::multicore_sdr_producers`.
+pub fn multicore_sdr_producers() -> usize {
+    installed_seal_settings(|s| s.multicore_sdr_producers)
+        .unwrap_or(SETTINGS.multicore_sdr_producers)
+}
+
+/// Nodes a producer claims at a time, unless `label_chunk` is set.
+pub fn multicore_sdr_producer_stride() -> u64 {
+    installed_seal_settings(|s| s.multicore_sdr_producer_stride)
+        .unwrap_or(SETTINGS.multicore_sdr_producer_stride)
+}
+
+/// The least number of nodes the producers prepare ahead of the hashing thread.
+pub fn multicore_sdr_lookahead() -> usize {
+    installed_seal_settings(|s| s.multicore_sdr_lookahead)
+        .unwrap_or(SETTINGS.multicore_sdr_lookahead)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_block_ignores_leftover_data() {
+        let replica_id = ReplicaId([7u8; 32]);
+        let layer = 3;
+
+        let mut expected = [0u8; 128];
+        prepare_block(&replica_id, layer, &mut expected);
+
+        let mut dirty = [0xFFu8; 128];
+        prepare_block(&replica_id, layer, &mut dirty);
+
+        assert_eq!(&expected[..], &dirty[..]);
+        assert_eq!(&dirty[..32], &replica_id.0[..]);
+        assert_eq!(dirty[35], layer as u8);
+        assert_eq!(dirty[64], 0x80);
+        assert_eq!(dirty[126], 0x02);
+        assert!(dirty[32..35].iter().all(|&b| b == 0));
+        assert!(dirty[36..64].iter().all(|&b| b == 0));
+        assert!(dirty[65..126].iter().all(|&b| b == 0));
+        assert_eq!(dirty[127], 0);
+    }
+
+    /// A `SlotBuffer` which checks every access and records the requested slots.
+    struct CheckedSlots {
+        inner: RingBuf,
+        accessed: std::sync::Mutex<Vec<usize>>,
+    }
+
+    impl SlotBuffer for CheckedSlots {
+        #[allow(clippy::mut_from_ref)]
+        unsafe fn slot_mut(&self, slot: usize) -> &mut [u8] {
+            assert!(slot < self.inner.num_slots, "slot {} out of range", slot);
+            self.accessed.lock().expect("poisoned lock").push(slot);
+            self.inner.slot_mut(slot)
+        }
+
+        fn release_slot(&self, slot: usize) {
+            self.inner.release_slot(slot)
+        }
+
+        fn num_slots(&self) -> usize {
+            SlotBuffer::num_slots(&self.inner)
+        }
+
+        fn slot_size(&self) -> usize {
+            SlotBuffer::slot_size(&self.inner)
+        }
+    }
+
+    fn fill_slots<B: SlotBuffer>(slots: &B) {
+        for i in 0..slots.num_slots() {
+            let slot = unsafe { slots.slot_mut(i) };
+            assert_eq!(slot.len(), slots.slot_size());
+            memset(slot, i as u8);
+            slots.release_slot(i);
+        }
+    }
+
+    #[test]
+    fn test_slot_buffer_fake() {
+        let mut checked = CheckedSlots {
+            inner: RingBuf::new(16, 4),
+            accessed: Default::default(),
+        };
+        fill_slots(&checked);
+
+        assert_eq!(
+            *checked.accessed.lock().expect("poisoned lock"),
+            vec![0, 1, 2, 3]
+        );
+        for (i, slot) in checked.inner.iter_slot_mut().enumerate() {
+            assert!(slot.iter().all(|&b| b == i as u8));
+        }
+    }
+
+    #[test]
+    fn test_core_group_wait_parse() {
+        assert_eq!("NoWait".parse(), Ok(CoreGroupWait::NoWait));
+        assert_eq!("Spin".parse(), Ok(CoreGroupWait::Spin));
+        assert_eq!("Yield".parse(), Ok(CoreGroupWait::Yield));
+        assert_eq!(
+            "Park".parse(),
+            Ok(CoreGroupWait::Park(Duration::from_micros(DEFAULT_PARK_MICROS)))
+        );
+        assert_eq!(
+            "Park:250".parse(),
+            Ok(CoreGroupWait::Park(Duration::from_micros(250)))
+        );
+        assert!("Park:abc".parse::<CoreGroupWait>().is_err());
+        assert!("Spin:1".parse::<CoreGroupWait>().is_err());
+        assert!("Sleep".parse::<CoreGroupWait>().is_err());
+    }
+
+    #[test]
+    fn test_unsafe_slice_len() {
+        let mut data = vec![0u32; 12];
+        let slice = UnsafeSlice::from_slice(&mut data);
+        assert_eq!(slice.len(), 12);
+        assert!(!slice.is_empty());
+        assert!(format!("{:?}", slice).contains("len: 12"));
+
+        let mut empty: Vec<u32> = Vec::new();
+        let slice = UnsafeSlice::from_slice(&mut empty);
+        assert_eq!(slice.len(), 0);
+        assert!(slice.is_empty());
+    }
+
+    #[test]
+    fn test_prepare_blocks() {
+        let replica_id = ReplicaId([3u8; 32]);
+        let mut bufs = [[0xAAu8; 128]; 4];
+        prepare_blocks(&replica_id, 2..6, &mut bufs);
+
+        for (buf, layer) in bufs.iter().zip(2..6) {
+            let mut expected = [0u8; 128];
+            prepare_block(&replica_id, layer, &mut expected);
+            assert_eq!(&buf[..], &expected[..]);
+        }
+
+        prepare_blocks(&replica_id, 1..1, &mut []);
+    }
+
+    #[test]
+    #[should_panic(expected = "one buffer per layer required")]
+    fn test_prepare_blocks_length_mismatch() {
+        let mut bufs = [[0u8; 128]; 2];
+        prepare_blocks(&ReplicaId([0u8; 32]), 1..4, &mut bufs);
+    }
+
+    #[test]
+    fn test_ring_buf_alignment() {
+        let mut ring_buf = RingBuf::new(128, 5);
+        for i in 0..5 {
+            let slot = unsafe { ring_buf.slot_mut(i) };
+            assert_eq!(slot.as_ptr() as usize % RING_BUF_ALIGNMENT, 0);
+            assert!(slot.iter().all(|&b| b == 0));
+        }
+        assert_eq!(ring_buf.iter_slot_mut().count(), 5);
+
+        let mut empty = RingBuf::new(128, 0);
+        assert_eq!(empty.iter_slot_mut().count(), 0);
+    }
+
+    #[test]
+    fn test_fil_proofs_env_vars_sorted() {
+        for pair in FIL_PROOFS_ENV_VARS.windows(2) {
+            assert!(pair[0] < pair[1], "{} is not sorted before {}", pair[0], pair[1]);
+        }
+        // Everything `BindingConfig` resolves is known.
+        for (name, _) in BindingConfig::default().to_env_vars() {
+            assert!(FIL_PROOFS_ENV_VARS.contains(&name.as_str()), "{} is missing", name);
+        }
+    }
+
+    #[test]
+    fn test_grant_labeling_threads() {
+        assert_eq!(grant_labeling_threads(4, 100, None), Some(4));
+        assert_eq!(grant_labeling_threads(4, 0, Some(8)), Some(4));
+        // Fewer producers than configured once the limit is nearly used up.
+        assert_eq!(grant_labeling_threads(4, 5, Some(8)), Some(3));
+        assert_eq!(grant_labeling_threads(4, 6, Some(8)), Some(2));
+        assert_eq!(grant_labeling_threads(4, 7, Some(8)), None);
+        // The limit is at least one producer and the hashing thread.
+        assert_eq!(grant_labeling_threads(4, 0, Some(1)), Some(2));
+        assert_eq!(grant_labeling_threads(4, 2, Some(1)), None);
+    }
+
+    #[test]
+    fn test_labeling_threads_over_limit() {
+        let initial = max_labeling_threads();
+        set_max_labeling_threads(Some(2));
+        // The second layer would wait for the first one, and with it for the cores its seal
+        // holds, if it did not go beyond the limit.
+        let first = LabelingThreads::acquire(4);
+        let second = LabelingThreads::acquire(4);
+        assert_eq!(first.count(), 2);
+        assert_eq!(second.count(), 2);
+        set_max_labeling_threads(initial);
+    }
+
+    #[test]
+    fn test_split_fil_proofs_env() {
+        let vars = vec![
+            ("FIL_PROOFS_NUMA_NODE".to_string(), "1".to_string()),
+            ("FIL_PROOFS_NUMA_NOD".to_string(), "1".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+            ("FIL_PROOFS_P2_BOUND_CORES".to_string(), "8".to_string()),
+        ];
+        let (recognized, unrecognized) = split_fil_proofs_env(vars);
+        assert_eq!(
+            recognized.keys().collect::<Vec<_>>(),
+            vec!["FIL_PROOFS_NUMA_NODE", "FIL_PROOFS_P2_BOUND_CORES"]
+        );
+        assert_eq!(unrecognized.keys().collect::<Vec<_>>(), vec!["FIL_PROOFS_NUMA_NOD"]);
+        assert_eq!(
+            render_fil_proofs_env(&recognized),
+            "FIL_PROOFS_NUMA_NODE=1\nFIL_PROOFS_P2_BOUND_CORES=8\n"
+        );
+    }
+
+    #[test]
+    fn test_binding_config_to_env_vars() {
+        let config = BindingConfig {
+            p1_binding_policy: P1BoundPolicy::Core,
+            p2_binding_policy: P2BoundPolicy::Strict,
+            p2_bound_cores: 12,
+            p2_binding_use_same_set: false,
+            binding_use_locality: true,
+            core_group_wait: CoreGroupWait::Park(Duration::from_micros(250)),
+            min_bind_bytes: 4096,
+            worker_stack_bytes: Some(8 << 20),
+            numa_node: Some(1),
+        };
+
+        let vars = config.to_env_vars();
+        let get = |key: &str| {
+            vars.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+                .expect("missing env var")
+        };
+        assert_eq!(get("FIL_PROOFS_P1_BINDING_POLICY"), "Core");
+        assert_eq!(get("FIL_PROOFS_P2_BINDING_POLICY"), "Strict");
+        assert_eq!(get("FIL_PROOFS_P2_BOUND_CORES"), "12");
+        assert_eq!(get("FIL_PROOFS_P2_BINDING_USE_SAME_SET"), "0");
+        assert_eq!(get("FIL_PROOFS_BINDING_USE_LOCALITY"), "1");
+        assert_eq!(get("FIL_PROOFS_CORE_GROUP_WAIT"), "Park:250");
+        assert_eq!(get("FIL_PROOFS_MIN_BIND_BYTES"), "4096");
+        assert_eq!(get("FIL_PROOFS_WORKER_STACK_BYTES"), "8388608");
+        assert_eq!(get("FIL_PROOFS_NUMA_NODE"), "1");
+
+        // The values round-trip through the parsers used by `from_env`.
+        assert_eq!(
+            get("FIL_PROOFS_P1_BINDING_POLICY").parse::<P1BoundPolicy>().ok(),
+            Some(config.p1_binding_policy)
+        );
+        assert_eq!(
+            get("FIL_PROOFS_P2_BINDING_POLICY").parse::<P2BoundPolicy>().ok(),
+            Some(config.p2_binding_policy)
+        );
+        assert_eq!(
+            get("FIL_PROOFS_CORE_GROUP_WAIT").parse::<CoreGroupWait>().ok(),
+            Some(config.core_group_wait)
+        );
+    }
+
+    #[test]
+    fn test_ring_buf_for_workers() {
+        let mut ring_buf = RingBuf::for_workers(64, 3, 4);
+        assert_eq!(ring_buf.iter_slot_mut().count(), 12);
+
+        assert_eq!(RingBuf::slots_for_workers(0, 4), 4);
+        assert_eq!(RingBuf::slots_for_workers(3, 0), 1);
+    }
+
+    #[test]
+    fn test_unsafe_slice_send() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let mut data = vec![0u64; 4];
+        let slice = UnsafeSlice::from_slice(&mut data);
+        assert_send(&slice);
+    }
+
+    #[test]
+    fn test_gather_column() {
+        let layers = (0..3u8)
+            .map(|layer| {
+                (0..4u8)
+                    .flat_map(|node| vec![layer * 16 + node; NODE_SIZE])
+                    .collect::<Vec<u8>>()
+            })
+            .collect::<Vec<_>>();
+        let layers = layers.iter().map(|l| l.as_slice()).collect::<Vec<_>>();
+
+        let mut out = vec![0u8; 3 * NODE_SIZE];
+        gather_column(&layers, 2, &mut out);
+        for (layer, label) in out.chunks(NODE_SIZE).enumerate() {
+            assert!(label.iter().all(|b| *b == layer as u8 * 16 + 2));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "node 4 out of range")]
+    fn test_gather_column_out_of_range() {
+        let layer = vec![0u8; 4 * NODE_SIZE];
+        let mut out = vec![0u8; NODE_SIZE];
+        gather_column(&[&layer], 4, &mut out);
+    }
+
+    #[test]
+    fn test_parse_p2_binding_policy() {
+        assert_eq!(
+            parse_p2_binding_policy("Strict"),
+            Ok((P2BoundPolicy::Strict, None))
+        );
+        assert_eq!(
+            parse_p2_binding_policy("Strict:12"),
+            Ok((P2BoundPolicy::Strict, Some(12)))
+        );
+        assert_eq!(
+            parse_p2_binding_policy("Weak:4"),
+            Ok((P2BoundPolicy::Weak, Some(4)))
+        );
+        assert!(parse_p2_binding_policy("Strict:abc").is_err());
+        assert!(parse_p2_binding_policy("Strict:").is_err());
+        assert!(parse_p2_binding_policy("Loose:12").is_err());
+        assert_eq!(
+            parse_p2_binding_policy("weak:4"),
+            Ok((P2BoundPolicy::Weak, Some(4)))
+        );
+        assert_eq!(parse_p1_binding_policy("processingunit"), Ok(P1BoundPolicy::ProcessingUnit));
+        assert!(parse_p1_binding_policy("Loose").is_err());
+    }
+
+    #[test]
+    fn test_unsafe_slice_into_slice() {
+        let mut data = vec![0u32; 8];
+        let slice = UnsafeSlice::from_slice(&mut data);
+        unsafe {
+            *slice.get_mut(3) = 7;
+        }
+
+        let data = slice.into_slice();
+        data[4] = 9;
+        assert_eq!(data, &[0, 0, 0, 7, 9, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_unsafe_slice_finish() {
+        let mut data = vec![0u32; 4];
+        let slice = UnsafeSlice::from_slice(&mut data);
+        unsafe {
+            *slice.get_mut(2) = 5;
+        }
+
+        let view = slice.finish();
+        assert_eq!(view.len(), 4);
+        assert_eq!(view.get(2), Some(&5));
+        assert_eq!(view.get(4), None);
+        assert_eq!(&view[..], &[0, 0, 5, 0]);
+    }
+
+    #[test]
+    fn test_unsafe_slice_zeroed() {
+        let (data, len) = UnsafeSlice::<u64>::zeroed(16, |slice| {
+            assert!(unsafe { slice.as_slice() }.iter().all(|&v| v == 0));
+            unsafe {
+                *slice.get_mut(5) = 11;
+            }
+            slice.len()
+        });
+        assert_eq!(len, 16);
+        assert_eq!(data.len(), 16);
+        assert_eq!(data[5], 11);
+        assert_eq!(data.iter().filter(|&&v| v == 0).count(), 15);
+
+        let (masks, _) = UnsafeSlice::<BitMask>::zeroed(4, |_| ());
+        assert!(masks.iter().all(|m| *m == BitMask::default()));
+    }
+
+    #[test]
+    fn test_unsafe_slice_write_nt() {
+        // Over-allocate, so there are both aligned and unaligned 32-byte elements.
+        let mut data = vec![[0u8; 32]; 9];
+        let slice = UnsafeSlice::from_slice(&mut data);
+        for i in 0..slice.len() {
+            unsafe { slice.write_nt(i, [i as u8; 32]) };
+        }
+        nt_store_fence();
+
+        for (i, value) in data.iter().enumerate() {
+            assert_eq!(value, &[i as u8; 32]);
+        }
+
+        let mut words = vec![0u32; 4];
+        let slice = UnsafeSlice::from_slice(&mut words);
+        unsafe { slice.write_nt(2, 5) };
+        nt_store_fence();
+        assert_eq!(words, [0, 0, 5, 0]);
+    }
+
+    #[test]
+    fn test_ring_buf_new_on_node() {
+        let mut ring_buf = RingBuf::new_on_node(64, 200, 0);
+        assert_eq!(ring_buf.num_slots(), 200);
+        assert_eq!(ring_buf.data.as_ptr() as usize % NUMA_ALIGNMENT, 0);
+        for slot in ring_buf.iter_slot_mut() {
+            assert!(slot.iter().all(|&b| b == 0));
+            slot[0] = 1;
+        }
+
+        let empty = RingBuf::new_on_node(64, 0, 0);
+        assert_eq!(empty.num_slots(), 0);
+    }
+
+    #[test]
+    fn test_ring_buf_copy_slot_to() {
+        let mut ring_buf = RingBuf::new(64, 3);
+        for (i, slot) in ring_buf.iter_slot_mut().enumerate() {
+            for (j, b) in slot.iter_mut().enumerate() {
+                *b = (i * 64 + j) as u8;
+            }
+        }
+
+        let mut dst = [0u8; 64];
+        unsafe { ring_buf.copy_slot_to(2, &mut dst) };
+        let expected: Vec<u8> = (128..192).map(|b| b as u8).collect();
+        assert_eq!(&dst[..], &expected[..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "slot 3 out of range")]
+    fn test_ring_buf_copy_slot_to_out_of_range() {
+        let ring_buf = RingBuf::new(64, 3);
+        let mut dst = [0u8; 64];
+        unsafe { ring_buf.copy_slot_to(3, &mut dst) };
+    }
+
+    #[test]
+    #[should_panic(expected = "destination must hold exactly one slot")]
+    fn test_ring_buf_copy_slot_to_short_dst() {
+        let ring_buf = RingBuf::new(64, 3);
+        let mut dst = [0u8; 32];
+        unsafe { ring_buf.copy_slot_to(0, &mut dst) };
+    }
+
+    #[test]
+    fn test_ring_buf_from_boxed_slice() {
+        let data: Vec<u8> = (0..192).map(|b| b as u8).collect();
+        let ring_buf = RingBuf::from_boxed_slice(data.into_boxed_slice(), 64);
+        assert_eq!(ring_buf.num_slots(), 3);
+        assert_eq!(ring_buf.slot_size(), 64);
+        assert_eq!(unsafe { ring_buf.slot_mut(1) }[0], 64);
+
+        let empty = RingBuf::from_boxed_slice(Vec::new().into_boxed_slice(), 64);
+        assert_eq!(empty.num_slots(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a multiple of the slot size")]
+    fn test_ring_buf_from_boxed_slice_uneven() {
+        RingBuf::from_boxed_slice(vec![0u8; 100].into_boxed_slice(), 64);
+    }
+
+    #[test]
+    fn test_partition_nodes() {
+        let ranges = partition_nodes(100, 7);
+        assert_eq!(ranges.len(), 7);
+        assert_eq!(ranges[0].start, 0);
+        assert_eq!(ranges[6].end, 100);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        let lens: Vec<usize> = ranges.iter().map(|r| r.len()).collect();
+        let max = lens.iter().max().expect("no ranges");
+        let min = lens.iter().min().expect("no ranges");
+        assert!(max - min <= 1);
+
+        assert_eq!(partition_nodes(3, 5), vec![0..1, 1..2, 2..3, 3..3, 3..3]);
+    }
+
+    #[test]
+    fn test_split_ranges() {
+        let mut data: Vec<u32> = (0..10).collect();
+        let parts = split_ranges(&mut data, &[0..2, 2..2, 4..7, 9..10]);
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], &[0, 1]);
+        assert!(parts[1].is_empty());
+        assert_eq!(parts[2], &[4, 5, 6]);
+        assert_eq!(parts[3], &[9]);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn test_split_ranges_overlapping() {
+        let mut data = vec![0u8; 10];
+        split_ranges(&mut data, &[0..5, 4..6]);
+    }
+
+    #[test]
+    fn test_numa_sub_arenas() {
+        let mut arena = vec![0u8; 10 * NODE_SIZE];
+        // Workers 0 and 2 on node 1, worker 1 on node 0.
+        let worker_nodes = [1, 0, 1];
+        let arenas = numa_sub_arenas(&mut arena, &worker_nodes);
+        assert_eq!(arenas.len(), worker_nodes.len());
+
+        // Each worker's arena is its own range of partition_nodes(10, 3), on its node.
+        for (worker, (arena, range)) in arenas.iter().zip(partition_nodes(10, 3)).enumerate() {
+            assert_eq!(arena.worker, worker);
+            assert_eq!(arena.node, worker_nodes[worker]);
+            assert_eq!(arena.nodes, range);
+            assert_eq!(arena.labels.len(), range.len() * NODE_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_unsafe_slice_chunks_for_workers() {
+        let mut data: Vec<u32> = (0..100).collect();
+        let slice = UnsafeSlice::from_slice(&mut data);
+        let chunks: Vec<&mut [u32]> = slice.chunks_for_workers(7).collect();
+
+        let lens: Vec<usize> = chunks.iter().map(|chunk| chunk.len()).collect();
+        let expected: Vec<usize> = partition_nodes(100, 7).iter().map(|r| r.len()).collect();
+        assert_eq!(lens, expected);
+
+        let mut next = 0;
+        for chunk in chunks {
+            for value in chunk.iter_mut() {
+                assert_eq!(*value, next);
+                *value += 1000;
+                next += 1;
+            }
+        }
+        assert_eq!(next, 100);
+        assert!(data.iter().enumerate().all(|(i, &v)| v == i as u32 + 1000));
+    }
+
+    #[test]
+    fn test_tiny_sector_sizing() {
+        assert_eq!(producers_for_nodes(1, 3), 1);
+        assert_eq!(producers_for_nodes(2, 3), 1);
+        assert_eq!(producers_for_nodes(8, 3), 1);
+        // 2KiB and 8MiB sectors.
+        assert_eq!(producers_for_nodes(64, 3), 1);
+        assert_eq!(producers_for_nodes(1 << 18, 3), 3);
+        assert_eq!(producers_for_nodes(2 * MIN_NODES_PER_PRODUCER + 1, 3), 2);
+        assert_eq!(producers_for_nodes(1 << 20, 3), 3);
+
+        assert_eq!(ring_slots_for_nodes(1, 1024), 1);
+        assert_eq!(ring_slots_for_nodes(2, 1024), 1);
+        assert_eq!(ring_slots_for_nodes(8, 1024), 7);
+        assert_eq!(ring_slots_for_nodes(1 << 20, 1024), 1024);
+
+        // 3 producers * 128 nodes * 4 strides, within the lookahead and the window.
+        assert_eq!(prefetch_slots(3, 128, 4, 2048, 4096), 1536);
+        assert_eq!(prefetch_slots(3, 128, 4, 800, 4096), 800);
+        assert_eq!(prefetch_slots(3, 128, 4, 2048, 1024), 1024);
+        // Never less than one stride, whatever the lookahead.
+        assert_eq!(prefetch_slots(3, 128, 4, 0, 1024), 128);
+        assert_eq!(prefetch_slots(3, 1024, 4, 800, 4096), 1024);
+    }
+
+    #[test]
+    fn test_clamp_label_chunk() {
+        assert_eq!(clamp_label_chunk(128, 1 << 20, 3), 128);
+        // 99 nodes to fill in, 33 per producer.
+        assert_eq!(clamp_label_chunk(128, 100, 3), 33);
+        assert_eq!(clamp_label_chunk(128, 101, 3), 34);
+        assert_eq!(clamp_label_chunk(0, 100, 3), 1);
+        assert_eq!(clamp_label_chunk(128, 1, 3), 1);
+    }
+
+    #[test]
+    fn test_replica_id_try_from() {
+        let bytes = [4u8; 33];
+        assert_eq!(ReplicaId::try_from(&bytes[..32]), Ok(ReplicaId([4u8; 32])));
+        assert_eq!(ReplicaId::try_from(&bytes[..]), Err(ReplicaIdLengthError(33)));
+        assert_eq!(ReplicaId::try_from(&bytes[..31]), Err(ReplicaIdLengthError(31)));
+    }
+
+    #[test]
+    fn test_parse_huge_pages() {
+        assert_eq!(parse_huge_pages("0"), Some(HugePages::Off));
+        assert_eq!(parse_huge_pages("off"), Some(HugePages::Off));
+        assert_eq!(parse_huge_pages("thp"), Some(HugePages::Transparent));
+        assert_eq!(
+            parse_huge_pages("/dev/hugepages1G"),
+            Some(HugePages::Hugetlbfs(PathBuf::from("/dev/hugepages1G")))
+        );
+        assert_eq!(parse_huge_pages("hugepages"), None);
+    }
+
+    #[test]
+    fn test_ring_buf_new_near() {
+        let mut ring_buf = RingBuf::new_near(64, 200, Some(&[CoreIndex(0)]));
+        assert_eq!(ring_buf.num_slots(), 200);
+        assert_eq!(ring_buf.data.as_ptr() as usize % RING_BUF_ALIGNMENT, 0);
+        for slot in ring_buf.iter_slot_mut() {
+            assert!(slot.iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn test_parse_label_poison() {
+        assert_eq!(
+            parse_label_poison(&hex::encode(DEFAULT_LABEL_POISON)),
+            Some(DEFAULT_LABEL_POISON)
+        );
+        assert_eq!(parse_label_poison("deadbeef"), None);
+        assert_eq!(parse_label_poison("not hex"), None);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_poison_labels() {
+        let mut buf = vec![0u8; 4 * NODE_SIZE];
+        poison_labels(&mut buf);
+        buf[NODE_SIZE..2 * NODE_SIZE].copy_from_slice(&[1u8; NODE_SIZE]);
+        debug_assert_labeled(1, &buf[NODE_SIZE..2 * NODE_SIZE]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "label of node 2 read before it was written")]
+    fn test_debug_assert_labeled_panics() {
+        let mut buf = vec![0u8; 4 * NODE_SIZE];
+        poison_labels(&mut buf);
+        debug_assert_labeled(2, &buf[2 * NODE_SIZE..3 * NODE_SIZE]);
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_binding_config_from_args() {
+        let config = BindingConfig::from_args(&[]).expect("no flags");
+        assert_eq!(config, BindingConfig::default());
+
+        let config = BindingConfig::from_args(&args(&[
+            "--p1-policy",
+            "Core",
+            "--p2-policy",
+            "Strict",
+            "--p2-cores",
+            "8",
+            "--no-same-set",
+            "--locality",
+            "--core-group-wait",
+            "Park:250",
+            "--min-bind-bytes",
+            "4096",
+            "--worker-stack-bytes",
+            "8388608",
+            "--numa-node",
+            "0",
+        ]))
+        .expect("valid flags");
+        assert_eq!(
+            config,
+            BindingConfig {
+                p1_binding_policy: P1BoundPolicy::Core,
+                p2_binding_policy: P2BoundPolicy::Strict,
+                p2_bound_cores: 8.min(logical_cpu_count()),
+                p2_binding_use_same_set: false,
+                binding_use_locality: true,
+                core_group_wait: CoreGroupWait::Park(Duration::from_micros(250)),
+                min_bind_bytes: 4096,
+                worker_stack_bytes: Some(8 << 20),
+                numa_node: Some(0),
+            }
+        );
+
+        let config = BindingConfig::from_args(&args(&["--p2-policy", "Weak:12", "--no-locality"]))
+            .expect("valid flags");
+        assert_eq!(config.p2_binding_policy, P2BoundPolicy::Weak);
+        assert_eq!(config.p2_bound_cores, 12.min(logical_cpu_count()));
+        assert!(!config.binding_use_locality);
+
+        // Later flags override earlier ones.
+        let config = BindingConfig::from_args(&args(&["--no-same-set", "--same-set"]))
+            .expect("valid flags");
+        assert!(config.p2_binding_use_same_set);
+
+        // Policies and strategies ignore case, like in the environment.
+        let config = BindingConfig::from_args(&args(&[
+            "--p1-policy",
+            "core",
+            "--p2-policy",
+            "strict",
+            "--core-group-wait",
+            "park:250",
+        ]))
+        .expect("valid flags");
+        assert_eq!(config.p1_binding_policy, P1BoundPolicy::Core);
+        assert_eq!(config.p2_binding_policy, P2BoundPolicy::Strict);
+        assert_eq!(config.core_group_wait, CoreGroupWait::Park(Duration::from_micros(250)));
+
+        // --p2-cores wins over the count in --p2-policy, in either order.
+        for flags in &[
+            ["--p2-cores", "1", "--p2-policy", "Weak:2"],
+            ["--p2-policy", "Weak:2", "--p2-cores", "1"],
+        ] {
+            let config = BindingConfig::from_args(&args(flags)).expect("valid flags");
+            assert_eq!(config.p2_binding_policy, P2BoundPolicy::Weak);
+            assert_eq!(config.p2_bound_cores, 1);
+        }
+
+        // Limited to the available CPUs.
+        let too_many = (logical_cpu_count() + 1).to_string();
+        let config =
+            BindingConfig::from_args(&args(&["--p2-cores", &too_many])).expect("valid flags");
+        assert_eq!(config.p2_bound_cores, logical_cpu_count());
+    }
+
+    #[test]
+    fn test_binding_config_from_args_errors() {
+        let err = |flags: &[&str]| {
+            BindingConfig::from_args(&args(flags))
+                .expect_err("invalid flags")
+                .to_string()
+        };
+        assert_eq!(err(&["--p3-policy", "Strict"]), "unknown binding flag: --p3-policy");
+        assert_eq!(err(&["Strict"]), "unknown binding flag: Strict");
+        assert_eq!(err(&["--p2-cores"]), "missing value for --p2-cores");
+        assert_eq!(
+            err(&["--p2-policy", "Tight"]),
+            "invalid value for --p2-policy: unknown P2 binding policy: Tight"
+        );
+        assert_eq!(
+            err(&["--p1-policy", "Tight"]),
+            "invalid value for --p1-policy: unknown P1 binding policy: Tight"
+        );
+        assert_eq!(
+            err(&["--min-bind-bytes", "lots"]),
+            "invalid value for --min-bind-bytes: not a number: lots"
+        );
+        assert_eq!(
+            err(&["--worker-stack-bytes", "0"]),
+            "invalid value for --worker-stack-bytes: the stack size must be positive"
+        );
+        assert_eq!(
+            err(&["--core-group-wait", "Sleep"]),
+            "invalid value for --core-group-wait: unknown core group wait strategy: Sleep"
+        );
+    }
+
+    #[test]
+    fn test_throttle_level() {
+        for level in &[ThrottleLevel::Off, ThrottleLevel::Light, ThrottleLevel::Heavy] {
+            assert_eq!(level.to_string().parse::<ThrottleLevel>().ok(), Some(*level));
+            assert_eq!(ThrottleLevel::from_u8(*level as u8), *level);
+        }
+        assert!("Medium".parse::<ThrottleLevel>().is_err());
+        assert_eq!(ThrottleLevel::Off.pause(), None);
+        assert!(ThrottleLevel::Light.pause() < ThrottleLevel::Heavy.pause());
+
+        let initial = throttle_level();
+        set_throttle_level(ThrottleLevel::Light);
+        assert_eq!(throttle_level(), ThrottleLevel::Light);
+        set_throttle_level(initial);
+    }
+
+    #[test]
+    fn test_ring_buf_release_slot() {
+        let ring_buf = RingBuf::new(64, 2);
+        for _ in 0..3 {
+            unsafe { ring_buf.slot_mut(0)[0] += 1 };
+            ring_buf.release_slot(0);
+        }
+        unsafe { ring_buf.slot_mut(1)[0] = 1 };
+        assert_eq!(unsafe { ring_buf.slot_mut(0) }[0], 3);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "ring buffer slot 1 claimed twice")]
+    fn test_ring_buf_double_claim_panics() {
+        let ring_buf = RingBuf::new(64, 2);
+        let _first = unsafe { ring_buf.slot_mut(1) };
+        let _second = unsafe { ring_buf.slot_mut(1) };
+    }
+
+    #[test]
+    fn test_ring_buf_swap_slots() {
+        for &slot_size in &[1, 3, 32] {
+            let mut ring_buf = RingBuf::new(slot_size, 4);
+            for (i, slot) in ring_buf.iter_slot_mut().enumerate() {
+                for (j, b) in slot.iter_mut().enumerate() {
+                    *b = (i * 16 + j) as u8;
+                }
+            }
+            let before: Vec<Vec<u8>> = ring_buf.iter_slot_mut().map(|s| s.to_vec()).collect();
+
+            ring_buf.swap_slots(3, 1);
+            ring_buf.swap_slots(2, 2);
+            let after: Vec<Vec<u8>> = ring_buf.iter_slot_mut().map(|s| s.to_vec()).collect();
+            assert_eq!(
+                after,
+                vec![
+                    before[0].clone(),
+                    before[3].clone(),
+                    before[2].clone(),
+                    before[1].clone()
+                ]
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "slot 4 out of range")]
+    fn test_ring_buf_swap_slots_out_of_range() {
+        RingBuf::new(8, 4).swap_slots(0, 4);
+    }
+
+    #[test]
+    fn test_ring_buf_capacity_bytes_checked() {
+        assert_eq!(RingBuf::capacity_bytes_checked(64, 4), Some(256));
+        assert_eq!(RingBuf::capacity_bytes_checked(0, usize::MAX), Some(0));
+        assert_eq!(RingBuf::capacity_bytes_checked(usize::MAX, 1), Some(usize::MAX));
+        assert_eq!(RingBuf::capacity_bytes_checked(usize::MAX, 2), None);
+        assert_eq!(
+            RingBuf::capacity_bytes_checked(usize::MAX / 2, 2),
+            Some(usize::MAX - 1)
+        );
+        assert_eq!(RingBuf::capacity_bytes_checked(usize::MAX / 2 + 1, 2), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ring buffer of 2 slots of")]
+    fn test_ring_buf_capacity_overflow_panics() {
+        RingBuf::new(usize::MAX, 2);
+    }
+
+    #[test]
+    fn test_prepare_block_layer_overflow() {
+        let replica_id = ReplicaId([7u8; 32]);
+        let mut buf = [0u8; 128];
+
+        assert_eq!(try_prepare_block(&replica_id, 255, &mut buf), Ok(()));
+        assert_eq!(buf[35], 255);
+
+        assert_eq!(
+            try_prepare_block(&replica_id, 256, &mut buf),
+            Err(PrepareBlockError::LayerOverflow(256))
+        );
+        // The buffer is left untouched on error.
+        assert_eq!(buf[35], 255);
+    }
+
+    #[test]
+    #[should_panic(expected = "layer 256 does not fit")]
+    fn test_prepare_block_layer_overflow_panics() {
+        let mut buf = [0u8; 128];
+        prepare_block(&ReplicaId([0u8; 32]), 256, &mut buf);
+    }
+
+    #[test]
+    fn test_ring_buf_iter_slot() {
+        let ring_buf = RingBuf::new(64, 3);
+        for i in 0..3 {
+            unsafe { ring_buf.slot_mut(i)[0] = i as u8 + 1 };
+        }
+
+        let firsts = unsafe { ring_buf.iter_slot() }
+            .map(|slot| {
+                assert_eq!(slot.len(), 64);
+                slot[0]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(firsts, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_seal_settings_install() {
+        let settings = SealSettings {
+            binding: BindingConfig {
+                p1_binding_policy: P1BoundPolicy::Core,
+                min_bind_bytes: 4096,
+                ..BindingConfig::default()
+            },
+            multicore_sdr_producers: 5,
+            multicore_sdr_producer_stride: 64,
+            multicore_sdr_lookahead: 400,
+            prefetch_depth: 2,
+            label_chunk: Some(32),
+            label_huge_pages: HugePages::Transparent,
+            parent_cache_prefetch: true,
+            log_binding: true,
+        };
+
+        for invalid in vec![
+            SealSettings {
+                multicore_sdr_producers: 0,
+                ..settings.clone()
+            },
+            SealSettings {
+                multicore_sdr_lookahead: 0,
+                ..settings.clone()
+            },
+            SealSettings {
+                multicore_sdr_producer_stride: 401,
+                ..settings.clone()
+            },
+            SealSettings {
+                label_chunk: Some(401),
+                ..settings.clone()
+            },
+        ] {
+            assert!(invalid.install().is_err());
+        }
+
+        settings.clone().install().expect("valid settings");
+        let current = SealSettings::current();
+        let (policy, bytes, producers, depth, chunk) = (
+            p1_binding_policy(),
+            min_bind_bytes(),
+            multicore_sdr_producers(),
+            prefetch_depth(),
+            label_chunk(),
+        );
+        let (prefetch, log) = (parent_cache_prefetch(), log_binding());
+        SealSettings::uninstall();
+
+        assert_eq!(current, settings);
+        assert_eq!(policy, P1BoundPolicy::Core);
+        assert_eq!(bytes, 4096);
+        assert_eq!(producers, 5);
+        assert_eq!(depth, 2);
+        assert_eq!(chunk, Some(32));
+        assert!(prefetch);
+        assert!(log);
+    }
+}
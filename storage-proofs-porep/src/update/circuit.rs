@@ -0,0 +1,259 @@
+use bellperson::{
+    bls::{Bls12, Fr},
+    gadgets::num::AllocatedNum,
+    Circuit, ConstraintSystem, SynthesisError,
+};
+use filecoin_hashers::{HashFunction, Hasher};
+use generic_array::typenum::{U0, U2};
+use storage_proofs_core::{
+    compound_proof::CircuitComponent,
+    gadgets::{
+        constraint,
+        por::{AuthPath, PoRCircuit},
+        variables::Root,
+    },
+    merkle::{BinaryMerkleTree, MerkleProofTrait, MerkleTreeTrait},
+};
+
+use crate::update::vanilla::{ChallengeProof as VanillaChallengeProof, PublicParams};
+
+type TreeAuthPath<T> = AuthPath<
+    <T as MerkleTreeTrait>::Hasher,
+    <T as MerkleTreeTrait>::Arity,
+    <T as MerkleTreeTrait>::SubTreeArity,
+    <T as MerkleTreeTrait>::TopTreeArity,
+>;
+
+/// Openings of one challenged node.
+pub struct ChallengeProof<Tree: MerkleTreeTrait, G: Hasher> {
+    /// The challenged node of the old replica and its inclusion path in tree_r_old.
+    pub leaf_r_old: Option<Fr>,
+    pub path_r_old: TreeAuthPath<Tree>,
+    /// The challenged data node and its inclusion path in tree_d_new.
+    pub leaf_d_new: Option<Fr>,
+    pub path_d_new: AuthPath<G, U2, U0, U0>,
+    /// The challenged node of the new replica and its inclusion path in tree_r_new.
+    pub leaf_r_new: Option<Fr>,
+    pub path_r_new: TreeAuthPath<Tree>,
+    /// The `rho` of the challenged node.
+    pub rho: Option<Fr>,
+}
+
+// We must manually implement Clone for all types generic over MerkleTreeTrait, see
+// `stacked::circuit::Proof`.
+impl<Tree: MerkleTreeTrait, G: Hasher> Clone for ChallengeProof<Tree, G> {
+    fn clone(&self) -> Self {
+        ChallengeProof {
+            leaf_r_old: self.leaf_r_old,
+            path_r_old: self.path_r_old.clone(),
+            leaf_d_new: self.leaf_d_new,
+            path_d_new: self.path_d_new.clone(),
+            leaf_r_new: self.leaf_r_new,
+            path_r_new: self.path_r_new.clone(),
+            rho: self.rho,
+        }
+    }
+}
+
+impl<Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> ChallengeProof<Tree, G> {
+    pub fn new(proof: &VanillaChallengeProof<Tree, G>, rho: Fr) -> Self {
+        ChallengeProof {
+            leaf_r_old: Some(proof.proof_r_old.leaf().into()),
+            path_r_old: proof.proof_r_old.as_options().into(),
+            leaf_d_new: Some(proof.proof_d_new.leaf().into()),
+            path_d_new: proof.proof_d_new.as_options().into(),
+            leaf_r_new: Some(proof.proof_r_new.leaf().into()),
+            path_r_new: proof.proof_r_new.as_options().into(),
+            rho: Some(rho),
+        }
+    }
+
+    /// Create an empty proof, used in `blank_circuit`s.
+    pub fn empty(pub_params: &PublicParams) -> Self {
+        ChallengeProof {
+            leaf_r_old: None,
+            path_r_old: AuthPath::blank(pub_params.sector_nodes),
+            leaf_d_new: None,
+            path_d_new: AuthPath::blank(pub_params.sector_nodes),
+            leaf_r_new: None,
+            path_r_new: AuthPath::blank(pub_params.sector_nodes),
+            rho: None,
+        }
+    }
+
+    /// Circuit synthesis.
+    pub fn synthesize<CS: ConstraintSystem<Bls12>>(
+        self,
+        mut cs: CS,
+        comm_r_last_old: &AllocatedNum<Bls12>,
+        comm_d_new: &AllocatedNum<Bls12>,
+        comm_r_last_new: &AllocatedNum<Bls12>,
+    ) -> Result<(), SynthesisError> {
+        let ChallengeProof {
+            leaf_r_old,
+            path_r_old,
+            leaf_d_new,
+            path_d_new,
+            leaf_r_new,
+            path_r_new,
+            rho,
+        } = self;
+
+        let leaf_r_old_num = AllocatedNum::alloc(cs.namespace(|| "leaf_r_old"), || {
+            leaf_r_old.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        let leaf_d_new_num = AllocatedNum::alloc(cs.namespace(|| "leaf_d_new"), || {
+            leaf_d_new.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        let leaf_r_new_num = AllocatedNum::alloc(cs.namespace(|| "leaf_r_new"), || {
+            leaf_r_new.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+
+        PoRCircuit::<Tree>::synthesize(
+            cs.namespace(|| "tree_r_old_inclusion"),
+            Root::from_allocated::<CS>(leaf_r_old_num.clone()),
+            path_r_old,
+            Root::from_allocated::<CS>(comm_r_last_old.clone()),
+            true,
+        )?;
+        PoRCircuit::<BinaryMerkleTree<G>>::synthesize(
+            cs.namespace(|| "tree_d_new_inclusion"),
+            Root::from_allocated::<CS>(leaf_d_new_num.clone()),
+            path_d_new,
+            Root::from_allocated::<CS>(comm_d_new.clone()),
+            true,
+        )?;
+        PoRCircuit::<Tree>::synthesize(
+            cs.namespace(|| "tree_r_new_inclusion"),
+            Root::from_allocated::<CS>(leaf_r_new_num.clone()),
+            path_r_new,
+            Root::from_allocated::<CS>(comm_r_last_new.clone()),
+            true,
+        )?;
+
+        // The verifier derives rho from comm_d_new, comm_r_old and the challenge.
+        let rho_num = AllocatedNum::alloc(cs.namespace(|| "rho"), || {
+            rho.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        rho_num.inputize(cs.namespace(|| "rho_input"))?;
+
+        // leaf_r_new = leaf_r_old + leaf_d_new * rho
+        cs.enforce(
+            || "enforce encoding",
+            |lc| lc + leaf_d_new_num.get_variable(),
+            |lc| lc + rho_num.get_variable(),
+            |lc| lc + leaf_r_new_num.get_variable() - leaf_r_old_num.get_variable(),
+        );
+
+        Ok(())
+    }
+}
+
+/// Empty sector update, see `EmptySectorUpdate`.
+pub struct EmptySectorUpdateCircuit<Tree: MerkleTreeTrait, G: Hasher> {
+    pub comm_r_old: Option<Fr>,
+    pub comm_d_new: Option<Fr>,
+    pub comm_r_new: Option<Fr>,
+    pub comm_c: Option<Fr>,
+    pub comm_r_last_old: Option<Fr>,
+    pub comm_r_last_new: Option<Fr>,
+    // one proof per challenge
+    pub challenge_proofs: Vec<ChallengeProof<Tree, G>>,
+}
+
+impl<Tree: MerkleTreeTrait, G: Hasher> Clone for EmptySectorUpdateCircuit<Tree, G> {
+    fn clone(&self) -> Self {
+        EmptySectorUpdateCircuit {
+            comm_r_old: self.comm_r_old,
+            comm_d_new: self.comm_d_new,
+            comm_r_new: self.comm_r_new,
+            comm_c: self.comm_c,
+            comm_r_last_old: self.comm_r_last_old,
+            comm_r_last_new: self.comm_r_last_new,
+            challenge_proofs: self.challenge_proofs.clone(),
+        }
+    }
+}
+
+impl<Tree: MerkleTreeTrait, G: Hasher> CircuitComponent for EmptySectorUpdateCircuit<Tree, G> {
+    type ComponentPrivateInputs = ();
+}
+
+impl<Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> Circuit<Bls12>
+    for EmptySectorUpdateCircuit<Tree, G>
+{
+    fn synthesize<CS: ConstraintSystem<Bls12>>(self, cs: &mut CS) -> Result<(), SynthesisError> {
+        let EmptySectorUpdateCircuit {
+            comm_r_old,
+            comm_d_new,
+            comm_r_new,
+            comm_c,
+            comm_r_last_old,
+            comm_r_last_new,
+            challenge_proofs,
+        } = self;
+
+        // Public inputs: comm_r_old, comm_d_new and comm_r_new
+        let comm_r_old_num = AllocatedNum::alloc(cs.namespace(|| "comm_r_old"), || {
+            comm_r_old.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        comm_r_old_num.inputize(cs.namespace(|| "comm_r_old_input"))?;
+
+        let comm_d_new_num = AllocatedNum::alloc(cs.namespace(|| "comm_d_new"), || {
+            comm_d_new.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        comm_d_new_num.inputize(cs.namespace(|| "comm_d_new_input"))?;
+
+        let comm_r_new_num = AllocatedNum::alloc(cs.namespace(|| "comm_r_new"), || {
+            comm_r_new.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        comm_r_new_num.inputize(cs.namespace(|| "comm_r_new_input"))?;
+
+        let comm_c_num = AllocatedNum::alloc(cs.namespace(|| "comm_c"), || {
+            comm_c.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        let comm_r_last_old_num = AllocatedNum::alloc(cs.namespace(|| "comm_r_last_old"), || {
+            comm_r_last_old.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        let comm_r_last_new_num = AllocatedNum::alloc(cs.namespace(|| "comm_r_last_new"), || {
+            comm_r_last_new.ok_or(SynthesisError::AssignmentMissing)
+        })?;
+
+        // Verify comm_r_old = H(comm_c || comm_r_last_old)
+        let comm_r_old_hash = <Tree::Hasher as Hasher>::Function::hash2_circuit(
+            cs.namespace(|| "H_comm_c_comm_r_last_old"),
+            &comm_c_num,
+            &comm_r_last_old_num,
+        )?;
+        constraint::equal(
+            cs,
+            || "enforce comm_r_old = H(comm_c || comm_r_last_old)",
+            &comm_r_old_num,
+            &comm_r_old_hash,
+        );
+
+        // Verify comm_r_new = H(comm_c || comm_r_last_new)
+        let comm_r_new_hash = <Tree::Hasher as Hasher>::Function::hash2_circuit(
+            cs.namespace(|| "H_comm_c_comm_r_last_new"),
+            &comm_c_num,
+            &comm_r_last_new_num,
+        )?;
+        constraint::equal(
+            cs,
+            || "enforce comm_r_new = H(comm_c || comm_r_last_new)",
+            &comm_r_new_num,
+            &comm_r_new_hash,
+        );
+
+        for (i, challenge_proof) in challenge_proofs.into_iter().enumerate() {
+            challenge_proof.synthesize(
+                cs.namespace(|| format!("challenge_{}", i)),
+                &comm_r_last_old_num,
+                &comm_d_new_num,
+                &comm_r_last_new_num,
+            )?;
+        }
+
+        Ok(())
+    }
+}
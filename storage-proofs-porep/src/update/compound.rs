@@ -0,0 +1,163 @@
+use std::marker::PhantomData;
+
+use anyhow::ensure;
+use bellperson::{
+    bls::{Bls12, Fr},
+    Circuit,
+};
+use filecoin_hashers::Hasher;
+use storage_proofs_core::{
+    compound_proof::{CircuitComponent, CompoundProof},
+    error::Result,
+    gadgets::por::PoRCompound,
+    merkle::{BinaryMerkleTree, MerkleTreeTrait},
+    parameter_cache::{CacheableParameters, ParameterSetMetadata},
+    por,
+    proof::ProofScheme,
+};
+
+use crate::update::{
+    circuit::{ChallengeProof, EmptySectorUpdateCircuit},
+    vanilla::{derive_challenges, phi, rho, EmptySectorUpdate},
+};
+
+pub struct EmptySectorUpdateCompound<Tree: MerkleTreeTrait, G: Hasher> {
+    _t: PhantomData<Tree>,
+    _g: PhantomData<G>,
+}
+
+impl<C: Circuit<Bls12>, P: ParameterSetMetadata, Tree: MerkleTreeTrait, G: Hasher>
+    CacheableParameters<C, P> for EmptySectorUpdateCompound<Tree, G>
+{
+    fn cache_prefix() -> String {
+        format!("empty-sector-update-{}-{}", Tree::display(), G::name())
+    }
+}
+
+impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher>
+    CompoundProof<'a, EmptySectorUpdate<'a, Tree, G>, EmptySectorUpdateCircuit<Tree, G>>
+    for EmptySectorUpdateCompound<Tree, G>
+{
+    fn generate_public_inputs(
+        pub_in: &<EmptySectorUpdate<'a, Tree, G> as ProofScheme<'a>>::PublicInputs,
+        pub_params: &<EmptySectorUpdate<'a, Tree, G> as ProofScheme<'a>>::PublicParams,
+        k: Option<usize>,
+    ) -> Result<Vec<Fr>> {
+        let mut inputs = vec![
+            pub_in.comm_r_old.into(),
+            pub_in.comm_d_new.into(),
+            pub_in.comm_r_new.into(),
+        ];
+
+        let por_params = por::PublicParams {
+            leaves: pub_params.sector_nodes,
+            private: true,
+        };
+
+        let phi = phi::<Tree::Hasher, _>(&pub_in.comm_d_new, &pub_in.comm_r_old);
+        let challenges = derive_challenges(
+            pub_params.sector_nodes,
+            pub_params.challenge_count,
+            &pub_in.comm_r_new,
+            k.unwrap_or(0),
+        );
+        for challenge in challenges {
+            // Inclusion Proofs: the challenged node in tree_r_old, tree_d_new and tree_r_new
+            inputs.extend(generate_inclusion_inputs::<Tree>(
+                &por_params,
+                challenge,
+                k,
+            )?);
+            inputs.extend(generate_inclusion_inputs::<BinaryMerkleTree<G>>(
+                &por_params,
+                challenge,
+                k,
+            )?);
+            inputs.extend(generate_inclusion_inputs::<Tree>(
+                &por_params,
+                challenge,
+                k,
+            )?);
+
+            inputs.push(rho::<Tree::Hasher>(
+                &phi,
+                challenge,
+                pub_params.sector_nodes,
+            ));
+        }
+
+        Ok(inputs)
+    }
+
+    fn circuit(
+        pub_in: &<EmptySectorUpdate<'a, Tree, G> as ProofScheme<'a>>::PublicInputs,
+        _component_private_inputs: <EmptySectorUpdateCircuit<Tree, G> as CircuitComponent>::ComponentPrivateInputs,
+        vanilla_proof: &<EmptySectorUpdate<'a, Tree, G> as ProofScheme<'a>>::Proof,
+        pub_params: &<EmptySectorUpdate<'a, Tree, G> as ProofScheme<'a>>::PublicParams,
+        k: Option<usize>,
+    ) -> Result<EmptySectorUpdateCircuit<Tree, G>> {
+        let challenges = derive_challenges(
+            pub_params.sector_nodes,
+            pub_params.challenge_count,
+            &pub_in.comm_r_new,
+            k.unwrap_or(0),
+        );
+        ensure!(
+            vanilla_proof.challenge_proofs.len() == challenges.len(),
+            "{} challenge proofs for {} challenges",
+            vanilla_proof.challenge_proofs.len(),
+            challenges.len()
+        );
+
+        let phi = phi::<Tree::Hasher, _>(&pub_in.comm_d_new, &pub_in.comm_r_old);
+        let challenge_proofs = vanilla_proof
+            .challenge_proofs
+            .iter()
+            .zip(challenges)
+            .map(|(proof, challenge)| {
+                ChallengeProof::new(
+                    proof,
+                    rho::<Tree::Hasher>(&phi, challenge, pub_params.sector_nodes),
+                )
+            })
+            .collect();
+
+        Ok(EmptySectorUpdateCircuit {
+            comm_r_old: Some(pub_in.comm_r_old.into()),
+            comm_d_new: Some(pub_in.comm_d_new.into()),
+            comm_r_new: Some(pub_in.comm_r_new.into()),
+            comm_c: Some(vanilla_proof.comm_c.into()),
+            comm_r_last_old: Some(vanilla_proof.comm_r_last_old.into()),
+            comm_r_last_new: Some(vanilla_proof.comm_r_last_new.into()),
+            challenge_proofs,
+        })
+    }
+
+    fn blank_circuit(
+        pub_params: &<EmptySectorUpdate<'a, Tree, G> as ProofScheme<'a>>::PublicParams,
+    ) -> EmptySectorUpdateCircuit<Tree, G> {
+        EmptySectorUpdateCircuit {
+            comm_r_old: None,
+            comm_d_new: None,
+            comm_r_new: None,
+            comm_c: None,
+            comm_r_last_old: None,
+            comm_r_last_new: None,
+            challenge_proofs: vec![ChallengeProof::empty(pub_params); pub_params.challenge_count],
+        }
+    }
+}
+
+/// Helper to generate public inputs for inclusion proofs.
+fn generate_inclusion_inputs<Tree: 'static + MerkleTreeTrait>(
+    por_params: &por::PublicParams,
+    challenge: usize,
+    k: Option<usize>,
+) -> Result<Vec<Fr>> {
+    let pub_inputs = por::PublicInputs::<<Tree::Hasher as Hasher>::Domain> {
+        challenge,
+        commitment: None,
+    };
+
+    PoRCompound::<Tree>::generate_public_inputs(&pub_inputs, por_params, k)
+}
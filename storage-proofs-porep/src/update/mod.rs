@@ -0,0 +1,7 @@
+mod circuit;
+mod compound;
+mod vanilla;
+
+pub use circuit::*;
+pub use compound::*;
+pub use vanilla::*;
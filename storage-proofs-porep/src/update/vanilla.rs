@@ -0,0 +1,592 @@
+//! Empty sector update, encoding deal data into the replica of a committed capacity sector
+//! without sealing it again.
+//!
+//! The new replica is the old one with the data added, each data node scaled by `rho`:
+//! `replica_new[i] = replica_old[i] + data[i] * rho(i)`. `rho(i)` is the hash of
+//! `phi = H(comm_d_new || comm_r_old)` and the `RHO_HIGH_BITS` high bits of `i`, so a sector only
+//! has that many distinct `rho`s. The sector keeps its tree_c, so
+//! `comm_r_new = H(comm_c || comm_r_last_new)`. Given the old replica, the data is recovered with
+//! `data[i] = (replica_new[i] - replica_old[i]) / rho(i)`.
+//!
+//! The proof opens challenged nodes of the old replica, the data and the new replica in their
+//! trees and checks the encoding of each. The challenges are derived from `comm_r_new`.
+
+use std::cmp::min;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+use anyhow::{ensure, Context};
+use bellperson::bls::Fr;
+use ff::Field;
+use filecoin_hashers::{Domain, HashFunction, Hasher};
+use fr32::{bytes_into_fr, u64_into_fr};
+use generic_array::typenum::{Unsigned, U0, U2};
+use log::info;
+use merkletree::{merkle::get_merkle_tree_leafs, store::StoreConfig};
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use rayon::prelude::{
+    IndexedParallelIterator, IntoParallelIterator, ParallelIterator, ParallelSlice,
+    ParallelSliceMut,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use storage_proofs_core::{
+    error::Result,
+    merkle::{
+        create_lc_tree, get_base_tree_count, split_config_and_replica, BinaryMerkleTree, LCTree,
+        MerkleProof, MerkleProofTrait, MerkleTreeTrait,
+    },
+    parameter_cache::ParameterSetMetadata,
+    proof::{NoRequirements, ProofScheme},
+    util::NODE_SIZE,
+};
+
+/// Number of high bits of a node index its `rho` is derived from.
+pub const RHO_HIGH_BITS: usize = 7;
+
+/// Sectors of at least this many nodes, 32GiB, are proven with more partitions and challenges.
+const LARGE_SECTOR_NODES: usize = 1 << 30;
+const LARGE_SECTOR_PARTITIONS: usize = 16;
+const LARGE_SECTOR_CHALLENGES: usize = 86;
+const SMALL_SECTOR_CHALLENGES: usize = 10;
+
+/// Number of partitions of the proof of a sector of `sector_nodes`.
+pub fn partition_count(sector_nodes: usize) -> usize {
+    if sector_nodes >= LARGE_SECTOR_NODES {
+        LARGE_SECTOR_PARTITIONS
+    } else {
+        1
+    }
+}
+
+/// Number of challenges per partition of the proof of a sector of `sector_nodes`.
+pub fn challenge_count(sector_nodes: usize) -> usize {
+    if sector_nodes >= LARGE_SECTOR_NODES {
+        LARGE_SECTOR_CHALLENGES
+    } else {
+        SMALL_SECTOR_CHALLENGES
+    }
+}
+
+/// The tree_r_last of a sector of shape `Tree`.
+pub type TreeRLast<Tree> = LCTree<
+    <Tree as MerkleTreeTrait>::Hasher,
+    <Tree as MerkleTreeTrait>::Arity,
+    <Tree as MerkleTreeTrait>::SubTreeArity,
+    <Tree as MerkleTreeTrait>::TopTreeArity,
+>;
+
+/// `phi = H(comm_d_new || comm_r_old)`, which all `rho`s of a sector are derived from.
+pub fn phi<H: Hasher, D: Domain>(comm_d_new: &D, comm_r_old: &H::Domain) -> H::Domain {
+    let comm_d_new: Fr = (*comm_d_new).into();
+    H::Function::hash2(&H::Domain::from(comm_d_new), comm_r_old)
+}
+
+/// The number of bits a node index is shifted right by to get its `rho` high bits.
+fn rho_shift(sector_nodes: usize) -> usize {
+    let node_bits = sector_nodes.trailing_zeros() as usize;
+    node_bits - min(RHO_HIGH_BITS, node_bits)
+}
+
+/// `rho` of `node`, see the module documentation.
+pub fn rho<H: Hasher>(phi: &H::Domain, node: usize, sector_nodes: usize) -> Fr {
+    let high = (node >> rho_shift(sector_nodes)) as u64;
+    H::Function::hash2(phi, &H::Domain::from(u64_into_fr(high))).into()
+}
+
+/// All distinct `rho`s of a sector, indexed by the high bits of the node index.
+fn rhos<H: Hasher>(phi: &H::Domain, sector_nodes: usize) -> Vec<Fr> {
+    let shift = rho_shift(sector_nodes);
+    (0..sector_nodes >> shift)
+        .map(|high| rho::<H>(phi, high << shift, sector_nodes))
+        .collect()
+}
+
+/// Derives the challenges of partition `k` of a sector of `sector_nodes` from `comm_r_new`.
+pub fn derive_challenges<D: Domain>(
+    sector_nodes: usize,
+    challenge_count: usize,
+    comm_r_new: &D,
+    k: usize,
+) -> Vec<usize> {
+    (0..challenge_count)
+        .map(|i| {
+            let j = ((challenge_count * k) + i) as u32;
+
+            let hash = Sha256::new()
+                .chain(comm_r_new.into_bytes())
+                .chain(&j.to_le_bytes())
+                .finalize();
+
+            let big_challenge = BigUint::from_bytes_le(hash.as_ref());
+            (big_challenge % sector_nodes)
+                .to_usize()
+                .expect("`big_challenge` exceeds size of `usize`")
+        })
+        .collect()
+}
+
+/// Builds the tree_r_last of `replica`, the contents of `replica_path`, into the cache of
+/// `tree_r_last_config`, whose size is the one of a base tree.
+pub fn build_tree_r_last<Tree: MerkleTreeTrait>(
+    replica: &[u8],
+    tree_r_last_config: StoreConfig,
+    replica_path: PathBuf,
+) -> Result<TreeRLast<Tree>> {
+    let tree_count = get_base_tree_count::<Tree>();
+    let nodes_count = replica.len() / NODE_SIZE / tree_count;
+    let (configs, replica_config) = split_config_and_replica(
+        tree_r_last_config.clone(),
+        replica_path,
+        nodes_count,
+        tree_count,
+    )?;
+
+    for (i, (config, nodes)) in configs
+        .iter()
+        .zip(replica.chunks(nodes_count * NODE_SIZE))
+        .enumerate()
+    {
+        info!("building base tree_r_last {}/{}", i + 1, tree_count);
+        LCTree::<Tree::Hasher, Tree::Arity, U0, U0>::from_par_iter_with_config(
+            nodes.par_chunks(NODE_SIZE).map(|node| {
+                <Tree::Hasher as Hasher>::Domain::try_from_bytes(node)
+                    .expect("invalid replica node")
+            }),
+            config.clone(),
+        )?;
+    }
+
+    create_lc_tree::<TreeRLast<Tree>>(
+        tree_r_last_config.size.expect("config size failure"),
+        &configs,
+        &replica_config,
+    )
+}
+
+/// Opens the tree_r_last of the replica at `replica_path`, stored in the cache of
+/// `tree_r_last_config`.
+pub fn open_tree_r_last<Tree: MerkleTreeTrait>(
+    tree_r_last_config: &StoreConfig,
+    replica_path: PathBuf,
+) -> Result<TreeRLast<Tree>> {
+    let tree_r_last_size = tree_r_last_config.size.expect("config size failure");
+    let (configs, replica_config) = split_config_and_replica(
+        tree_r_last_config.clone(),
+        replica_path,
+        get_merkle_tree_leafs(tree_r_last_size, Tree::Arity::to_usize())?,
+        get_base_tree_count::<Tree>(),
+    )?;
+
+    create_lc_tree::<TreeRLast<Tree>>(tree_r_last_size, &configs, &replica_config)
+}
+
+#[derive(Debug, Clone)]
+pub struct SetupParams {
+    pub sector_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicParams {
+    pub sector_nodes: usize,
+    /// Number of challenges per partition.
+    pub challenge_count: usize,
+}
+
+impl PublicParams {
+    pub fn from_sector_size(sector_bytes: u64) -> Self {
+        let sector_nodes = sector_bytes as usize / NODE_SIZE;
+        PublicParams {
+            sector_nodes,
+            challenge_count: challenge_count(sector_nodes),
+        }
+    }
+}
+
+impl ParameterSetMetadata for PublicParams {
+    fn identifier(&self) -> String {
+        format!(
+            "empty_sector_update::PublicParams{{ sector_nodes: {}, challenge_count: {}, rho_high_bits: {} }}",
+            self.sector_nodes, self.challenge_count, RHO_HIGH_BITS
+        )
+    }
+
+    fn sector_size(&self) -> u64 {
+        (self.sector_nodes * NODE_SIZE) as u64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublicInputs<T: Domain, S: Domain> {
+    /// Partition index
+    pub k: usize,
+    #[serde(bound = "")]
+    pub comm_r_old: T,
+    #[serde(bound = "")]
+    pub comm_d_new: S,
+    #[serde(bound = "")]
+    pub comm_r_new: T,
+}
+
+#[derive(Debug)]
+pub struct PrivateInputs<'a, Tree: MerkleTreeTrait, G: Hasher> {
+    pub comm_c: <Tree::Hasher as Hasher>::Domain,
+    pub tree_r_old: &'a TreeRLast<Tree>,
+    pub tree_d_new: &'a BinaryMerkleTree<G>,
+    pub tree_r_new: &'a TreeRLast<Tree>,
+    /// Rows to discard of both tree_r_lasts.
+    pub tree_r_rows_to_discard: usize,
+}
+
+/// Openings of one challenged node.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeProof<Tree: MerkleTreeTrait, G: Hasher> {
+    #[serde(bound(
+        serialize = "MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>: Serialize",
+        deserialize = "MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>: Deserialize<'de>"
+    ))]
+    pub proof_r_old: MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+    #[serde(bound(
+        serialize = "MerkleProof<G, U2>: Serialize",
+        deserialize = "MerkleProof<G, U2>: Deserialize<'de>"
+    ))]
+    pub proof_d_new: MerkleProof<G, U2>,
+    #[serde(bound(
+        serialize = "MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>: Serialize",
+        deserialize = "MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>: Deserialize<'de>"
+    ))]
+    pub proof_r_new: MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+}
+
+// We must manually implement Clone for all types generic over MerkleTreeTrait, see
+// `stacked::Proof`.
+impl<Tree: MerkleTreeTrait, G: Hasher> Clone for ChallengeProof<Tree, G> {
+    fn clone(&self) -> Self {
+        Self {
+            proof_r_old: self.proof_r_old.clone(),
+            proof_d_new: self.proof_d_new.clone(),
+            proof_r_new: self.proof_r_new.clone(),
+        }
+    }
+}
+
+impl<Tree: MerkleTreeTrait, G: Hasher> ChallengeProof<Tree, G> {
+    /// Whether the openings are of `challenge` and its new replica node encodes its data into its
+    /// old replica node with `rho`.
+    pub fn verify(&self, challenge: usize, rho: &Fr) -> bool {
+        if !(self.proof_r_old.validate(challenge)
+            && self.proof_d_new.validate(challenge)
+            && self.proof_r_new.validate(challenge))
+        {
+            return false;
+        }
+
+        let mut encoded: Fr = self.proof_d_new.leaf().into();
+        encoded.mul_assign(rho);
+        encoded.add_assign(&self.proof_r_old.leaf().into());
+        let new: Fr = self.proof_r_new.leaf().into();
+
+        encoded == new
+    }
+}
+
+/// Proof of one partition.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartitionProof<Tree: MerkleTreeTrait, G: Hasher> {
+    pub comm_c: <Tree::Hasher as Hasher>::Domain,
+    pub comm_r_last_old: <Tree::Hasher as Hasher>::Domain,
+    pub comm_r_last_new: <Tree::Hasher as Hasher>::Domain,
+    #[serde(bound(
+        serialize = "ChallengeProof<Tree, G>: Serialize",
+        deserialize = "ChallengeProof<Tree, G>: Deserialize<'de>"
+    ))]
+    pub challenge_proofs: Vec<ChallengeProof<Tree, G>>,
+}
+
+impl<Tree: MerkleTreeTrait, G: Hasher> Clone for PartitionProof<Tree, G> {
+    fn clone(&self) -> Self {
+        Self {
+            comm_c: self.comm_c,
+            comm_r_last_old: self.comm_r_last_old,
+            comm_r_last_new: self.comm_r_last_new,
+            challenge_proofs: self.challenge_proofs.clone(),
+        }
+    }
+}
+
+pub struct EmptySectorUpdate<'a, Tree: MerkleTreeTrait, G: Hasher> {
+    _tree: PhantomData<&'a Tree>,
+    _g: PhantomData<G>,
+}
+
+impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> EmptySectorUpdate<'a, Tree, G> {
+    /// Encodes `data` into `replica_old`, writing the result into `replica_new`. All three hold
+    /// a whole sector.
+    pub fn encode(
+        comm_d_new: &G::Domain,
+        comm_r_old: &<Tree::Hasher as Hasher>::Domain,
+        replica_old: &[u8],
+        data: &[u8],
+        replica_new: &mut [u8],
+    ) -> Result<()> {
+        ensure!(
+            replica_old.len() == data.len() && data.len() == replica_new.len(),
+            "replicas and data differ in size"
+        );
+        let sector_nodes = replica_old.len() / NODE_SIZE;
+        let phi = phi::<Tree::Hasher, _>(comm_d_new, comm_r_old);
+        let rhos = rhos::<Tree::Hasher>(&phi, sector_nodes);
+        let shift = rho_shift(sector_nodes);
+
+        replica_new
+            .par_chunks_mut(NODE_SIZE)
+            .zip(
+                replica_old
+                    .par_chunks(NODE_SIZE)
+                    .zip(data.par_chunks(NODE_SIZE)),
+            )
+            .enumerate()
+            .try_for_each(|(node, (new, (old, data)))| -> Result<()> {
+                let mut value = bytes_into_fr(data).context("invalid data node")?;
+                value.mul_assign(&rhos[node >> shift]);
+                value.add_assign(&bytes_into_fr(old).context("invalid replica node")?);
+                <Tree::Hasher as Hasher>::Domain::from(value).write_bytes(new)
+            })
+    }
+
+    /// Recovers the data encoded by `encode` into `data`.
+    pub fn decode(
+        comm_d_new: &G::Domain,
+        comm_r_old: &<Tree::Hasher as Hasher>::Domain,
+        replica_old: &[u8],
+        replica_new: &[u8],
+        data: &mut [u8],
+    ) -> Result<()> {
+        ensure!(
+            replica_old.len() == data.len() && data.len() == replica_new.len(),
+            "replicas and data differ in size"
+        );
+        let sector_nodes = replica_old.len() / NODE_SIZE;
+        let phi = phi::<Tree::Hasher, _>(comm_d_new, comm_r_old);
+        let rho_invs = rhos::<Tree::Hasher>(&phi, sector_nodes)
+            .iter()
+            .map(|rho| rho.inverse().context("rho is not invertible"))
+            .collect::<Result<Vec<_>>>()?;
+        let shift = rho_shift(sector_nodes);
+
+        data.par_chunks_mut(NODE_SIZE)
+            .zip(
+                replica_old
+                    .par_chunks(NODE_SIZE)
+                    .zip(replica_new.par_chunks(NODE_SIZE)),
+            )
+            .enumerate()
+            .try_for_each(|(node, (data, (old, new)))| -> Result<()> {
+                let mut value = bytes_into_fr(new).context("invalid replica node")?;
+                value.sub_assign(&bytes_into_fr(old).context("invalid replica node")?);
+                value.mul_assign(&rho_invs[node >> shift]);
+                <Tree::Hasher as Hasher>::Domain::from(value).write_bytes(data)
+            })
+    }
+}
+
+impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> ProofScheme<'a>
+    for EmptySectorUpdate<'a, Tree, G>
+{
+    type PublicParams = PublicParams;
+    type SetupParams = SetupParams;
+    type PublicInputs = PublicInputs<<Tree::Hasher as Hasher>::Domain, G::Domain>;
+    type PrivateInputs = PrivateInputs<'a, Tree, G>;
+    type Proof = PartitionProof<Tree, G>;
+    type Requirements = NoRequirements;
+
+    fn setup(sp: &Self::SetupParams) -> Result<Self::PublicParams> {
+        Ok(PublicParams::from_sector_size(sp.sector_bytes))
+    }
+
+    fn prove(
+        pub_params: &Self::PublicParams,
+        pub_inputs: &Self::PublicInputs,
+        priv_inputs: &Self::PrivateInputs,
+    ) -> Result<Self::Proof> {
+        let PrivateInputs {
+            comm_c,
+            tree_r_old,
+            tree_d_new,
+            tree_r_new,
+            tree_r_rows_to_discard,
+        } = priv_inputs;
+        ensure!(
+            tree_d_new.root() == pub_inputs.comm_d_new,
+            "comm_d_new does not match tree_d_new"
+        );
+
+        let challenges = derive_challenges(
+            pub_params.sector_nodes,
+            pub_params.challenge_count,
+            &pub_inputs.comm_r_new,
+            pub_inputs.k,
+        );
+        let challenge_proofs = challenges
+            .into_par_iter()
+            .map(|challenge| -> Result<_> {
+                Ok(ChallengeProof {
+                    proof_r_old: tree_r_old
+                        .gen_cached_proof(challenge, Some(*tree_r_rows_to_discard))?,
+                    proof_d_new: tree_d_new.gen_proof(challenge)?,
+                    proof_r_new: tree_r_new
+                        .gen_cached_proof(challenge, Some(*tree_r_rows_to_discard))?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PartitionProof {
+            comm_c: *comm_c,
+            comm_r_last_old: tree_r_old.root(),
+            comm_r_last_new: tree_r_new.root(),
+            challenge_proofs,
+        })
+    }
+
+    fn verify(
+        pub_params: &Self::PublicParams,
+        pub_inputs: &Self::PublicInputs,
+        proof: &Self::Proof,
+    ) -> Result<bool> {
+        let PartitionProof {
+            comm_c,
+            comm_r_last_old,
+            comm_r_last_new,
+            challenge_proofs,
+        } = proof;
+
+        // comm_r = H(comm_c || comm_r_last), the sector keeps its comm_c.
+        if <Tree::Hasher as Hasher>::Function::hash2(comm_c, comm_r_last_old)
+            != pub_inputs.comm_r_old
+            || <Tree::Hasher as Hasher>::Function::hash2(comm_c, comm_r_last_new)
+                != pub_inputs.comm_r_new
+        {
+            return Ok(false);
+        }
+
+        let challenges = derive_challenges(
+            pub_params.sector_nodes,
+            pub_params.challenge_count,
+            &pub_inputs.comm_r_new,
+            pub_inputs.k,
+        );
+        if challenge_proofs.len() != challenges.len() {
+            return Ok(false);
+        }
+
+        let phi = phi::<Tree::Hasher, _>(&pub_inputs.comm_d_new, &pub_inputs.comm_r_old);
+        for (challenge, challenge_proof) in challenges.into_iter().zip(challenge_proofs) {
+            if challenge_proof.proof_r_old.root() != *comm_r_last_old
+                || challenge_proof.proof_d_new.root() != pub_inputs.comm_d_new
+                || challenge_proof.proof_r_new.root() != *comm_r_last_new
+            {
+                return Ok(false);
+            }
+
+            let rho = rho::<Tree::Hasher>(&phi, challenge, pub_params.sector_nodes);
+            if !challenge_proof.verify(challenge, &rho) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn with_partition(pub_in: Self::PublicInputs, k: Option<usize>) -> Self::PublicInputs {
+        PublicInputs {
+            k: k.unwrap_or(0),
+            ..pub_in
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use filecoin_hashers::{poseidon::PoseidonHasher, sha256::Sha256Hasher};
+    use fr32::fr_into_bytes;
+    use generic_array::typenum::U8;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+    use storage_proofs_core::{merkle::DiskTree, TEST_SEED};
+
+    type Tree = DiskTree<PoseidonHasher, U8, U0, U0>;
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let nodes = 1 << 10;
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+        let random_nodes = |rng: &mut XorShiftRng| {
+            (0..nodes)
+                .flat_map(|_| fr_into_bytes(&Fr::random(rng)))
+                .collect::<Vec<u8>>()
+        };
+        let replica_old = random_nodes(rng);
+        let data = random_nodes(rng);
+        let comm_d_new = <Sha256Hasher as Hasher>::Domain::random(rng);
+        let comm_r_old = <PoseidonHasher as Hasher>::Domain::random(rng);
+
+        let mut replica_new = vec![0u8; data.len()];
+        EmptySectorUpdate::<Tree, Sha256Hasher>::encode(
+            &comm_d_new,
+            &comm_r_old,
+            &replica_old,
+            &data,
+            &mut replica_new,
+        )
+        .expect("encode failed");
+        assert_ne!(replica_new, replica_old);
+
+        let mut decoded = vec![0u8; data.len()];
+        EmptySectorUpdate::<Tree, Sha256Hasher>::decode(
+            &comm_d_new,
+            &comm_r_old,
+            &replica_old,
+            &replica_new,
+            &mut decoded,
+        )
+        .expect("decode failed");
+        assert_eq!(decoded, data);
+
+        // A node encodes with the `rho` of its high bits.
+        let phi = phi::<PoseidonHasher, _>(&comm_d_new, &comm_r_old);
+        for node in &[0, 7, 8, nodes / 2, nodes - 1] {
+            let range = node * NODE_SIZE..(node + 1) * NODE_SIZE;
+            let mut expected = bytes_into_fr(&data[range.clone()]).expect("invalid fr");
+            expected.mul_assign(&rho::<PoseidonHasher>(&phi, *node, nodes));
+            expected.add_assign(&bytes_into_fr(&replica_old[range.clone()]).expect("invalid fr"));
+            assert_eq!(
+                bytes_into_fr(&replica_new[range]).expect("invalid fr"),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_rho_high_bits() {
+        let nodes = 1 << 10;
+        let phi =
+            <PoseidonHasher as Hasher>::Domain::random(&mut XorShiftRng::from_seed(TEST_SEED));
+        let rhos = rhos::<PoseidonHasher>(&phi, nodes);
+        assert_eq!(rhos.len(), 1 << RHO_HIGH_BITS);
+        // Nodes sharing their high bits share their rho.
+        assert_eq!(
+            rho::<PoseidonHasher>(&phi, 0, nodes),
+            rho::<PoseidonHasher>(&phi, 7, nodes)
+        );
+        assert_ne!(
+            rho::<PoseidonHasher>(&phi, 7, nodes),
+            rho::<PoseidonHasher>(&phi, 8, nodes)
+        );
+
+        // Small sectors have a rho per node.
+        assert_eq!(rhos::<PoseidonHasher>(&phi, 64).len(), 64);
+    }
+}
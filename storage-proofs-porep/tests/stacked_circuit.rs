@@ -32,33 +32,58 @@ use tempfile::tempdir;
 
 #[test]
 fn test_stacked_porep_circuit_poseidon_base_2() {
-    test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U2, U0, U0>>(22, 1_206_212);
+    test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U2, U0, U0>>(
+        LayerChallenges::new(2, 1),
+        22,
+        1_206_212,
+    );
 }
 
 #[test]
 fn test_stacked_input_circuit_poseidon_base_8() {
-    test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U8, U0, U0>>(22, 1_199_620);
+    test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U8, U0, U0>>(
+        LayerChallenges::new(2, 1),
+        22,
+        1_199_620,
+    );
+}
+
+#[test]
+fn test_stacked_input_circuit_non_interactive_poseidon_base_8() {
+    // The same circuit as for interactive challenges, only its inputs differ.
+    test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U8, U0, U0>>(
+        LayerChallenges::new_non_interactive(2, 1),
+        22,
+        1_199_620,
+    );
 }
 
 #[test]
 fn test_stacked_input_circuit_poseidon_sub_8_4() {
-    test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U8, U4, U0>>(22, 1_296_576);
+    test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U8, U4, U0>>(
+        LayerChallenges::new(2, 1),
+        22,
+        1_296_576,
+    );
 }
 
 #[test]
 fn test_stacked_input_circuit_poseidon_top_8_4_2() {
-    test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U8, U4, U2>>(22, 1_346_982);
+    test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U8, U4, U2>>(
+        LayerChallenges::new(2, 1),
+        22,
+        1_346_982,
+    );
 }
 
 fn test_stacked_porep_circuit<Tree: MerkleTreeTrait + 'static>(
+    layer_challenges: LayerChallenges,
     expected_inputs: usize,
     expected_constraints: usize,
 ) {
     let nodes = 8 * get_base_tree_count::<Tree>();
     let degree = BASE_DEGREE;
     let expansion_degree = EXP_DEGREE;
-    let num_layers = 2;
-    let layer_challenges = LayerChallenges::new(num_layers, 1);
 
     let rng = &mut XorShiftRng::from_seed(TEST_SEED);
 
@@ -0,0 +1,185 @@
+use std::path::Path;
+
+use bellperson::{
+    bls::Fr,
+    util_cs::{metric_cs::MetricCS, test_cs::TestConstraintSystem},
+    Circuit,
+};
+use ff::Field;
+use filecoin_hashers::{
+    poseidon::PoseidonHasher, sha256::Sha256Hasher, Domain, HashFunction, Hasher,
+};
+use fr32::fr_into_bytes;
+use generic_array::typenum::{Unsigned, U0, U2, U8};
+use merkletree::{merkle::get_merkle_tree_len, store::StoreConfig};
+use rand::SeedableRng;
+use rand_xorshift::XorShiftRng;
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    compound_proof::{self, CompoundProof},
+    merkle::{
+        create_base_merkle_tree, get_base_tree_count, BinaryMerkleTree, DiskTree, MerkleTreeTrait,
+    },
+    proof::ProofScheme,
+    test_helper::setup_replica,
+    util::{default_rows_to_discard, NODE_SIZE},
+    TEST_SEED,
+};
+use storage_proofs_porep::update::{
+    build_tree_r_last, EmptySectorUpdate, EmptySectorUpdateCompound, PrivateInputs, PublicInputs,
+    SetupParams, TreeRLast,
+};
+use tempfile::tempdir;
+
+#[test]
+fn test_empty_sector_update_circuit_poseidon_base_8() {
+    test_empty_sector_update_circuit::<DiskTree<PoseidonHasher, U8, U0, U0>>();
+}
+
+#[test]
+fn test_empty_sector_update_circuit_poseidon_sub_8_2() {
+    test_empty_sector_update_circuit::<DiskTree<PoseidonHasher, U8, U2, U0>>();
+}
+
+fn tree_r_last_config<Tree: MerkleTreeTrait>(cache_dir: &Path, nodes: usize) -> StoreConfig {
+    let base_tree_nodes = nodes / get_base_tree_count::<Tree>();
+    let config = StoreConfig::new(
+        cache_dir,
+        CacheKey::CommRLastTree.to_string(),
+        default_rows_to_discard(base_tree_nodes, Tree::Arity::to_usize()),
+    );
+    StoreConfig::from_config(
+        &config,
+        CacheKey::CommRLastTree.to_string(),
+        Some(
+            get_merkle_tree_len(base_tree_nodes, Tree::Arity::to_usize())
+                .expect("invalid tree size"),
+        ),
+    )
+}
+
+fn build_tree<Tree: 'static + MerkleTreeTrait>(
+    cache_dir: &Path,
+    replica: &[u8],
+) -> (StoreConfig, TreeRLast<Tree>) {
+    let nodes = replica.len() / NODE_SIZE;
+    let config = tree_r_last_config::<Tree>(cache_dir, nodes);
+    let replica_path = cache_dir.join("replica-path");
+    let _mmapped_replica = setup_replica(replica, &replica_path);
+    let tree = build_tree_r_last::<Tree>(replica, config.clone(), replica_path)
+        .expect("failed to build tree_r_last");
+    (config, tree)
+}
+
+fn test_empty_sector_update_circuit<Tree: 'static + MerkleTreeTrait>() {
+    let nodes = 64 * get_base_tree_count::<Tree>();
+    let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+    let random_nodes = |rng: &mut XorShiftRng| {
+        (0..nodes)
+            .flat_map(|_| fr_into_bytes(&Fr::random(rng)))
+            .collect::<Vec<u8>>()
+    };
+
+    // The committed capacity sector.
+    let replica_old = random_nodes(rng);
+    let comm_c = <Tree::Hasher as Hasher>::Domain::random(rng);
+    let cache_dir_old = tempdir().expect("tempdir failure");
+    let (config_old, tree_r_old) = build_tree::<Tree>(cache_dir_old.path(), &replica_old);
+    let comm_r_old = <Tree::Hasher as Hasher>::Function::hash2(&comm_c, &tree_r_old.root());
+
+    // The deal data.
+    let data = random_nodes(rng);
+    let tree_d_new = create_base_merkle_tree::<BinaryMerkleTree<Sha256Hasher>>(None, nodes, &data)
+        .expect("failed to build tree_d");
+    let comm_d_new = tree_d_new.root();
+
+    let mut replica_new = vec![0u8; data.len()];
+    EmptySectorUpdate::<Tree, Sha256Hasher>::encode(
+        &comm_d_new,
+        &comm_r_old,
+        &replica_old,
+        &data,
+        &mut replica_new,
+    )
+    .expect("encode failed");
+    let cache_dir_new = tempdir().expect("tempdir failure");
+    let (_config_new, tree_r_new) = build_tree::<Tree>(cache_dir_new.path(), &replica_new);
+    let comm_r_new = <Tree::Hasher as Hasher>::Function::hash2(&comm_c, &tree_r_new.root());
+
+    let setup_params = compound_proof::SetupParams {
+        vanilla_params: SetupParams {
+            sector_bytes: (nodes * NODE_SIZE) as u64,
+        },
+        partitions: Some(1),
+        priority: false,
+    };
+    let pub_params = EmptySectorUpdateCompound::<Tree, Sha256Hasher>::setup(&setup_params)
+        .expect("setup failed");
+    let pub_inputs = PublicInputs {
+        k: 0,
+        comm_r_old,
+        comm_d_new,
+        comm_r_new,
+    };
+    let priv_inputs = PrivateInputs::<Tree, Sha256Hasher> {
+        comm_c,
+        tree_r_old: &tree_r_old,
+        tree_d_new: &tree_d_new,
+        tree_r_new: &tree_r_new,
+        tree_r_rows_to_discard: config_old.rows_to_discard,
+    };
+
+    let proof = EmptySectorUpdate::<Tree, Sha256Hasher>::prove(
+        &pub_params.vanilla_params,
+        &pub_inputs,
+        &priv_inputs,
+    )
+    .expect("vanilla proving failed");
+    assert!(EmptySectorUpdate::<Tree, Sha256Hasher>::verify(
+        &pub_params.vanilla_params,
+        &pub_inputs,
+        &proof
+    )
+    .expect("vanilla verification failed"));
+
+    // The proof does not verify for other data.
+    let wrong_inputs = PublicInputs {
+        comm_d_new: <Sha256Hasher as Hasher>::Domain::random(rng),
+        ..pub_inputs.clone()
+    };
+    assert!(!EmptySectorUpdate::<Tree, Sha256Hasher>::verify(
+        &pub_params.vanilla_params,
+        &wrong_inputs,
+        &proof
+    )
+    .expect("vanilla verification failed"));
+
+    let (circuit, inputs) =
+        EmptySectorUpdateCompound::circuit_for_test(&pub_params, &pub_inputs, &priv_inputs)
+            .expect("circuit_for_test failed");
+    let mut cs = TestConstraintSystem::new();
+    circuit.synthesize(&mut cs).expect("failed to synthesize");
+    if !cs.is_satisfied() {
+        panic!(
+            "failed to satisfy: {:?}",
+            cs.which_is_unsatisfied()
+                .expect("no unsatisfied constraint")
+        );
+    }
+    assert!(
+        cs.verify(&inputs),
+        "verification failed with TestContraintSystem and generated inputs"
+    );
+
+    // The blank circuit has the same shape.
+    let blank_circuit = <EmptySectorUpdateCompound<Tree, Sha256Hasher> as CompoundProof<
+        EmptySectorUpdate<'_, Tree, Sha256Hasher>,
+        _,
+    >>::blank_circuit(&pub_params.vanilla_params);
+    let mut cs_blank = MetricCS::new();
+    blank_circuit
+        .synthesize(&mut cs_blank)
+        .expect("failed to synthesize");
+    assert_eq!(cs_blank.num_inputs(), cs.num_inputs());
+    assert_eq!(cs_blank.num_constraints(), cs.num_constraints());
+}